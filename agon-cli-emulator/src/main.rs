@@ -2,7 +2,7 @@ mod parse_args;
 //use agon_ez80_emulator::debugger;
 //use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, DebuggerConnection, Trigger};
 use crate::parse_args::parse_args;
-use agon_ez80_emulator::{gpio, AgonMachine, AgonMachineConfig, GpioVgaFrame, RamInit, SerialLink};
+use agon_ez80_emulator::{gpio, AgonMachine, AgonMachineConfig, ExitReason, GpioVgaFrame, RamInit, SerialLink};
 use std::io::{self, BufRead, Write};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
@@ -244,6 +244,7 @@ fn main() {
     let soft_reset = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let emulator_shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let exit_status = std::sync::Arc::new(std::sync::atomic::AtomicI32::new(0));
+    let exit_reason = std::sync::Arc::new(std::sync::atomic::AtomicU8::new(ExitReason::None as u8));
     let gpios = std::sync::Arc::new(gpio::GpioSet::new());
     let ez80_paused = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let gpios_ = gpios.clone();
@@ -290,6 +291,7 @@ fn main() {
 
     let _cpu_thread = {
         let _exit_status = exit_status.clone();
+        let _exit_reason = exit_reason.clone();
         let _emulator_shutdown = emulator_shutdown.clone();
         std::thread::spawn(move || {
             let _ez80_paused = ez80_paused.clone();
@@ -306,11 +308,13 @@ fn main() {
                 uart1_link: Box::new(DummySerialLink {}),
                 soft_reset,
                 exit_status: _exit_status,
+                exit_reason: _exit_reason,
                 paused: _ez80_paused,
                 emulator_shutdown: _emulator_shutdown,
                 gpios: gpios_,
                 tx_gpio_vga_frame,
                 interrupt_precision: 16, /* cycles */
+                max_instructions: None,
                 clockspeed_hz: if args.unlimited_cpu {
                     1000_000_000
                 } else {
@@ -357,5 +361,10 @@ fn main() {
         emulator_shutdown.clone(),
     );
 
-    std::process::exit(exit_status.load(std::sync::atomic::Ordering::Relaxed));
+    let status = exit_status.load(std::sync::atomic::Ordering::Relaxed);
+    let reason = ExitReason::from_u8(exit_reason.load(std::sync::atomic::Ordering::Relaxed));
+    if reason.ends_run() {
+        eprintln!("{}", reason.exit_message(status));
+    }
+    std::process::exit(status);
 }
@@ -37,6 +37,27 @@ pub const CMD_REMOVE_WATCHPOINT: u8 = 43;
 pub const CMD_STEP_OVER: u8 = 44;
 pub const CMD_STEP_OUT: u8 = 45;
 
+// Agon extensions (not part of DeZog's DZRP)
+/// Disassemble with the emulator's eZ80 decoder.
+/// Payload: [address (3 bytes), count (2 bytes), mode (1 byte, optional)]
+pub const CMD_DISASSEMBLE: u8 = 0x80;
+
+// Modes for CMD_DISASSEMBLE
+pub const DISASM_MODE_CURRENT: u8 = 0;
+pub const DISASM_MODE_Z80: u8 = 1;
+pub const DISASM_MODE_ADL: u8 = 2;
+
+// Capability flags in the INIT response. A client that understands the
+// pause notification extensions asks for them with the same flags, in a
+// byte after the program name in its INIT; DeZog sends none and gets the
+// standard layout.
+/// CMD_DISASSEMBLE is supported
+pub const CAP_DISASSEMBLE: u8 = 0x01;
+/// Registers and pause notifications carry MBASE
+pub const CAP_MBASE: u8 = 0x02;
+/// Pause notifications carry the call stack
+pub const CAP_CALL_STACK: u8 = 0x04;
+
 // DZRP Notifications (from emulator to DeZog)
 pub const NTF_PAUSE: u8 = 1;
 
@@ -51,6 +72,8 @@ pub const BREAK_REASON_OTHER: u8 = 5;
 pub const BP_TYPE_PROGRAM: u16 = 0;
 pub const BP_TYPE_CONDITION: u16 = 1;
 pub const BP_TYPE_LOG: u16 = 2;
+/// One-shot breakpoint, removed once hit (e.g. for step-over)
+pub const BP_TYPE_TEMPORARY: u16 = 3;
 
 /// A DZRP message received from DeZog
 #[derive(Debug, Clone)]
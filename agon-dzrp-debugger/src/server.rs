@@ -2,7 +2,7 @@
 
 use crate::protocol::*;
 use crate::translator::*;
-use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, PauseReason};
+use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, PauseReason, Reg16, Registers};
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
@@ -11,15 +11,24 @@ use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::time::Duration;
 
+/// Stack slots examined for return addresses when the CPU pauses
+const CALL_STACK_SCAN_SLOTS: u32 = 64;
+/// Most return addresses reported in a pause notification
+const CALL_STACK_MAX_DEPTH: usize = 16;
+/// Candidate return addresses this close together share one memory read
+const CALL_STACK_READ_GAP: u32 = 256;
+
 /// DZRP Server that bridges DeZog IDE to the emulator's debugger
 pub struct DzrpServer {
     tx: Sender<DebugCmd>,
     rx: Receiver<DebugResp>,
     shutdown: Arc<AtomicBool>,
     port: u16,
-    breakpoint_ids: HashMap<u32, u16>, // address -> DZRP breakpoint ID
+    breakpoint_ids: HashMap<u32, (u16, bool)>, // address -> (DZRP breakpoint ID, one-shot)
     next_bp_id: u16,
     last_pc: u32,
+    /// Pause notification extensions the client asked for in INIT
+    client_caps: u8,
 }
 
 impl DzrpServer {
@@ -37,6 +46,7 @@ impl DzrpServer {
             breakpoint_ids: HashMap::new(),
             next_bp_id: 1,
             last_pc: 0,
+            client_caps: 0,
         }
     }
 
@@ -90,6 +100,7 @@ impl DzrpServer {
 
         let mut buffer = vec![0u8; 65536];
         let mut pending_data = Vec::new();
+        self.client_caps = 0;
 
         while !self.shutdown.load(Ordering::Relaxed) {
             // Check for responses from the debugger (async notifications)
@@ -163,9 +174,11 @@ impl DzrpServer {
                         self.last_pc = registers.pc;
                     }
 
-                    // Send notification for pause events
+                    // Send notification for pause events. The emulator
+                    // follows a pause with its state, which gives the PC
+                    // and SP for the call stack, and the addressing mode.
                     if let DebugResp::Paused(reason) = &resp {
-                        let payload = pause_to_notification_payload(reason, self.last_pc);
+                        let payload = self.pause_notification(reason);
                         let notification = create_notification(NTF_PAUSE, &payload);
                         let _ = stream.write_all(&notification);
                     }
@@ -179,10 +192,34 @@ impl DzrpServer {
         }
     }
 
+    /// NTF_PAUSE payload for a pause, from the state that follows it. A
+    /// one-shot breakpoint that caused the pause is forgotten, as the
+    /// emulator has removed it.
+    fn pause_notification(&mut self, reason: &PauseReason) -> Vec<u8> {
+        let (pc, call_stack, adl, mbase) = match self.wait_for_state() {
+            Some(registers) => {
+                if matches!(reason, PauseReason::DebuggerBreakpoint)
+                    && self.breakpoint_ids.get(&registers.pc).is_some_and(|&(_, once)| once)
+                {
+                    self.breakpoint_ids.remove(&registers.pc);
+                }
+                let call_stack = if self.client_caps & CAP_CALL_STACK != 0 {
+                    self.walk_call_stack(&registers)
+                } else {
+                    vec![]
+                };
+                (registers.pc, call_stack, registers.adl, registers.mbase)
+            }
+            None => (self.last_pc, vec![], false, 0),
+        };
+        pause_to_notification_payload(reason, pc, &call_stack, adl, mbase, self.client_caps)
+    }
+
     /// Handle a DZRP message and return the response
     fn handle_message(&mut self, msg: &DzrpMessage) -> Option<Vec<u8>> {
         match msg.cmd_id {
             CMD_INIT => {
+                self.client_caps = init_client_caps(&msg.payload);
                 let payload = create_init_response();
                 Some(msg.response(payload))
             }
@@ -211,7 +248,8 @@ impl DzrpServer {
                 };
 
                 // Store mapping
-                self.breakpoint_ids.insert(address, bp_id);
+                let once = read_u16_le(&msg.payload, 2) == BP_TYPE_TEMPORARY;
+                self.breakpoint_ids.insert(address, (bp_id, once));
 
                 // Send to debugger
                 if let Some(cmds) = dzrp_to_debug_cmd(msg) {
@@ -228,6 +266,12 @@ impl DzrpServer {
                 Some(msg.response(response))
             }
             CMD_REMOVE_BREAKPOINT => {
+                // Nothing to do for a breakpoint that is gone, e.g. a
+                // one-shot that has fired
+                let address = (msg.payload.len() >= 3).then(|| read_u24_le(&msg.payload, 0));
+                if address.is_some_and(|a| self.breakpoint_ids.remove(&a).is_none()) {
+                    return Some(msg.response(vec![]));
+                }
                 if let Some(cmds) = dzrp_to_debug_cmd(msg) {
                     for cmd in cmds {
                         self.tx.send(cmd).ok();
@@ -306,6 +350,38 @@ impl DzrpServer {
                 }
                 Some(msg.response(vec![]))
             }
+            CMD_DISASSEMBLE => {
+                let count = read_u16_le(&msg.payload, 3) as usize;
+                if let Some(cmds) = dzrp_to_debug_cmd(msg) {
+                    for cmd in cmds {
+                        self.tx.send(cmd).ok();
+                    }
+                    if let Some(resp) = self.wait_for_response() {
+                        if let Some(payload) = disassembly_to_dzrp(&resp, count) {
+                            return Some(msg.response(payload));
+                        }
+                    }
+                }
+                Some(msg.response(vec![]))
+            }
+            CMD_READ_STATE => Some(msg.response(self.read_state().unwrap_or_default())),
+            CMD_WRITE_STATE => {
+                if let Err(e) = self.write_state(&msg.payload) {
+                    eprintln!("DZRP: WRITE_STATE failed: {}", e);
+                }
+                Some(msg.response(vec![]))
+            }
+            CMD_SET_BORDER
+            | CMD_SET_SLOT
+            | CMD_GET_TBBLUE_REG
+            | CMD_GET_SPRITES_PALETTE
+            | CMD_GET_SPRITES_CLIP
+            | CMD_GET_SPRITES
+            | CMD_GET_SPRITE_PATTERNS => {
+                // Intentional no-ops: ZX Spectrum Next features that DeZog
+                // probes, with no Agon equivalent. Succeed silently.
+                Some(msg.response(zx_noop_response(msg)))
+            }
             _ => {
                 // Unknown command - return empty response
                 eprintln!("DZRP: Unknown command 0x{:02x}", msg.cmd_id);
@@ -314,6 +390,135 @@ impl DzrpServer {
         }
     }
 
+    /// Wait for the next State response, skipping others
+    fn wait_for_state(&mut self) -> Option<Registers> {
+        loop {
+            if let DebugResp::State { registers, .. } = self.wait_for_response()? {
+                return Some(registers);
+            }
+        }
+    }
+
+    /// Best-effort call stack: scan up from SP for values that point just
+    /// after a CALL or RST instruction
+    fn walk_call_stack(&mut self, registers: &Registers) -> Vec<u32> {
+        // 24-bit stack entries in ADL mode; 16-bit within MBASE otherwise
+        let (sp, slot) = if registers.adl {
+            (registers.get24(Reg16::SP), 3)
+        } else {
+            (registers.get16_mbase(Reg16::SP), 2)
+        };
+        let segment = registers.pc & 0xff0000;
+
+        self.tx
+            .send(DebugCmd::GetMemory {
+                start: sp,
+                len: CALL_STACK_SCAN_SLOTS * slot,
+            })
+            .ok();
+        let stack = match self.wait_for_response() {
+            Some(DebugResp::Memory { data, .. }) => data,
+            _ => return vec![],
+        };
+
+        let candidates: Vec<u32> = stack
+            .chunks_exact(slot as usize)
+            .map(|entry| {
+                if registers.adl {
+                    read_u24_le(entry, 0)
+                } else {
+                    segment | read_u16_le(entry, 0) as u32
+                }
+            })
+            .filter(|&addr| addr >= 4)
+            .collect();
+
+        // The code before each candidate, read a cluster of nearby
+        // candidates at a time, with every read sent before waiting on any
+        let mut sorted = candidates.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let mut regions: Vec<(u32, u32)> = vec![];
+        for addr in sorted {
+            match regions.last_mut() {
+                Some((_, end)) if addr - 4 <= *end + CALL_STACK_READ_GAP => *end = addr,
+                _ => regions.push((addr - 4, addr)),
+            }
+        }
+        for &(start, end) in &regions {
+            self.tx.send(DebugCmd::GetMemory { start, len: end - start }).ok();
+        }
+        let mut code = Vec::with_capacity(regions.len());
+        for &(start, _) in &regions {
+            match self.wait_for_response() {
+                Some(DebugResp::Memory { data, .. }) => code.push((start, data)),
+                _ => return vec![],
+            }
+        }
+
+        candidates
+            .into_iter()
+            .filter(|&addr| {
+                code.iter()
+                    .find(|(start, data)| addr - 4 >= *start && addr <= *start + data.len() as u32)
+                    .is_some_and(|(start, data)| {
+                        let at = (addr - 4 - start) as usize;
+                        looks_like_return(&data[at..at + 4], registers.adl)
+                    })
+            })
+            .take(CALL_STACK_MAX_DEPTH)
+            .collect()
+    }
+
+    /// Snapshot the machine for READ_STATE: a version byte, the registers
+    /// as `registers_to_dzrp`, then each `STATE_RAM` region in turn
+    fn read_state(&mut self) -> Option<Vec<u8>> {
+        self.tx.send(DebugCmd::GetRegisters).ok()?;
+        let registers = match self.wait_for_response()? {
+            DebugResp::Registers(r) => r,
+            _ => return None,
+        };
+
+        let mut state = vec![STATE_VERSION];
+        state.extend(registers_to_dzrp(&registers));
+        for (start, len) in STATE_RAM {
+            self.tx.send(DebugCmd::GetMemory { start, len }).ok()?;
+            match self.wait_for_response()? {
+                DebugResp::Memory { data, .. } if data.len() == len as usize => state.extend(data),
+                _ => return None,
+            }
+        }
+        Some(state)
+    }
+
+    /// Restore a snapshot taken by `read_state`
+    fn write_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let ram_len: usize = STATE_RAM.iter().map(|&(_, len)| len as usize).sum();
+        if state.len() != 1 + REG_SIZE + ram_len || state[0] != STATE_VERSION {
+            return Err(format!("unrecognised state data ({} bytes)", state.len()));
+        }
+
+        // Start from the live registers, for what DZRP doesn't carry (the
+        // interrupt enables)
+        self.tx.send(DebugCmd::GetRegisters).ok();
+        let mut registers = match self.wait_for_response() {
+            Some(DebugResp::Registers(r)) => r,
+            _ => return Err("no registers from emulator".to_string()),
+        };
+        dzrp_to_registers(&state[1..1 + REG_SIZE], &mut registers);
+        self.tx.send(DebugCmd::SetRegisters(registers)).ok();
+        self.wait_for_pong();
+
+        let mut offset = 1 + REG_SIZE;
+        for (start, len) in STATE_RAM {
+            let data = state[offset..offset + len as usize].to_vec();
+            self.tx.send(DebugCmd::WriteMemory { start, data }).ok();
+            self.wait_for_pong();
+            offset += len as usize;
+        }
+        Ok(())
+    }
+
     /// Wait for a response from the debugger
     fn wait_for_response(&mut self) -> Option<DebugResp> {
         let timeout = Duration::from_secs(5);
@@ -364,3 +569,152 @@ impl DzrpServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Stand-in for the emulator's debugger, with sparse memory
+    fn spawn_mock_emulator(rx: Receiver<DebugCmd>, tx: Sender<DebugResp>) {
+        std::thread::spawn(move || {
+            let mut registers = Registers::new();
+            registers.pc = 0x040123;
+            let mut mem: HashMap<u32, u8> = HashMap::new();
+            for cmd in rx {
+                let resp = match cmd {
+                    DebugCmd::GetRegisters => DebugResp::Registers(registers.clone()),
+                    DebugCmd::SetRegister { reg_index: 0, value } => {
+                        registers.pc = value;
+                        DebugResp::Pong
+                    }
+                    DebugCmd::SetRegisters(r) => {
+                        registers = r;
+                        DebugResp::Pong
+                    }
+                    DebugCmd::GetMemory { start, len } => DebugResp::Memory {
+                        start,
+                        data: (start..start + len)
+                            .map(|a| *mem.get(&a).unwrap_or(&0))
+                            .collect(),
+                    },
+                    DebugCmd::WriteMemory { start, data } => {
+                        for (i, b) in data.iter().enumerate() {
+                            mem.insert(start + i as u32, *b);
+                        }
+                        DebugResp::Pong
+                    }
+                    _ => DebugResp::Pong,
+                };
+                if tx.send(resp).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn request(server: &mut DzrpServer, cmd_id: u8, payload: Vec<u8>) -> Vec<u8> {
+        let msg = DzrpMessage {
+            seq_num: 1,
+            cmd_id,
+            payload,
+        };
+        // strip length and sequence number
+        server.handle_message(&msg).unwrap()[5..].to_vec()
+    }
+
+    #[test]
+    fn test_walk_call_stack_batches_code_reads() {
+        let (tx_cmd, rx_cmd) = mpsc::channel();
+        let (tx_resp, rx_resp) = mpsc::channel();
+        let mut server = DzrpServer::new(tx_cmd, rx_resp, Arc::new(AtomicBool::new(false)), 0);
+
+        // SP -> [0x040104, 0x000002, 0x040110, 0x050000]; CALLs end at
+        // 0x040104 and 0x050000, and an RST ends at 0x040110
+        let mut mem: HashMap<u32, u8> = HashMap::new();
+        let entries = [0x040104u32, 0x000002, 0x040110, 0x050000];
+        for (i, e) in entries.iter().enumerate() {
+            for b in 0..3 {
+                mem.insert(0x0b0000 + 3 * i as u32 + b, (e >> (8 * b)) as u8);
+            }
+        }
+        mem.insert(0x040100, 0xcd);
+        mem.insert(0x04010f, 0xff);
+        mem.insert(0x04fffc, 0xcd);
+
+        let (tx_log, rx_log) = mpsc::channel();
+        std::thread::spawn(move || {
+            for cmd in rx_cmd {
+                if let DebugCmd::GetMemory { start, len } = cmd {
+                    tx_log.send((start, len)).unwrap();
+                    let data = (start..start + len).map(|a| *mem.get(&a).unwrap_or(&0)).collect();
+                    tx_resp.send(DebugResp::Memory { start, data }).unwrap();
+                }
+            }
+        });
+
+        let mut registers = Registers::new();
+        registers.adl = true;
+        registers.set24(Reg16::SP, 0x0b0000);
+        assert_eq!(server.walk_call_stack(&registers), vec![0x040104, 0x040110, 0x050000]);
+
+        // the stack, then one read per cluster of candidates
+        let reads: Vec<(u32, u32)> = rx_log.try_iter().collect();
+        assert_eq!(
+            reads,
+            vec![(0x0b0000, CALL_STACK_SCAN_SLOTS * 3), (0x040100, 0x10), (0x04fffc, 4)]
+        );
+    }
+
+    #[test]
+    fn test_fired_one_shot_breakpoint_is_forgotten() {
+        let (tx_cmd, rx_cmd) = mpsc::channel();
+        let (tx_resp, rx_resp) = mpsc::channel();
+        let mut server = DzrpServer::new(tx_cmd, rx_resp, Arc::new(AtomicBool::new(false)), 0);
+
+        tx_resp.send(DebugResp::Pong).unwrap();
+        request(&mut server, CMD_ADD_BREAKPOINT, vec![7, 0, BP_TYPE_TEMPORARY as u8, 0, 0x10, 0x00, 0x04]);
+        assert_eq!(server.breakpoint_ids.get(&0x040010), Some(&(7, true)));
+
+        let mut registers = Registers::new();
+        registers.pc = 0x040010;
+        tx_resp
+            .send(DebugResp::State {
+                registers,
+                instructions_executed: 0,
+                total_cycles_elapsed: 0,
+                stack: [0; 16],
+                pc_instruction: String::new(),
+            })
+            .unwrap();
+        server.pause_notification(&PauseReason::DebuggerBreakpoint);
+        assert!(server.breakpoint_ids.is_empty());
+
+        // removing it is a no-op, rather than deleting whatever is there now
+        while rx_cmd.try_recv().is_ok() {}
+        request(&mut server, CMD_REMOVE_BREAKPOINT, vec![0x10, 0x00, 0x04]);
+        assert!(rx_cmd.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_read_write_state_restores_machine() {
+        let (tx_cmd, rx_cmd) = mpsc::channel();
+        let (tx_resp, rx_resp) = mpsc::channel();
+        spawn_mock_emulator(rx_cmd, tx_resp);
+        let mut server = DzrpServer::new(tx_cmd, rx_resp, Arc::new(AtomicBool::new(false)), 0);
+
+        request(&mut server, CMD_WRITE_MEM, vec![0x10, 0x00, 0x04, 0x55]);
+        let state = request(&mut server, CMD_READ_STATE, vec![]);
+        assert_eq!(state[0], STATE_VERSION);
+
+        // change memory and PC, then restore
+        request(&mut server, CMD_WRITE_MEM, vec![0x10, 0x00, 0x04, 0xAA]);
+        request(&mut server, CMD_SET_REGISTER, vec![REG_PC, 0x00, 0x00, 0x05]);
+        request(&mut server, CMD_WRITE_STATE, state);
+
+        let mem = request(&mut server, CMD_READ_MEM, vec![0x10, 0x00, 0x04, 0x01, 0x00]);
+        assert_eq!(mem, vec![0x55]);
+        let regs = request(&mut server, CMD_GET_REGISTERS, vec![]);
+        assert_eq!(read_u24_le(&regs, 0), 0x040123);
+    }
+}
@@ -6,10 +6,18 @@ use crate::protocol::*;
 use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, PauseReason, Reg8, Reg16, Registers, Trigger};
 
 /// eZ80 register indices as used in DZRP
-/// The register format for eZ80 is 38 bytes:
+/// The register format for eZ80 is 39 bytes:
 /// PC(3), SP(3), AF(2), BC(3), DE(3), HL(3), IX(3), IY(3),
-/// AF'(2), BC'(3), DE'(3), HL'(3), I(1), R(1), IM(1), ADL(1)
-pub const REG_SIZE: usize = 38;
+/// AF'(2), BC'(3), DE'(3), HL'(3), I(1), R(1), IM(1), ADL(1), MBASE(1)
+pub const REG_SIZE: usize = 39;
+
+/// Format version of READ_STATE/WRITE_STATE data
+/// (version 2 added MBASE to the registers)
+pub const STATE_VERSION: u8 = 2;
+
+/// Memory saved by READ_STATE, as (start, len): external RAM and on-chip
+/// SRAM, where MOS maps them
+pub const STATE_RAM: [(u32, u32); 2] = [(0x40000, 0x80000), (0xB7E000, 0x2000)];
 
 /// Register indices for SET_REGISTER command (extended for eZ80)
 pub const REG_PC: u8 = 0;
@@ -28,6 +36,13 @@ pub const REG_I: u8 = 12;
 pub const REG_R: u8 = 13;
 pub const REG_IM: u8 = 14;
 
+/// Longest eZ80 instruction (prefix, two opcode bytes, displacement and a
+/// 24-bit immediate is at most 6 bytes)
+const MAX_INSTRUCTION_LEN: u32 = 6;
+
+/// Most instructions returned by one CMD_DISASSEMBLE
+pub const DISASM_MAX_COUNT: u16 = 1024;
+
 /// Convert a DZRP command to internal DebugCmd(s)
 /// Returns None if the command is not supported or invalid
 pub fn dzrp_to_debug_cmd(msg: &DzrpMessage) -> Option<Vec<DebugCmd>> {
@@ -94,10 +109,11 @@ pub fn dzrp_to_debug_cmd(msg: &DzrpMessage) -> Option<Vec<DebugCmd>> {
             if msg.payload.len() < 7 {
                 return None;
             }
+            let bp_type = read_u16_le(&msg.payload, 2);
             let address = read_u24_le(&msg.payload, 4);
             let trigger = Trigger {
                 address,
-                once: false,
+                once: bp_type == BP_TYPE_TEMPORARY,
                 actions: vec![
                     DebugCmd::Pause(PauseReason::DebuggerBreakpoint),
                     DebugCmd::GetState,
@@ -117,6 +133,23 @@ pub fn dzrp_to_debug_cmd(msg: &DzrpMessage) -> Option<Vec<DebugCmd>> {
             // Loopback - just echo back, no debug command needed
             None
         }
+        CMD_DISASSEMBLE => {
+            // Payload: [address (3 bytes), count (2 bytes), mode (1 byte, optional)]
+            if msg.payload.len() < 5 {
+                return None;
+            }
+            let start = read_u24_le(&msg.payload, 0);
+            let count = read_u16_le(&msg.payload, 3).min(DISASM_MAX_COUNT) as u32;
+            let adl = match msg.payload.get(5).copied().unwrap_or(DISASM_MODE_CURRENT) {
+                DISASM_MODE_Z80 => Some(false),
+                DISASM_MODE_ADL => Some(true),
+                _ => None,
+            };
+            // Enough bytes for `count` of the longest instructions; the
+            // response is cut down to `count`
+            let end = (start + count.max(1) * MAX_INSTRUCTION_LEN).min(0x1000000);
+            Some(vec![DebugCmd::Disassemble { adl, start, end }])
+        }
         _ => {
             // Unsupported command
             None
@@ -124,7 +157,7 @@ pub fn dzrp_to_debug_cmd(msg: &DzrpMessage) -> Option<Vec<DebugCmd>> {
     }
 }
 
-/// Convert internal registers to DZRP register format (38 bytes for eZ80)
+/// Convert internal registers to DZRP register format (39 bytes for eZ80)
 pub fn registers_to_dzrp(reg: &Registers) -> Vec<u8> {
     let mut data = Vec::with_capacity(REG_SIZE);
 
@@ -157,17 +190,21 @@ pub fn registers_to_dzrp(reg: &Registers) -> Vec<u8> {
     // IY (3 bytes)
     write_u24_le(&mut data, reg.get24(Reg16::IY));
 
-    // AF' (2 bytes) - alternate registers not accessible via ez80 public API, return 0
-    write_u16_le(&mut data, 0);
+    // Alternate registers, read from a copy with them swapped in
+    let mut alt = reg.clone();
+    swap_alternates(&mut alt);
+
+    // AF' (2 bytes)
+    write_u16_le(&mut data, alt.get16(Reg16::AF));
 
     // BC' (3 bytes)
-    write_u24_le(&mut data, 0);
+    write_u24_le(&mut data, alt.get24(Reg16::BC));
 
     // DE' (3 bytes)
-    write_u24_le(&mut data, 0);
+    write_u24_le(&mut data, alt.get24(Reg16::DE));
 
     // HL' (3 bytes)
-    write_u24_le(&mut data, 0);
+    write_u24_le(&mut data, alt.get24(Reg16::HL));
 
     // I (1 byte)
     data.push(reg.get8(Reg8::I));
@@ -175,15 +212,63 @@ pub fn registers_to_dzrp(reg: &Registers) -> Vec<u8> {
     // R (1 byte)
     data.push(reg.get8(Reg8::R));
 
-    // IM (1 byte) - interrupt mode (not accessible via ez80 public API)
-    data.push(0);
+    // IM (1 byte) - interrupt mode
+    data.push(reg.get_interrupt_mode().1);
 
     // ADL (1 byte) - ADL mode flag
     data.push(if reg.adl { 1 } else { 0 });
 
+    // MBASE (1 byte) - upper address byte for 16-bit addresses in Z80 mode
+    data.push(reg.mbase);
+
     data
 }
 
+/// Exchange AF, BC, DE and HL with their alternates
+fn swap_alternates(reg: &mut Registers) {
+    for rr in [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL] {
+        reg.swap(rr);
+    }
+}
+
+/// Apply registers in DZRP format (as from `registers_to_dzrp`) on top of
+/// `reg`, alternates and IM included. Only what DZRP doesn't carry (such
+/// as the interrupt enables) is left as is. Returns false if `data` is too
+/// short.
+pub fn dzrp_to_registers(data: &[u8], reg: &mut Registers) -> bool {
+    if data.len() < REG_SIZE {
+        return false;
+    }
+
+    // ADL first, as it decides how SP is interpreted
+    reg.adl = data[37] != 0;
+    reg.mbase = data[38];
+    reg.pc = read_u24_le(data, 0);
+    let sp = read_u24_le(data, 3);
+    if reg.adl {
+        reg.set24(Reg16::SP, sp);
+    } else {
+        reg.set16(Reg16::SP, sp as u16);
+    }
+    reg.set16(Reg16::AF, read_u16_le(data, 6));
+    reg.set24(Reg16::BC, read_u24_le(data, 8));
+    reg.set24(Reg16::DE, read_u24_le(data, 11));
+    reg.set24(Reg16::HL, read_u24_le(data, 14));
+    reg.set24(Reg16::IX, read_u24_le(data, 17));
+    reg.set24(Reg16::IY, read_u24_le(data, 20));
+    reg.set8(Reg8::I, data[34]);
+    reg.set8(Reg8::R, data[35]);
+    reg.set_interrupt_mode(data[36]);
+
+    swap_alternates(reg);
+    reg.set16(Reg16::AF, read_u16_le(data, 23));
+    reg.set24(Reg16::BC, read_u24_le(data, 25));
+    reg.set24(Reg16::DE, read_u24_le(data, 28));
+    reg.set24(Reg16::HL, read_u24_le(data, 31));
+    swap_alternates(reg);
+    true
+}
+
 /// Convert DebugResp to DZRP response payload
 pub fn debug_resp_to_dzrp(resp: &DebugResp) -> Option<Vec<u8>> {
     match resp {
@@ -205,15 +290,54 @@ pub fn debug_resp_to_dzrp(resp: &DebugResp) -> Option<Vec<u8>> {
         }
         DebugResp::Paused(reason) => {
             // Paused responses are handled as notifications
-            Some(pause_to_notification_payload(reason, 0))
+            Some(pause_to_notification_payload(reason, 0, &[], false, 0, 0))
         }
         _ => None,
     }
 }
 
-/// Convert a PauseReason to NTF_PAUSE notification payload
-pub fn pause_to_notification_payload(reason: &PauseReason, pc: u32) -> Vec<u8> {
-    let mut payload = Vec::with_capacity(4);
+/// Convert a Disassembly response to the CMD_DISASSEMBLE response payload:
+/// [adl (1 byte), count (2 bytes)], then for each instruction
+/// [address (3 bytes), len (1 byte), bytes..., mnemonic_len (1 byte), mnemonic...]
+pub fn disassembly_to_dzrp(resp: &DebugResp, count: usize) -> Option<Vec<u8>> {
+    let (adl, disasm) = match resp {
+        DebugResp::Disassembly { adl, disasm, .. } => (adl, disasm),
+        _ => return None,
+    };
+    let count = count.min(disasm.len());
+    let mut payload = vec![if *adl { 1 } else { 0 }];
+    write_u16_le(&mut payload, count as u16);
+    for inst in &disasm[..count] {
+        write_instruction(&mut payload, inst.loc, &inst.bytes, &inst.asm);
+    }
+    Some(payload)
+}
+
+/// Append one disassembled instruction to a CMD_DISASSEMBLE response
+fn write_instruction(payload: &mut Vec<u8>, address: u32, bytes: &[u8], mnemonic: &str) {
+    write_u24_le(payload, address);
+    payload.push(bytes.len() as u8);
+    payload.extend_from_slice(bytes);
+    let mnemonic = &mnemonic.as_bytes()[..mnemonic.len().min(255)];
+    payload.push(mnemonic.len() as u8);
+    payload.extend_from_slice(mnemonic);
+}
+
+/// Convert a PauseReason to NTF_PAUSE notification payload: the reason,
+/// the PC and an empty reason string.
+/// If the client asked for CAP_CALL_STACK in INIT, the call stack follows:
+/// a count, then that many 24-bit return addresses, innermost first. Last,
+/// for CAP_MBASE, are the ADL flag and MBASE, to place 16-bit addresses
+/// when paused in Z80 mode.
+pub fn pause_to_notification_payload(
+    reason: &PauseReason,
+    pc: u32,
+    call_stack: &[u32],
+    adl: bool,
+    mbase: u8,
+    client_caps: u8,
+) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8 + 3 * call_stack.len());
 
     // Break reason
     let break_reason = match reason {
@@ -221,15 +345,82 @@ pub fn pause_to_notification_payload(reason: &PauseReason, pc: u32) -> Vec<u8> {
         PauseReason::DebuggerBreakpoint => BREAK_REASON_BREAKPOINT,
         PauseReason::IOBreakpoint(_) => BREAK_REASON_OTHER,
         PauseReason::OutOfBoundsMemAccess(_) => BREAK_REASON_OTHER,
+        PauseReason::OutputMatch => BREAK_REASON_OTHER,
     };
     payload.push(break_reason);
 
     // PC (3 bytes LE)
     write_u24_le(&mut payload, pc);
 
+    // Reason string (NUL-terminated), which DeZog reads up to the NUL
+    payload.push(0);
+
+    // Call stack
+    if client_caps & CAP_CALL_STACK != 0 {
+        let depth = call_stack.len().min(255);
+        payload.push(depth as u8);
+        for addr in &call_stack[..depth] {
+            write_u24_le(&mut payload, *addr);
+        }
+    }
+
+    // Addressing mode
+    if client_caps & CAP_MBASE != 0 {
+        payload.push(if adl { 1 } else { 0 });
+        payload.push(mbase);
+    }
+
     payload
 }
 
+/// Whether a value found on the stack is plausibly a return address, given
+/// the 4 bytes of code just before it: a CALL (24-bit operand in ADL mode,
+/// 16-bit otherwise) or an RST must end there.
+pub fn looks_like_return(code_before: &[u8], adl: bool) -> bool {
+    fn is_call(op: u8) -> bool {
+        matches!(op, 0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc)
+    }
+    fn is_rst(op: u8) -> bool {
+        op & 0xc7 == 0xc7
+    }
+
+    if code_before.len() < 4 {
+        return false;
+    }
+    let call_at = if adl { 0 } else { 1 };
+    is_call(code_before[call_at]) || is_rst(code_before[3])
+}
+
+/// Response payload for a ZX Spectrum Next command that the Agon has no
+/// equivalent for: success, with zeroed data of the size DeZog expects
+pub fn zx_noop_response(msg: &DzrpMessage) -> Vec<u8> {
+    // [index, count] for the sprite queries
+    let count = msg.payload.get(1).copied().unwrap_or(0) as usize;
+    match msg.cmd_id {
+        // error code
+        CMD_SET_SLOT => vec![0],
+        // register value
+        CMD_GET_TBBLUE_REG => vec![0],
+        // 256 9-bit colours, 2 bytes each
+        CMD_GET_SPRITES_PALETTE => vec![0; 512],
+        // xl, xr, yt, yb, control
+        CMD_GET_SPRITES_CLIP => vec![0; 5],
+        // 5 attribute bytes per sprite
+        CMD_GET_SPRITES => vec![0; count * 5],
+        // 256 bytes per pattern
+        CMD_GET_SPRITE_PATTERNS => vec![0; count * 256],
+        // CMD_SET_BORDER
+        _ => vec![],
+    }
+}
+
+/// Extensions a client asked for in its INIT: a flags byte after the
+/// NUL-terminated program name, which follows the 3-byte version
+pub fn init_client_caps(payload: &[u8]) -> u8 {
+    let name_end = payload.iter().skip(3).position(|&b| b == 0);
+    name_end.and_then(|end| payload.get(3 + end + 1)).copied().unwrap_or(0)
+}
+
 /// Create the INIT response payload
 /// Returns machine type info for eZ80
 pub fn create_init_response() -> Vec<u8> {
@@ -250,5 +441,142 @@ pub fn create_init_response() -> Vec<u8> {
     // Number of breakpoints available
     write_u16_le(&mut payload, 255);
 
+    // Agon extensions
+    payload.push(CAP_DISASSEMBLE | CAP_MBASE | CAP_CALL_STACK);
+
     payload
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_return() {
+        // CALL $012345 in ADL mode
+        assert!(looks_like_return(&[0xcd, 0x45, 0x23, 0x01], true));
+        // CALL $1234 in Z80 mode
+        assert!(looks_like_return(&[0x00, 0xcd, 0x34, 0x12], false));
+        // RST $10
+        assert!(looks_like_return(&[0x00, 0x00, 0x00, 0xd7], true));
+        // LD HL,$012345 isn't a call
+        assert!(!looks_like_return(&[0x21, 0x45, 0x23, 0x01], true));
+    }
+
+    #[test]
+    fn test_temporary_breakpoint_is_one_shot() {
+        let msg = |bp_type: u16| DzrpMessage {
+            seq_num: 1,
+            cmd_id: CMD_ADD_BREAKPOINT,
+            payload: vec![1, 0, bp_type as u8, 0, 0x10, 0x00, 0x04],
+        };
+        for (bp_type, once) in [(BP_TYPE_PROGRAM, false), (BP_TYPE_TEMPORARY, true)] {
+            match &dzrp_to_debug_cmd(&msg(bp_type)).unwrap()[..] {
+                [DebugCmd::AddTrigger(t)] => {
+                    assert_eq!(t.address, 0x040010);
+                    assert_eq!(t.once, once);
+                }
+                cmds => panic!("unexpected {:?}", cmds),
+            }
+        }
+    }
+
+    #[test]
+    fn test_registers_carry_mbase() {
+        let mut reg = Registers::new();
+        reg.adl = false;
+        reg.mbase = 0x04;
+        let data = registers_to_dzrp(&reg);
+        assert_eq!(data.len(), REG_SIZE);
+        assert_eq!(&data[37..], &[0, 0x04]);
+
+        let mut restored = Registers::new();
+        assert!(dzrp_to_registers(&data, &mut restored));
+        assert!(!restored.adl);
+        assert_eq!(restored.mbase, 0x04);
+    }
+
+    #[test]
+    fn test_registers_carry_alternates_and_im() {
+        let mut reg = Registers::new();
+        reg.set24(Reg16::HL, 0x123456);
+        swap_alternates(&mut reg);
+        reg.set16(Reg16::AF, 0xa55a);
+        reg.set24(Reg16::BC, 0x040102);
+        reg.set24(Reg16::HL, 0x0abcde);
+        swap_alternates(&mut reg);
+        reg.set_interrupt_mode(2);
+        let data = registers_to_dzrp(&reg);
+        assert_eq!(read_u16_le(&data, 23), 0xa55a);
+        assert_eq!(read_u24_le(&data, 25), 0x040102);
+        assert_eq!(read_u24_le(&data, 31), 0x0abcde);
+        assert_eq!(data[36], 2);
+
+        let mut restored = Registers::new();
+        assert!(dzrp_to_registers(&data, &mut restored));
+        assert_eq!(registers_to_dzrp(&restored), data);
+        assert_eq!(restored.get24(Reg16::HL), 0x123456);
+    }
+
+    #[test]
+    fn test_init_response_advertises_extensions() {
+        let payload = create_init_response();
+        assert_eq!(payload.last(), Some(&(CAP_DISASSEMBLE | CAP_MBASE | CAP_CALL_STACK)));
+    }
+
+    #[test]
+    fn test_init_client_caps() {
+        // DeZog: version, then the program name
+        assert_eq!(init_client_caps(&[3, 0, 0, b'D', b'e', b'Z', b'o', b'g', 0]), 0);
+        assert_eq!(init_client_caps(&[3, 0, 0, b'x', 0, CAP_CALL_STACK]), CAP_CALL_STACK);
+        assert_eq!(init_client_caps(&[3, 0]), 0);
+    }
+
+    #[test]
+    fn test_disassemble_request() {
+        let msg = |payload: Vec<u8>| DzrpMessage {
+            seq_num: 1,
+            cmd_id: CMD_DISASSEMBLE,
+            payload,
+        };
+        for (mode, adl) in [(DISASM_MODE_CURRENT, None), (DISASM_MODE_Z80, Some(false)), (DISASM_MODE_ADL, Some(true))] {
+            match &dzrp_to_debug_cmd(&msg(vec![0x00, 0x00, 0x04, 10, 0, mode])).unwrap()[..] {
+                [DebugCmd::Disassemble { adl: a, start, end }] => {
+                    assert_eq!(*a, adl);
+                    assert_eq!(*start, 0x040000);
+                    assert_eq!(*end, 0x040000 + 10 * MAX_INSTRUCTION_LEN);
+                }
+                cmds => panic!("unexpected {:?}", cmds),
+            }
+        }
+        // mode is optional
+        assert!(matches!(
+            &dzrp_to_debug_cmd(&msg(vec![0x00, 0x00, 0x04, 1, 0])).unwrap()[..],
+            [DebugCmd::Disassemble { adl: None, .. }]
+        ));
+        assert!(dzrp_to_debug_cmd(&msg(vec![0x00, 0x00, 0x04])).is_none());
+    }
+
+    #[test]
+    fn test_write_instruction() {
+        let mut payload = vec![];
+        write_instruction(&mut payload, 0x040010, &[0x5b, 0x21, 0x56, 0x34, 0x12], "ld.lil hl,$123456");
+        let mut expected = vec![0x10, 0x00, 0x04, 5, 0x5b, 0x21, 0x56, 0x34, 0x12, 17];
+        expected.extend_from_slice(b"ld.lil hl,$123456");
+        assert_eq!(payload, expected);
+    }
+
+    #[test]
+    fn test_pause_payload_has_call_stack() {
+        let pause = |caps| {
+            pause_to_notification_payload(&PauseReason::DebuggerBreakpoint, 0x040010, &[0x040100, 0x000200], true, 0, caps)
+        };
+        assert_eq!(
+            pause(CAP_CALL_STACK | CAP_MBASE),
+            vec![BREAK_REASON_BREAKPOINT, 0x10, 0x00, 0x04, 0, 2, 0x00, 0x01, 0x04, 0x00, 0x02, 0x00, 1, 0]
+        );
+        assert_eq!(pause(CAP_MBASE), vec![BREAK_REASON_BREAKPOINT, 0x10, 0x00, 0x04, 0, 1, 0]);
+        // DeZog gets the standard layout
+        assert_eq!(pause(0), vec![BREAK_REASON_BREAKPOINT, 0x10, 0x00, 0x04, 0]);
+    }
+}
@@ -1,7 +1,7 @@
-use crate::{debugger, gpio, gpio_video, i2c, mos, prt_timer, spi_sdcard, uart};
+use crate::{cycle_gate, debugger, gpio, gpio_video, i2c, mos, prt_timer, spi_sdcard, uart};
 use chrono::{Datelike, Timelike};
 use ez80::*;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
@@ -13,6 +13,64 @@ const ONCHIP_RAM_SIZE: u32 = 0x2000; // 8KiB
 pub enum RamInit {
     Zero,
     Random,
+    /// Pseudo-random contents from a fixed seed, for reproducible runs
+    Seeded(u64),
+}
+
+/// Why the machine last stopped or reset, stored in `AgonMachineConfig::exit_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExitReason {
+    None = 0,
+    /// Guest wrote 0 to the emulator shutdown port (IO 0x00)
+    NormalExit = 1,
+    /// Guest wrote a non-zero status to the emulator shutdown port
+    GuestPanic = 2,
+    /// Guest exited with non-zero status after accessing unmapped memory
+    OutOfBoundsMemAccess = 3,
+    /// A soft reset was performed
+    SoftReset = 4,
+    /// The CPU ran its `max_instructions`
+    InstructionLimit = 5,
+}
+
+impl ExitReason {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ExitReason::NormalExit,
+            2 => ExitReason::GuestPanic,
+            3 => ExitReason::OutOfBoundsMemAccess,
+            4 => ExitReason::SoftReset,
+            5 => ExitReason::InstructionLimit,
+            _ => ExitReason::None,
+        }
+    }
+
+    /// Whether the guest (or the instruction limit) ended the run, rather
+    /// than the user or the VDP
+    pub fn ends_run(&self) -> bool {
+        matches!(
+            self,
+            ExitReason::NormalExit
+                | ExitReason::GuestPanic
+                | ExitReason::OutOfBoundsMemAccess
+                | ExitReason::InstructionLimit
+        )
+    }
+
+    /// What to tell the user when the emulator exits with `status`
+    pub fn exit_message(&self, status: i32) -> String {
+        match self {
+            ExitReason::None => "eZ80 stopped".to_string(),
+            ExitReason::NormalExit => "eZ80 exited normally".to_string(),
+            ExitReason::GuestPanic => format!("eZ80 exited with error status {}", status),
+            ExitReason::OutOfBoundsMemAccess => {
+                format!("eZ80 exited with status {} after an out-of-bounds memory access", status)
+            }
+            ExitReason::SoftReset => "eZ80 stopped (last reset was a soft reset)".to_string(),
+            ExitReason::InstructionLimit => "eZ80 stopped at the instruction limit".to_string(),
+        }
+    }
 }
 
 pub struct AgonMachine {
@@ -34,6 +92,7 @@ pub struct AgonMachine {
     soft_reset: Arc<std::sync::atomic::AtomicBool>,
     emulator_shutdown: Arc<std::sync::atomic::AtomicBool>,
     exit_status: Arc<std::sync::atomic::AtomicI32>,
+    exit_reason: Arc<std::sync::atomic::AtomicU8>,
     clockspeed_hz: u64,
     prt_timers: [prt_timer::PrtTimer; 6],
     gpios: Arc<gpio::GpioSet>,
@@ -43,6 +102,19 @@ pub struct AgonMachine {
     // CPU cycles elapsed before evaluating pending interrupts
     // and applying ticks to hardware (PRTs, uarts)
     interrupt_precision: i32,
+    // when set, the CPU only runs cycles granted through the gate (lockstep mode)
+    cycle_gate: Option<Arc<cycle_gate::CycleGate>>,
+    // when set, the PC of each instruction is published here for other threads
+    pc_monitor: Option<Arc<std::sync::atomic::AtomicU32>>,
+    // when set by another thread, the interactive debugger pauses the CPU
+    output_break: Option<Arc<std::sync::atomic::AtomicBool>>,
+    // stop after executing this many instructions
+    max_instructions: Option<u64>,
+    instructions_executed: u64,
+    // read-only debugger requests served whether or not a debugger is attached
+    control: Vec<debugger::DebuggerServer>,
+    // a debugger connection arriving here is attached while running
+    debugger_attach: Option<std::sync::mpsc::Receiver<debugger::DebuggerConnection>>,
 
     // memory map config
     onchip_mem_enable: bool,
@@ -474,6 +546,14 @@ impl Machine for AgonMachine {
                     );
                     self.exit_status
                         .store(value as i32, std::sync::atomic::Ordering::Relaxed);
+                    if value == 0 {
+                        self.set_exit_reason(ExitReason::NormalExit);
+                    } else if self.mem_out_of_bounds.get().is_some() {
+                        // an earlier out-of-bounds access is the more useful explanation
+                        self.set_exit_reason(ExitReason::OutOfBoundsMemAccess);
+                    } else {
+                        self.set_exit_reason(ExitReason::GuestPanic);
+                    }
                     self.emulator_shutdown
                         .store(true, std::sync::atomic::Ordering::Relaxed);
                 } else {
@@ -491,6 +571,7 @@ pub struct AgonMachineConfig {
     pub soft_reset: Arc<std::sync::atomic::AtomicBool>,
     pub emulator_shutdown: Arc<std::sync::atomic::AtomicBool>,
     pub exit_status: Arc<std::sync::atomic::AtomicI32>,
+    pub exit_reason: Arc<std::sync::atomic::AtomicU8>,
     pub paused: Arc<std::sync::atomic::AtomicBool>,
     pub clockspeed_hz: u64,
     pub ram_init: RamInit,
@@ -498,7 +579,138 @@ pub struct AgonMachineConfig {
     pub embedded_mos: Option<&'static [u8]>,
     pub gpios: Arc<gpio::GpioSet>,
     pub tx_gpio_vga_frame: std::sync::mpsc::Sender<gpio_video::GpioVgaFrame>,
+    /// CPU cycles run between checks for pending interrupts (and updates to
+    /// the timers and UARTs). Smaller is more accurate: an interrupt is
+    /// taken up to this many cycles late. Larger is faster. 1 checks after
+    /// every instruction.
     pub interrupt_precision: i32,
+    /// Stop the CPU and request shutdown after exactly this many
+    /// instructions, whatever the timing (e.g. for fuzzing)
+    pub max_instructions: Option<u64>,
+}
+
+impl AgonMachineConfig {
+    pub fn builder() -> AgonMachineConfigBuilder {
+        AgonMachineConfigBuilder::new()
+    }
+}
+
+/// Builds an [`AgonMachineConfig`]. Anything not set gets a default:
+/// random RAM, dummy serial links, an 18.432 MHz clock, interrupts polled
+/// every 16 cycles, fresh shared flags and GPIOs, and no GPIO video
+/// listener. MOS is loaded from `MOS.bin` in the current directory.
+pub struct AgonMachineConfigBuilder {
+    config: AgonMachineConfig,
+}
+
+impl AgonMachineConfigBuilder {
+    pub fn new() -> Self {
+        AgonMachineConfigBuilder {
+            config: AgonMachineConfig {
+                uart0_link: Box::new(uart::DummySerialLink),
+                uart1_link: Box::new(uart::DummySerialLink),
+                soft_reset: Arc::default(),
+                emulator_shutdown: Arc::default(),
+                exit_status: Arc::default(),
+                exit_reason: Arc::default(),
+                paused: Arc::default(),
+                clockspeed_hz: 18_432_000,
+                ram_init: RamInit::Random,
+                mos_bin: std::path::PathBuf::from("MOS.bin"),
+                embedded_mos: None,
+                gpios: Arc::new(gpio::GpioSet::new()),
+                tx_gpio_vga_frame: std::sync::mpsc::channel().0,
+                interrupt_precision: 16,
+                max_instructions: None,
+            },
+        }
+    }
+
+    pub fn with_uart0_link(mut self, link: Box<dyn uart::SerialLink>) -> Self {
+        self.config.uart0_link = link;
+        self
+    }
+
+    pub fn with_uart1_link(mut self, link: Box<dyn uart::SerialLink>) -> Self {
+        self.config.uart1_link = link;
+        self
+    }
+
+    pub fn with_soft_reset(mut self, soft_reset: Arc<std::sync::atomic::AtomicBool>) -> Self {
+        self.config.soft_reset = soft_reset;
+        self
+    }
+
+    pub fn with_emulator_shutdown(mut self, emulator_shutdown: Arc<std::sync::atomic::AtomicBool>) -> Self {
+        self.config.emulator_shutdown = emulator_shutdown;
+        self
+    }
+
+    pub fn with_exit_status(mut self, exit_status: Arc<std::sync::atomic::AtomicI32>) -> Self {
+        self.config.exit_status = exit_status;
+        self
+    }
+
+    pub fn with_exit_reason(mut self, exit_reason: Arc<std::sync::atomic::AtomicU8>) -> Self {
+        self.config.exit_reason = exit_reason;
+        self
+    }
+
+    pub fn with_paused(mut self, paused: Arc<std::sync::atomic::AtomicBool>) -> Self {
+        self.config.paused = paused;
+        self
+    }
+
+    pub fn with_clockspeed_hz(mut self, clockspeed_hz: u64) -> Self {
+        self.config.clockspeed_hz = clockspeed_hz;
+        self
+    }
+
+    pub fn with_ram_init(mut self, ram_init: RamInit) -> Self {
+        self.config.ram_init = ram_init;
+        self
+    }
+
+    pub fn with_mos_bin(mut self, mos_bin: std::path::PathBuf) -> Self {
+        self.config.mos_bin = mos_bin;
+        self
+    }
+
+    /// Firmware to use if `mos_bin` can't be read
+    pub fn with_embedded_mos(mut self, embedded_mos: Option<&'static [u8]>) -> Self {
+        self.config.embedded_mos = embedded_mos;
+        self
+    }
+
+    pub fn with_gpios(mut self, gpios: Arc<gpio::GpioSet>) -> Self {
+        self.config.gpios = gpios;
+        self
+    }
+
+    pub fn with_tx_gpio_vga_frame(mut self, tx: std::sync::mpsc::Sender<gpio_video::GpioVgaFrame>) -> Self {
+        self.config.tx_gpio_vga_frame = tx;
+        self
+    }
+
+    pub fn with_interrupt_precision(mut self, interrupt_precision: i32) -> Self {
+        self.config.interrupt_precision = interrupt_precision;
+        self
+    }
+
+    pub fn with_max_instructions(mut self, max_instructions: Option<u64>) -> Self {
+        self.config.max_instructions = max_instructions;
+        self
+    }
+
+    pub fn build(self) -> AgonMachineConfig {
+        self.config
+    }
+}
+
+impl Default for AgonMachineConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AgonMachine {
@@ -520,6 +732,7 @@ impl AgonMachine {
             soft_reset: config.soft_reset,
             emulator_shutdown: config.emulator_shutdown,
             exit_status: config.exit_status,
+            exit_reason: config.exit_reason,
             clockspeed_hz: config.clockspeed_hz,
             prt_timers: [
                 prt_timer::PrtTimer::new(),
@@ -547,6 +760,13 @@ impl AgonMachine {
             cs0_ubr: 0xff,
             flash_waitstates: 4,
             interrupt_precision: config.interrupt_precision,
+            max_instructions: config.max_instructions,
+            instructions_executed: 0,
+            cycle_gate: None,
+            pc_monitor: None,
+            output_break: None,
+            control: vec![],
+            debugger_attach: None,
         }
     }
 
@@ -566,6 +786,20 @@ impl AgonMachine {
         self.paused.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    pub fn set_exit_reason(&self, reason: ExitReason) {
+        self.exit_reason
+            .store(reason as u8, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Instructions executed since `start`
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    pub fn get_exit_reason(&self) -> ExitReason {
+        ExitReason::from_u8(self.exit_reason.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
     #[inline]
     fn get_rom_address(&self, address: u32) -> Option<u32> {
         let a: u32 = address.wrapping_sub((self.flash_addr_u as u32) << 16);
@@ -613,6 +847,52 @@ impl AgonMachine {
         self.spi_sdcard.set_image_file(file);
     }
 
+    /// Model a write-protected SD card: writes to the image are rejected.
+    pub fn set_sdcard_write_protected(&mut self, state: bool) {
+        self.spi_sdcard.set_write_protected(state);
+    }
+
+    /// Run in lockstep: execute only the cycles granted through `gate`,
+    /// instead of pacing by wall-clock time.
+    pub fn set_cycle_gate(&mut self, gate: Option<Arc<cycle_gate::CycleGate>>) {
+        self.cycle_gate = gate;
+    }
+
+    /// Serve read-only debugger requests (e.g. `DebugCmd::GetMemory`) over
+    /// `con`, whether or not an interactive debugger is attached. Several
+    /// connections may be added. Requests are served between timeslices,
+    /// about once per millisecond of guest time.
+    pub fn add_control_connection(&mut self, con: debugger::DebuggerConnection) {
+        self.control.push(debugger::DebuggerServer::new(con));
+    }
+
+    /// Let a debugger attach after `start`: the first connection sent on
+    /// `rx` becomes the interactive debugger, if there isn't one already.
+    /// It is picked up within a millisecond or so of guest time.
+    pub fn set_debugger_attach(&mut self, rx: Option<std::sync::mpsc::Receiver<debugger::DebuggerConnection>>) {
+        self.debugger_attach = rx;
+    }
+
+    /// Publish the PC of every executed instruction to `monitor`, so other
+    /// threads (e.g. trace logging) can see where the guest is running.
+    pub fn set_pc_monitor(&mut self, monitor: Option<Arc<std::sync::atomic::AtomicU32>>) {
+        self.pc_monitor = monitor;
+    }
+
+    /// Pause in the interactive debugger, reported as
+    /// `PauseReason::OutputMatch`, when another thread sets `flag`. With no
+    /// debugger attached the request is dropped.
+    pub fn set_output_break(&mut self, flag: Option<Arc<std::sync::atomic::AtomicBool>>) {
+        self.output_break = flag;
+    }
+
+    /// Take a pending output break request, clearing it
+    pub fn take_output_break(&self) -> bool {
+        self.output_break
+            .as_ref()
+            .is_some_and(|f| f.swap(false, std::sync::atomic::Ordering::Relaxed))
+    }
+
     fn load_mos(&mut self) {
         let code = match std::fs::read(&self.mos_bin) {
             Ok(data) => data,
@@ -1391,6 +1671,9 @@ impl AgonMachine {
         // when out-of-bounds memory accesses happen (since they can't be
         // trapped mid-execution)
         self.last_pc = pc;
+        if let Some(monitor) = &self.pc_monitor {
+            monitor.store(pc, std::sync::atomic::Ordering::Relaxed);
+        }
 
         if self.enable_hostfs && pc < 0x20000 && self.flash_addr_u == 0 {
             // Don't use any cycles in hostfs functionality.
@@ -1511,6 +1794,7 @@ impl AgonMachine {
 
     #[inline]
     pub fn do_interrupts(&mut self, cpu: &mut Cpu) {
+        self.gpios.deliver_vsync();
         if cpu.state.reg.get_iff1() {
             // Interrupts in priority order
             for i in 0..self.prt_timers.len() {
@@ -1558,9 +1842,104 @@ impl AgonMachine {
     fn debugger_tick(&mut self, debugger: &mut Option<debugger::DebuggerServer>, cpu: &mut Cpu) {
         if let Some(ref mut ds) = debugger {
             ds.tick(self, cpu);
+        } else {
+            self.take_output_break();
         }
     }
 
+    // serve the control connections. Called once per timeslice rather than
+    // per instruction, as nothing there needs to stop the CPU
+    fn control_tick(&mut self, cpu: &mut Cpu) {
+        if !self.control.is_empty() {
+            let mut control = std::mem::take(&mut self.control);
+            for con in &mut control {
+                con.tick_passive(self, cpu);
+            }
+            self.control = control;
+        }
+    }
+
+    // count an instruction about to be executed against max_instructions.
+    // Once they are all used up, request shutdown and return false
+    fn take_instruction(&mut self) -> bool {
+        if let Some(max) = self.max_instructions {
+            if self.instructions_executed >= max {
+                self.set_exit_reason(ExitReason::InstructionLimit);
+                self.emulator_shutdown
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                return false;
+            }
+        }
+        self.instructions_executed += 1;
+        true
+    }
+
+    // attach a debugger sent through set_debugger_attach, if there's none
+    fn maybe_attach_debugger(&mut self, debugger: &mut Option<debugger::DebuggerServer>) {
+        if debugger.is_some() {
+            return;
+        }
+        if let Some(con) = self.debugger_attach.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            *debugger = Some(debugger::DebuggerServer::new(con));
+        }
+    }
+
+    // perform a soft reset if requested
+    fn maybe_soft_reset(&mut self, cpu: &mut Cpu) {
+        if self.soft_reset.load(std::sync::atomic::Ordering::Relaxed) {
+            // MOS soft reset code always runs from ADL mode.
+            // and set_pc(0) will actually set pc := (mb<<16 + 0) in non-adl mode
+            cpu.state.reg.adl = true;
+            cpu.state.set_pc(0);
+            self.soft_reset
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+            self.set_exit_reason(ExitReason::SoftReset);
+        }
+    }
+
+    // Lockstep main loop: only execute cycles granted through the gate.
+    // Cycles overshot by the last instruction of a budget are deducted
+    // from the next one, so the total executed tracks the total granted.
+    // Returns once max_instructions have been executed.
+    fn run_lockstep(
+        &mut self,
+        gate: &cycle_gate::CycleGate,
+        debugger: &mut Option<debugger::DebuggerServer>,
+        cpu: &mut Cpu,
+    ) {
+        let mut budget: i64 = 0;
+        loop {
+            let granted = gate.acquire(std::time::Duration::from_millis(10));
+            // released at the end of this budget, or on return
+            let _release = (granted > 0).then(|| gate.release_guard());
+            budget += granted as i64;
+
+            let mut cycle: u64 = 0;
+            // keep the debugger responsive even when no cycles are granted
+            self.debugger_tick(debugger, cpu);
+            while (cycle as i64) < budget {
+                self.debugger_tick(debugger, cpu);
+                if self.is_paused() {
+                    break;
+                }
+                if !self.take_instruction() {
+                    return;
+                }
+                self.execute_instruction(cpu);
+                if self.cycle_counter.get() >= self.interrupt_precision {
+                    cycle += self.apply_elapsed_cycles() as u64;
+                    self.do_interrupts(cpu);
+                }
+            }
+            budget -= cycle as i64;
+
+            self.maybe_soft_reset(cpu);
+            self.maybe_attach_debugger(debugger);
+            self.control_tick(cpu);
+        }
+    }
+
+    /// Run the CPU. Only returns once `max_instructions` have been executed.
     pub fn start(&mut self, debugger_con: Option<debugger::DebuggerConnection>) {
         let mut cpu = Cpu::new_ez80();
 
@@ -1580,6 +1959,11 @@ impl AgonMachine {
                     self.mem_internal[i as usize] = rand::thread_rng().gen_range(0..=255);
                 }
             }
+            RamInit::Seeded(seed) => {
+                let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+                rng.fill(&mut self.mem_external[..]);
+                rng.fill(&mut self.mem_internal[..]);
+            }
             RamInit::Zero => {}
         }
 
@@ -1590,6 +1974,11 @@ impl AgonMachine {
         // This extra call is needed, or breakpoints at 0 don't work. I don't understand why :)
         self.debugger_tick(&mut debugger, &mut cpu);
 
+        if let Some(gate) = self.cycle_gate.clone() {
+            self.run_lockstep(&gate, &mut debugger, &mut cpu);
+            return;
+        }
+
         let cycles_per_ms: u64 = self.clockspeed_hz / 1000;
         let mut timeslice_start = std::time::Instant::now();
         loop {
@@ -1599,6 +1988,9 @@ impl AgonMachine {
                 if self.is_paused() {
                     break;
                 }
+                if !self.take_instruction() {
+                    return;
+                }
                 self.execute_instruction(&mut cpu);
                 if self.cycle_counter.get() >= self.interrupt_precision {
                     cycle += self.apply_elapsed_cycles() as u64;
@@ -1606,15 +1998,9 @@ impl AgonMachine {
                 }
             }
 
-            // perform a soft reset if requested
-            if self.soft_reset.load(std::sync::atomic::Ordering::Relaxed) {
-                // MOS soft reset code always runs from ADL mode.
-                // and set_pc(0) will actually set pc := (mb<<16 + 0) in non-adl mode
-                cpu.state.reg.adl = true;
-                cpu.state.set_pc(0);
-                self.soft_reset
-                    .store(false, std::sync::atomic::Ordering::Relaxed);
-            }
+            self.maybe_soft_reset(&mut cpu);
+            self.maybe_attach_debugger(&mut debugger);
+            self.control_tick(&mut cpu);
 
             while timeslice_start.elapsed() < std::time::Duration::from_millis(1) {
                 std::thread::sleep(std::time::Duration::from_micros(500));
@@ -1625,3 +2011,195 @@ impl AgonMachine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_machine(gpios: Arc<gpio::GpioSet>) -> AgonMachine {
+        let mut machine = AgonMachine::new(
+            AgonMachineConfig::builder()
+                .with_ram_init(RamInit::Zero)
+                .with_gpios(gpios)
+                .with_interrupt_precision(0)
+                .build(),
+        );
+        machine.enable_hostfs = false;
+        machine
+    }
+
+    #[test]
+    fn test_exit_message_per_reason() {
+        assert_eq!(ExitReason::NormalExit.exit_message(0), "eZ80 exited normally");
+        assert_eq!(ExitReason::GuestPanic.exit_message(3), "eZ80 exited with error status 3");
+        assert!(!ExitReason::SoftReset.exit_message(0).contains("exited"));
+        assert!(!ExitReason::SoftReset.ends_run());
+        assert!(ExitReason::InstructionLimit.ends_run());
+    }
+
+    #[test]
+    fn test_out_of_bounds_access_is_only_an_exit_reason_on_exit() {
+        let mut machine = test_machine(Arc::new(gpio::GpioSet::new()));
+        machine.poke(0x100000, 0);
+        assert_eq!(machine.mem_out_of_bounds.get(), Some(0x100000));
+        assert!(!machine.get_exit_reason().ends_run());
+
+        machine.port_out(0x00, 3);
+        assert_eq!(machine.get_exit_reason(), ExitReason::OutOfBoundsMemAccess);
+        assert!(machine.get_exit_reason().ends_run());
+    }
+
+    #[test]
+    fn test_config_builder_defaults() {
+        let config = AgonMachineConfig::builder().with_max_instructions(Some(10)).build();
+        assert!(matches!(config.ram_init, RamInit::Random));
+        assert_eq!(config.clockspeed_hz, 18_432_000);
+        assert_eq!(config.interrupt_precision, 16);
+        assert_eq!(config.max_instructions, Some(10));
+    }
+
+    #[test]
+    fn test_vsync_edge_takes_gpio_vector() {
+        let gpios = Arc::new(gpio::GpioSet::new());
+        let mut machine = test_machine(gpios.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        // im 2; ei; nop
+        machine.mem_rom[..4].copy_from_slice(&[0xed, 0x5e, 0xfb, 0x00]);
+        // port B pin 1 vector (I = 0) -> handler at 0x0100
+        machine.mem_rom[0x32..0x34].copy_from_slice(&[0x00, 0x01]);
+
+        // pin 1 as a rising edge interrupt, the way MOS configures vsync
+        machine.port_out(0x9a, 0x02);
+        machine.port_out(0x9b, 0xff);
+        machine.port_out(0x9c, 0x02);
+        machine.port_out(0x9d, 0x02);
+        assert_eq!(gpios.b.get_mode(1), 15);
+
+        cpu.state.set_pc(0);
+        for _ in 0..3 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert!(cpu.state.reg.get_iff1());
+
+        // nothing pending yet
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(cpu.state.pc(), 4);
+
+        // vsync pulse, as agon-ez80 signals it
+        gpios.b.set_input_pin(1, true);
+        gpios.b.set_input_pin(1, false);
+        assert_eq!(gpios.b.get_interrupt_due(), 0x02);
+
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(cpu.state.pc(), 0x0100);
+        assert!(!cpu.state.reg.get_iff1());
+    }
+
+    #[test]
+    fn test_queued_vsyncs_each_interrupt() {
+        let gpios = Arc::new(gpio::GpioSet::new());
+        let mut machine = test_machine(gpios.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        // im 2; ei; nop
+        machine.mem_rom[..4].copy_from_slice(&[0xed, 0x5e, 0xfb, 0x00]);
+        machine.mem_rom[0x32..0x34].copy_from_slice(&[0x00, 0x01]);
+        machine.port_out(0x9a, 0x02);
+        machine.port_out(0x9b, 0xff);
+        machine.port_out(0x9c, 0x02);
+        machine.port_out(0x9d, 0x02);
+
+        cpu.state.set_pc(0);
+        for _ in 0..3 {
+            machine.execute_instruction(&mut cpu);
+        }
+
+        // a batch of 3, as VSYNC_BATCH signals it
+        gpios.queue_vsyncs(3);
+        let mut taken = 0;
+        for _ in 0..10 {
+            machine.do_interrupts(&mut cpu);
+            if cpu.state.pc() == 0x0100 {
+                taken += 1;
+                // the handler acknowledges the interrupt, then ei
+                machine.port_out(0x9a, 0x02);
+                cpu.state.set_pc(2);
+                machine.execute_instruction(&mut cpu);
+                machine.execute_instruction(&mut cpu);
+            }
+        }
+        assert_eq!(taken, 3);
+        assert_eq!(gpios.vsyncs_pending(), 0);
+    }
+
+    #[test]
+    fn test_debugger_attaches_while_running() {
+        let mut machine = test_machine(Arc::new(gpio::GpioSet::new()));
+        let mut debugger = None;
+        machine.maybe_attach_debugger(&mut debugger);
+        assert!(debugger.is_none());
+
+        let (tx_attach, rx_attach) = std::sync::mpsc::channel();
+        machine.set_debugger_attach(Some(rx_attach));
+        let con = || debugger::DebuggerConnection {
+            tx: std::sync::mpsc::channel().0,
+            rx: std::sync::mpsc::channel().1,
+        };
+        tx_attach.send(con()).unwrap();
+        machine.maybe_attach_debugger(&mut debugger);
+        assert!(debugger.is_some());
+
+        // one debugger at a time: a second connection waits
+        tx_attach.send(con()).unwrap();
+        machine.maybe_attach_debugger(&mut debugger);
+        assert!(machine.debugger_attach.as_ref().unwrap().try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_output_break_pauses_through_debugger() {
+        let mut machine = test_machine(Arc::new(gpio::GpioSet::new()));
+        let mut cpu = Cpu::new_ez80();
+        let flag = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        machine.set_output_break(Some(flag.clone()));
+
+        // with no debugger the request is dropped
+        flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        machine.debugger_tick(&mut None, &mut cpu);
+        assert!(!flag.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(!machine.is_paused());
+
+        let (tx_resp, rx_resp) = std::sync::mpsc::channel();
+        let (_tx_cmd, rx_cmd) = std::sync::mpsc::channel();
+        let mut debugger = Some(debugger::DebuggerServer::new(debugger::DebuggerConnection {
+            tx: tx_resp,
+            rx: rx_cmd,
+        }));
+        flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        machine.debugger_tick(&mut debugger, &mut cpu);
+        assert!(machine.is_paused());
+        assert!(matches!(
+            rx_resp.try_recv(),
+            Ok(debugger::DebugResp::Paused(debugger::PauseReason::OutputMatch))
+        ));
+        assert!(matches!(rx_resp.try_recv(), Ok(debugger::DebugResp::State { .. })));
+
+        // already paused: nothing more is reported
+        flag.store(true, std::sync::atomic::Ordering::Relaxed);
+        machine.debugger_tick(&mut debugger, &mut cpu);
+        assert!(rx_resp.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_instruction_limit_requests_shutdown() {
+        let mut machine = test_machine(Arc::new(gpio::GpioSet::new()));
+        machine.max_instructions = Some(2);
+        assert!(machine.take_instruction());
+        assert!(machine.take_instruction());
+        assert!(!machine.emulator_shutdown.load(std::sync::atomic::Ordering::Relaxed));
+        assert!(!machine.take_instruction());
+        assert_eq!(machine.instructions_executed(), 2);
+        assert!(machine.emulator_shutdown.load(std::sync::atomic::Ordering::Relaxed));
+        assert_eq!(machine.get_exit_reason(), ExitReason::InstructionLimit);
+    }
+}
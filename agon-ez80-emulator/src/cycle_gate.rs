@@ -0,0 +1,122 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Hands out CPU cycle budgets to the emulation thread.
+///
+/// Used for lockstep execution: the eZ80 only runs when cycles have been
+/// granted (typically once per VSYNC), so a run depends on the number of
+/// frames rather than on wall-clock timing. This trades realism (the CPU no
+/// longer runs at a steady real-time rate) for reproducibility.
+pub struct CycleGate {
+    state: Mutex<GateState>,
+    cond: Condvar,
+}
+
+struct GateState {
+    // cycles granted but not yet picked up by the CPU
+    pending: u64,
+    // CPU is currently executing a budget it has acquired
+    busy: bool,
+}
+
+impl CycleGate {
+    pub fn new() -> Self {
+        CycleGate {
+            state: Mutex::new(GateState {
+                pending: 0,
+                busy: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Allow the CPU to run `cycles` more cycles.
+    pub fn grant(&self, cycles: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pending += cycles;
+        self.cond.notify_all();
+    }
+
+    /// Wait until the CPU has used up all granted cycles.
+    /// Returns false if `timeout` elapsed first.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (_state, result) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| s.pending > 0 || s.busy)
+            .unwrap();
+        !result.timed_out()
+    }
+
+    /// Called by the CPU thread: take all pending cycles, waiting up to
+    /// `timeout` for some to be granted. Returns 0 on timeout.
+    pub(crate) fn acquire(&self, timeout: Duration) -> u64 {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .cond
+            .wait_timeout_while(state, timeout, |s| s.pending == 0)
+            .unwrap();
+        let cycles = state.pending;
+        state.pending = 0;
+        state.busy = cycles > 0;
+        cycles
+    }
+
+    /// Called by the CPU thread once it has finished executing an acquired budget.
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.busy = false;
+        self.cond.notify_all();
+    }
+
+    /// Release the gate when the returned guard is dropped, even if the
+    /// CPU loop returns part way through a budget.
+    pub(crate) fn release_guard(&self) -> ReleaseGuard<'_> {
+        ReleaseGuard(self)
+    }
+}
+
+impl Default for CycleGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Releases a `CycleGate` on drop
+pub(crate) struct ReleaseGuard<'a>(&'a CycleGate);
+
+impl Drop for ReleaseGuard<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CycleGate;
+    use std::time::Duration;
+
+    #[test]
+    fn test_acquire_takes_all_pending() {
+        let gate = CycleGate::new();
+        assert_eq!(gate.acquire(Duration::from_millis(1)), 0);
+        gate.grant(100);
+        gate.grant(50);
+        assert_eq!(gate.acquire(Duration::from_millis(1)), 150);
+        assert!(!gate.wait_idle(Duration::from_millis(1)));
+        gate.release();
+        assert!(gate.wait_idle(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_release_guard() {
+        let gate = CycleGate::new();
+        gate.grant(100);
+        assert_eq!(gate.acquire(Duration::from_millis(1)), 100);
+        {
+            let _release = gate.release_guard();
+            assert!(!gate.wait_idle(Duration::from_millis(1)));
+        }
+        assert!(gate.wait_idle(Duration::from_millis(1)));
+    }
+}
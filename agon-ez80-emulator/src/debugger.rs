@@ -20,6 +20,8 @@ pub enum PauseReason {
     OutOfBoundsMemAccess(u32), // address
     DebuggerBreakpoint,
     IOBreakpoint(u8),
+    /// The guest sent a watched byte sequence to the VDP
+    OutputMatch,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +53,8 @@ pub enum DebugCmd {
         reg_index: u8,
         value: u32,
     },
+    /// Replace the whole register file (e.g. restoring a saved state)
+    SetRegisters(Registers),
     GetState,
     DisassemblePc {
         adl: Option<bool>,
@@ -170,24 +174,20 @@ impl DebuggerServer {
         // debugger functions triggered by IO read/write
         self.on_unhandled_io(machine, cpu);
 
-        // check triggers
-        if !machine.is_paused() {
-            let to_run: Vec<Trigger> = self
-                .triggers
-                .iter()
-                .filter(|t| t.address == pc)
-                .map(|f| f.clone())
-                .collect();
-
-            for t in to_run {
-                for a in &t.actions {
-                    self.handle_debug_cmd(a, machine, cpu);
-                }
+        // a pause requested from outside the CPU thread
+        if machine.take_output_break() && !machine.is_paused() {
+            for a in [DebugCmd::Pause(PauseReason::OutputMatch), DebugCmd::GetState] {
+                self.handle_debug_cmd(&a, machine, cpu);
             }
         }
 
-        // delete triggers that are only to execute once
-        self.triggers.retain(|b| !(b.address == pc && b.once));
+        // check triggers
+        let to_run = self.take_triggers(pc, !machine.is_paused());
+        for t in to_run {
+            for a in &t.actions {
+                self.handle_debug_cmd(a, machine, cpu);
+            }
+        }
 
         loop {
             match self.con.rx.try_recv() {
@@ -203,6 +203,43 @@ impl DebuggerServer {
         machine.mem_out_of_bounds.set(None);
     }
 
+    /// Serve read-only requests (memory, registers, state) without the
+    /// pausing, triggers and IO hooks of `tick`. For an always-on control
+    /// channel when no interactive debugger is attached.
+    pub fn tick_passive(&mut self, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) {
+        while let Ok(cmd) = self.con.rx.try_recv() {
+            match cmd {
+                DebugCmd::Ping
+                | DebugCmd::GetMemory { .. }
+                | DebugCmd::GetMemoryAtReg { .. }
+                | DebugCmd::GetRegisters
+                | DebugCmd::GetState => self.handle_debug_cmd(&cmd, machine, cpu),
+                _ => self
+                    .con
+                    .tx
+                    .send(DebugResp::Message(format!("{:?} needs the debugger", cmd)))
+                    .unwrap(),
+            }
+        }
+        machine.mem_out_of_bounds.set(None);
+    }
+
+    /// The triggers to run at `pc` (none if `fire` is false). Triggers
+    /// that are only to execute once are deleted either way.
+    fn take_triggers(&mut self, pc: u32, fire: bool) -> Vec<Trigger> {
+        let to_run: Vec<Trigger> = if fire {
+            self.triggers
+                .iter()
+                .filter(|t| t.address == pc)
+                .cloned()
+                .collect()
+        } else {
+            vec![]
+        };
+        self.triggers.retain(|b| !(b.address == pc && b.once));
+        to_run
+    }
+
     fn handle_debug_cmd(&mut self, cmd: &DebugCmd, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) {
         let pc = cpu.state.pc();
 
@@ -342,6 +379,10 @@ impl DebuggerServer {
             DebugCmd::SetRegister { reg_index, value } => {
                 self.set_register(cpu, *reg_index, *value);
             }
+            DebugCmd::SetRegisters(registers) => {
+                cpu.state.reg = registers.clone();
+                self.con.tx.send(DebugResp::Pong).unwrap();
+            }
         }
     }
 
@@ -365,11 +406,22 @@ impl DebuggerServer {
             5 => cpu.state.reg.set24(Reg16::HL, value), // HL
             6 => cpu.state.reg.set24(Reg16::IX, value), // IX
             7 => cpu.state.reg.set24(Reg16::IY, value), // IY
-            // Alternate registers (AF', BC', DE', HL') not settable via ez80 crate
+            // Alternate registers, set by swapping them in and out again
+            8 => {
+                cpu.state.reg.swap(Reg16::AF);
+                cpu.state.reg.set16(Reg16::AF, value as u16); // AF'
+                cpu.state.reg.swap(Reg16::AF);
+            }
+            9..=11 => {
+                let rr = [Reg16::BC, Reg16::DE, Reg16::HL][reg_index as usize - 9];
+                cpu.state.reg.swap(rr);
+                cpu.state.reg.set24(rr, value); // BC', DE', HL'
+                cpu.state.reg.swap(rr);
+            }
             // I and R registers
             12 => cpu.state.reg.set8(Reg8::I, value as u8), // I
             13 => cpu.state.reg.set8(Reg8::R, value as u8), // R
-            // IM not directly settable via ez80 crate public API
+            14 => cpu.state.reg.set_interrupt_mode(value as u8), // IM
             _ => {} // Unknown or unsupported register, ignore
         }
         self.con.tx.send(DebugResp::Pong).unwrap();
@@ -448,3 +500,34 @@ impl DebuggerServer {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakpoint(address: u32, once: bool) -> Trigger {
+        Trigger {
+            address,
+            once,
+            actions: vec![DebugCmd::Pause(PauseReason::DebuggerBreakpoint)],
+        }
+    }
+
+    #[test]
+    fn test_once_trigger_fires_only_first_time() {
+        let (_tx_cmd, rx_cmd) = mpsc::channel();
+        let (tx_resp, _rx_resp) = mpsc::channel();
+        let mut server = DebuggerServer::new(DebuggerConnection {
+            tx: tx_resp,
+            rx: rx_cmd,
+        });
+        server.triggers.push(breakpoint(0x40000, true));
+        server.triggers.push(breakpoint(0x40010, false));
+
+        assert_eq!(server.take_triggers(0x40000, true).len(), 1);
+        assert_eq!(server.take_triggers(0x40000, true).len(), 0);
+
+        assert_eq!(server.take_triggers(0x40010, true).len(), 1);
+        assert_eq!(server.take_triggers(0x40010, true).len(), 1);
+    }
+}
@@ -1,10 +1,17 @@
-use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU32, AtomicU8};
+
+/// VSYNC arrives on port B pin 1
+const VSYNC_PIN: u8 = 1;
+/// Most VSYNCs held for a CPU that isn't taking them (a second's worth)
+const MAX_PENDING_VSYNCS: u32 = 60;
 
 pub struct GpioSet {
     pub b: Gpio,
     pub c: Gpio,
     pub d: Gpio,
+    // VSYNCs queued but not yet pulsed on the pin
+    vsync_pending: AtomicU32,
 }
 
 impl GpioSet {
@@ -13,6 +20,36 @@ impl GpioSet {
             b: Gpio::new(),
             c: Gpio::new(),
             d: Gpio::new(),
+            vsync_pending: AtomicU32::new(0),
+        }
+    }
+
+    /// Queue `count` VSYNC pulses. The CPU thread delivers them one at a
+    /// time with `deliver_vsync`, so each raises its own interrupt instead
+    /// of all landing on the one latched edge.
+    pub fn queue_vsyncs(&self, count: u32) {
+        let _ = self.vsync_pending.fetch_update(Relaxed, Relaxed, |n| {
+            Some(n.saturating_add(count).min(MAX_PENDING_VSYNCS))
+        });
+    }
+
+    pub fn vsyncs_pending(&self) -> u32 {
+        self.vsync_pending.load(Relaxed)
+    }
+
+    /// Pulse the VSYNC pin for the next queued VSYNC, unless the interrupt
+    /// raised by the previous one has yet to be acknowledged
+    pub fn deliver_vsync(&self) {
+        if self.b.get_interrupt_due() & (1 << VSYNC_PIN) != 0 {
+            return;
+        }
+        if self
+            .vsync_pending
+            .fetch_update(Relaxed, Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            self.b.set_input_pin(VSYNC_PIN, true);
+            self.b.set_input_pin(VSYNC_PIN, false);
         }
     }
 }
@@ -90,13 +127,13 @@ impl Gpio {
             .store(self.interrupt_due.load(Relaxed) & !modified, Relaxed);
     }
     pub fn set_alt1(&self, val: u8) {
-        let modified = self.ddr.load(Relaxed) ^ val;
+        let modified = self.alt1.load(Relaxed) ^ val;
         self.alt1.store(val, Relaxed);
         self.interrupt_due
             .store(self.interrupt_due.load(Relaxed) & !modified, Relaxed);
     }
     pub fn set_alt2(&self, val: u8) {
-        let modified = self.ddr.load(Relaxed) ^ val;
+        let modified = self.alt2.load(Relaxed) ^ val;
         self.alt2.store(val, Relaxed);
         self.interrupt_due
             .store(self.interrupt_due.load(Relaxed) & !modified, Relaxed);
@@ -234,7 +271,7 @@ impl Gpio {
 
 #[cfg(test)]
 mod tests {
-    use super::Gpio;
+    use super::{Gpio, GpioSet, MAX_PENDING_VSYNCS};
     use std::sync::atomic::Ordering::Relaxed;
 
     #[test]
@@ -369,5 +406,49 @@ mod tests {
         assert_eq!(gpio.get_interrupt_due(), 0x0);
     }
 
+    #[test]
+    fn test_alt_writes_keep_unchanged_pins_pending() {
+        // pin 6 active high, pin 1 rising edge (vsync, as MOS sets it up)
+        let gpio = Gpio::new();
+        gpio.set_dr(0x42);
+        gpio.set_ddr(0xbf);
+        gpio.set_alt1(0x42);
+        gpio.set_alt2(0x42);
+        assert_eq!(gpio.get_mode(6), 13);
+        assert_eq!(gpio.get_mode(1), 15);
+        gpio.set_input_pins(0x42);
+        assert_eq!(gpio.get_interrupt_due(), 0x42);
+        // rewriting the same ALT values must not drop pending interrupts
+        gpio.set_alt1(0x42);
+        gpio.set_alt2(0x42);
+        assert_eq!(gpio.get_interrupt_due(), 0x42);
+        // but changing a pin's mode clears its pending interrupt
+        gpio.set_alt2(0x40);
+        assert_eq!(gpio.get_interrupt_due(), 0x40);
+    }
+
+    #[test]
+    fn test_queued_vsyncs_wait_for_acknowledge() {
+        let gpios = GpioSet::new();
+        // pin 1 rising edge, as MOS sets it up
+        gpios.b.set_dr(0x02);
+        gpios.b.alt1.store(0x02, Relaxed);
+        gpios.b.alt2.store(0x02, Relaxed);
+
+        gpios.queue_vsyncs(2);
+        gpios.deliver_vsync();
+        assert_eq!(gpios.b.get_interrupt_due(), 0x02);
+        // still pending: the next one waits
+        gpios.deliver_vsync();
+        assert_eq!(gpios.vsyncs_pending(), 1);
+        gpios.b.set_dr(0x02);
+        gpios.deliver_vsync();
+        assert_eq!(gpios.b.get_interrupt_due(), 0x02);
+        assert_eq!(gpios.vsyncs_pending(), 0);
+
+        gpios.queue_vsyncs(1000);
+        assert_eq!(gpios.vsyncs_pending(), MAX_PENDING_VSYNCS);
+    }
+
     // note - modes 4,5, 6,7, 10,11 are not tested (or probably implemented ;)
 }
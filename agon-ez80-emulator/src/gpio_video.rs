@@ -95,9 +95,9 @@ impl GpioVga {
                     self.num_scanlines
                 );*/
                 if self.num_scanlines >= 200 && self.num_scanlines <= 600 {
-                    // send frame
+                    // send frame (dropped if nobody is listening)
                     if let Some(img) = self.img.take() {
-                        self.tx_gpio_vga_frame.send(img).unwrap();
+                        let _ = self.tx_gpio_vga_frame.send(img);
                     }
                     // Now we have sync, allocate a GpioVgaFrame for the next frame
                     let scanline_duration_cycles = (frame_duration as u32 / self.num_scanlines)
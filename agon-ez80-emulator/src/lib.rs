@@ -1,4 +1,5 @@
 mod agon_machine;
+mod cycle_gate;
 pub mod debugger;
 pub mod gpio;
 mod gpio_video;
@@ -10,6 +11,10 @@ mod symbol_map;
 mod uart;
 pub use agon_machine::AgonMachine;
 pub use agon_machine::AgonMachineConfig;
+pub use agon_machine::AgonMachineConfigBuilder;
+pub use agon_machine::ExitReason;
 pub use agon_machine::RamInit;
+pub use cycle_gate::CycleGate;
 pub use gpio_video::GpioVgaFrame;
+pub use uart::DummySerialLink;
 pub use uart::SerialLink;
@@ -8,6 +8,9 @@ pub struct SpiSdcard {
     image: Option<std::fs::File>,
     next_write_sector: Option<usize>,
     next_write_started: bool,
+    write_protected: bool,
+    // set when a write was rejected, reported (and cleared) by CMD13
+    wp_violation: bool,
 }
 
 impl SpiSdcard {
@@ -20,6 +23,8 @@ impl SpiSdcard {
             image: None,
             next_write_sector: None,
             next_write_started: false,
+            write_protected: false,
+            wp_violation: false,
         }
     }
 
@@ -27,6 +32,10 @@ impl SpiSdcard {
         self.image = file;
     }
 
+    pub fn set_write_protected(&mut self, state: bool) {
+        self.write_protected = state;
+    }
+
     pub fn recv_byte(&mut self, val: u8) {
         if let Some(image) = self.image.as_mut() {
             // 0x80 means transfer finished (immediate on the emulator)
@@ -50,6 +59,13 @@ impl SpiSdcard {
                     let sector = self.next_write_sector.unwrap();
                     self.next_write_started = false;
                     self.next_write_sector = None;
+                    if self.write_protected {
+                        self.wp_violation = true;
+                        self.in_buf.clear();
+                        // data response token: write error
+                        self.out_buf.push(0x0d);
+                        return;
+                    }
                     //println!("WRITE!!!!!!!!! sector {}, {:?}", sector, self.in_buf);
                     match image
                         .seek(SeekFrom::Start(sector as u64 * 512))
@@ -73,6 +89,7 @@ impl SpiSdcard {
 
             const CMD0: u8 = 0 | 0x40;
             const CMD8: u8 = 8 | 0x40;
+            const CMD13: u8 = 13 | 0x40;
             const CMD55: u8 = 55 | 0x40;
             const ACMD41: u8 = 41 | 0x40;
             const CMD58: u8 = 58 | 0x40;
@@ -93,6 +110,16 @@ impl SpiSdcard {
                         self.in_buf.drain(0..6);
                         self.out_buf.extend_from_slice(&[1, 0, 0, 1, check_pattern]);
                     }
+                    // CMD13 (send status). R2 response, with the
+                    // write-protect bit set while the card is write-protected
+                    // or a write was rejected since the last query
+                    [CMD13, _a3, _a2, _a1, _a0, _crc, ..] => {
+                        self.in_buf.drain(0..6);
+                        self.out_buf.push(0);
+                        let wp = self.write_protected || self.wp_violation;
+                        self.out_buf.push(if wp { 0x20 } else { 0 });
+                        self.wp_violation = false;
+                    }
                     // CMD55
                     [CMD55, _a3, _a2, _a1, _a0, _crc, ..] => {
                         self.out_buf.push(1); // OK
@@ -6,6 +6,20 @@ pub trait SerialLink {
     fn read_clear_to_send(&mut self) -> bool;
 }
 
+/// A link with nothing on the other end: output is discarded, there is
+/// never input, and it is always clear to send
+pub struct DummySerialLink;
+
+impl SerialLink for DummySerialLink {
+    fn send(&mut self, _byte: u8) {}
+    fn recv(&mut self) -> Option<u8> {
+        None
+    }
+    fn read_clear_to_send(&mut self) -> bool {
+        true
+    }
+}
+
 pub struct Uart {
     link: Box<dyn SerialLink>,
     rx_buf: Option<u8>,
@@ -0,0 +1,83 @@
+//! Optional user config file supplying defaults for command-line options.
+//!
+//! Read from `$XDG_CONFIG_HOME/fab-agon-emulator/config.toml` (or
+//! `~/.config/fab-agon-emulator/config.toml`). Only simple string keys are
+//! supported:
+//!
+//! ```text
+//! sdcard = "/home/me/agon/sdcard"
+//! mos_bin = "/home/me/agon/MOS.bin"
+//! socket = "/tmp/agon-vdp.sock"
+//! ```
+//!
+//! Command-line flags always override values from the config file.
+
+use std::path::PathBuf;
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub sdcard: Option<String>,
+    pub mos_bin: Option<PathBuf>,
+    pub socket_path: Option<String>,
+}
+
+/// Location of the config file, if a home/config directory can be found
+pub fn config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("fab-agon-emulator").join("config.toml"))
+}
+
+/// Load the config file. A missing file yields an empty config; a
+/// malformed one is reported on stderr and otherwise ignored.
+pub fn load() -> Config {
+    let Some(path) = config_path() else {
+        return Config::default();
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Config::default(),
+        Err(e) => {
+            eprintln!("Warning: could not read {}: {}", path.display(), e);
+            return Config::default();
+        }
+    };
+    match parse(&text) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Warning: ignoring {}: {}", path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = \"value\"", n + 1))?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .ok_or_else(|| format!("line {}: value must be a quoted string", n + 1))?
+            .to_string();
+
+        match key.trim() {
+            "sdcard" => config.sdcard = Some(value),
+            "mos_bin" => config.mos_bin = Some(PathBuf::from(value)),
+            "socket" => config.socket_path = Some(value),
+            other => eprintln!("Warning: unknown config key '{}'", other),
+        }
+    }
+
+    Ok(config)
+}
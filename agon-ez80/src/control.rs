@@ -0,0 +1,88 @@
+//! Commands typed on stdin while no debugger is attached.
+//!
+//! These go over a read-only debugger connection, so they work without
+//! --debugger (which takes over stdin itself).
+
+use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, DebuggerConnection};
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// External RAM as mapped by MOS
+const RAM_START: u32 = 0x40000;
+const RAM_LEN: u32 = 0x80000;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELP: &str = "\
+Commands:
+  dump [file]   Write guest RAM (&40000-&BFFFF) to file
+                (default: agon-ram-<unix time>.bin)
+  help          Show this message";
+
+/// Start reading commands from stdin. Returns the connection to hand to
+/// `AgonMachine::add_control_connection`.
+pub fn start() -> DebuggerConnection {
+    let (tx_cmd, rx_cmd): (Sender<DebugCmd>, Receiver<DebugCmd>) = mpsc::channel();
+    let (tx_resp, rx_resp): (Sender<DebugResp>, Receiver<DebugResp>) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines().map_while(Result::ok) {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("dump") => dump_ram(&tx_cmd, &rx_resp, words.next()),
+                Some("help") => eprintln!("{}", HELP),
+                Some(cmd) => eprintln!("Unknown command '{}' (try 'help')", cmd),
+                None => {}
+            }
+        }
+    });
+
+    DebuggerConnection {
+        tx: tx_resp,
+        rx: rx_cmd,
+    }
+}
+
+fn dump_ram(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, path: Option<&str>) {
+    let path = match path {
+        Some(p) => p.to_string(),
+        None => {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            format!("agon-ram-{}.bin", secs)
+        }
+    };
+
+    if tx
+        .send(DebugCmd::GetMemory {
+            start: RAM_START,
+            len: RAM_LEN,
+        })
+        .is_err()
+    {
+        eprintln!("eZ80 CPU is not running");
+        return;
+    }
+
+    loop {
+        match rx.recv_timeout(RESPONSE_TIMEOUT) {
+            Ok(DebugResp::Memory { data, .. }) => {
+                match std::fs::write(&path, &data) {
+                    Ok(()) => eprintln!("Wrote {} bytes of RAM to {}", data.len(), path),
+                    Err(e) => eprintln!("Failed to write '{}': {}", path, e),
+                }
+                return;
+            }
+            Ok(DebugResp::Message(msg)) => eprintln!("{}", msg),
+            Ok(_) => {}
+            Err(_) => {
+                eprintln!("Timed out waiting for RAM contents");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Attach a DeZog debugger to a running eZ80, without --debugger.
+//!
+//! On SIGUSR2 (unix only) the DZRP server is started on --dzrp-port and
+//! its debugger connection handed to the running machine. This happens
+//! once; later signals are ignored.
+
+use crate::output_break::DebuggerCmds;
+use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, DebuggerConnection};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+/// Listen for the attach signal. Returns the receiver to hand to
+/// `AgonMachine::set_debugger_attach`. The attached debugger's commands are
+/// also published in `debugger_cmds`.
+#[cfg(unix)]
+pub fn start(port: u16, emulator_shutdown: Arc<AtomicBool>, debugger_cmds: DebuggerCmds) -> Receiver<DebuggerConnection> {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_sigusr2(_: libc::c_int) {
+        REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    let (tx_attach, rx_attach) = mpsc::channel();
+    unsafe {
+        libc::signal(libc::SIGUSR2, on_sigusr2 as libc::sighandler_t);
+    }
+
+    std::thread::spawn(move || {
+        while !REQUESTED.load(Ordering::Relaxed) {
+            if emulator_shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        eprintln!("SIGUSR2: attaching DZRP debugger on port {}", port);
+        let _ = tx_attach.send(start_dzrp(port, emulator_shutdown, &debugger_cmds));
+    });
+
+    rx_attach
+}
+
+/// Without signals there is nothing to listen for; the machine never sees
+/// an attach request.
+#[cfg(not(unix))]
+pub fn start(_port: u16, _emulator_shutdown: Arc<AtomicBool>, _debugger_cmds: DebuggerCmds) -> Receiver<DebuggerConnection> {
+    mpsc::channel().1
+}
+
+/// Start the DZRP server on its own thread, returning the machine's end of
+/// its debugger connection
+#[cfg_attr(not(unix), allow(dead_code))]
+fn start_dzrp(port: u16, emulator_shutdown: Arc<AtomicBool>, debugger_cmds: &DebuggerCmds) -> DebuggerConnection {
+    let (tx_cmd, rx_cmd): (Sender<DebugCmd>, Receiver<DebugCmd>) = mpsc::channel();
+    let (tx_resp, rx_resp): (Sender<DebugResp>, Receiver<DebugResp>) = mpsc::channel();
+    let _ = debugger_cmds.set(tx_cmd.clone());
+    std::thread::spawn(move || {
+        agon_dzrp_debugger::start(tx_cmd, rx_resp, emulator_shutdown, port);
+    });
+    DebuggerConnection {
+        tx: tx_resp,
+        rx: rx_cmd,
+    }
+}
@@ -3,18 +3,122 @@
 use crate::parse_args::Verbosity;
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Format of each log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Plain text message
+    #[default]
+    Text,
+    /// One JSON object per line: `{"ts":...,"level":"...","msg":"..."}`
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format '{}' (expected text or json)", other)),
+        }
+    }
+}
 
 /// Output destination for logger
 enum Output {
     Stderr,
-    File(BufWriter<File>),
+    File(LogFile),
+}
+
+/// Log file, optionally rotated to `<path>.1` when it exceeds `max_size` bytes
+struct LogFile {
+    writer: BufWriter<File>,
+    path: String,
+    written: u64,
+    max_size: Option<u64>,
+}
+
+impl LogFile {
+    fn write_line(&mut self, line: &str) {
+        let len = line.len() as u64 + 1;
+        if let Some(max_size) = self.max_size {
+            if self.written > 0 && self.written + len > max_size {
+                if let Err(e) = self.rotate() {
+                    eprintln!("Failed to rotate log file '{}': {}", self.path, e);
+                }
+            }
+        }
+        let _ = writeln!(self.writer, "{}", line);
+        let _ = self.writer.flush();
+        self.written += len;
+    }
+
+    /// Move the current log to `<path>.1` (replacing any older backup) and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// Parse a size such as `1048576`, `512K`, `10M` or `1G`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Parse an inclusive hex address range such as `40000-4FFFF` (an optional
+/// `0x` or `&` prefix is accepted on either end)
+pub fn parse_pc_range(s: &str) -> Result<(u32, u32), String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range '{}' (expected START-END)", s))?;
+    let parse = |v: &str| {
+        let v = v.trim();
+        let v = v.strip_prefix("0x").or_else(|| v.strip_prefix('&')).unwrap_or(v);
+        u32::from_str_radix(v, 16).map_err(|_| format!("invalid hex address '{}'", v))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        return Err(format!("invalid range '{}' (start > end)", s));
+    }
+    Ok((start, end))
+}
+
+/// Only emit trace-level lines while the guest PC is inside a range
+#[derive(Clone)]
+struct PcFilter {
+    pc: Arc<AtomicU32>,
+    start: u32,
+    end: u32,
 }
 
 /// Thread-safe logger
+#[derive(Clone)]
 pub struct Logger {
     output: Arc<Mutex<Output>>,
     verbosity: Verbosity,
+    format: LogFormat,
+    timestamps: bool,
+    start: Instant,
+    pc_filter: Option<PcFilter>,
 }
 
 impl Logger {
@@ -23,6 +127,10 @@ impl Logger {
         Logger {
             output: Arc::new(Mutex::new(Output::Stderr)),
             verbosity,
+            format: LogFormat::Text,
+            timestamps: false,
+            start: Instant::now(),
+            pc_filter: None,
         }
     }
 
@@ -30,11 +138,55 @@ impl Logger {
     pub fn file(path: &str, verbosity: Verbosity) -> io::Result<Self> {
         let file = File::create(path)?;
         Ok(Logger {
-            output: Arc::new(Mutex::new(Output::File(BufWriter::new(file)))),
+            output: Arc::new(Mutex::new(Output::File(LogFile {
+                writer: BufWriter::new(file),
+                path: path.to_string(),
+                written: 0,
+                max_size: None,
+            }))),
             verbosity,
+            format: LogFormat::Text,
+            timestamps: false,
+            start: Instant::now(),
+            pc_filter: None,
         })
     }
 
+    /// Rotate the log file to `<path>.1` when it grows beyond `max_size`
+    /// bytes (unlimited if None). Has no effect when logging to stderr.
+    pub fn with_max_size(self, max_size: Option<u64>) -> Self {
+        if let Ok(mut output) = self.output.lock() {
+            if let Output::File(f) = &mut *output {
+                f.max_size = max_size;
+            }
+        }
+        self
+    }
+
+    /// Set the line format
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Prefix text lines with seconds elapsed since the logger was created
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Suppress trace and UART trace lines unless the PC published in `pc`
+    /// is within `range` (inclusive) at the time the line is logged.
+    /// Verbose and info messages are not affected.
+    pub fn with_pc_filter(mut self, pc: Arc<AtomicU32>, range: (u32, u32)) -> Self {
+        self.pc_filter = Some(PcFilter {
+            pc,
+            start: range.0,
+            end: range.1,
+        });
+        self
+    }
+
     /// Get verbosity level
     pub fn verbosity(&self) -> Verbosity {
         self.verbosity
@@ -42,19 +194,24 @@ impl Logger {
 
     /// Log a message if verbosity level is met
     pub fn log(&self, level: Verbosity, msg: &str) {
-        if self.verbosity >= level {
-            if let Ok(mut output) = self.output.lock() {
-                match &mut *output {
-                    Output::Stderr => {
-                        eprintln!("{}", msg);
-                    }
-                    Output::File(f) => {
-                        let _ = writeln!(f, "{}", msg);
-                        let _ = f.flush();
-                    }
+        if level >= Verbosity::Trace {
+            if let Some(filter) = &self.pc_filter {
+                let pc = filter.pc.load(Ordering::Relaxed);
+                if !(filter.start..=filter.end).contains(&pc) {
+                    return;
                 }
             }
         }
+        if self.verbosity >= level {
+            let level_name = match level {
+                Verbosity::Quiet => "info",
+                Verbosity::Verbose => "verbose",
+                Verbosity::Trace => "trace",
+                Verbosity::TraceVdu => "trace_vdu",
+                Verbosity::TraceUart => "trace_uart",
+            };
+            self.write_line(level_name, msg);
+        }
     }
 
     /// Log at Verbose level
@@ -67,6 +224,11 @@ impl Logger {
         self.log(Verbosity::Trace, msg);
     }
 
+    /// Log at TraceVdu level
+    pub fn trace_vdu(&self, msg: &str) {
+        self.log(Verbosity::TraceVdu, msg);
+    }
+
     /// Log at TraceUart level
     pub fn trace_uart(&self, msg: &str) {
         self.log(Verbosity::TraceUart, msg);
@@ -74,25 +236,68 @@ impl Logger {
 
     /// Always log (for errors, important info)
     pub fn info(&self, msg: &str) {
+        self.write_line("info", msg);
+    }
+
+    fn write_line(&self, level_name: &str, msg: &str) {
+        let line = match self.format {
+            LogFormat::Text if self.timestamps => {
+                format!("[{:8.3}] {}", self.start.elapsed().as_secs_f64(), msg)
+            }
+            LogFormat::Text => msg.to_string(),
+            LogFormat::Json => {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or(0.0);
+                format!(
+                    r#"{{"ts":{:.6},"level":"{}","msg":"{}"}}"#,
+                    ts,
+                    level_name,
+                    json_escape(msg)
+                )
+            }
+        };
         if let Ok(mut output) = self.output.lock() {
             match &mut *output {
                 Output::Stderr => {
-                    eprintln!("{}", msg);
+                    eprintln!("{}", line);
                 }
                 Output::File(f) => {
-                    let _ = writeln!(f, "{}", msg);
-                    let _ = f.flush();
+                    f.write_line(&line);
                 }
             }
         }
     }
 }
 
-impl Clone for Logger {
-    fn clone(&self) -> Self {
-        Logger {
-            output: self.output.clone(),
-            verbosity: self.verbosity,
+/// Escape a string for use inside a JSON string literal
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
     }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("x").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
 }
@@ -1,36 +1,84 @@
+mod config;
+mod control;
+mod debug_attach;
 mod logger;
+mod mem_watch;
+mod output_break;
 mod parse_args;
+mod session_stats;
 mod socket_link;
 
 use agon_ez80_emulator::{
     debugger::{DebugCmd, DebugResp, DebuggerConnection, PauseReason, Trigger},
-    gpio, AgonMachine, AgonMachineConfig, GpioVgaFrame, RamInit,
+    gpio, AgonMachine, AgonMachineConfig, CycleGate, ExitReason, RamInit,
 };
-use agon_protocol::{Message, ProtocolError, SocketAddr, SocketListener, WebSocketConnection, WebSocketListener, PROTOCOL_VERSION};
+use agon_protocol::{check_hello_flags, fmt_payload, negotiate_version, Message, ProtocolError, SocketAddr, SocketListener, VduDecoder, WebSocketConnection, WebSocketListener, MAX_UART_DATA_SIZE, PROTOCOL_VERSION, SUPPORTED_REQUIRED_FLAGS};
+use agon_protocol::websocket::HELLO_FLAG_TEXT_FRAMES;
 use logger::Logger;
+use output_break::{DebuggerCmds, OutputMatcher};
 use parse_args::{parse_args, Verbosity};
-use socket_link::{DummySerialLink, SocketState};
+use session_stats::SessionStats;
+use socket_link::SocketState;
 
-use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU8, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 
 const PREFIX: Option<&'static str> = option_env!("PREFIX");
 
+/// eZ80 clock speed (Hz), unless running with --unlimited-cpu or --cpu-speed
+const CLOCKSPEED_HZ: u64 = 18_432_000;
+const UNLIMITED_CLOCKSPEED_HZ: u64 = 1_000_000_000;
+/// Slowest --cpu-speed: the CPU runs in 1ms timeslices of clockspeed/1000 cycles
+const MIN_CLOCKSPEED_HZ: u64 = 1000;
+
+/// How long a VSYNC waits for the CPU to finish its previous frame in lockstep mode
+const LOCKSTEP_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for a WebSocket VDP to acknowledge our close frame
+const WEBSOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// State shared by every VDP session
+struct SessionContext<'a> {
+    socket_state: &'a SocketState,
+    gpios: &'a Arc<gpio::GpioSet>,
+    cycle_gate: Option<&'a CycleGate>,
+    clockspeed_hz: u64,
+    soft_reset: &'a AtomicBool,
+    emulator_shutdown: &'a Arc<AtomicBool>,
+    logger: &'a Logger,
+    /// Log session statistics at this interval (verbose level)
+    stats_interval: Option<Duration>,
+    /// Flush pending TX bytes at least this often
+    tx_batch_interval: Duration,
+    /// ...or as soon as this many bytes are pending
+    tx_batch_bytes: Option<usize>,
+    /// Give up on a VDP that hasn't sent HELLO within this time
+    handshake_timeout: Duration,
+    /// Session token of the last VDP to connect, to recognise it resuming
+    last_session: &'a Cell<Option<u64>>,
+    /// Pause the CPU when the guest sends this (--break-on-output)
+    break_on_output: Option<&'a [u8]>,
+    debugger_cmds: &'a DebuggerCmds,
+    /// Set to have the CPU thread pause at --break-on-output
+    break_request: &'a AtomicBool,
+}
+
 /// Listener type for accepting VDP connections
 enum Listener {
     Socket(SocketListener),
     WebSocket(WebSocketListener),
 }
 
-/// Format bytes as hex string for debug output
-fn fmt_hex(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|b| format!("{:02X}", b))
-        .collect::<Vec<_>>()
-        .join(" ")
+impl Listener {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Listener::Socket(l) => l.set_nonblocking(nonblocking),
+            Listener::WebSocket(l) => l.set_nonblocking(nonblocking),
+        }
+    }
 }
 
 fn main() {
@@ -57,6 +105,25 @@ fn main() {
             }
         }
         None => Logger::stderr(args.verbosity),
+    }
+    .with_max_size(args.log_max_size)
+    .with_format(args.log_format)
+    .with_timestamps(args.log_timestamps);
+
+    // Random RAM always comes from a seed, so a failing run can be replayed
+    let ram_seed = if args.zero {
+        None
+    } else {
+        let seed = args.ram_seed.unwrap_or_else(agon_protocol::random_u64);
+        logger.info(&format!("RAM seed: {} (reproduce with --ram-seed {})", seed, seed));
+        Some(seed)
+    };
+
+    // The CPU thread publishes its PC here when trace output is PC-filtered
+    let pc_monitor = Arc::new(AtomicU32::new(0));
+    let logger = match args.trace_pc_range {
+        Some(range) => logger.with_pc_filter(pc_monitor.clone(), range),
+        None => logger,
     };
 
     // Create listener based on options
@@ -80,10 +147,10 @@ fn main() {
             let path = args
                 .socket_path
                 .clone()
-                .unwrap_or_else(|| agon_protocol::socket::DEFAULT_SOCKET_PATH.to_string());
+                .unwrap_or_else(agon_protocol::socket::default_socket_path);
             #[cfg(unix)]
             {
-                SocketAddr::unix(&path)
+                SocketAddr::unix_or_abstract(&path)
             }
             #[cfg(not(unix))]
             {
@@ -92,7 +159,15 @@ fn main() {
             }
         };
 
-        match SocketListener::bind(&addr) {
+        #[cfg(unix)]
+        let bound = match args.socket_mode {
+            Some(mode) => SocketListener::bind_with_mode(&addr, mode),
+            None => SocketListener::bind(&addr),
+        };
+        #[cfg(not(unix))]
+        let bound = SocketListener::bind(&addr);
+
+        match bound {
             Ok(l) => {
                 eprintln!("Listening on {}", addr);
                 Listener::Socket(l)
@@ -103,14 +178,45 @@ fn main() {
             }
         }
     };
+    // Poll for connections, so that a shutdown (e.g. at --max-insns) with
+    // no VDP connected isn't stuck in accept()
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Failed to set up listener: {}", e);
+        std::process::exit(1);
+    }
 
     // Shared state for CPU communication (persists across VDP reconnections)
     let socket_state = SocketState::new();
     let soft_reset = Arc::new(AtomicBool::new(false));
     let emulator_shutdown = Arc::new(AtomicBool::new(false));
     let exit_status = Arc::new(AtomicI32::new(0));
+    let exit_reason = Arc::new(AtomicU8::new(ExitReason::None as u8));
     let gpios = Arc::new(gpio::GpioSet::new());
     let ez80_paused = Arc::new(AtomicBool::new(false));
+    let clockspeed_hz = if args.unlimited_cpu {
+        UNLIMITED_CLOCKSPEED_HZ
+    } else {
+        args.cpu_speed_hz.unwrap_or(CLOCKSPEED_HZ)
+    };
+    if args.cpu_speed_hz.is_some() {
+        eprintln!(
+            "eZ80 clock: {:.3} MHz ({:.2}x)",
+            clockspeed_hz as f64 / 1e6,
+            clockspeed_hz as f64 / CLOCKSPEED_HZ as f64
+        );
+    }
+    if args.irq_precision < 4 {
+        eprintln!("Warning: --irq-precision {} checks interrupts very often, and the eZ80 may run slower than real time", args.irq_precision);
+    } else if args.irq_precision > 256 {
+        eprintln!("Warning: --irq-precision {} may delay interrupts enough to upset timers and serial I/O", args.irq_precision);
+    }
+    // In lockstep mode each VSYNC grants the CPU one frame's worth of cycles
+    let cycle_gate = if args.lockstep {
+        eprintln!("Lockstep mode: {} cycles per VSYNC", clockspeed_hz / 60);
+        Some(Arc::new(CycleGate::new()))
+    } else {
+        None
+    };
 
     // Default firmware path
     let default_firmware = match PREFIX {
@@ -123,9 +229,17 @@ fn main() {
             .join("mos_console8.bin"),
     };
 
-    eprintln!("Waiting for VDP to connect...");
+    let vdp_connected = Arc::new(AtomicBool::new(false));
 
-    // Track if CPU has been started (only start on first VDP connection)
+    // The interactive debugger's commands, for --break-on-output
+    let debugger_cmds: DebuggerCmds = Arc::new(OnceLock::new());
+    let break_request = Arc::new(AtomicBool::new(false));
+    if args.break_on_output.is_some() && !args.debugger {
+        eprintln!("Note: --break-on-output pauses through the debugger; without --debugger it only logs until one is attached with SIGUSR2");
+    }
+
+    // Track if CPU has been started (only start on first VDP connection,
+    // unless --no-wait-vdp)
     let mut cpu_started = false;
 
     // Helper closure to start CPU on first VDP connection
@@ -141,6 +255,7 @@ fn main() {
             mpsc::channel();
 
         let debugger_con = if args.debugger {
+            let _ = debugger_cmds.set(tx_cmd_debugger.clone());
             let _ez80_paused = ez80_paused.clone();
             let _emulator_shutdown = emulator_shutdown.clone();
             let _breakpoints = args.breakpoints.clone();
@@ -176,57 +291,71 @@ fn main() {
             None
         };
 
-        let (tx_gpio_vga_frame, rx_gpio_vga_frame) = mpsc::channel::<GpioVgaFrame>();
-
-        // Ignore GPIO VGA frames (only relevant for GPIO video mode)
-        std::thread::spawn(move || loop {
-            std::thread::sleep(Duration::from_millis(50));
-            match rx_gpio_vga_frame.recv() {
-                Ok(_) => {}
-                Err(_) => break,
-            }
-        });
+        // Without the debugger, stdin takes a few commands such as 'dump'
+        let mut control_cons = vec![];
+        if !args.debugger {
+            control_cons.push(control::start());
+        }
+        if let Some((start, len)) = args.watch_mem {
+            control_cons.push(mem_watch::start(start, len, logger.clone()));
+        }
+        // Without --debugger, SIGUSR2 attaches DeZog later
+        let debugger_attach = if args.debugger {
+            None
+        } else {
+            logger.verbose(&format!(
+                "Send SIGUSR2 (kill -USR2 {}) to attach a DZRP debugger on port {}",
+                std::process::id(),
+                args.dzrp_port
+            ));
+            Some(debug_attach::start(args.dzrp_port, emulator_shutdown.clone(), debugger_cmds.clone()))
+        };
 
         let gpios_cpu = gpios.clone();
         let emulator_shutdown_cpu = emulator_shutdown.clone();
         let exit_status_cpu = exit_status.clone();
+        let exit_reason_cpu = exit_reason.clone();
         let ez80_paused_cpu = ez80_paused.clone();
         let soft_reset_cpu = soft_reset.clone();
         let uart0_link = socket_state.create_serial_link();
         let mos_bin = args.mos_bin.clone().unwrap_or_else(|| default_firmware.clone());
         let sdcard = args.sdcard.clone();
         let sdcard_img = args.sdcard_img.clone();
-        let unlimited_cpu = args.unlimited_cpu;
-        let zero = args.zero;
+        let sdcard_img_ro = args.sdcard_img_ro;
+        let cycle_gate_cpu = cycle_gate.clone();
+        let pc_monitor_cpu = args.trace_pc_range.map(|_| pc_monitor.clone());
+        let output_break_cpu = args.break_on_output.as_ref().map(|_| break_request.clone());
+        let max_instructions = args.max_insns;
+        let irq_precision = args.irq_precision;
 
         std::thread::spawn(move || {
-            let mut machine = AgonMachine::new(AgonMachineConfig {
-                ram_init: if zero {
-                    RamInit::Zero
-                } else {
-                    RamInit::Random
-                },
-                uart0_link: Box::new(uart0_link),
-                uart1_link: Box::new(DummySerialLink),
-                soft_reset: soft_reset_cpu,
-                exit_status: exit_status_cpu,
-                paused: ez80_paused_cpu,
-                emulator_shutdown: emulator_shutdown_cpu,
-                gpios: gpios_cpu,
-                tx_gpio_vga_frame,
-                interrupt_precision: 16,
-                clockspeed_hz: if unlimited_cpu {
-                    1_000_000_000
-                } else {
-                    18_432_000
-                },
-                mos_bin,
-                embedded_mos: Some(include_bytes!("../../firmware/mos_console8.bin")),
-            });
+            let mut machine = AgonMachine::new(
+                AgonMachineConfig::builder()
+                    .with_ram_init(match ram_seed {
+                        Some(seed) => RamInit::Seeded(seed),
+                        None => RamInit::Zero,
+                    })
+                    .with_uart0_link(Box::new(uart0_link))
+                    .with_soft_reset(soft_reset_cpu)
+                    .with_exit_status(exit_status_cpu)
+                    .with_exit_reason(exit_reason_cpu)
+                    .with_paused(ez80_paused_cpu)
+                    .with_emulator_shutdown(emulator_shutdown_cpu)
+                    .with_gpios(gpios_cpu)
+                    .with_interrupt_precision(irq_precision)
+                    .with_max_instructions(max_instructions)
+                    .with_clockspeed_hz(clockspeed_hz)
+                    .with_mos_bin(mos_bin)
+                    .with_embedded_mos(Some(include_bytes!("../../firmware/mos_console8.bin")))
+                    .build(),
+            );
 
             if let Some(f) = sdcard_img {
-                match std::fs::File::options().read(true).write(true).open(&f) {
-                    Ok(file) => machine.set_sdcard_image(Some(file)),
+                match std::fs::File::options().read(true).write(!sdcard_img_ro).open(&f) {
+                    Ok(file) => {
+                        machine.set_sdcard_image(Some(file));
+                        machine.set_sdcard_write_protected(sdcard_img_ro);
+                    }
                     Err(e) => {
                         eprintln!("Could not open sdcard image '{}': {:?}", f, e);
                         std::process::exit(1);
@@ -239,25 +368,103 @@ fn main() {
                 });
             }
 
+            machine.set_cycle_gate(cycle_gate_cpu);
+            machine.set_pc_monitor(pc_monitor_cpu);
+            machine.set_output_break(output_break_cpu);
+            machine.set_debugger_attach(debugger_attach);
+            for con in control_cons {
+                machine.add_control_connection(con);
+            }
             machine.start(debugger_con);
+
+            // Only returns at --max-insns, having set the shutdown flag. The
+            // VDP session or the accept loop notices it, and main exits
+            eprintln!("Instruction limit of {} reached", machine.instructions_executed());
         });
 
         *cpu_started = true;
         eprintln!("eZ80 CPU started");
     };
 
+    if args.no_wait_vdp {
+        socket_state.detach();
+        start_cpu(&mut cpu_started);
+    }
+    eprintln!("Waiting for VDP to connect...");
+
+    // Keep the guest's vsync ticking while no VDP is connected
+    if args.keep_vsync {
+        let vdp_connected = vdp_connected.clone();
+        let emulator_shutdown = emulator_shutdown.clone();
+        let gpios = gpios.clone();
+        let cycle_gate = cycle_gate.clone();
+        let logger = logger.clone();
+        std::thread::spawn(move || {
+            let interval = Duration::from_micros(16666);
+            let mut next = Instant::now() + interval;
+            while !emulator_shutdown.load(Ordering::Relaxed) {
+                std::thread::sleep(next.saturating_duration_since(Instant::now()));
+                next += interval;
+                if !vdp_connected.load(Ordering::Relaxed) {
+                    signal_vsync(&gpios, cycle_gate.as_deref(), clockspeed_hz, 1, &logger);
+                }
+            }
+        });
+    }
+
+    // Stop after --run-seconds. A VDP session notices the flag, says
+    // SHUTDOWN and returns; with no VDP the accept loop notices it instead
+    if let Some(secs) = args.run_seconds {
+        let emulator_shutdown = emulator_shutdown.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(secs));
+            eprintln!("Run time of {}s reached, shutting down", secs);
+            emulator_shutdown.store(true, Ordering::Relaxed);
+        });
+    }
+
+    let last_session = Cell::new(None);
+    let session_ctx = SessionContext {
+        socket_state: &socket_state,
+        gpios: &gpios,
+        cycle_gate: cycle_gate.as_deref(),
+        clockspeed_hz,
+        soft_reset: &soft_reset,
+        emulator_shutdown: &emulator_shutdown,
+        logger: &logger,
+        stats_interval: args.stats_interval.map(Duration::from_secs),
+        tx_batch_interval: Duration::from_micros(args.tx_batch_us),
+        tx_batch_bytes: args.tx_batch_bytes,
+        handshake_timeout: Duration::from_secs(args.handshake_timeout),
+        last_session: &last_session,
+        break_on_output: args.break_on_output.as_deref(),
+        debugger_cmds: &debugger_cmds,
+        break_request: &break_request,
+    };
+
     // Main server loop - accept VDP connections (supports reconnection)
     loop {
         let session_result = match &listener {
             Listener::Socket(sock_listener) => {
                 match sock_listener.accept() {
-                    Ok(conn) => {
-                        logger.verbose("[PROTO] VDP connected (socket)");
+                    Ok(mut conn) => {
+                        conn.set_framed(args.framed);
+                        let peer = conn.peer_addr();
+                        logger.verbose(&format!("[PROTO] VDP connected from {} (socket)", peer));
                         if logger.verbosity() < Verbosity::Verbose {
-                            eprintln!("VDP connected");
+                            eprintln!("VDP connected from {}", peer);
                         }
                         start_cpu(&mut cpu_started);
-                        handle_vdp_session(conn, &socket_state, &gpios, &emulator_shutdown, &logger)
+                        socket_state.attach();
+                        vdp_connected.store(true, Ordering::Relaxed);
+                        handle_vdp_session(conn, &session_ctx)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        if emulator_shutdown.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_millis(50));
+                        continue;
                     }
                     Err(e) => {
                         eprintln!("Accept error: {}", e);
@@ -269,12 +476,22 @@ fn main() {
             Listener::WebSocket(ws_listener) => {
                 match ws_listener.accept() {
                     Ok(conn) => {
-                        logger.verbose("[PROTO] VDP connected (WebSocket)");
+                        let peer = conn.peer_addr();
+                        logger.verbose(&format!("[PROTO] VDP connected from {} (WebSocket)", peer));
                         if logger.verbosity() < Verbosity::Verbose {
-                            eprintln!("WebSocket VDP connected");
+                            eprintln!("WebSocket VDP connected from {}", peer);
                         }
                         start_cpu(&mut cpu_started);
-                        handle_vdp_websocket_session(conn, &socket_state, &gpios, &emulator_shutdown, &logger)
+                        socket_state.attach();
+                        vdp_connected.store(true, Ordering::Relaxed);
+                        handle_vdp_websocket_session(conn, &session_ctx)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        if emulator_shutdown.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_millis(50));
+                        continue;
                     }
                     Err(e) => {
                         eprintln!("WebSocket accept error: {}", e);
@@ -285,8 +502,11 @@ fn main() {
             }
         };
 
-        if let Err(e) = session_result {
-            eprintln!("VDP session error: {}", e);
+        vdp_connected.store(false, Ordering::Relaxed);
+        match session_result {
+            Err(e) if e.is_disconnect() => logger.verbose(&format!("[PROTO] VDP connection lost: {}", e)),
+            Err(e) => eprintln!("VDP session error: {}", e),
+            Ok(()) => {}
         }
         if emulator_shutdown.load(Ordering::Relaxed) {
             break;
@@ -295,43 +515,169 @@ fn main() {
     }
 
     let status = exit_status.load(Ordering::Relaxed);
+    let reason = ExitReason::from_u8(exit_reason.load(Ordering::Relaxed));
+    eprintln!("{}", reason.exit_message(status));
     if status != 0 {
         std::process::exit(status);
     }
 }
 
-fn handle_vdp_session(
-    conn: agon_protocol::SocketConnection,
+/// Signal `count` vsyncs to eZ80 via GPIO (pin 1 of GPIO port B). They are
+/// queued, and the CPU pulses the pin for each in turn as it takes the
+/// previous one's interrupt.
+///
+/// In lockstep mode, before each one wait for the CPU to finish the previous
+/// frame's cycles, so the vsync lands at the same point in the instruction
+/// stream on every run, then grant the next frame's cycles.
+fn signal_vsync(gpios: &gpio::GpioSet, cycle_gate: Option<&CycleGate>, clockspeed_hz: u64, count: u8, logger: &Logger) {
+    let Some(gate) = cycle_gate else {
+        gpios.queue_vsyncs(count as u32);
+        return;
+    };
+    for _ in 0..count {
+        if !gate.wait_idle(LOCKSTEP_WAIT_TIMEOUT) {
+            logger.verbose("[LOCKSTEP] CPU did not finish previous frame in time");
+        }
+        gpios.queue_vsyncs(1);
+        gate.grant(clockspeed_hz / 60);
+    }
+}
+
+/// Tell the VDP when the eZ80 falls behind reading its input, and when it
+/// catches up again. agon-vdp-cli paces typed keys by this.
+fn report_rx_ready(
     socket_state: &SocketState,
-    gpios: &Arc<gpio::GpioSet>,
-    emulator_shutdown: &Arc<AtomicBool>,
     logger: &Logger,
+    send: impl FnOnce(&Message) -> Result<(), ProtocolError>,
+) {
+    if let Some(ready) = socket_state.rx_ready_change() {
+        logger.trace(&format!("[PROTO] -> CTS ready={}", ready));
+        let _ = send(&Message::Cts(ready));
+    }
+}
+
+/// Note the session token from a VDP's HELLO, logging whether the VDP is
+/// resuming the previous session. Returns the token to echo in HELLO_ACK.
+fn note_session(session: Option<u64>, last_session: &Cell<Option<u64>>, logger: &Logger) -> Option<u64> {
+    if let Some(token) = session {
+        if last_session.get() == Some(token) {
+            logger.verbose(&format!("[PROTO] VDP resumed session {:016x}", token));
+        } else {
+            logger.verbose(&format!("[PROTO] New VDP session {:016x}", token));
+        }
+    }
+    last_session.set(session);
+    session
+}
+
+/// Report a read timeout during the handshake as a protocol error
+fn hello_timeout_error(e: ProtocolError) -> ProtocolError {
+    if e.is_timeout() {
+        ProtocolError::InvalidFormat("Timed out waiting for HELLO from VDP".to_string())
+    } else {
+        e
+    }
+}
+
+/// At --trace-vdu, name the VDU commands in a batch of eZ80 output
+fn trace_vdu(decoder: &mut VduDecoder, bytes: &[u8], logger: &Logger) {
+    if logger.verbosity() < Verbosity::TraceVdu {
+        return;
+    }
+    let names = decoder.decode(bytes);
+    if !names.is_empty() {
+        logger.trace_vdu(&format!("[VDU] -> {}", names.join(", ")));
+    }
+}
+
+/// At --break-on-output, pause the CPU if a batch of eZ80 output completes
+/// the pattern
+fn check_output_break(
+    matcher: &mut Option<OutputMatcher>,
+    bytes: &[u8],
+    debugger_cmds: &DebuggerCmds,
+    break_request: &AtomicBool,
+    logger: &Logger,
+) {
+    let Some(matcher) = matcher else {
+        return;
+    };
+    if matcher.feed(bytes) {
+        if output_break::pause(debugger_cmds, break_request) {
+            logger.info("Output pattern seen, pausing the CPU");
+        } else {
+            logger.info("Output pattern seen, but no debugger is attached to pause the CPU");
+        }
+    }
+}
+
+fn handle_vdp_session(
+    conn: agon_protocol::SocketConnection,
+    ctx: &SessionContext,
 ) -> Result<(), ProtocolError> {
+    let SessionContext {
+        socket_state,
+        gpios,
+        cycle_gate,
+        clockspeed_hz,
+        soft_reset,
+        emulator_shutdown,
+        logger,
+        stats_interval,
+        tx_batch_interval,
+        tx_batch_bytes,
+        handshake_timeout,
+        last_session,
+        break_on_output,
+        debugger_cmds,
+        break_request,
+    } = *ctx;
+
     // Split connection for bidirectional communication
     let (mut reader, mut writer) = conn.split();
 
     // Wait for HELLO from VDP (VDP is the connector, so it sends HELLO)
     logger.verbose("[PROTO] Waiting for HELLO from VDP...");
-    let msg = reader.recv()?;
-    match msg {
-        Message::Hello { version, flags } => {
+    reader.set_read_timeout(Some(handshake_timeout))?;
+    let msg = reader.recv().map_err(hello_timeout_error)?;
+    reader.set_read_timeout(None)?;
+    let session = match msg {
+        Message::Hello {
+            version,
+            flags,
+            session,
+        } => {
             logger.verbose(&format!("[PROTO] <- HELLO version={}, flags={}", version, flags));
             if logger.verbosity() < Verbosity::Verbose {
                 eprintln!("VDP version {}, flags={}", version, flags);
             }
+            if let Err(e) = negotiate_version(version) {
+                logger.verbose(&format!("[PROTO] -> VERSION_REJECT supported={}", PROTOCOL_VERSION));
+                let _ = writer.send(&Message::VersionReject {
+                    supported: PROTOCOL_VERSION,
+                });
+                return Err(e);
+            }
+            if let Err(e) = check_hello_flags(flags, SUPPORTED_REQUIRED_FLAGS) {
+                logger.verbose(&format!("[PROTO] -> SHUTDOWN ({})", e));
+                let _ = writer.send(&Message::Shutdown);
+                return Err(e);
+            }
+            note_session(session, last_session, logger)
         }
         _ => {
             return Err(ProtocolError::InvalidFormat(
                 "Expected HELLO from VDP".to_string(),
             ));
         }
-    }
+    };
 
     // Send HELLO_ACK
     let caps = r#"{"type":"ez80","version":"1.0"}"#;
     writer.send(&Message::HelloAck {
         version: PROTOCOL_VERSION,
         capabilities: caps.to_string(),
+        session,
     })?;
     logger.verbose(&format!("[PROTO] -> HELLO_ACK version={}, caps={}", PROTOCOL_VERSION, caps));
     if logger.verbosity() < Verbosity::Verbose {
@@ -342,51 +688,64 @@ fn handle_vdp_session(
     let (tx_from_vdp, rx_from_vdp): (Sender<Message>, Receiver<Message>) = mpsc::channel();
     let emulator_shutdown_reader = emulator_shutdown.clone();
 
-    std::thread::spawn(move || loop {
-        if emulator_shutdown_reader.load(Ordering::Relaxed) {
-            break;
-        }
-        match reader.recv() {
-            Ok(msg) => {
-                if tx_from_vdp.send(msg).is_err() {
-                    break;
-                }
-            }
-            Err(ProtocolError::ConnectionClosed) => break,
-            Err(e) => {
-                eprintln!("Socket read error: {}", e);
+    std::thread::spawn(move || {
+        for msg in reader.messages() {
+            if emulator_shutdown_reader.load(Ordering::Relaxed) {
                 break;
             }
+            match msg {
+                Ok(msg) => {
+                    if tx_from_vdp.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => eprintln!("Socket read error: {}", e),
+            }
         }
     });
 
     // Main communication loop
     let mut last_tx_time = Instant::now();
-    let tx_interval = Duration::from_micros(100); // Send at most every 100us
-    let mut vsync_count: u64 = 0;
+    let mut stats = SessionStats::new();
+    let mut vdu_decoder = VduDecoder::new();
+    let mut output_matcher = break_on_output.map(OutputMatcher::new);
+    let mut last_stats = Instant::now();
 
     while !emulator_shutdown.load(Ordering::Relaxed) {
         // Process messages from VDP
         let mut vdp_disconnected = false;
         while let Ok(msg) = rx_from_vdp.try_recv() {
+            stats.record_rx(&msg);
             match msg {
                 Message::UartData(data) => {
-                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_hex(&data)));
+                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_payload(&data)));
                     socket_state.queue_rx(&data);
                 }
                 Message::Vsync => {
-                    vsync_count += 1;
-                    if vsync_count % 60 == 0 {
-                        logger.trace(&format!("[PROTO] <- VSYNC #{} (~{} seconds)", vsync_count, vsync_count / 60));
+                    if stats.vsyncs % 60 == 0 {
+                        logger.trace(&format!("[PROTO] <- VSYNC #{} (~{} seconds)", stats.vsyncs, stats.vsyncs / 60));
                     }
-                    // Signal vsync to eZ80 via GPIO (pin 1 of GPIO port B)
-                    gpios.b.set_input_pin(1, true);
-                    gpios.b.set_input_pin(1, false);
+                    signal_vsync(gpios, cycle_gate, clockspeed_hz, 1, logger);
+                }
+                Message::VsyncBatch(count) => {
+                    logger.trace(&format!("[PROTO] <- VSYNC_BATCH count={} (total {})", count, stats.vsyncs));
+                    signal_vsync(gpios, cycle_gate, clockspeed_hz, count, logger);
                 }
                 Message::Cts(ready) => {
                     logger.trace(&format!("[PROTO] <- CTS ready={}", ready));
                     socket_state.set_cts(ready);
                 }
+                Message::Echo(data) => {
+                    logger.trace(&format!("[PROTO] <- ECHO ({} bytes), echoing back", data.len()));
+                    let _ = writer.send(&Message::Echo(data));
+                }
+                Message::Reset => {
+                    logger.verbose("[PROTO] <- RESET");
+                    if logger.verbosity() < Verbosity::Verbose {
+                        eprintln!("VDP requested soft reset");
+                    }
+                    soft_reset.store(true, Ordering::Relaxed);
+                }
                 Message::Shutdown => {
                     logger.verbose("[PROTO] <- SHUTDOWN");
                     if logger.verbosity() < Verbosity::Verbose {
@@ -405,12 +764,24 @@ fn handle_vdp_session(
             break;
         }
 
-        // Send pending TX bytes to VDP (batched)
-        if last_tx_time.elapsed() >= tx_interval {
+        report_rx_ready(socket_state, logger, |msg| writer.send(msg));
+
+        // Send pending TX bytes to VDP (batched): when the batch window has
+        // elapsed, or enough bytes have accumulated, whichever comes first
+        let batch_full = tx_batch_bytes.is_some_and(|n| socket_state.tx_len() >= n);
+        if batch_full || last_tx_time.elapsed() >= tx_batch_interval {
             let tx_bytes = socket_state.drain_tx();
             if !tx_bytes.is_empty() {
-                logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_hex(&tx_bytes)));
-                if let Err(e) = writer.send(&Message::UartData(tx_bytes)) {
+                logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_payload(&tx_bytes)));
+                trace_vdu(&mut vdu_decoder, &tx_bytes, logger);
+                check_output_break(&mut output_matcher, &tx_bytes, debugger_cmds, break_request, logger);
+                stats.record_tx(tx_bytes.len());
+                // A large batch goes out as several messages, flushed once
+                let sent = tx_bytes
+                    .chunks(MAX_UART_DATA_SIZE)
+                    .try_for_each(|chunk| writer.send_deferred(&Message::UartData(chunk.to_vec())))
+                    .and_then(|()| writer.flush());
+                if let Err(e) = sent {
                     eprintln!("Socket write error: {}", e);
                     break;
                 }
@@ -418,10 +789,19 @@ fn handle_vdp_session(
             last_tx_time = Instant::now();
         }
 
+        if let Some(interval) = stats_interval {
+            if last_stats.elapsed() >= interval {
+                logger.verbose(&format!("[STATS] {}", stats.summary()));
+                last_stats = Instant::now();
+            }
+        }
+
         // Small sleep to avoid busy-waiting
         std::thread::sleep(Duration::from_micros(100));
     }
 
+    logger.info(&format!("Session stats: {}", stats.summary()));
+
     // Send shutdown to VDP
     logger.verbose("[PROTO] -> SHUTDOWN");
     let _ = writer.send(&Message::Shutdown);
@@ -431,82 +811,164 @@ fn handle_vdp_session(
 
 fn handle_vdp_websocket_session(
     mut conn: WebSocketConnection,
-    socket_state: &SocketState,
-    gpios: &Arc<gpio::GpioSet>,
-    emulator_shutdown: &Arc<AtomicBool>,
-    logger: &Logger,
+    ctx: &SessionContext,
 ) -> Result<(), ProtocolError> {
+    let SessionContext {
+        socket_state,
+        gpios,
+        cycle_gate,
+        clockspeed_hz,
+        soft_reset,
+        emulator_shutdown,
+        logger,
+        stats_interval,
+        tx_batch_interval,
+        tx_batch_bytes,
+        handshake_timeout,
+        last_session,
+        break_on_output,
+        debugger_cmds,
+        break_request,
+    } = *ctx;
+
     // Wait for HELLO from VDP (VDP is the connector, so it sends HELLO)
     logger.verbose("[PROTO] Waiting for HELLO from WebSocket VDP...");
-    let msg = conn.recv()?;
-    match msg {
-        Message::Hello { version, flags } => {
+    conn.set_read_timeout(Some(handshake_timeout))?;
+    let msg = conn.recv().map_err(hello_timeout_error)?;
+    conn.set_read_timeout(None)?;
+    let session = match msg {
+        Message::Hello {
+            version,
+            flags,
+            session,
+        } => {
             logger.verbose(&format!("[PROTO] <- HELLO version={}, flags={}", version, flags));
             if logger.verbosity() < Verbosity::Verbose {
                 eprintln!("WebSocket VDP version {}, flags={}", version, flags);
             }
+            if flags & HELLO_FLAG_TEXT_FRAMES != 0 {
+                logger.verbose("[PROTO] VDP asked for base64 text frames");
+                conn.set_text_frames(true);
+            }
+            if let Err(e) = negotiate_version(version) {
+                logger.verbose(&format!("[PROTO] -> VERSION_REJECT supported={}", PROTOCOL_VERSION));
+                let _ = conn.send(&Message::VersionReject {
+                    supported: PROTOCOL_VERSION,
+                });
+                return Err(e);
+            }
+            if let Err(e) = check_hello_flags(flags, SUPPORTED_REQUIRED_FLAGS) {
+                logger.verbose(&format!("[PROTO] -> SHUTDOWN ({})", e));
+                let _ = conn.send(&Message::Shutdown);
+                return Err(e);
+            }
+            note_session(session, last_session, logger)
         }
         _ => {
             return Err(ProtocolError::InvalidFormat(
                 "Expected HELLO from VDP".to_string(),
             ));
         }
-    }
+    };
 
     // Send HELLO_ACK
     let caps = r#"{"type":"ez80","version":"1.0"}"#;
     conn.send(&Message::HelloAck {
         version: PROTOCOL_VERSION,
         capabilities: caps.to_string(),
+        session,
     })?;
     logger.verbose(&format!("[PROTO] -> HELLO_ACK version={}, caps={}", PROTOCOL_VERSION, caps));
     if logger.verbosity() < Verbosity::Verbose {
         eprintln!("WebSocket handshake complete");
     }
 
-    // Main communication loop (WebSocket is already message-based, no need for split)
+    // Split connection, so a blocking reader thread can feed the main loop
+    let (mut reader, mut writer) = conn.split()?;
+
+    let (tx_from_vdp, rx_from_vdp): (Sender<Message>, Receiver<Message>) = mpsc::channel();
+    let emulator_shutdown_reader = emulator_shutdown.clone();
+
+    std::thread::spawn(move || loop {
+        if emulator_shutdown_reader.load(Ordering::Relaxed) {
+            break;
+        }
+        match reader.recv() {
+            Ok(msg) => {
+                if tx_from_vdp.send(msg).is_err() {
+                    break;
+                }
+            }
+            Err(ProtocolError::ConnectionClosed) => break,
+            Err(e) => {
+                eprintln!("WebSocket read error: {}", e);
+                break;
+            }
+        }
+    });
+
+    // Main communication loop
     let mut last_tx_time = Instant::now();
-    let tx_interval = Duration::from_micros(100);
-    let mut vsync_count: u64 = 0;
+    let mut stats = SessionStats::new();
+    let mut vdu_decoder = VduDecoder::new();
+    let mut output_matcher = break_on_output.map(OutputMatcher::new);
+    let mut last_stats = Instant::now();
 
     while !emulator_shutdown.load(Ordering::Relaxed) {
-        // Try to receive messages from VDP (non-blocking)
+        // Process messages from VDP
         let mut vdp_disconnected = false;
-        match conn.try_recv() {
-            Ok(Some(msg)) => match msg {
+        loop {
+            let msg = match rx_from_vdp.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::TryRecvError::Empty) => break,
+                // reader thread has exited
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    vdp_disconnected = true;
+                    break;
+                }
+            };
+            stats.record_rx(&msg);
+            match msg {
                 Message::UartData(data) => {
-                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_hex(&data)));
+                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_payload(&data)));
                     socket_state.queue_rx(&data);
                 }
                 Message::Vsync => {
-                    vsync_count += 1;
-                    if vsync_count % 60 == 0 {
-                        logger.trace(&format!("[PROTO] <- VSYNC #{} (~{} seconds)", vsync_count, vsync_count / 60));
+                    if stats.vsyncs % 60 == 0 {
+                        logger.trace(&format!("[PROTO] <- VSYNC #{} (~{} seconds)", stats.vsyncs, stats.vsyncs / 60));
                     }
-                    gpios.b.set_input_pin(1, true);
-                    gpios.b.set_input_pin(1, false);
+                    signal_vsync(gpios, cycle_gate, clockspeed_hz, 1, logger);
+                }
+                Message::VsyncBatch(count) => {
+                    logger.trace(&format!("[PROTO] <- VSYNC_BATCH count={} (total {})", count, stats.vsyncs));
+                    signal_vsync(gpios, cycle_gate, clockspeed_hz, count, logger);
                 }
                 Message::Cts(ready) => {
                     logger.trace(&format!("[PROTO] <- CTS ready={}", ready));
                     socket_state.set_cts(ready);
                 }
+                Message::Echo(data) => {
+                    logger.trace(&format!("[PROTO] <- ECHO ({} bytes), echoing back", data.len()));
+                    let _ = writer.send(&Message::Echo(data));
+                }
+                Message::Reset => {
+                    logger.verbose("[PROTO] <- RESET");
+                    if logger.verbosity() < Verbosity::Verbose {
+                        eprintln!("VDP requested soft reset");
+                    }
+                    soft_reset.store(true, Ordering::Relaxed);
+                }
                 Message::Shutdown => {
                     logger.verbose("[PROTO] <- SHUTDOWN");
                     if logger.verbosity() < Verbosity::Verbose {
                         eprintln!("WebSocket VDP requested shutdown");
                     }
                     vdp_disconnected = true;
+                    break;
                 }
                 other => {
                     logger.trace(&format!("[PROTO] <- {:?} (unexpected)", other));
                 }
-            },
-            Ok(None) => {
-                // No message available
-            }
-            Err(e) => {
-                eprintln!("WebSocket read error: {}", e);
-                vdp_disconnected = true;
             }
         }
 
@@ -514,12 +976,19 @@ fn handle_vdp_websocket_session(
             break;
         }
 
-        // Send pending TX bytes to VDP (batched)
-        if last_tx_time.elapsed() >= tx_interval {
+        report_rx_ready(socket_state, logger, |msg| writer.send(msg));
+
+        // Send pending TX bytes to VDP (batched): when the batch window has
+        // elapsed, or enough bytes have accumulated, whichever comes first
+        let batch_full = tx_batch_bytes.is_some_and(|n| socket_state.tx_len() >= n);
+        if batch_full || last_tx_time.elapsed() >= tx_batch_interval {
             let tx_bytes = socket_state.drain_tx();
             if !tx_bytes.is_empty() {
-                logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_hex(&tx_bytes)));
-                if let Err(e) = conn.send(&Message::UartData(tx_bytes)) {
+                logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_payload(&tx_bytes)));
+                trace_vdu(&mut vdu_decoder, &tx_bytes, logger);
+                check_output_break(&mut output_matcher, &tx_bytes, debugger_cmds, break_request, logger);
+                stats.record_tx(tx_bytes.len());
+                if let Err(e) = writer.send(&Message::UartData(tx_bytes)) {
                     eprintln!("WebSocket write error: {}", e);
                     break;
                 }
@@ -527,13 +996,102 @@ fn handle_vdp_websocket_session(
             last_tx_time = Instant::now();
         }
 
+        if let Some(interval) = stats_interval {
+            if last_stats.elapsed() >= interval {
+                logger.verbose(&format!("[STATS] {}", stats.summary()));
+                last_stats = Instant::now();
+            }
+        }
+
         // Small sleep to avoid busy-waiting
         std::thread::sleep(Duration::from_micros(100));
     }
 
+    logger.info(&format!("Session stats: {}", stats.summary()));
+
     // Send shutdown to VDP
     logger.verbose("[PROTO] -> SHUTDOWN");
-    let _ = conn.send(&Message::Shutdown);
+    let _ = writer.send(&Message::Shutdown);
+
+    // Close cleanly, so browsers don't report an abnormal closure: send a
+    // close frame, then wait for the reader thread to see the peer's reply
+    if writer.close().is_ok() {
+        let deadline = Instant::now() + WEBSOCKET_CLOSE_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx_from_vdp.recv_timeout(remaining) {
+                Ok(_) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    logger.verbose("[PROTO] No WebSocket close reply from VDP");
+                    break;
+                }
+            }
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// GPIO port B with pin 1 as a rising-edge interrupt, as MOS sets it up
+    fn vsync_gpios() -> gpio::GpioSet {
+        let gpios = gpio::GpioSet::new();
+        gpios.b.set_dr(0x02);
+        gpios.b.alt1.store(0x02, Ordering::Relaxed);
+        gpios.b.alt2.store(0x02, Ordering::Relaxed);
+        assert_eq!(gpios.b.get_mode(1), 15);
+        gpios
+    }
+
+    #[test]
+    fn test_vsync_batch_queues() {
+        let logger = Logger::stderr(Verbosity::Quiet);
+
+        let gpios = vsync_gpios();
+        signal_vsync(&gpios, None, 18_432_000, 0, &logger);
+        gpios.deliver_vsync();
+        assert_eq!(gpios.b.get_interrupt_due(), 0);
+
+        let gpios = vsync_gpios();
+        signal_vsync(&gpios, None, 18_432_000, 3, &logger);
+        assert_eq!(gpios.vsyncs_pending(), 3);
+        // one edge at a time, each acknowledged before the next
+        for left in (0..3).rev() {
+            gpios.deliver_vsync();
+            assert_eq!(gpios.b.get_interrupt_due(), 0x02);
+            assert_eq!(gpios.vsyncs_pending(), left);
+            // every pulse leaves the line low again
+            assert_eq!(gpios.b.get_output_level() & 0x02, 0);
+            gpios.b.set_dr(0x02);
+        }
+        assert_eq!(gpios.b.get_interrupt_due(), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_rx_backlog_reaches_vdp_as_cts() {
+        use agon_ez80_emulator::SerialLink;
+        use agon_protocol::SocketConnection;
+
+        let addr = SocketAddr::unix("/tmp/agon-ez80-test-cts.sock");
+        let listener = SocketListener::bind(&addr).unwrap();
+        let mut vdp = SocketConnection::connect(&addr).unwrap();
+        let (_reader, mut writer) = listener.accept().unwrap().split();
+        let logger = Logger::stderr(Verbosity::Quiet);
+
+        let state = SocketState::new();
+        let mut link = state.create_serial_link();
+        // a burst of typed keys the guest hasn't read yet
+        state.queue_rx(&[0; 100]);
+        report_rx_ready(&state, &logger, |msg| writer.send(msg));
+        assert_eq!(vdp.recv().unwrap(), Message::Cts(false));
+
+        while link.recv().is_some() {}
+        report_rx_ready(&state, &logger, |msg| writer.send(msg));
+        assert_eq!(vdp.recv().unwrap(), Message::Cts(true));
+    }
+}
@@ -0,0 +1,109 @@
+//! --watch-mem: log every change to a region of guest memory.
+//!
+//! Polls over a read-only debugger connection, so works without
+//! --debugger. The PC logged is where the CPU was when the change was
+//! seen, which may be a little after the instruction that made it.
+
+use crate::logger::Logger;
+use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, DebuggerConnection};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size of the eZ80's 24-bit address space
+const ADDRESS_SPACE: u32 = 0x1000000;
+
+/// Parse a watch region such as "4A000" or "4A000:10" (hex address and
+/// optional hex length, default 1)
+pub fn parse_watch_mem(s: &str) -> Result<(u32, u32), String> {
+    let parse_hex = |v: &str| {
+        u32::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| format!("invalid hex number '{}'", v))
+    };
+    let (start, len) = match s.split_once(':') {
+        Some((start, len)) => (parse_hex(start)?, parse_hex(len)?),
+        None => (parse_hex(s)?, 1),
+    };
+    if len == 0 {
+        return Err("watch length must be at least 1".to_string());
+    }
+    match start.checked_add(len) {
+        Some(end) if end <= ADDRESS_SPACE => Ok((start, len)),
+        _ => Err(format!("watch region &{:X}:{:X} goes past the end of memory (&FFFFFF)", start, len)),
+    }
+}
+
+/// Start watching `len` bytes from `start`. Returns the connection to hand
+/// to `AgonMachine::add_control_connection`.
+pub fn start(start: u32, len: u32, logger: Logger) -> DebuggerConnection {
+    let (tx_cmd, rx_cmd): (Sender<DebugCmd>, Receiver<DebugCmd>) = mpsc::channel();
+    let (tx_resp, rx_resp): (Sender<DebugResp>, Receiver<DebugResp>) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        logger.info(&format!("Watching &{:06X}-&{:06X}", start, start + len - 1));
+        let mut last: Option<Vec<u8>> = None;
+        while let Some(data) = request(&tx_cmd, &rx_resp, DebugCmd::GetMemory { start, len }) {
+            let data = match data {
+                DebugResp::Memory { data, .. } => data,
+                _ => continue,
+            };
+            if let Some(old) = &last {
+                let changed = changes(start, old, &data);
+                if !changed.is_empty() {
+                    let pc = match request(&tx_cmd, &rx_resp, DebugCmd::GetRegisters) {
+                        Some(DebugResp::Registers(r)) => format!("&{:06X}", r.pc),
+                        _ => "?".to_string(),
+                    };
+                    for (address, old, new) in changed {
+                        logger.info(&format!("[WATCH] &{:06X}: {:02X} -> {:02X} (PC {})", address, old, new, pc));
+                    }
+                }
+            }
+            last = Some(data);
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    });
+
+    DebuggerConnection {
+        tx: tx_resp,
+        rx: rx_cmd,
+    }
+}
+
+/// Send `cmd` and wait for its response. None once the CPU has stopped.
+fn request(tx: &Sender<DebugCmd>, rx: &Receiver<DebugResp>, cmd: DebugCmd) -> Option<DebugResp> {
+    tx.send(cmd).ok()?;
+    rx.recv_timeout(RESPONSE_TIMEOUT).ok()
+}
+
+/// (address, old, new) for each byte that differs
+fn changes(start: u32, old: &[u8], new: &[u8]) -> Vec<(u32, u8, u8)> {
+    old.iter()
+        .zip(new)
+        .enumerate()
+        .filter(|(_, (o, n))| o != n)
+        .map(|(i, (&o, &n))| (start + i as u32, o, n))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watch_mem() {
+        assert_eq!(parse_watch_mem("4A000"), Ok((0x4a000, 1)));
+        assert_eq!(parse_watch_mem("0x4A000:10"), Ok((0x4a000, 0x10)));
+        assert!(parse_watch_mem("4A000:0").is_err());
+        assert!(parse_watch_mem("xyz").is_err());
+        assert_eq!(parse_watch_mem("FFFFF0:10"), Ok((0xfffff0, 0x10)));
+        assert!(parse_watch_mem("FFFFF0:11").is_err());
+        assert!(parse_watch_mem("1:FFFFFFFF").is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        assert_eq!(changes(0x40000, &[1, 2, 3], &[1, 5, 3]), vec![(0x40001, 2, 5)]);
+        assert!(changes(0x40000, &[1, 2], &[1, 2]).is_empty());
+    }
+}
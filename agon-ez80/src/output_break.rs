@@ -0,0 +1,91 @@
+//! --break-on-output: pause the CPU when the guest sends a given byte
+//! sequence to the VDP.
+//!
+//! The pause goes through the interactive debugger (--debugger, or one
+//! attached with SIGUSR2), which reports it like a breakpoint, with reason
+//! `PauseReason::OutputMatch`. The CPU will have run on a little past the
+//! write, by up to one TX batch.
+
+use agon_ez80_emulator::debugger::DebugCmd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, OnceLock};
+
+/// Command channel of the interactive debugger, once there is one
+pub type DebuggerCmds = Arc<OnceLock<Sender<DebugCmd>>>;
+
+/// Parse a byte pattern such as "4E6F" or "4e 6f 20" (hex, spaces ignored)
+pub fn parse_hex_pattern(s: &str) -> Result<Vec<u8>, String> {
+    let digits: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(format!("expected an even number of hex digits, got '{}'", s));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| format!("invalid hex byte '{}'", &digits[i..i + 2])))
+        .collect()
+}
+
+/// Finds a byte pattern in a stream that arrives in batches
+pub struct OutputMatcher {
+    pattern: Vec<u8>,
+    /// Tail of the stream so far, long enough to hold all but the last
+    /// byte of a match
+    tail: Vec<u8>,
+}
+
+impl OutputMatcher {
+    pub fn new(pattern: &[u8]) -> Self {
+        OutputMatcher {
+            pattern: pattern.to_vec(),
+            tail: Vec::new(),
+        }
+    }
+
+    /// Feed the next batch. Returns true if the pattern ends within it.
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        self.tail.extend_from_slice(bytes);
+        let found = self.tail.windows(self.pattern.len()).any(|w| w == self.pattern);
+        let keep = self.pattern.len() - 1;
+        if found {
+            self.tail.clear();
+        } else if self.tail.len() > keep {
+            self.tail.drain(..self.tail.len() - keep);
+        }
+        found
+    }
+}
+
+/// Ask the machine to pause at its next instruction, if a debugger is
+/// attached to act on it. Returns false if not.
+pub fn pause(debugger: &DebuggerCmds, request: &AtomicBool) -> bool {
+    if debugger.get().is_none() {
+        return false;
+    }
+    request.store(true, Ordering::Relaxed);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_pattern() {
+        assert_eq!(parse_hex_pattern("4E6f 20"), Ok(vec![0x4e, 0x6f, 0x20]));
+        assert!(parse_hex_pattern("4E6").is_err());
+        assert!(parse_hex_pattern("zz").is_err());
+        assert!(parse_hex_pattern("").is_err());
+    }
+
+    #[test]
+    fn test_match_across_batches() {
+        let mut m = OutputMatcher::new(b"Bad");
+        assert!(!m.feed(b"xxB"));
+        assert!(m.feed(b"ad command"));
+        // each occurrence matches once
+        assert!(!m.feed(b"!"));
+        assert!(!m.feed(b"Ba"));
+        assert!(m.feed(b"d"));
+    }
+}
@@ -1,3 +1,8 @@
+use crate::logger::{parse_pc_range, parse_size, LogFormat};
+use crate::{CLOCKSPEED_HZ, MIN_CLOCKSPEED_HZ, UNLIMITED_CLOCKSPEED_HZ};
+use crate::mem_watch::parse_watch_mem;
+use crate::output_break::parse_hex_pattern;
+
 const HELP: &str = "\
 Agon eZ80 - Standalone eZ80 emulator
 
@@ -8,20 +13,87 @@ USAGE:
 
 OPTIONS:
   -h, --help            Prints help information
-  --socket <path>       Unix socket path (default: /tmp/agon-vdp.sock)
+  --socket <path>       Unix socket path, or @name for a Linux abstract
+                        socket (default: $AGON_VDP_SOCKET, or /tmp/agon-vdp.sock)
+  --socket-mode <mode>  Permissions for the Unix socket file, in octal
+                        (e.g. 660 to let only the owner and group connect)
   --tcp <port>          Listen on TCP port instead of Unix socket
   --websocket <port>    Listen for WebSocket connections on port (for web VDPs)
+  --framed              Precede each message with a resync marker, for links
+                        that may lose bytes (proxies, serial bridges). The VDP
+                        must use --framed too. Not used with --websocket
   --mos <path>          Use a different MOS.bin firmware
   --sdcard-img <file>   Use a raw SDCard image rather than the host filesystem
+  --sdcard-img-ro <file>  As --sdcard-img, but read-only (write-protected card)
   --sdcard <path>       Sets the path of the emulated SDCard
   -u, --unlimited-cpu   Don't limit eZ80 CPU frequency
+  --cpu-speed <hz|Nx>   eZ80 clock as a frequency in Hz (e.g. 36864000) or a
+                        multiple of the real 18.432 MHz (e.g. 2x, 0.5x)
+  --keep-vsync          Generate 60Hz vsync internally while no VDP is connected
+  --no-wait-vdp         Start the eZ80 straight away rather than on the first
+                        VDP connection. Its output is discarded until a VDP
+                        connects. Combine with --keep-vsync and --run-seconds
+                        for headless boot tests
+  --irq-precision <n>   CPU cycles between checks for interrupts, 1-4096
+                        (default: 16). Interrupts and timer/UART updates can
+                        be up to <n> cycles late; smaller values are more
+                        accurate for timing-sensitive code but run slower
+  --lockstep            Run exactly clockspeed/60 CPU cycles per VSYNC from the VDP.
+                        Trades realism for deterministic, timing-independent runs
   -z, --zero            Initialize RAM with zeroes instead of random values
+  --ram-seed <n>        Seed for the random initial RAM contents. The seed used
+                        is logged at startup, so a run can be reproduced.
+                        Not with --zero
   -d, --debugger        Enable debugger
   -b, --breakpoint <addr>  Set initial breakpoint (hex address)
+  --dzrp-port <port>    Port for a DeZog (DZRP) debugger attached while running
+                        (default: 11000). Without --debugger, sending the
+                        process SIGUSR2 starts the DZRP server (unix only)
+  --break-on-output <hex>  Pause the CPU (via the debugger) when the guest
+                        sends this byte sequence to the VDP, e.g.
+                        --break-on-output \"4E 6F 20\" for \"No \"
+  --watch-mem <addr[:len]>  Log every change to guest memory at this hex
+                        address (and length, default 1), with the PC.
+                        Works without --debugger
   -v, --verbose         Show connection and protocol events
   -vv, --trace          Show all protocol messages
+  --trace-vdu           As --trace, and name the VDU commands the eZ80 sends
+                        (e.g. \"VDU 25 PLOT\")
   -vvv, --trace-uart    Show individual UART bytes (very verbose)
   --log <file>          Write trace output to file instead of stderr
+  --log-format <fmt>    Log line format: text (default) or json
+  --trace-pc-range <start-end>  Only show -vv/-vvv trace lines while the
+                        CPU PC is in this hex range (e.g. 40000-4FFFF)
+  --log-timestamps      Prefix log lines with seconds since startup
+  --tx-batch-us <us>    Send eZ80->VDP UART data at most every <us> microseconds
+                        (default: 100). Larger values mean fewer, bigger
+                        messages but more output latency
+  --tx-batch-bytes <n>  Send early once <n> bytes are pending, even if the
+                        --tx-batch-us window has not elapsed
+  --handshake-timeout <s>  Drop a VDP that hasn't sent HELLO within <s> seconds
+                        (default: 5, minimum 1)
+  --stats-interval <s>  Log link statistics every <s> seconds (verbose level,
+                        minimum 1). A summary is always printed when a VDP
+                        disconnects
+  --max-insns <n>       Stop the CPU and shut down after exactly <n> instructions,
+                        however long they take. With --ram-seed, makes runs
+                        fully reproducible (e.g. for fuzzing)
+  --run-seconds <n>     Shut down after <n> seconds of wall time, exiting with
+                        status 0 or the guest's exit status if it set one
+  --log-max-size <size> Rotate the --log file to <file>.1 beyond this size
+                        (e.g. 10M; default unlimited)
+
+STDIN COMMANDS (without --debugger):
+  dump [file]           Write guest RAM to file (default: agon-ram-<time>.bin)
+  help                  List commands
+
+CONFIG FILE:
+  Defaults for --sdcard, --mos and --socket can be set in
+  ~/.config/fab-agon-emulator/config.toml, e.g.:
+    sdcard = \"/home/me/agon/sdcard\"
+    mos_bin = \"/home/me/agon/MOS.bin\"
+    socket = \"/tmp/agon-vdp.sock\"
+  Command-line options always take precedence.
 ";
 
 /// Verbosity level for debug output
@@ -33,8 +105,10 @@ pub enum Verbosity {
     Verbose = 1,
     /// All protocol messages (VSYNC, CTS, etc.)
     Trace = 2,
+    /// Protocol messages, with the VDU commands in eZ80 output named
+    TraceVdu = 3,
     /// Individual UART bytes
-    TraceUart = 3,
+    TraceUart = 4,
 }
 
 impl Default for Verbosity {
@@ -46,17 +120,39 @@ impl Default for Verbosity {
 #[derive(Debug)]
 pub struct AppArgs {
     pub socket_path: Option<String>,
+    pub socket_mode: Option<u32>,
     pub tcp_port: Option<u16>,
     pub websocket_port: Option<u16>,
+    pub framed: bool,
     pub sdcard: Option<String>,
     pub sdcard_img: Option<String>,
+    pub sdcard_img_ro: bool,
     pub unlimited_cpu: bool,
+    pub cpu_speed_hz: Option<u64>,
+    pub lockstep: bool,
+    pub irq_precision: i32,
+    pub keep_vsync: bool,
+    pub no_wait_vdp: bool,
     pub zero: bool,
+    pub ram_seed: Option<u64>,
     pub mos_bin: Option<std::path::PathBuf>,
     pub debugger: bool,
     pub breakpoints: Vec<u32>,
+    pub dzrp_port: u16,
+    pub break_on_output: Option<Vec<u8>>,
+    pub watch_mem: Option<(u32, u32)>,
     pub verbosity: Verbosity,
     pub log_file: Option<String>,
+    pub log_format: LogFormat,
+    pub log_timestamps: bool,
+    pub log_max_size: Option<u64>,
+    pub trace_pc_range: Option<(u32, u32)>,
+    pub stats_interval: Option<u64>,
+    pub tx_batch_us: u64,
+    pub tx_batch_bytes: Option<usize>,
+    pub handshake_timeout: u64,
+    pub run_seconds: Option<u64>,
+    pub max_insns: Option<u64>,
 }
 
 pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
@@ -81,6 +177,8 @@ pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
     // Count -v flags for verbosity level
     let verbosity = if pargs.contains("--trace-uart") || pargs.contains("-vvv") {
         Verbosity::TraceUart
+    } else if pargs.contains("--trace-vdu") {
+        Verbosity::TraceVdu
     } else if pargs.contains("--trace") || pargs.contains("-vv") {
         Verbosity::Trace
     } else if pargs.contains(["-v", "--verbose"]) {
@@ -89,21 +187,82 @@ pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
         Verbosity::Quiet
     };
 
-    let args = AppArgs {
+    let sdcard_img: Option<String> = pargs.opt_value_from_str("--sdcard-img")?;
+    let sdcard_img_ro: Option<String> = pargs.opt_value_from_str("--sdcard-img-ro")?;
+    if sdcard_img.is_some() && sdcard_img_ro.is_some() {
+        return Err(pico_args::Error::ArgumentParsingFailed {
+            cause: "--sdcard-img and --sdcard-img-ro are mutually exclusive".to_string(),
+        });
+    }
+
+    let mut args = AppArgs {
         socket_path: pargs.opt_value_from_str("--socket")?,
+        socket_mode: pargs.opt_value_from_fn("--socket-mode", parse_mode)?,
         tcp_port: pargs.opt_value_from_str("--tcp")?,
         websocket_port: pargs.opt_value_from_str("--websocket")?,
+        framed: pargs.contains("--framed"),
         sdcard: pargs.opt_value_from_str("--sdcard")?,
-        sdcard_img: pargs.opt_value_from_str("--sdcard-img")?,
+        sdcard_img_ro: sdcard_img_ro.is_some(),
+        sdcard_img: sdcard_img.or(sdcard_img_ro),
         unlimited_cpu: pargs.contains(["-u", "--unlimited-cpu"]),
+        cpu_speed_hz: pargs.opt_value_from_fn("--cpu-speed", parse_cpu_speed)?,
+        lockstep: pargs.contains("--lockstep"),
+        irq_precision: pargs
+            .opt_value_from_fn("--irq-precision", parse_irq_precision)?
+            .unwrap_or(16),
+        keep_vsync: pargs.contains("--keep-vsync"),
+        no_wait_vdp: pargs.contains("--no-wait-vdp"),
         zero: pargs.contains(["-z", "--zero"]),
+        ram_seed: pargs.opt_value_from_str("--ram-seed")?,
         mos_bin: pargs.opt_value_from_str("--mos")?,
         debugger: pargs.contains(["-d", "--debugger"]),
         breakpoints,
+        dzrp_port: pargs
+            .opt_value_from_str("--dzrp-port")?
+            .unwrap_or(agon_dzrp_debugger::DEFAULT_PORT),
+        break_on_output: pargs.opt_value_from_fn("--break-on-output", parse_hex_pattern)?,
+        watch_mem: pargs.opt_value_from_fn("--watch-mem", parse_watch_mem)?,
         verbosity,
         log_file: pargs.opt_value_from_str("--log")?,
+        log_format: pargs
+            .opt_value_from_str("--log-format")?
+            .unwrap_or_default(),
+        log_timestamps: pargs.contains("--log-timestamps"),
+        log_max_size: pargs.opt_value_from_fn("--log-max-size", parse_size)?,
+        trace_pc_range: pargs.opt_value_from_fn("--trace-pc-range", parse_pc_range)?,
+        stats_interval: pargs.opt_value_from_fn("--stats-interval", parse_seconds)?,
+        tx_batch_us: pargs.opt_value_from_str("--tx-batch-us")?.unwrap_or(100),
+        tx_batch_bytes: pargs.opt_value_from_str("--tx-batch-bytes")?,
+        handshake_timeout: pargs
+            .opt_value_from_fn("--handshake-timeout", parse_seconds)?
+            .unwrap_or(5),
+        run_seconds: pargs.opt_value_from_str("--run-seconds")?,
+        max_insns: pargs.opt_value_from_str("--max-insns")?,
     };
 
+    if args.unlimited_cpu && args.cpu_speed_hz.is_some() {
+        return Err(pico_args::Error::ArgumentParsingFailed {
+            cause: "--unlimited-cpu and --cpu-speed are mutually exclusive".to_string(),
+        });
+    }
+    if args.zero && args.ram_seed.is_some() {
+        return Err(pico_args::Error::ArgumentParsingFailed {
+            cause: "--zero and --ram-seed are mutually exclusive".to_string(),
+        });
+    }
+
+    // Fill in anything not given on the command line from the config file
+    let config = crate::config::load();
+    if args.socket_path.is_none() {
+        args.socket_path = config.socket_path;
+    }
+    if args.sdcard.is_none() {
+        args.sdcard = config.sdcard;
+    }
+    if args.mos_bin.is_none() {
+        args.mos_bin = config.mos_bin;
+    }
+
     let remaining = pargs.finish();
     if !remaining.is_empty() {
         eprintln!("Warning: unused arguments left: {:?}.", remaining);
@@ -111,3 +270,69 @@ pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
 
     Ok(args)
 }
+
+/// Parse an octal file mode, e.g. "660" or "0o660"
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {}", s)),
+    }
+}
+
+/// Parse --cpu-speed: a clock in Hz, or a multiple of the real clock
+/// such as "2x"
+fn parse_cpu_speed(s: &str) -> Result<u64, String> {
+    let hz = match s.strip_suffix(['x', 'X']) {
+        Some(m) => match m.parse::<f64>() {
+            Ok(m) if m.is_finite() && m > 0.0 => (CLOCKSPEED_HZ as f64 * m).round() as u64,
+            _ => return Err(format!("invalid speed multiplier '{}'", s)),
+        },
+        None => s.parse().map_err(|_| format!("invalid CPU speed '{}' (expected Hz, or a multiplier such as 2x)", s))?,
+    };
+    if !(MIN_CLOCKSPEED_HZ..=UNLIMITED_CLOCKSPEED_HZ).contains(&hz) {
+        return Err(format!(
+            "CPU speed {} Hz out of range ({}-{} Hz)",
+            hz, MIN_CLOCKSPEED_HZ, UNLIMITED_CLOCKSPEED_HZ
+        ));
+    }
+    Ok(hz)
+}
+
+/// Parse --irq-precision, in cycles
+fn parse_irq_precision(s: &str) -> Result<i32, String> {
+    match s.parse::<i32>() {
+        Ok(n) if (1..=4096).contains(&n) => Ok(n),
+        _ => Err(format!("invalid interrupt precision '{}' (expected 1-4096 cycles)", s)),
+    }
+}
+
+/// Parse a whole number of seconds, at least 1, for the options where 0
+/// would mean "at once, every time"
+fn parse_seconds(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(n) if n >= 1 => Ok(n),
+        _ => Err(format!("invalid number of seconds '{}' (expected 1 or more)", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(parse_seconds("5"), Ok(5));
+        assert!(parse_seconds("0").is_err());
+        assert!(parse_seconds("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_cpu_speed() {
+        assert_eq!(parse_cpu_speed("2x"), Ok(36_864_000));
+        assert_eq!(parse_cpu_speed("0.5X"), Ok(9_216_000));
+        assert_eq!(parse_cpu_speed("4000000"), Ok(4_000_000));
+        assert!(parse_cpu_speed("0x").is_err());
+        assert!(parse_cpu_speed("100").is_err());
+        assert!(parse_cpu_speed("fast").is_err());
+    }
+}
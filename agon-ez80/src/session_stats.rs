@@ -0,0 +1,96 @@
+//! Per-session traffic statistics for the eZ80 <-> VDP link.
+
+use agon_protocol::Message;
+use std::time::{Duration, Instant};
+
+/// Counters updated by the session loop
+pub struct SessionStats {
+    start: Instant,
+    pub bytes_tx: u64,
+    pub bytes_rx: u64,
+    pub msgs_tx: u64,
+    pub msgs_rx: u64,
+    pub vsyncs: u64,
+    /// Largest number of TX bytes waiting when the queue was drained
+    pub max_tx_queue: usize,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        SessionStats {
+            start: Instant::now(),
+            bytes_tx: 0,
+            bytes_rx: 0,
+            msgs_tx: 0,
+            msgs_rx: 0,
+            vsyncs: 0,
+            max_tx_queue: 0,
+        }
+    }
+
+    /// Record a UART_DATA batch sent to the VDP
+    pub fn record_tx(&mut self, len: usize) {
+        self.bytes_tx += len as u64;
+        self.msgs_tx += 1;
+        self.max_tx_queue = self.max_tx_queue.max(len);
+    }
+
+    /// Record a message received from the VDP
+    pub fn record_rx(&mut self, msg: &Message) {
+        match msg {
+            Message::UartData(data) => self.bytes_rx += data.len() as u64,
+            Message::Vsync => self.vsyncs += 1,
+            Message::VsyncBatch(count) => self.vsyncs += *count as u64,
+            _ => {}
+        }
+        self.msgs_rx += 1;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Mean UART bytes per TX message
+    pub fn mean_tx_batch(&self) -> f64 {
+        if self.msgs_tx == 0 {
+            0.0
+        } else {
+            self.bytes_tx as f64 / self.msgs_tx as f64
+        }
+    }
+
+    /// One-line human readable summary
+    pub fn summary(&self) -> String {
+        let secs = self.elapsed().as_secs_f64().max(0.001);
+        format!(
+            "{:.1}s: tx {} bytes in {} msgs ({:.0} B/s, mean batch {:.1}, max queue {}), \
+             rx {} bytes in {} msgs ({:.0} B/s), {} vsyncs ({:.1}/s)",
+            secs,
+            self.bytes_tx,
+            self.msgs_tx,
+            self.bytes_tx as f64 / secs,
+            self.mean_tx_batch(),
+            self.max_tx_queue,
+            self.bytes_rx,
+            self.msgs_rx,
+            self.bytes_rx as f64 / secs,
+            self.vsyncs,
+            self.vsyncs as f64 / secs,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vsync_batch_counts_every_frame() {
+        let mut stats = SessionStats::new();
+        for msg in [Message::Vsync, Message::VsyncBatch(4), Message::VsyncBatch(0), Message::Cts(true)] {
+            stats.record_rx(&msg);
+        }
+        assert_eq!(stats.vsyncs, 5);
+        assert_eq!(stats.msgs_rx, 4);
+    }
+}
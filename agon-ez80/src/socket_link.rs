@@ -2,11 +2,14 @@
 
 use agon_ez80_emulator::SerialLink;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 /// SerialLink implementation that communicates over socket protocol.
 ///
-/// This is used for UART0 (eZ80 <-> VDP communication).
+/// This is used for UART0 (eZ80 <-> VDP communication). While detached (see
+/// [`SocketState::detach`]) it behaves like a
+/// [`DummySerialLink`](agon_ez80_emulator::DummySerialLink).
 pub struct SocketSerialLink {
     /// Shared send queue - bytes are queued here and sent by the main thread
     tx_queue: Arc<Mutex<VecDeque<u8>>>,
@@ -14,6 +17,8 @@ pub struct SocketSerialLink {
     rx_queue: Arc<Mutex<VecDeque<u8>>>,
     /// Clear-to-send status
     cts: Arc<Mutex<bool>>,
+    /// Whether a VDP is there to talk to
+    attached: Arc<AtomicBool>,
 }
 
 impl SocketSerialLink {
@@ -21,23 +26,35 @@ impl SocketSerialLink {
         tx_queue: Arc<Mutex<VecDeque<u8>>>,
         rx_queue: Arc<Mutex<VecDeque<u8>>>,
         cts: Arc<Mutex<bool>>,
+        attached: Arc<AtomicBool>,
     ) -> Self {
         SocketSerialLink {
             tx_queue,
             rx_queue,
             cts,
+            attached,
         }
     }
+
+    fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Relaxed)
+    }
 }
 
 impl SerialLink for SocketSerialLink {
     fn send(&mut self, byte: u8) {
+        if !self.is_attached() {
+            return;
+        }
         if let Ok(mut queue) = self.tx_queue.lock() {
             queue.push_back(byte);
         }
     }
 
     fn recv(&mut self) -> Option<u8> {
+        if !self.is_attached() {
+            return None;
+        }
         if let Ok(mut queue) = self.rx_queue.lock() {
             queue.pop_front()
         } else {
@@ -46,6 +63,9 @@ impl SerialLink for SocketSerialLink {
     }
 
     fn read_clear_to_send(&mut self) -> bool {
+        if !self.is_attached() {
+            return true;
+        }
         if let Ok(cts) = self.cts.lock() {
             *cts
         } else {
@@ -54,24 +74,58 @@ impl SerialLink for SocketSerialLink {
     }
 }
 
-/// Dummy serial link that does nothing (used for UART1)
-pub struct DummySerialLink;
+/// Serial link that plays a canned RX byte sequence and records everything
+/// the eZ80 transmits, for headless tests without a VDP.
+///
+/// Clones share the same buffers, so keep a clone to inspect TX after
+/// handing the link to the machine.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ScriptedSerialLink {
+    rx: Arc<Mutex<VecDeque<u8>>>,
+    tx: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl ScriptedSerialLink {
+    pub fn new(input: &[u8]) -> Self {
+        ScriptedSerialLink {
+            rx: Arc::new(Mutex::new(input.iter().copied().collect())),
+            tx: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Take all bytes transmitted so far
+    pub fn take_tx(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.tx.lock().unwrap())
+    }
+}
 
-impl SerialLink for DummySerialLink {
-    fn send(&mut self, _byte: u8) {}
+#[cfg(test)]
+impl SerialLink for ScriptedSerialLink {
+    fn send(&mut self, byte: u8) {
+        self.tx.lock().unwrap().push(byte);
+    }
     fn recv(&mut self) -> Option<u8> {
-        None
+        self.rx.lock().unwrap().pop_front()
     }
     fn read_clear_to_send(&mut self) -> bool {
         true
     }
 }
 
+/// RX backlog (bytes from the VDP the eZ80 has yet to read) beyond which
+/// the VDP is told the eZ80 is busy. A dozen or so key packets.
+const RX_BUSY_LEN: usize = 64;
+
 /// Shared state for socket communication
 pub struct SocketState {
     pub tx_queue: Arc<Mutex<VecDeque<u8>>>,
     pub rx_queue: Arc<Mutex<VecDeque<u8>>>,
     pub cts: Arc<Mutex<bool>>,
+    attached: Arc<AtomicBool>,
+    /// Whether the VDP was last told the eZ80 is busy
+    rx_busy: AtomicBool,
 }
 
 impl SocketState {
@@ -80,15 +134,30 @@ impl SocketState {
             tx_queue: Arc::new(Mutex::new(VecDeque::new())),
             rx_queue: Arc::new(Mutex::new(VecDeque::new())),
             cts: Arc::new(Mutex::new(true)),
+            attached: Arc::new(AtomicBool::new(true)),
+            rx_busy: AtomicBool::new(false),
         }
     }
 
+    /// Run the link with no VDP: the eZ80's output is discarded and it is
+    /// always clear to send, until [`attach`](Self::attach)
+    pub fn detach(&self) {
+        self.attached.store(false, Ordering::Relaxed);
+    }
+
+    /// A VDP has connected and takes over the link
+    pub fn attach(&self) {
+        self.attached.store(true, Ordering::Relaxed);
+        self.rx_busy.store(false, Ordering::Relaxed);
+    }
+
     /// Create a SerialLink for this socket state
     pub fn create_serial_link(&self) -> SocketSerialLink {
         SocketSerialLink::new(
             self.tx_queue.clone(),
             self.rx_queue.clone(),
             self.cts.clone(),
+            self.attached.clone(),
         )
     }
 
@@ -101,6 +170,15 @@ impl SocketState {
         }
     }
 
+    /// Number of TX bytes waiting to be sent
+    pub fn tx_len(&self) -> usize {
+        if let Ok(queue) = self.tx_queue.lock() {
+            queue.len()
+        } else {
+            0
+        }
+    }
+
     /// Queue received bytes from VDP
     pub fn queue_rx(&self, bytes: &[u8]) {
         if let Ok(mut queue) = self.rx_queue.lock() {
@@ -110,6 +188,32 @@ impl SocketState {
         }
     }
 
+    /// Number of RX bytes the eZ80 has yet to read
+    pub fn rx_len(&self) -> usize {
+        if let Ok(queue) = self.rx_queue.lock() {
+            queue.len()
+        } else {
+            0
+        }
+    }
+
+    /// The clear-to-send to report to the VDP, when it changes: not ready
+    /// once the eZ80 falls more than `RX_BUSY_LEN` bytes behind on its
+    /// input, ready again once it has read everything
+    pub fn rx_ready_change(&self) -> Option<bool> {
+        let len = self.rx_len();
+        let busy = self.rx_busy.load(Ordering::Relaxed);
+        if !busy && len > RX_BUSY_LEN {
+            self.rx_busy.store(true, Ordering::Relaxed);
+            Some(false)
+        } else if busy && len == 0 {
+            self.rx_busy.store(false, Ordering::Relaxed);
+            Some(true)
+        } else {
+            None
+        }
+    }
+
     /// Update CTS status
     pub fn set_cts(&self, ready: bool) {
         if let Ok(mut cts) = self.cts.lock() {
@@ -123,3 +227,62 @@ impl Default for SocketState {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_serial_link() {
+        let handle = ScriptedSerialLink::new(&[0x81, 0x04]);
+        let mut link: Box<dyn SerialLink> = Box::new(handle.clone());
+
+        assert_eq!(link.recv(), Some(0x81));
+        assert_eq!(link.recv(), Some(0x04));
+        assert_eq!(link.recv(), None);
+
+        link.send(b'O');
+        link.send(b'K');
+        assert_eq!(handle.take_tx(), b"OK");
+        assert!(handle.take_tx().is_empty());
+    }
+
+    #[test]
+    fn test_detached_link_discards_until_attached() {
+        let state = SocketState::new();
+        let mut link = state.create_serial_link();
+        state.detach();
+        state.set_cts(false);
+        state.queue_rx(&[0x81]);
+        link.send(b'X');
+        assert_eq!(link.recv(), None);
+        assert!(link.read_clear_to_send());
+        assert_eq!(state.tx_len(), 0);
+
+        state.attach();
+        link.send(b'Y');
+        assert_eq!(link.recv(), Some(0x81));
+        assert!(!link.read_clear_to_send());
+        assert_eq!(state.drain_tx(), b"Y");
+    }
+
+    #[test]
+    fn test_rx_ready_follows_backlog() {
+        let state = SocketState::new();
+        let mut link = state.create_serial_link();
+        assert_eq!(state.rx_ready_change(), None);
+
+        state.queue_rx(&[0; RX_BUSY_LEN]);
+        assert_eq!(state.rx_ready_change(), None);
+        state.queue_rx(&[0]);
+        assert_eq!(state.rx_ready_change(), Some(false));
+        assert_eq!(state.rx_ready_change(), None);
+
+        // ready again only once the guest has read everything
+        while link.recv().is_some() && state.rx_len() > 0 {
+            assert_eq!(state.rx_ready_change(), None);
+        }
+        assert_eq!(state.rx_ready_change(), Some(true));
+        assert_eq!(state.rx_ready_change(), None);
+    }
+}
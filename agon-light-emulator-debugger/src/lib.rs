@@ -186,6 +186,9 @@ fn handle_debug_resp(resp: &DebugResp, state: &EmuState) {
                         io_address
                     );
                 }
+                PauseReason::OutputMatch => {
+                    println!("{color_yellow}CPU paused (output pattern seen){color_reset}");
+                }
             }
             state.set_in_debugger(true);
         }
@@ -0,0 +1,134 @@
+//! Async (tokio) socket connections, for hosting many eZ80/VDP links from
+//! one runtime without a thread per connection.
+//!
+//! Messages use the same wire format as [`crate::SocketConnection`].
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::messages::MAX_UART_DATA_SIZE;
+use crate::{Message, ProtocolError, SocketAddr};
+
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// An async connection to a remote socket
+pub struct AsyncSocketConnection {
+    reader: BufReader<BoxedReader>,
+    writer: BufWriter<BoxedWriter>,
+}
+
+impl AsyncSocketConnection {
+    /// Wrap an accepted or connected Unix stream
+    #[cfg(unix)]
+    pub fn from_unix(stream: UnixStream) -> Self {
+        let (r, w) = stream.into_split();
+        Self::from_halves(Box::new(r), Box::new(w))
+    }
+
+    /// Wrap an accepted or connected TCP stream
+    pub fn from_tcp(stream: TcpStream) -> Self {
+        // Disable Nagle's algorithm for lower latency
+        let _ = stream.set_nodelay(true);
+        let (r, w) = stream.into_split();
+        Self::from_halves(Box::new(r), Box::new(w))
+    }
+
+    fn from_halves(reader: BoxedReader, writer: BoxedWriter) -> Self {
+        AsyncSocketConnection {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    /// Connect to a socket address
+    pub async fn connect(addr: &SocketAddr) -> Result<Self, std::io::Error> {
+        match addr {
+            #[cfg(unix)]
+            SocketAddr::Unix(path) => Ok(Self::from_unix(UnixStream::connect(path).await?)),
+            #[cfg(target_os = "linux")]
+            SocketAddr::UnixAbstract(name) => {
+                // tokio can't connect to abstract names itself; the connect
+                // is local, so doing it blocking is cheap
+                let stream =
+                    std::os::unix::net::UnixStream::connect_addr(&crate::socket::abstract_addr(name)?)?;
+                stream.set_nonblocking(true)?;
+                Ok(Self::from_unix(UnixStream::from_std(stream)?))
+            }
+            SocketAddr::Tcp(addr_str) => Ok(Self::from_tcp(TcpStream::connect(addr_str).await?)),
+        }
+    }
+
+    /// Send a message
+    pub async fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
+        self.writer.write_all(&msg.encode()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Receive a message
+    pub async fn recv(&mut self) -> Result<Message, ProtocolError> {
+        let mut len_buf = [0u8; 2];
+        self.reader.read_exact(&mut len_buf).await?;
+        let len = u16::from_le_bytes(len_buf) as usize;
+
+        if len == 0 {
+            return Err(ProtocolError::InvalidFormat(
+                "Zero-length message".to_string(),
+            ));
+        }
+
+        if len > MAX_UART_DATA_SIZE + 1 {
+            return Err(ProtocolError::PayloadTooLarge(len));
+        }
+
+        // Read type + payload after the length prefix, then decode the
+        // whole frame
+        let mut frame = vec![0u8; 2 + len];
+        frame[..2].copy_from_slice(&len_buf);
+        self.reader.read_exact(&mut frame[2..]).await?;
+        let (message, _) = Message::decode(&frame)?;
+        Ok(message)
+    }
+
+    /// Shutdown the write side of the connection
+    pub async fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        self.writer.shutdown().await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_unix_communication() {
+        let socket_path = "/tmp/agon-test-async.sock";
+        let _ = std::fs::remove_file(socket_path);
+        let listener = tokio::net::UnixListener::bind(socket_path).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = AsyncSocketConnection::from_unix(stream);
+            let msg = conn.recv().await.unwrap();
+            assert_eq!(msg, Message::UartData(vec![0x41, 0x42]));
+            conn.send(&Message::Vsync).await.unwrap();
+        });
+
+        let mut conn = AsyncSocketConnection::connect(&SocketAddr::unix(socket_path))
+            .await
+            .unwrap();
+        conn.send(&Message::UartData(vec![0x41, 0x42])).await.unwrap();
+        assert_eq!(conn.recv().await.unwrap(), Message::Vsync);
+
+        server.await.unwrap();
+        // Peer has gone: the next receive reports a closed connection
+        assert!(matches!(
+            conn.recv().await,
+            Err(ProtocolError::ConnectionClosed)
+        ));
+        let _ = std::fs::remove_file(socket_path);
+    }
+}
@@ -0,0 +1,74 @@
+//! Hex formatting helpers for trace output.
+
+/// Bytes per hexdump line
+const BYTES_PER_LINE: usize = 16;
+
+/// Format bytes as space-separated hex on one line, e.g. `41 42 0D`
+pub fn fmt_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Classic hexdump: `offset  XX XX ... |ascii|`, 16 bytes per line.
+/// Non-printable bytes are shown as `.` in the ASCII column.
+pub fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+                .collect();
+            format!(
+                "{:04X}  {:<width$}  |{}|",
+                i * BYTES_PER_LINE,
+                fmt_hex(chunk),
+                ascii,
+                width = BYTES_PER_LINE * 3 - 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format a message payload for a trace line: inline hex if it fits on one
+/// hexdump line, otherwise a full hexdump starting on the next line.
+pub fn fmt_payload(bytes: &[u8]) -> String {
+    if bytes.len() <= BYTES_PER_LINE {
+        fmt_hex(bytes)
+    } else {
+        format!("\n{}", hexdump(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fmt_hex() {
+        assert_eq!(fmt_hex(&[0x41, 0x0d, 0xff]), "41 0D FF");
+        assert_eq!(fmt_hex(&[]), "");
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let data: Vec<u8> = (0x30..0x30 + 20).collect();
+        assert_eq!(
+            hexdump(&data),
+            "0000  30 31 32 33 34 35 36 37 38 39 3A 3B 3C 3D 3E 3F  |0123456789:;<=>?|\n\
+             0010  40 41 42 43                                      |@ABC|"
+        );
+        assert_eq!(hexdump(&[0x16, 0x41]), format!("0000  16 41{}  |.A|", " ".repeat(42)));
+    }
+
+    #[test]
+    fn test_fmt_payload() {
+        assert_eq!(fmt_payload(&[0x41]), "41");
+        assert!(fmt_payload(&[0u8; 17]).starts_with("\n0000  00 00"));
+    }
+}
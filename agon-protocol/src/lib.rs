@@ -15,15 +15,71 @@
 //! |------|------|-----------|---------|
 //! | 0x01 | UART_DATA | bidirectional | raw bytes (1-1024) |
 //! | 0x02 | VSYNC | VDP→eZ80 | empty |
-//! | 0x03 | CTS | VDP→eZ80 | u8 (0=busy, 1=ready) |
-//! | 0x10 | HELLO | eZ80→VDP | version:u8, flags:u8 |
-//! | 0x11 | HELLO_ACK | VDP→eZ80 | version:u8, caps_json |
+//! | 0x03 | CTS | either | u8 (0=busy, 1=ready) |
+//! | 0x04 | VSYNC_BATCH | VDP→eZ80 | count:u8 (protocol v3+) |
+//! | 0x06 | ECHO | VDP→eZ80, echoed back | raw bytes (0-1024) |
+//! | 0x10 | HELLO | eZ80→VDP | version:u8, flags:u8, [session:u64] |
+//! | 0x11 | HELLO_ACK | VDP→eZ80 | version:u8, caps_json, [0x00, session:u64] |
+//! | 0x12 | VERSION_REJECT | either | supported:u8 |
 //! | 0x20 | SHUTDOWN | either | empty |
+//! | 0x21 | RESET | VDP→eZ80 | empty |
+//!
+//! ## Version Negotiation
+//!
+//! Each side checks the version in the peer's HELLO/HELLO_ACK with
+//! [`negotiate_version`]. Older peers (down to `MIN_PROTOCOL_VERSION`) are
+//! served at their own version, using only the [`Features`] it supports;
+//! otherwise the side answers VERSION_REJECT and closes the connection.
+//!
+//! ## Framed Mode
+//!
+//! For links that may lose or duplicate bytes (proxies, serial bridges),
+//! both ends can opt in to framed mode with
+//! [`SocketConnection::set_framed`]: every message is then preceded by
+//! [`FRAME_MAGIC`], and a reader that sees an impossible length scans
+//! forward to the next magic instead of waiting for bytes that never come.
+//! The agon-ez80 and VDP binaries turn it on with `--framed`.
+//!
+//! ## HELLO Flags
+//!
+//! The low four bits of the HELLO flags are optional hints, which a peer
+//! that doesn't understand them ignores. The high four bits
+//! ([`HELLO_FLAGS_REQUIRED`]) mark features the client requires; a server
+//! that doesn't implement one refuses the connection (see
+//! [`check_hello_flags`]).
+//!
+//! ## Session Tokens
+//!
+//! A client may end its HELLO with a 64-bit session token, kept the same
+//! for every connection it makes (see [`process_session_token`]). The
+//! server echoes it in HELLO_ACK. A HELLO carrying the same token as the
+//! previous connection is a *resume*: the same client reconnecting, which
+//! the server may, e.g., bring up to date rather than treat as new. Peers
+//! that don't know about tokens ignore the extra bytes.
+//!
+//! ## Cargo Features
+//!
+//! - `serde`: derive `Serialize`/`Deserialize` for [`Message`] and
+//!   [`Features`], so tools can record message streams as structured data.
+//! - `tokio`: `AsyncSocketConnection`, an async connection over tokio TCP
+//!   and Unix streams.
 
+#[cfg(feature = "tokio")]
+pub mod async_socket;
+pub mod hexdump;
 mod messages;
 pub mod socket;
+pub mod vdu;
 pub mod websocket;
 
-pub use messages::{Message, ProtocolError, PROTOCOL_VERSION};
-pub use socket::{SocketAddr, SocketConnection, SocketListener, SocketReader, SocketWriter};
-pub use websocket::{WebSocketConnection, WebSocketListener};
+#[cfg(feature = "tokio")]
+pub use async_socket::AsyncSocketConnection;
+pub use hexdump::{fmt_hex, fmt_payload, hexdump};
+pub use messages::{
+    check_hello_flags, negotiate_version, process_session_token, random_u64, Features, Message,
+    MessageRef, ProtocolError, FRAME_MAGIC, HELLO_FLAGS_REQUIRED, MAX_UART_DATA_SIZE,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SUPPORTED_REQUIRED_FLAGS,
+};
+pub use socket::{Messages, SocketAddr, SocketConnection, SocketListener, SocketReader, SocketWriter};
+pub use vdu::VduDecoder;
+pub use websocket::{WebSocketConnection, WebSocketListener, WebSocketReader, WebSocketWriter};
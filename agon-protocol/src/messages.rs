@@ -3,19 +3,41 @@
 use std::io::{Read, Write};
 
 /// Protocol version number
-pub const PROTOCOL_VERSION: u8 = 1;
+///
+/// Version 2 added RESET, ECHO and VERSION_REJECT; version 3 added
+/// VSYNC_BATCH.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Oldest protocol version we can still talk to
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// HELLO flag bits that mark a feature the sender *requires*. A peer that
+/// doesn't implement a required feature must refuse the connection; the
+/// low bits are optional hints it may ignore (e.g. `HELLO_FLAG_TEXT_FRAMES`).
+pub const HELLO_FLAGS_REQUIRED: u8 = 0xF0;
+
+/// Required HELLO flags this implementation understands (none yet)
+pub const SUPPORTED_REQUIRED_FLAGS: u8 = 0x00;
 
 /// Maximum payload size for UART_DATA messages
 pub const MAX_UART_DATA_SIZE: usize = 1024;
 
+/// Marker written before each message in framed mode (see
+/// [`Message::write_framed_to`]), so a reader can resync after corruption
+pub const FRAME_MAGIC: [u8; 2] = [0xA5, 0x5A];
+
 /// Message type constants
 mod msg_type {
     pub const UART_DATA: u8 = 0x01;
     pub const VSYNC: u8 = 0x02;
     pub const CTS: u8 = 0x03;
+    pub const VSYNC_BATCH: u8 = 0x04;
+    pub const ECHO: u8 = 0x06;
     pub const HELLO: u8 = 0x10;
     pub const HELLO_ACK: u8 = 0x11;
+    pub const VERSION_REJECT: u8 = 0x12;
     pub const SHUTDOWN: u8 = 0x20;
+    pub const RESET: u8 = 0x21;
 }
 
 /// Protocol error types
@@ -31,6 +53,10 @@ pub enum ProtocolError {
     InvalidFormat(String),
     /// Connection closed
     ConnectionClosed,
+    /// Peer speaks a protocol version we can't talk to
+    UnsupportedVersion { peer: u8, supported: u8 },
+    /// Peer's HELLO requires features we don't implement
+    UnsupportedFlags(u8),
 }
 
 impl std::fmt::Display for ProtocolError {
@@ -41,12 +67,65 @@ impl std::fmt::Display for ProtocolError {
             ProtocolError::PayloadTooLarge(size) => write!(f, "Payload too large: {} bytes", size),
             ProtocolError::InvalidFormat(msg) => write!(f, "Invalid format: {}", msg),
             ProtocolError::ConnectionClosed => write!(f, "Connection closed"),
+            ProtocolError::UnsupportedVersion { peer, supported } => write!(
+                f,
+                "Unsupported protocol version {} (supported: {}-{})",
+                peer, MIN_PROTOCOL_VERSION, supported
+            ),
+            ProtocolError::UnsupportedFlags(flags) => {
+                write!(f, "Peer requires unsupported HELLO flags: 0x{:02x}", flags)
+            }
         }
     }
 }
 
 impl std::error::Error for ProtocolError {}
 
+impl ProtocolError {
+    /// True if this is an I/O error caused by a read timeout expiring
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::Io(e) if matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            )
+        )
+    }
+
+    /// Kind of the underlying I/O error, if this is one
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            ProtocolError::Io(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+
+    /// True if the peer has gone away: the connection was closed, reset or
+    /// broken. Reconnecting is the usual response.
+    pub fn is_disconnect(&self) -> bool {
+        use std::io::ErrorKind;
+
+        matches!(self, ProtocolError::ConnectionClosed)
+            || matches!(
+                self.io_kind(),
+                Some(
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::NotConnected
+                        | ErrorKind::UnexpectedEof
+                )
+            )
+    }
+
+    /// True if only the failed operation was affected (a timeout or an
+    /// interrupted call), so the connection can still be used
+    pub fn is_recoverable(&self) -> bool {
+        self.is_timeout() || self.io_kind() == Some(std::io::ErrorKind::Interrupted)
+    }
+}
+
 impl From<std::io::Error> for ProtocolError {
     fn from(e: std::io::Error) -> Self {
         if e.kind() == std::io::ErrorKind::UnexpectedEof {
@@ -57,8 +136,64 @@ impl From<std::io::Error> for ProtocolError {
     }
 }
 
+/// Optional protocol features, determined by the negotiated version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Features {
+    /// RESET messages are understood
+    pub reset: bool,
+    /// ECHO messages are answered
+    pub echo: bool,
+    /// VSYNC_BATCH messages are understood
+    pub vsync_batch: bool,
+}
+
+impl Features {
+    pub fn for_version(version: u8) -> Features {
+        Features {
+            reset: version >= 2,
+            echo: version >= 2,
+            vsync_batch: version >= 3,
+        }
+    }
+}
+
+/// Decide which protocol version to speak with a peer that advertised
+/// `peer_version` in its HELLO/HELLO_ACK.
+///
+/// An older peer is talked to at its own version (with the matching
+/// `Features`); a peer that is newer than us, or older than
+/// `MIN_PROTOCOL_VERSION`, should be sent VERSION_REJECT.
+pub fn negotiate_version(peer_version: u8) -> Result<u8, ProtocolError> {
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&peer_version) {
+        Ok(peer_version)
+    } else {
+        Err(ProtocolError::UnsupportedVersion {
+            peer: peer_version,
+            supported: PROTOCOL_VERSION,
+        })
+    }
+}
+
+/// Check the flags of a peer's HELLO against the required flags we
+/// `supported`. Unknown optional flags are ignored; an unknown required
+/// flag means the peer can't work with us, and the connection should be
+/// closed.
+pub fn check_hello_flags(flags: u8, supported: u8) -> Result<(), ProtocolError> {
+    let missing = flags & HELLO_FLAGS_REQUIRED & !supported;
+    if missing != 0 {
+        Err(ProtocolError::UnsupportedFlags(missing))
+    } else {
+        Ok(())
+    }
+}
+
 /// Messages exchanged between eZ80 and VDP over socket
+///
+/// With the `serde` feature, messages can also be serialized (e.g. to JSON)
+/// for logging; the wire format is always the one from [`Message::encode`].
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     /// UART data bytes (bidirectional)
     UartData(Vec<u8>),
@@ -66,23 +201,45 @@ pub enum Message {
     /// VSync signal from VDP to eZ80
     Vsync,
 
-    /// Clear-to-send status from VDP to eZ80
+    /// Several VSyncs at once from VDP to eZ80, sent by a VDP catching up
+    /// on frames it missed
+    VsyncBatch(u8),
+
+    /// Clear-to-send status: from the VDP, whether the eZ80 may send; from
+    /// the eZ80, whether it is keeping up with its input
     Cts(bool),
 
-    /// Hello message from eZ80 to VDP during connection setup
+    /// Loopback probe: the eZ80 sends the payload straight back
+    Echo(Vec<u8>),
+
+    /// Hello message from eZ80 to VDP during connection setup.
+    /// `session` is an optional token identifying the connecting client
+    /// across reconnections.
     Hello {
         version: u8,
         flags: u8,
+        session: Option<u64>,
     },
 
-    /// Hello acknowledgment from VDP to eZ80
+    /// Hello acknowledgment from VDP to eZ80, echoing the HELLO's session
+    /// token (if any)
     HelloAck {
         version: u8,
         capabilities: String,
+        session: Option<u64>,
+    },
+
+    /// Handshake refused: the peer's version is not supported.
+    /// Carries the highest version the sender supports.
+    VersionReject {
+        supported: u8,
     },
 
     /// Shutdown request (either direction)
     Shutdown,
+
+    /// Soft-reset request from VDP to eZ80
+    Reset,
 }
 
 impl Message {
@@ -91,17 +248,36 @@ impl Message {
         let (msg_type, payload) = match self {
             Message::UartData(data) => (msg_type::UART_DATA, data.clone()),
             Message::Vsync => (msg_type::VSYNC, vec![]),
+            Message::VsyncBatch(count) => (msg_type::VSYNC_BATCH, vec![*count]),
             Message::Cts(ready) => (msg_type::CTS, vec![if *ready { 1 } else { 0 }]),
-            Message::Hello { version, flags } => (msg_type::HELLO, vec![*version, *flags]),
+            Message::Echo(data) => (msg_type::ECHO, data.clone()),
+            Message::Hello {
+                version,
+                flags,
+                session,
+            } => {
+                let mut p = vec![*version, *flags];
+                if let Some(token) = session {
+                    p.extend(token.to_le_bytes());
+                }
+                (msg_type::HELLO, p)
+            }
             Message::HelloAck {
                 version,
                 capabilities,
+                session,
             } => {
                 let mut p = vec![*version];
                 p.extend(capabilities.as_bytes());
+                if let Some(token) = session {
+                    p.push(0);
+                    p.extend(token.to_le_bytes());
+                }
                 (msg_type::HELLO_ACK, p)
             }
+            Message::VersionReject { supported } => (msg_type::VERSION_REJECT, vec![*supported]),
             Message::Shutdown => (msg_type::SHUTDOWN, vec![]),
+            Message::Reset => (msg_type::RESET, vec![]),
         };
 
         // Format: [len:u16-LE][type:u8][payload...]
@@ -116,70 +292,20 @@ impl Message {
 
     /// Decode message from wire format
     pub fn decode(data: &[u8]) -> Result<(Message, usize), ProtocolError> {
-        if data.len() < 3 {
-            return Err(ProtocolError::InvalidFormat(
-                "Message too short".to_string(),
-            ));
-        }
-
-        let len = u16::from_le_bytes([data[0], data[1]]) as usize;
-        if len == 0 {
-            return Err(ProtocolError::InvalidFormat(
-                "Zero-length message".to_string(),
-            ));
-        }
-
-        let total_len = 2 + len;
-        if data.len() < total_len {
-            return Err(ProtocolError::InvalidFormat(format!(
-                "Incomplete message: have {} bytes, need {}",
-                data.len(),
-                total_len
-            )));
-        }
-
-        let msg_type = data[2];
-        let payload = &data[3..total_len];
+        let (msg_type, payload, total_len) = split_frame(data)?;
+        Ok((decode_body(msg_type, payload)?, total_len))
+    }
 
+    /// Decode message from wire format without copying UART_DATA payloads.
+    ///
+    /// The returned [`MessageRef`] borrows the payload from `data`; other
+    /// message types are decoded as by [`Message::decode`].
+    pub fn decode_borrowed(data: &[u8]) -> Result<(MessageRef<'_>, usize), ProtocolError> {
+        let (msg_type, payload, total_len) = split_frame(data)?;
         let message = match msg_type {
-            msg_type::UART_DATA => Message::UartData(payload.to_vec()),
-            msg_type::VSYNC => Message::Vsync,
-            msg_type::CTS => {
-                if payload.is_empty() {
-                    return Err(ProtocolError::InvalidFormat(
-                        "CTS message missing payload".to_string(),
-                    ));
-                }
-                Message::Cts(payload[0] != 0)
-            }
-            msg_type::HELLO => {
-                if payload.len() < 2 {
-                    return Err(ProtocolError::InvalidFormat(
-                        "HELLO message too short".to_string(),
-                    ));
-                }
-                Message::Hello {
-                    version: payload[0],
-                    flags: payload[1],
-                }
-            }
-            msg_type::HELLO_ACK => {
-                if payload.is_empty() {
-                    return Err(ProtocolError::InvalidFormat(
-                        "HELLO_ACK message too short".to_string(),
-                    ));
-                }
-                let version = payload[0];
-                let capabilities = String::from_utf8_lossy(&payload[1..]).to_string();
-                Message::HelloAck {
-                    version,
-                    capabilities,
-                }
-            }
-            msg_type::SHUTDOWN => Message::Shutdown,
-            _ => return Err(ProtocolError::UnknownMessageType(msg_type)),
+            msg_type::UART_DATA => MessageRef::UartData(payload),
+            _ => MessageRef::Owned(decode_body(msg_type, payload)?),
         };
-
         Ok((message, total_len))
     }
 
@@ -191,6 +317,42 @@ impl Message {
         Ok(())
     }
 
+    /// Write message to a writer, preceded by `FRAME_MAGIC`
+    pub fn write_framed_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        writer.write_all(&FRAME_MAGIC)?;
+        self.write_to(writer)
+    }
+
+    /// Read a message written by `write_framed_to`.
+    ///
+    /// Bytes before the next `FRAME_MAGIC` are skipped, and a frame with an
+    /// impossible length is dropped by scanning on for the next magic, so
+    /// a link that lost or duplicated bytes (e.g. through a faulty proxy or
+    /// serial bridge) recovers instead of blocking on a bogus length.
+    pub fn read_framed_from<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {
+        let mut window = [0u8; 2];
+        reader.read_exact(&mut window)?;
+        loop {
+            while window != FRAME_MAGIC {
+                window[0] = window[1];
+                reader.read_exact(&mut window[1..])?;
+            }
+
+            let mut len_buf = [0u8; 2];
+            reader.read_exact(&mut len_buf)?;
+            let len = u16::from_le_bytes(len_buf) as usize;
+            if len == 0 || len > MAX_UART_DATA_SIZE + 1 {
+                // Not a real frame: the magic may start in its length bytes
+                window = len_buf;
+                continue;
+            }
+
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data)?;
+            return decode_body(data[0], &data[1..]);
+        }
+    }
+
     /// Read message from a reader
     pub fn read_from<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {
         // Read length (2 bytes)
@@ -212,50 +374,155 @@ impl Message {
         let mut data = vec![0u8; len];
         reader.read_exact(&mut data)?;
 
-        let msg_type = data[0];
-        let payload = &data[1..];
+        decode_body(data[0], &data[1..])
+    }
+}
 
-        let message = match msg_type {
-            msg_type::UART_DATA => Message::UartData(payload.to_vec()),
-            msg_type::VSYNC => Message::Vsync,
-            msg_type::CTS => {
-                if payload.is_empty() {
-                    return Err(ProtocolError::InvalidFormat(
-                        "CTS message missing payload".to_string(),
-                    ));
-                }
-                Message::Cts(payload[0] != 0)
+/// A decoded message that may borrow its payload from the input buffer
+/// (see [`Message::decode_borrowed`])
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageRef<'a> {
+    /// UART data bytes, borrowed from the input
+    UartData(&'a [u8]),
+    /// Any other message
+    Owned(Message),
+}
+
+impl MessageRef<'_> {
+    /// Convert to an owned message, copying any borrowed payload
+    pub fn into_owned(self) -> Message {
+        match self {
+            MessageRef::UartData(data) => Message::UartData(data.to_vec()),
+            MessageRef::Owned(msg) => msg,
+        }
+    }
+}
+
+/// Split a wire-format frame into (type, payload, total length)
+fn split_frame(data: &[u8]) -> Result<(u8, &[u8], usize), ProtocolError> {
+    if data.len() < 3 {
+        return Err(ProtocolError::InvalidFormat(
+            "Message too short".to_string(),
+        ));
+    }
+
+    let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+    if len == 0 {
+        return Err(ProtocolError::InvalidFormat(
+            "Zero-length message".to_string(),
+        ));
+    }
+
+    let total_len = 2 + len;
+    if data.len() < total_len {
+        return Err(ProtocolError::InvalidFormat(format!(
+            "Incomplete message: have {} bytes, need {}",
+            data.len(),
+            total_len
+        )));
+    }
+
+    Ok((data[2], &data[3..total_len], total_len))
+}
+
+/// Decode a message from its type byte and payload
+fn decode_body(msg_type: u8, payload: &[u8]) -> Result<Message, ProtocolError> {
+    let message = match msg_type {
+        msg_type::UART_DATA => Message::UartData(payload.to_vec()),
+        msg_type::VSYNC => Message::Vsync,
+        msg_type::VSYNC_BATCH => {
+            if payload.is_empty() {
+                return Err(ProtocolError::InvalidFormat(
+                    "VSYNC_BATCH message missing payload".to_string(),
+                ));
             }
-            msg_type::HELLO => {
-                if payload.len() < 2 {
-                    return Err(ProtocolError::InvalidFormat(
-                        "HELLO message too short".to_string(),
-                    ));
-                }
-                Message::Hello {
-                    version: payload[0],
-                    flags: payload[1],
-                }
+            Message::VsyncBatch(payload[0])
+        }
+        msg_type::CTS => {
+            if payload.is_empty() {
+                return Err(ProtocolError::InvalidFormat(
+                    "CTS message missing payload".to_string(),
+                ));
             }
-            msg_type::HELLO_ACK => {
-                if payload.is_empty() {
-                    return Err(ProtocolError::InvalidFormat(
-                        "HELLO_ACK message too short".to_string(),
-                    ));
-                }
-                let version = payload[0];
-                let capabilities = String::from_utf8_lossy(&payload[1..]).to_string();
-                Message::HelloAck {
-                    version,
-                    capabilities,
-                }
+            Message::Cts(payload[0] != 0)
+        }
+        msg_type::ECHO => Message::Echo(payload.to_vec()),
+        msg_type::HELLO => decode_hello(payload)?,
+        msg_type::HELLO_ACK => decode_hello_ack(payload)?,
+        msg_type::VERSION_REJECT => {
+            if payload.is_empty() {
+                return Err(ProtocolError::InvalidFormat(
+                    "VERSION_REJECT message missing payload".to_string(),
+                ));
             }
-            msg_type::SHUTDOWN => Message::Shutdown,
-            _ => return Err(ProtocolError::UnknownMessageType(msg_type)),
-        };
+            Message::VersionReject {
+                supported: payload[0],
+            }
+        }
+        msg_type::SHUTDOWN => Message::Shutdown,
+        msg_type::RESET => Message::Reset,
+        _ => return Err(ProtocolError::UnknownMessageType(msg_type)),
+    };
+    Ok(message)
+}
 
-        Ok(message)
+/// HELLO payload: version, flags, then an optional 8-byte session token
+fn decode_hello(payload: &[u8]) -> Result<Message, ProtocolError> {
+    if payload.len() < 2 {
+        return Err(ProtocolError::InvalidFormat(
+            "HELLO message too short".to_string(),
+        ));
     }
+    Ok(Message::Hello {
+        version: payload[0],
+        flags: payload[1],
+        session: session_token(&payload[2..]),
+    })
+}
+
+/// HELLO_ACK payload: version, capabilities, then optionally a NUL and an
+/// 8-byte session token
+fn decode_hello_ack(payload: &[u8]) -> Result<Message, ProtocolError> {
+    if payload.is_empty() {
+        return Err(ProtocolError::InvalidFormat(
+            "HELLO_ACK message too short".to_string(),
+        ));
+    }
+    let version = payload[0];
+    let mut caps = &payload[1..];
+    let mut session = None;
+    if caps.len() >= 9 && caps[caps.len() - 9] == 0 {
+        session = session_token(&caps[caps.len() - 8..]);
+        caps = &caps[..caps.len() - 9];
+    }
+    Ok(Message::HelloAck {
+        version,
+        capabilities: String::from_utf8_lossy(caps).to_string(),
+        session,
+    })
+}
+
+fn session_token(bytes: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = bytes.get(..8)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// A session token for HELLO, fixed for the life of this process, so a
+/// reconnecting client presents the same one
+pub fn process_session_token() -> u64 {
+    use std::sync::OnceLock;
+
+    static TOKEN: OnceLock<u64> = OnceLock::new();
+    *TOKEN.get_or_init(random_u64)
+}
+
+/// A fresh random value, from the keys std seeds each hasher with. Fine for
+/// tokens, seeds and jitter; not for anything security-sensitive.
+pub fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    RandomState::new().build_hasher().finish()
 }
 
 #[cfg(test)]
@@ -279,6 +546,19 @@ mod tests {
         assert_eq!(decoded, msg);
     }
 
+    #[test]
+    fn test_encode_decode_vsync_batch() {
+        let msg = Message::VsyncBatch(5);
+        let encoded = msg.encode();
+        assert_eq!(encoded, vec![0x02, 0x00, 0x04, 0x05]);
+        let (decoded, _) = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+        let read = Message::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(read, msg);
+
+        assert!(Message::decode(&[0x01, 0x00, 0x04]).is_err());
+    }
+
     #[test]
     fn test_encode_decode_cts() {
         for ready in [true, false] {
@@ -294,6 +574,7 @@ mod tests {
         let msg = Message::Hello {
             version: 1,
             flags: 0,
+            session: None,
         };
         let encoded = msg.encode();
         let (decoded, _) = Message::decode(&encoded).unwrap();
@@ -305,12 +586,46 @@ mod tests {
         let msg = Message::HelloAck {
             version: 1,
             capabilities: r#"{"type":"cli","cols":80}"#.to_string(),
+            session: None,
         };
         let encoded = msg.encode();
         let (decoded, _) = Message::decode(&encoded).unwrap();
         assert_eq!(decoded, msg);
     }
 
+    #[test]
+    fn test_encode_decode_session_token() {
+        let token = 0x0123_4567_89ab_cdef;
+        let hello = Message::Hello {
+            version: 2,
+            flags: 0,
+            session: Some(token),
+        };
+        let ack = Message::HelloAck {
+            version: 2,
+            capabilities: r#"{"type":"ez80"}"#.to_string(),
+            session: Some(token),
+        };
+        for msg in [hello, ack] {
+            let encoded = msg.encode();
+            let (decoded, _) = Message::decode(&encoded).unwrap();
+            assert_eq!(decoded, msg);
+            let read = Message::read_from(&mut &encoded[..]).unwrap();
+            assert_eq!(read, msg);
+        }
+
+        // a pre-token HELLO_ACK still decodes
+        let old = [2u8, b'{', b'}'];
+        assert_eq!(
+            decode_hello_ack(&old).unwrap(),
+            Message::HelloAck {
+                version: 2,
+                capabilities: "{}".to_string(),
+                session: None,
+            }
+        );
+    }
+
     #[test]
     fn test_encode_decode_shutdown() {
         let msg = Message::Shutdown;
@@ -319,6 +634,162 @@ mod tests {
         assert_eq!(decoded, msg);
     }
 
+    #[test]
+    fn test_encode_decode_reset() {
+        let msg = Message::Reset;
+        let encoded = msg.encode();
+        assert_eq!(encoded, vec![0x01, 0x00, 0x21]);
+        let (decoded, _) = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+        let read = Message::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(read, msg);
+    }
+
+    #[test]
+    fn test_encode_decode_echo() {
+        let msg = Message::Echo(vec![1, 2, 3]);
+        let encoded = msg.encode();
+        assert_eq!(encoded, vec![0x04, 0x00, 0x06, 1, 2, 3]);
+        let (decoded, _) = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+        let read = Message::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(read, msg);
+    }
+
+    #[test]
+    fn test_encode_decode_version_reject() {
+        let msg = Message::VersionReject { supported: 2 };
+        let encoded = msg.encode();
+        assert_eq!(encoded, vec![0x02, 0x00, 0x12, 0x02]);
+        let (decoded, _) = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+        let read = Message::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(read, msg);
+    }
+
+    #[test]
+    fn test_negotiate_same_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION);
+        assert!(Features::for_version(PROTOCOL_VERSION).reset);
+        assert!(Features::for_version(PROTOCOL_VERSION).vsync_batch);
+    }
+
+    #[test]
+    fn test_negotiate_newer_version() {
+        match negotiate_version(PROTOCOL_VERSION + 1) {
+            Err(ProtocolError::UnsupportedVersion { peer, supported }) => {
+                assert_eq!(peer, PROTOCOL_VERSION + 1);
+                assert_eq!(supported, PROTOCOL_VERSION);
+            }
+            other => panic!("expected UnsupportedVersion, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_older_version() {
+        // Version 1 peers are still accepted, without RESET
+        assert_eq!(negotiate_version(1).unwrap(), 1);
+        assert!(!Features::for_version(1).reset);
+        // Version 2 peers don't understand VSYNC_BATCH
+        assert!(Features::for_version(2).reset);
+        assert!(!Features::for_version(2).vsync_batch);
+        assert!(negotiate_version(MIN_PROTOCOL_VERSION - 1).is_err());
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        use std::io::{Error, ErrorKind};
+
+        let closed = ProtocolError::ConnectionClosed;
+        assert!(closed.is_disconnect() && !closed.is_recoverable());
+        assert_eq!(closed.io_kind(), None);
+
+        let reset = ProtocolError::Io(Error::from(ErrorKind::ConnectionReset));
+        assert!(reset.is_disconnect() && !reset.is_recoverable());
+        assert_eq!(reset.io_kind(), Some(ErrorKind::ConnectionReset));
+
+        let timeout = ProtocolError::Io(Error::from(ErrorKind::WouldBlock));
+        assert!(timeout.is_recoverable() && !timeout.is_disconnect());
+
+        let version = ProtocolError::UnsupportedVersion { peer: 9, supported: 3 };
+        assert!(!version.is_disconnect() && !version.is_recoverable());
+    }
+
+    #[test]
+    fn test_hello_flags_compatible() {
+        // No flags, and optional flags we don't know, are fine
+        assert!(check_hello_flags(0x00, SUPPORTED_REQUIRED_FLAGS).is_ok());
+        assert!(check_hello_flags(0x0F, SUPPORTED_REQUIRED_FLAGS).is_ok());
+        // A required flag we do support
+        assert!(check_hello_flags(0x11, 0x10).is_ok());
+    }
+
+    #[test]
+    fn test_hello_flags_incompatible() {
+        match check_hello_flags(0x31, 0x10) {
+            Err(ProtocolError::UnsupportedFlags(missing)) => assert_eq!(missing, 0x20),
+            other => panic!("expected UnsupportedFlags, got {:?}", other),
+        }
+        assert!(check_hello_flags(0x80, SUPPORTED_REQUIRED_FLAGS).is_err());
+    }
+
+    #[test]
+    fn test_decode_borrowed_burst() {
+        let mut burst = Vec::new();
+        for i in 0..1000u32 {
+            burst.extend(Message::UartData(i.to_le_bytes().to_vec()).encode());
+        }
+        burst.extend(Message::Vsync.encode());
+
+        let mut rest = &burst[..];
+        let mut count = 0;
+        while !rest.is_empty() {
+            let (msg, len) = Message::decode_borrowed(rest).unwrap();
+            let (owned, owned_len) = Message::decode(rest).unwrap();
+            assert_eq!(len, owned_len);
+            match msg {
+                MessageRef::UartData(data) => {
+                    assert_eq!(data, (count as u32).to_le_bytes());
+                    // the payload points into the input buffer
+                    assert!(burst.as_ptr_range().contains(&data.as_ptr()));
+                }
+                MessageRef::Owned(ref m) => assert_eq!(*m, Message::Vsync),
+            }
+            assert_eq!(msg.into_owned(), owned);
+            rest = &rest[len..];
+            count += 1;
+        }
+        assert_eq!(count, 1001);
+    }
+
+    #[test]
+    fn test_framed_resync() {
+        let mut stream = Vec::new();
+        Message::UartData(vec![0x41]).write_framed_to(&mut stream).unwrap();
+        // Garbage, then a magic with an impossible length
+        stream.extend([0x12, 0x34, 0xA5]);
+        stream.extend(FRAME_MAGIC);
+        stream.extend([0xff, 0xff]);
+        Message::Vsync.write_framed_to(&mut stream).unwrap();
+
+        let mut reader = &stream[..];
+        assert_eq!(
+            Message::read_framed_from(&mut reader).unwrap(),
+            Message::UartData(vec![0x41])
+        );
+        assert_eq!(Message::read_framed_from(&mut reader).unwrap(), Message::Vsync);
+        assert!(matches!(
+            Message::read_framed_from(&mut reader),
+            Err(ProtocolError::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn test_random_values() {
+        assert_ne!(random_u64(), random_u64());
+        assert_eq!(process_session_token(), process_session_token());
+    }
+
     #[test]
     fn test_wire_format() {
         // Verify exact wire format: [len:u16-LE][type:u8][payload...]
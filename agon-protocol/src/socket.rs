@@ -3,6 +3,8 @@
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::net::{TcpListener, TcpStream};
 #[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::time::Duration;
@@ -12,11 +14,28 @@ use crate::{Message, ProtocolError};
 /// Default socket path for Unix sockets
 pub const DEFAULT_SOCKET_PATH: &str = "/tmp/agon-vdp.sock";
 
+/// Environment variable that overrides `DEFAULT_SOCKET_PATH`
+pub const SOCKET_PATH_ENV: &str = "AGON_VDP_SOCKET";
+
+/// Socket path to use when none was given on the command line: the
+/// `AGON_VDP_SOCKET` environment variable if set, else `DEFAULT_SOCKET_PATH`.
+/// Setting it per user avoids clashes on multi-user systems.
+pub fn default_socket_path() -> String {
+    match std::env::var(SOCKET_PATH_ENV) {
+        Ok(path) if !path.is_empty() => path,
+        _ => DEFAULT_SOCKET_PATH.to_string(),
+    }
+}
+
 /// Socket address type - either Unix socket path or TCP address
 #[derive(Debug, Clone)]
 pub enum SocketAddr {
     #[cfg(unix)]
     Unix(String),
+    /// Linux abstract-namespace Unix socket: no file on disk, so nothing to
+    /// clean up and no stale socket left behind by an unclean exit
+    #[cfg(target_os = "linux")]
+    UnixAbstract(String),
     Tcp(String),
 }
 
@@ -27,6 +46,23 @@ impl SocketAddr {
         SocketAddr::Unix(path.as_ref().to_string_lossy().to_string())
     }
 
+    /// Create an abstract-namespace Unix socket address (Linux only)
+    #[cfg(target_os = "linux")]
+    pub fn unix_abstract<S: Into<String>>(name: S) -> Self {
+        SocketAddr::UnixAbstract(name.into())
+    }
+
+    /// Create a Unix socket address from a `--socket` argument. On Linux a
+    /// leading `@` names an abstract-namespace socket, as in `ss` output.
+    #[cfg(unix)]
+    pub fn unix_or_abstract(path: &str) -> Self {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.strip_prefix('@') {
+            return SocketAddr::unix_abstract(name);
+        }
+        SocketAddr::unix(path)
+    }
+
     /// Create a TCP socket address
     pub fn tcp<S: Into<String>>(addr: S) -> Self {
         SocketAddr::Tcp(addr.into())
@@ -38,11 +74,20 @@ impl std::fmt::Display for SocketAddr {
         match self {
             #[cfg(unix)]
             SocketAddr::Unix(path) => write!(f, "{}", path),
+            #[cfg(target_os = "linux")]
+            SocketAddr::UnixAbstract(name) => write!(f, "@{}", name),
             SocketAddr::Tcp(addr) => write!(f, "{}", addr),
         }
     }
 }
 
+/// Address of an abstract-namespace Unix socket
+#[cfg(target_os = "linux")]
+pub(crate) fn abstract_addr(name: &str) -> Result<std::os::unix::net::SocketAddr, std::io::Error> {
+    use std::os::linux::net::SocketAddrExt;
+    std::os::unix::net::SocketAddr::from_abstract_name(name)
+}
+
 /// Internal enum for listener types
 enum ListenerInner {
     #[cfg(unix)]
@@ -70,6 +115,14 @@ impl SocketListener {
                     addr: addr.clone(),
                 })
             }
+            #[cfg(target_os = "linux")]
+            SocketAddr::UnixAbstract(name) => {
+                let listener = UnixListener::bind_addr(&abstract_addr(name)?)?;
+                Ok(SocketListener {
+                    inner: ListenerInner::Unix(listener),
+                    addr: addr.clone(),
+                })
+            }
             SocketAddr::Tcp(addr_str) => {
                 let listener = TcpListener::bind(addr_str)?;
                 Ok(SocketListener {
@@ -80,16 +133,33 @@ impl SocketListener {
         }
     }
 
-    /// Accept a new connection (blocking)
+    /// Bind as `bind`, then set the permissions of the Unix socket file to
+    /// `mode` (e.g. 0o660 to let only the owner and group connect).
+    /// Abstract and TCP sockets have no file, so are bound as by `bind`.
+    #[cfg(unix)]
+    pub fn bind_with_mode(addr: &SocketAddr, mode: u32) -> Result<Self, std::io::Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let listener = Self::bind(addr)?;
+        if let SocketAddr::Unix(path) = addr {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(listener)
+    }
+
+    /// Accept a new connection. The connection is blocking, even if the
+    /// listener isn't.
     pub fn accept(&self) -> Result<SocketConnection, std::io::Error> {
         match &self.inner {
             #[cfg(unix)]
             ListenerInner::Unix(listener) => {
                 let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
                 Ok(SocketConnection::from_unix(stream))
             }
             ListenerInner::Tcp(listener) => {
                 let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(false)?;
                 Ok(SocketConnection::from_tcp(stream))
             }
         }
@@ -113,7 +183,7 @@ impl SocketListener {
 #[cfg(unix)]
 impl Drop for SocketListener {
     fn drop(&mut self) {
-        // Clean up Unix socket file on drop
+        // Clean up Unix socket file on drop (abstract sockets have none)
         if let SocketAddr::Unix(path) = &self.addr {
             let _ = std::fs::remove_file(path);
         }
@@ -167,6 +237,40 @@ impl StreamInner {
             StreamInner::Tcp(s) => s.shutdown(how),
         }
     }
+
+    fn peer_addr(&self) -> String {
+        match self {
+            // An accepted Unix connection's peer is normally unnamed
+            #[cfg(unix)]
+            StreamInner::Unix(s) => s
+                .peer_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+                .unwrap_or_else(|| "unix socket".to_string()),
+            StreamInner::Tcp(s) => s
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            #[cfg(unix)]
+            StreamInner::Unix(_) => None,
+            StreamInner::Tcp(s) => Some(s),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for StreamInner {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            StreamInner::Unix(s) => s.as_raw_fd(),
+            StreamInner::Tcp(s) => s.as_raw_fd(),
+        }
+    }
 }
 
 impl Read for StreamInner {
@@ -201,6 +305,7 @@ impl Write for StreamInner {
 pub struct SocketConnection {
     reader: BufReader<StreamInner>,
     writer: BufWriter<StreamInner>,
+    framed: bool,
 }
 
 impl SocketConnection {
@@ -208,7 +313,11 @@ impl SocketConnection {
     fn from_unix(stream: UnixStream) -> Self {
         let reader = BufReader::new(StreamInner::Unix(stream.try_clone().unwrap()));
         let writer = BufWriter::new(StreamInner::Unix(stream));
-        SocketConnection { reader, writer }
+        SocketConnection {
+            reader,
+            writer,
+            framed: false,
+        }
     }
 
     fn from_tcp(stream: TcpStream) -> Self {
@@ -216,7 +325,11 @@ impl SocketConnection {
         let _ = stream.set_nodelay(true);
         let reader = BufReader::new(StreamInner::Tcp(stream.try_clone().unwrap()));
         let writer = BufWriter::new(StreamInner::Tcp(stream));
-        SocketConnection { reader, writer }
+        SocketConnection {
+            reader,
+            writer,
+            framed: false,
+        }
     }
 
     /// Connect to a socket address
@@ -227,6 +340,11 @@ impl SocketConnection {
                 let stream = UnixStream::connect(path)?;
                 Ok(Self::from_unix(stream))
             }
+            #[cfg(target_os = "linux")]
+            SocketAddr::UnixAbstract(name) => {
+                let stream = UnixStream::connect_addr(&abstract_addr(name)?)?;
+                Ok(Self::from_unix(stream))
+            }
             SocketAddr::Tcp(addr_str) => {
                 let stream = TcpStream::connect(addr_str)?;
                 Ok(Self::from_tcp(stream))
@@ -243,6 +361,8 @@ impl SocketConnection {
                 let stream = UnixStream::connect(path)?;
                 Ok(Self::from_unix(stream))
             }
+            #[cfg(target_os = "linux")]
+            SocketAddr::UnixAbstract(_) => Self::connect(addr),
             SocketAddr::Tcp(addr_str) => {
                 let socket_addr: std::net::SocketAddr = addr_str
                     .parse()
@@ -253,6 +373,22 @@ impl SocketConnection {
         }
     }
 
+    /// Connect, retrying with exponential backoff (plus jitter) on failure.
+    ///
+    /// Makes up to `max_attempts` attempts. The delay starts at
+    /// `initial_backoff` and doubles after each failure, capped at
+    /// `max_backoff`. Returns the error from the last attempt if all fail.
+    pub fn connect_retry(
+        addr: &SocketAddr,
+        max_attempts: u32,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Result<Self, std::io::Error> {
+        retry_with_backoff(max_attempts, initial_backoff, max_backoff, |_| {
+            Self::connect(addr)
+        })
+    }
+
     /// Set non-blocking mode
     pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
         self.reader.get_ref().set_nonblocking(nonblocking)?;
@@ -260,6 +396,28 @@ impl SocketConnection {
         Ok(())
     }
 
+    /// The underlying TCP stream (read side), if this is a TCP connection.
+    ///
+    /// Intended for registering with an event loop; reading from or writing
+    /// to it directly will corrupt the message stream.
+    pub fn tcp_stream(&self) -> Option<&TcpStream> {
+        self.reader.get_ref().tcp_stream()
+    }
+
+    /// The peer's address, for logging: `host:port` for TCP, the socket
+    /// path when connected to a Unix socket, or "unix socket" for an
+    /// accepted Unix connection (whose peer has no name)
+    pub fn peer_addr(&self) -> String {
+        self.reader.get_ref().peer_addr()
+    }
+
+    /// True if bytes have already been read from the socket but not yet
+    /// consumed by `recv`. When waiting for readiness on the raw fd, check
+    /// this first: buffered data does not make the fd readable again.
+    pub fn has_buffered_data(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+
     /// Set read timeout
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<(), std::io::Error> {
         self.reader.get_ref().set_read_timeout(dur)
@@ -270,14 +428,51 @@ impl SocketConnection {
         self.writer.get_ref().set_write_timeout(dur)
     }
 
+    /// Send each message preceded by `FRAME_MAGIC`, and resync on it when
+    /// receiving (see `Message::read_framed_from`). Both ends must agree;
+    /// set it before splitting the connection.
+    pub fn set_framed(&mut self, framed: bool) {
+        self.framed = framed;
+    }
+
     /// Send a message
     pub fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
-        msg.write_to(&mut self.writer)
+        send_message(&mut self.writer, msg, self.framed)
+    }
+
+    /// Buffer a message without flushing, so several can go out together;
+    /// call `flush` to send them
+    pub fn send_deferred(&mut self, msg: &Message) -> Result<(), ProtocolError> {
+        queue_message(&mut self.writer, msg, self.framed)
+    }
+
+    /// Send any messages buffered by `send_deferred`
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        self.writer.flush()?;
+        Ok(())
     }
 
     /// Receive a message (blocking)
     pub fn recv(&mut self) -> Result<Message, ProtocolError> {
-        Message::read_from(&mut self.reader)
+        recv_message(&mut self.reader, self.framed)
+    }
+
+    /// Round-trip `payload` through the peer with an ECHO message and
+    /// return what came back.
+    ///
+    /// Any other messages received while waiting are discarded, so use this
+    /// on a probe connection or before starting normal traffic. The peer
+    /// must support ECHO (protocol version 2).
+    pub fn ping_echo(&mut self, payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        if payload.len() > crate::messages::MAX_UART_DATA_SIZE {
+            return Err(ProtocolError::PayloadTooLarge(payload.len()));
+        }
+        self.send(&Message::Echo(payload.to_vec()))?;
+        loop {
+            if let Message::Echo(data) = self.recv()? {
+                return Ok(data);
+            }
+        }
     }
 
     /// Try to receive a message (non-blocking)
@@ -289,7 +484,7 @@ impl SocketConnection {
             .set_nonblocking(true)
             .map_err(ProtocolError::Io)?;
 
-        let result = match Message::read_from(&mut self.reader) {
+        let result = match recv_message(&mut self.reader, self.framed) {
             Ok(msg) => Ok(Some(msg)),
             Err(ProtocolError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
             Err(e) => Err(e),
@@ -304,7 +499,11 @@ impl SocketConnection {
     pub fn try_clone(&self) -> Result<Self, std::io::Error> {
         let reader = BufReader::new(self.reader.get_ref().try_clone()?);
         let writer = BufWriter::new(self.writer.get_ref().try_clone()?);
-        Ok(SocketConnection { reader, writer })
+        Ok(SocketConnection {
+            reader,
+            writer,
+            framed: self.framed,
+        })
     }
 
     /// Shutdown the connection
@@ -317,23 +516,109 @@ impl SocketConnection {
         (
             SocketReader {
                 reader: self.reader,
+                framed: self.framed,
             },
             SocketWriter {
                 writer: self.writer,
+                framed: self.framed,
             },
         )
     }
 }
 
+fn send_message<W: Write>(writer: &mut W, msg: &Message, framed: bool) -> Result<(), ProtocolError> {
+    if framed {
+        msg.write_framed_to(writer)
+    } else {
+        msg.write_to(writer)
+    }
+}
+
+/// Write a message into `writer`'s buffer, without flushing
+fn queue_message<W: Write>(writer: &mut W, msg: &Message, framed: bool) -> Result<(), ProtocolError> {
+    if framed {
+        writer.write_all(&crate::messages::FRAME_MAGIC)?;
+    }
+    writer.write_all(&msg.encode())?;
+    Ok(())
+}
+
+fn recv_message<R: Read>(reader: &mut R, framed: bool) -> Result<Message, ProtocolError> {
+    if framed {
+        Message::read_framed_from(reader)
+    } else {
+        Message::read_from(reader)
+    }
+}
+
+/// Call `f` (passing the 1-based attempt number) until it succeeds or
+/// `max_attempts` is reached, sleeping with exponential backoff in between.
+fn retry_with_backoff<T, F>(
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    mut f: F,
+) -> Result<T, std::io::Error>
+where
+    F: FnMut(u32) -> Result<T, std::io::Error>,
+{
+    let mut backoff = initial_backoff.min(max_backoff);
+    let mut attempt = 1;
+    loop {
+        match f(attempt) {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= max_attempts => return Err(e),
+            Err(_) => {}
+        }
+        // Sleep somewhere between half and all of the current backoff, so
+        // several clients started together don't retry in lockstep
+        let half = backoff / 2;
+        std::thread::sleep(half + half.mul_f64(jitter()));
+        backoff = (backoff * 2).min(max_backoff);
+        attempt += 1;
+    }
+}
+
+/// Pseudo-random value in [0, 1), good enough for retry jitter
+fn jitter() -> f64 {
+    (crate::random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
 /// Reader half of a split connection
 pub struct SocketReader {
     reader: BufReader<StreamInner>,
+    framed: bool,
 }
 
 impl SocketReader {
     /// Receive a message (blocking)
     pub fn recv(&mut self) -> Result<Message, ProtocolError> {
-        Message::read_from(&mut self.reader)
+        recv_message(&mut self.reader, self.framed)
+    }
+
+    /// Iterate over received messages (blocking).
+    ///
+    /// The iterator ends when the peer closes the connection, or after
+    /// yielding any other error.
+    ///
+    /// ```no_run
+    /// use agon_protocol::{Message, SocketAddr, SocketConnection};
+    ///
+    /// let conn = SocketConnection::connect(&SocketAddr::tcp("127.0.0.1:5000")).unwrap();
+    /// let (mut reader, _writer) = conn.split();
+    /// for msg in reader.messages() {
+    ///     match msg {
+    ///         Ok(Message::UartData(data)) => println!("{} bytes", data.len()),
+    ///         Ok(_) => {}
+    ///         Err(e) => eprintln!("Read error: {}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages {
+            reader: self,
+            done: false,
+        }
     }
 
     /// Set read timeout
@@ -345,17 +630,78 @@ impl SocketReader {
     pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
         self.reader.get_ref().set_nonblocking(nonblocking)
     }
+
+    /// True if bytes are buffered but not yet consumed by `recv`
+    /// (see `SocketConnection::has_buffered_data`)
+    pub fn has_buffered_data(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+}
+
+/// Iterator over the messages received by a `SocketReader`
+/// (see `SocketReader::messages`)
+pub struct Messages<'a> {
+    reader: &'a mut SocketReader,
+    done: bool,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<Message, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.reader.recv() {
+            Ok(msg) => Some(Ok(msg)),
+            Err(ProtocolError::ConnectionClosed) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Poll the raw fd for readability, e.g. with epoll or mio.
+#[cfg(unix)]
+impl AsRawFd for SocketConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for SocketReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
 }
 
 /// Writer half of a split connection
 pub struct SocketWriter {
     writer: BufWriter<StreamInner>,
+    framed: bool,
 }
 
 impl SocketWriter {
     /// Send a message
     pub fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
-        msg.write_to(&mut self.writer)
+        send_message(&mut self.writer, msg, self.framed)
+    }
+
+    /// Buffer a message without flushing (see `SocketConnection::send_deferred`)
+    pub fn send_deferred(&mut self, msg: &Message) -> Result<(), ProtocolError> {
+        queue_message(&mut self.writer, msg, self.framed)
+    }
+
+    /// Send any messages buffered by `send_deferred`
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        self.writer.flush()?;
+        Ok(())
     }
 
     /// Set write timeout
@@ -394,6 +740,7 @@ mod tests {
             conn.send(&Message::HelloAck {
                 version: 1,
                 capabilities: "{}".to_string(),
+                session: None,
             })
             .unwrap();
 
@@ -415,6 +762,7 @@ mod tests {
         conn.send(&Message::Hello {
             version: 1,
             flags: 0,
+            session: None,
         })
         .unwrap();
 
@@ -431,4 +779,198 @@ mod tests {
 
         server_thread.join().unwrap();
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_connect_retry_succeeds_on_third_attempt() {
+        let socket_path = "/tmp/agon-test-retry.sock";
+        let _ = std::fs::remove_file(socket_path);
+        let addr = SocketAddr::unix(socket_path);
+
+        // Only start listening just before the third attempt
+        let mut listener = None;
+        let mut attempts = 0;
+        let conn = retry_with_backoff(
+            5,
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+            |attempt| {
+                attempts = attempt;
+                if attempt == 3 {
+                    listener = Some(SocketListener::bind(&addr).unwrap());
+                }
+                SocketConnection::connect(&addr)
+            },
+        );
+        assert!(conn.is_ok());
+        assert_eq!(attempts, 3);
+        let _server_conn = listener.unwrap().accept().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_recv_timeout_is_reported() {
+        let socket_path = "/tmp/agon-test-timeout.sock";
+        let _ = std::fs::remove_file(socket_path);
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind(&addr).unwrap();
+
+        // Peer connects but never sends anything
+        let _client = SocketConnection::connect(&addr).unwrap();
+        let mut conn = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let err = conn.recv().unwrap_err();
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_raw_fd_and_buffered_data() {
+        let socket_path = "/tmp/agon-test-rawfd.sock";
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind(&addr).unwrap();
+        let mut client = SocketConnection::connect(&addr).unwrap();
+        let mut server = listener.accept().unwrap();
+
+        assert!(server.as_raw_fd() >= 0);
+        assert!(server.tcp_stream().is_none());
+        assert_eq!(client.peer_addr(), socket_path);
+        assert_eq!(server.peer_addr(), "unix socket");
+
+        // Two messages arrive in one read; the second stays buffered
+        client.send(&Message::Vsync).unwrap();
+        client.send(&Message::Cts(true)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(server.recv().unwrap(), Message::Vsync);
+        assert!(server.has_buffered_data());
+        assert_eq!(server.recv().unwrap(), Message::Cts(true));
+        assert!(!server.has_buffered_data());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ping_echo() {
+        let socket_path = "/tmp/agon-test-echo.sock";
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind(&addr).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let mut conn = listener.accept().unwrap();
+            // Unrelated traffic first, then the echo
+            conn.send(&Message::UartData(vec![0x41])).unwrap();
+            if let Message::Echo(data) = conn.recv().unwrap() {
+                conn.send(&Message::Echo(data)).unwrap();
+            }
+        });
+
+        let mut conn = SocketConnection::connect(&addr).unwrap();
+        assert_eq!(conn.ping_echo(b"ping").unwrap(), b"ping");
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_send_deferred() {
+        let socket_path = "/tmp/agon-test-deferred.sock";
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind(&addr).unwrap();
+        let (_reader, mut writer) = SocketConnection::connect(&addr).unwrap().split();
+        let mut server = listener.accept().unwrap();
+
+        writer.send_deferred(&Message::UartData(vec![0x41])).unwrap();
+        writer.send_deferred(&Message::Vsync).unwrap();
+        // Nothing is sent until the flush
+        assert!(server.try_recv().unwrap().is_none());
+        writer.flush().unwrap();
+        assert_eq!(server.recv().unwrap(), Message::UartData(vec![0x41]));
+        assert_eq!(server.recv().unwrap(), Message::Vsync);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_messages_iterator() {
+        let socket_path = "/tmp/agon-test-iter.sock";
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind(&addr).unwrap();
+        let mut client = SocketConnection::connect(&addr).unwrap();
+        let server = listener.accept().unwrap();
+
+        client.send(&Message::Vsync).unwrap();
+        client.send(&Message::Cts(true)).unwrap();
+        drop(client);
+
+        let (mut reader, _writer) = server.split();
+        let msgs: Vec<Message> = reader.messages().map(Result::unwrap).collect();
+        assert_eq!(msgs, vec![Message::Vsync, Message::Cts(true)]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_framed_round_trip() {
+        let addr = SocketAddr::unix("/tmp/agon-test-framed.sock");
+        let listener = SocketListener::bind(&addr).unwrap();
+        let mut client = SocketConnection::connect(&addr).unwrap();
+        let mut server = listener.accept().unwrap();
+        client.set_framed(true);
+        server.set_framed(true);
+
+        client.send(&Message::UartData(vec![0x41])).unwrap();
+        assert_eq!(server.recv().unwrap(), Message::UartData(vec![0x41]));
+
+        // Split halves keep framing
+        let (mut reader, mut writer) = server.split();
+        writer.send(&Message::Vsync).unwrap();
+        assert_eq!(client.recv().unwrap(), Message::Vsync);
+        client.send(&Message::Cts(true)).unwrap();
+        assert_eq!(reader.recv().unwrap(), Message::Cts(true));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_bind_with_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let socket_path = "/tmp/agon-test-mode.sock";
+        let addr = SocketAddr::unix(socket_path);
+        let listener = SocketListener::bind_with_mode(&addr, 0o600).unwrap();
+        let mode = std::fs::metadata(socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        assert!(!Path::new(socket_path).exists());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_abstract_socket() {
+        let addr = SocketAddr::unix_or_abstract("@agon-test-abstract");
+        assert!(matches!(addr, SocketAddr::UnixAbstract(ref name) if name == "agon-test-abstract"));
+        assert_eq!(addr.to_string(), "@agon-test-abstract");
+
+        let listener = SocketListener::bind(&addr).unwrap();
+        let mut client = SocketConnection::connect(&addr).unwrap();
+        let mut server = listener.accept().unwrap();
+        client.send(&Message::Vsync).unwrap();
+        assert_eq!(server.recv().unwrap(), Message::Vsync);
+        // Nothing on the filesystem
+        assert!(!Path::new("agon-test-abstract").exists());
+
+        // The name is free again once the listener is gone
+        drop(listener);
+        drop(server);
+        let _listener = SocketListener::bind(&addr).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_connect_retry_gives_up() {
+        let addr = SocketAddr::unix("/tmp/agon-test-retry-none.sock");
+        let _ = std::fs::remove_file("/tmp/agon-test-retry-none.sock");
+        let result = SocketConnection::connect_retry(
+            &addr,
+            3,
+            Duration::from_millis(1),
+            Duration::from_millis(2),
+        );
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,212 @@
+//! VDU command stream knowledge, for trace output.
+//!
+//! The eZ80 sends the VDP a stream of VDU commands: a control code
+//! (0-31, 127) followed by a fixed number of argument bytes, or printable
+//! characters. [`VduDecoder`] follows the stream and names each command as
+//! it starts, e.g. `VDU 25 PLOT`.
+
+/// Name of a VDU control code
+pub fn vdu_name(code: u8) -> &'static str {
+    match code {
+        0 => "NUL",
+        1 => "PRINTER CHAR",
+        2 => "PRINTER ON",
+        3 => "PRINTER OFF",
+        4 => "TEXT CURSOR",
+        5 => "GRAPHICS CURSOR",
+        6 => "ENABLE SCREEN",
+        7 => "BELL",
+        8 => "BACKSPACE",
+        9 => "CURSOR RIGHT",
+        10 => "LINE FEED",
+        11 => "CURSOR UP",
+        12 => "CLS",
+        13 => "CARRIAGE RETURN",
+        14 => "PAGED ON",
+        15 => "PAGED OFF",
+        16 => "CLG",
+        17 => "COLOUR",
+        18 => "GCOL",
+        19 => "PALETTE",
+        20 => "RESET COLOURS",
+        21 => "DISABLE SCREEN",
+        22 => "MODE",
+        23 => "SYSTEM",
+        24 => "GRAPHICS VIEWPORT",
+        25 => "PLOT",
+        26 => "RESET VIEWPORTS",
+        27 => "ESCAPE",
+        28 => "TEXT VIEWPORT",
+        29 => "GRAPHICS ORIGIN",
+        30 => "HOME",
+        31 => "TAB",
+        127 => "DELETE",
+        _ => "CHAR",
+    }
+}
+
+/// Number of argument bytes following a VDU control code. For `VDU 23`
+/// this is just the subcommand byte; see [`vdu_23_args`].
+pub fn vdu_args(code: u8) -> usize {
+    match code {
+        1 | 17 | 22 | 23 | 27 => 1,
+        18 | 31 => 2,
+        28 | 29 => 4,
+        19 | 25 => 5,
+        24 => 8,
+        _ => 0,
+    }
+}
+
+/// Number of bytes following `VDU 23, n` (n != 0).
+/// Agon-specific commands differ from the BBC Micro's fixed 8.
+pub fn vdu_23_args(n: u8) -> usize {
+    match n {
+        // cursor on/off
+        1 => 1,
+        // scroll: extent, direction, speed
+        7 => 3,
+        // cursor behaviour: setting, mask
+        16 => 2,
+        // line thickness
+        23 => 1,
+        // dotted line pattern, and character redefinition (n >= 32)
+        _ => 8,
+    }
+}
+
+/// Name and argument count of a `VDU 23, 0, cmd` system command, where
+/// the length is known up front
+fn vdu_23_0_command(cmd: u8) -> Option<(&'static str, usize)> {
+    Some(match cmd {
+        0x80 => ("GENERAL POLL", 1),
+        0x81 => ("KEYBOARD LOCALE", 1),
+        0x82 => ("CURSOR POSITION", 0),
+        0x83 => ("READ CHAR", 4),
+        0x84 => ("READ PIXEL", 4),
+        0x86 => ("MODE INFO", 0),
+        // mode byte; setting the clock (mode 1) adds 6 more
+        0x87 => ("RTC", 1),
+        0x88 => ("KEYBOARD STATE", 5),
+        0xc0 => ("LOGICAL COORDS", 1),
+        0xfe => ("CONSOLE MODE", 1),
+        0xff => ("TERMINAL MODE", 0),
+        _ => return None,
+    })
+}
+
+/// Follows a VDU stream across calls, naming each command as it starts.
+///
+/// `VDU 23, 0` commands whose length isn't known (audio, buffers, ...) are
+/// named, but their arguments can't be skipped: the decoder picks up again
+/// at the next byte, so what follows may be misnamed until the stream
+/// comes back into step.
+#[derive(Debug, Default)]
+pub struct VduDecoder {
+    /// Bytes of the command in progress, from its control code
+    cmd: Vec<u8>,
+    /// Argument bytes still to come
+    remaining: usize,
+}
+
+impl VduDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names of the commands started in `bytes`, in order. A run of
+    /// printable characters is shown quoted, as one entry.
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut names = vec![];
+        let mut text = String::new();
+        for &b in bytes {
+            if self.remaining == 0 && (0x20..0x7f).contains(&b) {
+                text.push(b as char);
+                continue;
+            }
+            if !text.is_empty() {
+                names.push(format!("{:?}", text));
+                text.clear();
+            }
+            names.extend(self.feed(b));
+        }
+        if !text.is_empty() {
+            names.push(format!("{:?}", text));
+        }
+        names
+    }
+
+    /// Feed one byte. Returns the name of the command it starts or, for
+    /// `VDU 23`, the byte that identifies it.
+    fn feed(&mut self, b: u8) -> Option<String> {
+        if self.remaining == 0 {
+            self.cmd.clear();
+            self.cmd.push(b);
+            self.remaining = vdu_args(b);
+            return match b {
+                // named once the subcommand arrives
+                23 => None,
+                0x80.. => Some(format!("CHAR &{:02X}", b)),
+                _ => Some(format!("VDU {} {}", b, vdu_name(b))),
+            };
+        }
+
+        self.cmd.push(b);
+        self.remaining -= 1;
+        match self.cmd[..] {
+            [23, 0] => {
+                self.remaining = 1;
+                None
+            }
+            [23, 0, cmd] => match vdu_23_0_command(cmd) {
+                Some((name, args)) => {
+                    self.remaining = args;
+                    Some(format!("VDU 23,0,&{:02X} {}", cmd, name))
+                }
+                None => Some(format!("VDU 23,0,&{:02X}", cmd)),
+            },
+            [23, 0, 0x87, 1] => {
+                self.remaining = 6;
+                None
+            }
+            [23, n] => {
+                self.remaining = vdu_23_args(n);
+                Some(match n {
+                    1 => "VDU 23,1 CURSOR".to_string(),
+                    7 => "VDU 23,7 SCROLL".to_string(),
+                    16 => "VDU 23,16 CURSOR BEHAVIOUR".to_string(),
+                    23 => "VDU 23,23 LINE THICKNESS".to_string(),
+                    32.. => format!("VDU 23,{} DEFINE CHAR", n),
+                    _ => format!("VDU 23,{}", n),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_commands_and_text() {
+        let mut d = VduDecoder::new();
+        let stream = [22, 3, 17, 1, b'H', b'i', 13, 10, 25, 69, 0, 1, 0, 2];
+        assert_eq!(
+            d.decode(&stream),
+            vec!["VDU 22 MODE", "VDU 17 COLOUR", "\"Hi\"", "VDU 13 CARRIAGE RETURN", "VDU 10 LINE FEED", "VDU 25 PLOT"]
+        );
+    }
+
+    #[test]
+    fn test_decode_across_calls() {
+        let mut d = VduDecoder::new();
+        // arguments that look like text are skipped
+        assert_eq!(d.decode(&[31, b'A']), vec!["VDU 31 TAB"]);
+        assert_eq!(d.decode(&[b'B', 23, 0]), Vec::<String>::new());
+        assert_eq!(d.decode(&[0x80, 0x41, 12]), vec!["VDU 23,0,&80 GENERAL POLL", "VDU 12 CLS"]);
+        // character redefinition swallows its 8 bytes
+        assert_eq!(d.decode(&[23, 65, 1, 2, 3, 4, 5, 6, 7, 8, 7]), vec!["VDU 23,65 DEFINE CHAR", "VDU 7 BELL"]);
+    }
+}
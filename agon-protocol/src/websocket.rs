@@ -2,13 +2,60 @@
 //!
 //! This module provides WebSocket server and connection handling that uses
 //! the same message protocol as Unix/TCP sockets.
+//!
+//! Messages normally travel one per binary frame. For clients where binary
+//! frames are awkward (e.g. plain browser JS), a text frame holding the
+//! base64 of a message is accepted too; a client that sets
+//! [`HELLO_FLAG_TEXT_FRAMES`] in its HELLO is answered in kind.
 
+use data_encoding::BASE64;
+use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
 use tungstenite::{accept, WebSocket};
-use tungstenite::protocol::Message as WsMessage;
+use tungstenite::protocol::{Message as WsMessage, Role};
 
 use crate::{Message, ProtocolError};
 
+/// HELLO flag: the client wants messages as base64 in text frames
+pub const HELLO_FLAG_TEXT_FRAMES: u8 = 0x01;
+
+/// The TCP stream under a WebSocket. Once split, the reader's copy drops
+/// whatever tungstenite writes to it (its automatic pong and close
+/// replies): the reader sends those through the writer instead, so frames
+/// only ever go out from one socket.
+struct WsStream {
+    stream: TcpStream,
+    discard_writes: bool,
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.discard_writes {
+            return Ok(buf.len());
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// The writing socket of a split connection, shared with the reader for
+/// control replies
+type SharedWebSocket = Arc<Mutex<WebSocket<WsStream>>>;
+
+fn lock(websocket: &SharedWebSocket) -> MutexGuard<'_, WebSocket<WsStream>> {
+    websocket.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 /// A WebSocket listener that accepts connections
 pub struct WebSocketListener {
     listener: TcpListener,
@@ -23,20 +70,29 @@ impl WebSocketListener {
         Ok(WebSocketListener { listener, port })
     }
 
-    /// Accept a new WebSocket connection (blocking)
+    /// Accept a new WebSocket connection
     ///
-    /// This performs the WebSocket handshake automatically.
+    /// This performs the WebSocket handshake automatically. The connection
+    /// is blocking, even if the listener isn't.
     pub fn accept(&self) -> Result<WebSocketConnection, std::io::Error> {
         let (stream, _addr) = self.listener.accept()?;
+        stream.set_nonblocking(false)?;
         // Disable Nagle's algorithm for lower latency
         let _ = stream.set_nodelay(true);
 
         // Perform WebSocket handshake
+        let stream = WsStream {
+            stream,
+            discard_writes: false,
+        };
         let websocket = accept(stream).map_err(|e| {
             std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e.to_string())
         })?;
 
-        Ok(WebSocketConnection { websocket })
+        Ok(WebSocketConnection {
+            websocket,
+            text_frames: false,
+        })
     }
 
     /// Set non-blocking mode on the listener
@@ -52,52 +108,61 @@ impl WebSocketListener {
 
 /// A WebSocket connection for bidirectional message exchange
 pub struct WebSocketConnection {
-    websocket: WebSocket<TcpStream>,
+    websocket: WebSocket<WsStream>,
+    /// Send messages as base64 text frames rather than binary
+    text_frames: bool,
 }
 
 impl WebSocketConnection {
+    /// Send messages as base64 in text frames (for a peer that set
+    /// `HELLO_FLAG_TEXT_FRAMES`), or as binary frames (the default)
+    pub fn set_text_frames(&mut self, enabled: bool) {
+        self.text_frames = enabled;
+    }
+
+    /// The peer's `host:port`, for logging
+    pub fn peer_addr(&self) -> String {
+        self.websocket
+            .get_ref()
+            .stream
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_else(|_| "unknown".to_string())
+    }
+
     /// Send a protocol message over WebSocket
     pub fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
-        let data = msg.encode();
-        self.websocket
-            .send(WsMessage::Binary(data.into()))
-            .map_err(|e| ProtocolError::Io(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                e.to_string(),
-            )))
+        send_message(&mut self.websocket, msg, self.text_frames)
     }
 
     /// Receive a protocol message from WebSocket (blocking)
     pub fn recv(&mut self) -> Result<Message, ProtocolError> {
-        loop {
-            let ws_msg = self.websocket.read().map_err(Self::convert_ws_error)?;
+        recv_message(&mut self.websocket, None)
+    }
 
-            match ws_msg {
-                WsMessage::Binary(data) => {
-                    let (msg, _len) = Message::decode(&data)?;
-                    return Ok(msg);
-                }
-                WsMessage::Close(_) => {
-                    return Err(ProtocolError::Io(std::io::Error::new(
-                        std::io::ErrorKind::ConnectionReset,
-                        "WebSocket closed",
-                    )));
-                }
-                WsMessage::Ping(data) => {
-                    // Respond to ping with pong
-                    let _ = self.websocket.send(WsMessage::Pong(data));
-                }
-                WsMessage::Pong(_) => {
-                    // Ignore pong messages
-                }
-                WsMessage::Text(_) => {
-                    // Ignore text messages, we only use binary
-                }
-                WsMessage::Frame(_) => {
-                    // Raw frames shouldn't appear in normal operation
-                }
-            }
-        }
+    /// Split into a reader and a writer for use on separate threads, e.g. a
+    /// blocking reader thread feeding a channel.
+    ///
+    /// Both halves share the TCP stream, but only the writer sends frames.
+    /// The reader answers pings and the peer's close through it.
+    pub fn split(mut self) -> Result<(WebSocketReader, WebSocketWriter), std::io::Error> {
+        let stream = WsStream {
+            stream: self.websocket.get_ref().stream.try_clone()?,
+            discard_writes: false,
+        };
+        let writer = Arc::new(Mutex::new(WebSocket::from_raw_socket(stream, Role::Server, None)));
+        // The reader keeps this socket, and with it any data already read
+        self.websocket.get_mut().discard_writes = true;
+        Ok((
+            WebSocketReader {
+                websocket: self.websocket,
+                writer: writer.clone(),
+            },
+            WebSocketWriter {
+                websocket: writer,
+                text_frames: self.text_frames,
+            },
+        ))
     }
 
     /// Convert tungstenite error to ProtocolError, preserving WouldBlock
@@ -111,11 +176,16 @@ impl WebSocketConnection {
         }
     }
 
+    /// Set read timeout on the underlying TCP stream
+    pub fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> Result<(), std::io::Error> {
+        self.websocket.get_ref().stream.set_read_timeout(dur)
+    }
+
     /// Try to receive a message (non-blocking)
     /// Returns None if no message is available
     pub fn try_recv(&mut self) -> Result<Option<Message>, ProtocolError> {
         // Get the underlying stream and set non-blocking
-        let stream = self.websocket.get_ref();
+        let stream = &self.websocket.get_ref().stream;
         stream.set_nonblocking(true).map_err(ProtocolError::Io)?;
 
         let result = match self.recv() {
@@ -125,18 +195,13 @@ impl WebSocketConnection {
         };
 
         // Restore blocking mode
-        let _ = self.websocket.get_ref().set_nonblocking(false);
+        let _ = self.websocket.get_ref().stream.set_nonblocking(false);
         result
     }
 
     /// Close the WebSocket connection gracefully
     pub fn close(&mut self) -> Result<(), std::io::Error> {
-        self.websocket.close(None).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-        })?;
-        // Flush pending close frame
-        let _ = self.websocket.flush();
-        Ok(())
+        close_websocket(&mut self.websocket)
     }
 
     /// Check if the connection is still open
@@ -144,3 +209,187 @@ impl WebSocketConnection {
         self.websocket.can_read() && self.websocket.can_write()
     }
 }
+
+/// Reading half of a split [`WebSocketConnection`]
+pub struct WebSocketReader {
+    websocket: WebSocket<WsStream>,
+    /// Where replies to the peer's pings and close go
+    writer: SharedWebSocket,
+}
+
+impl WebSocketReader {
+    /// Receive a protocol message from WebSocket (blocking)
+    pub fn recv(&mut self) -> Result<Message, ProtocolError> {
+        recv_message(&mut self.websocket, Some(&self.writer))
+    }
+
+    /// Set read timeout on the underlying TCP stream
+    pub fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> Result<(), std::io::Error> {
+        self.websocket.get_ref().stream.set_read_timeout(dur)
+    }
+}
+
+/// Writing half of a split [`WebSocketConnection`]
+pub struct WebSocketWriter {
+    websocket: SharedWebSocket,
+    text_frames: bool,
+}
+
+impl WebSocketWriter {
+    /// Send a protocol message over WebSocket
+    pub fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
+        send_message(&mut lock(&self.websocket), msg, self.text_frames)
+    }
+
+    /// Start the close handshake by sending a close frame. The peer's reply
+    /// arrives on the reader, whose `recv` then returns `ConnectionClosed`.
+    /// If the peer closed first, the reader has replied already and nothing
+    /// more is sent.
+    pub fn close(&mut self) -> Result<(), std::io::Error> {
+        close_websocket(&mut lock(&self.websocket))
+    }
+}
+
+/// Send a close frame, unless one has been sent already
+fn close_websocket(websocket: &mut WebSocket<WsStream>) -> Result<(), std::io::Error> {
+    websocket.close(None).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+    })?;
+    // Flush pending close frame
+    let _ = websocket.flush();
+    Ok(())
+}
+
+fn send_message(
+    websocket: &mut WebSocket<WsStream>,
+    msg: &Message,
+    text_frames: bool,
+) -> Result<(), ProtocolError> {
+    let data = msg.encode();
+    let ws_msg = if text_frames {
+        WsMessage::Text(BASE64.encode(&data))
+    } else {
+        WsMessage::Binary(data.into())
+    };
+    websocket
+        .send(ws_msg)
+        .map_err(|e| ProtocolError::Io(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            e.to_string(),
+        )))
+}
+
+/// Receive the next protocol message. tungstenite answers pings and the
+/// peer's close itself; on the reader of a split connection, whose own
+/// writes are dropped, they are answered through `writer` instead.
+fn recv_message(
+    websocket: &mut WebSocket<WsStream>,
+    writer: Option<&SharedWebSocket>,
+) -> Result<Message, ProtocolError> {
+    loop {
+        let ws_msg = websocket
+            .read()
+            .map_err(WebSocketConnection::convert_ws_error)?;
+
+        match ws_msg {
+            WsMessage::Binary(data) => {
+                let (msg, _len) = Message::decode(&data)?;
+                return Ok(msg);
+            }
+            WsMessage::Close(_) => {
+                // Reply through the writer, which sends nothing if it has
+                // closed already
+                if let Some(writer) = writer {
+                    let _ = close_websocket(&mut lock(writer));
+                }
+                return Err(ProtocolError::ConnectionClosed);
+            }
+            WsMessage::Ping(data) => {
+                if let Some(writer) = writer {
+                    let _ = lock(writer).send(WsMessage::Pong(data));
+                }
+            }
+            WsMessage::Pong(_) => {
+                // Ignore pong messages
+            }
+            WsMessage::Text(text) => {
+                let data = BASE64.decode(text.trim().as_bytes()).map_err(|e| {
+                    ProtocolError::InvalidFormat(format!("Bad base64 text frame: {}", e))
+                })?;
+                let (msg, _len) = Message::decode(&data)?;
+                return Ok(msg);
+            }
+            WsMessage::Frame(_) => {
+                // Raw frames shouldn't appear in normal operation
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A server connection, and the raw TCP stream of a client that has
+    /// completed the handshake, for writing and reading frames directly.
+    /// Client frames are masked with a zero key, which leaves them as is.
+    fn connect() -> (WebSocketConnection, TcpStream) {
+        let listener = WebSocketListener::bind(0).unwrap();
+        let port = listener.listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let raw = stream.try_clone().unwrap();
+            tungstenite::client(format!("ws://127.0.0.1:{}/", port), stream).unwrap();
+            raw
+        });
+        let conn = listener.accept().unwrap();
+        let raw = client.join().unwrap();
+        raw.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        (conn, raw)
+    }
+
+    /// Everything the server sends until it goes quiet
+    fn read_frames(raw: &mut TcpStream) -> Vec<u8> {
+        let mut frames = vec![];
+        let mut buf = [0u8; 64];
+        while let Ok(n @ 1..) = raw.read(&mut buf) {
+            frames.extend_from_slice(&buf[..n]);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_split_reader_pongs_through_writer() {
+        let (conn, mut raw) = connect();
+        let (mut reader, _writer) = conn.split().unwrap();
+
+        let data = Message::Vsync.encode();
+        let mut frames = vec![0x89, 0x80, 0, 0, 0, 0, 0x82, 0x80 | data.len() as u8, 0, 0, 0, 0];
+        frames.extend_from_slice(&data);
+        raw.write_all(&frames).unwrap();
+
+        assert!(matches!(reader.recv(), Ok(Message::Vsync)));
+        // one pong, from the writer
+        assert_eq!(read_frames(&mut raw), [0x8a, 0x00]);
+    }
+
+    #[test]
+    fn test_split_sends_one_close() {
+        // we close first: the peer's reply is not answered again
+        let (conn, mut raw) = connect();
+        let (mut reader, mut writer) = conn.split().unwrap();
+        writer.close().unwrap();
+        raw.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        assert!(matches!(reader.recv(), Err(ProtocolError::ConnectionClosed)));
+        assert_eq!(read_frames(&mut raw), [0x88, 0x00]);
+
+        // the peer closes first: closing after the reader's reply sends nothing
+        let (conn, mut raw) = connect();
+        let (mut reader, mut writer) = conn.split().unwrap();
+        raw.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        assert!(matches!(reader.recv(), Err(ProtocolError::ConnectionClosed)));
+        let _ = writer.close();
+        assert_eq!(read_frames(&mut raw), [0x88, 0x00]);
+    }
+}
@@ -0,0 +1,68 @@
+//! Recording of eZ80 output in the VSYNC-chunked format that
+//! `agon-vdp-sdl --replay` reads.
+
+use std::io::{self, Write};
+
+/// Largest chunk the replay format can hold
+const MAX_CHUNK: usize = u16::MAX as usize;
+
+/// Collects UART data from the eZ80 and writes it as one `[len:u16-LE][data]`
+/// chunk per VSYNC.
+///
+/// Frames without data are skipped (a zero length marks the end of a replay
+/// file), and no end marker is written, so later sessions can append.
+pub struct Capture<W: Write> {
+    out: W,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> Capture<W> {
+    pub fn new(out: W) -> Self {
+        Capture { out, frame: vec![] }
+    }
+
+    /// Add UART data received during the current frame
+    pub fn record(&mut self, data: &[u8]) {
+        self.frame.extend_from_slice(data);
+    }
+
+    /// Write out the current frame's data, at a VSYNC
+    pub fn end_frame(&mut self) -> io::Result<()> {
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        for chunk in self.frame.chunks(MAX_CHUNK) {
+            self.out.write_all(&(chunk.len() as u16).to_le_bytes())?;
+            self.out.write_all(chunk)?;
+        }
+        self.frame.clear();
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_chunk_per_frame() {
+        let mut capture = Capture::new(vec![]);
+        capture.record(b"AB");
+        capture.record(b"C");
+        capture.end_frame().unwrap();
+        // empty frames are skipped
+        capture.end_frame().unwrap();
+        capture.record(&[0x16, 0x03]);
+        capture.end_frame().unwrap();
+        assert_eq!(capture.out, vec![3, 0, b'A', b'B', b'C', 2, 0, 0x16, 0x03]);
+    }
+
+    #[test]
+    fn test_large_frame_is_split() {
+        let mut capture = Capture::new(vec![]);
+        capture.record(&vec![0x41; MAX_CHUNK + 1]);
+        capture.end_frame().unwrap();
+        assert_eq!(&capture.out[..2], &[0xff, 0xff]);
+        assert_eq!(&capture.out[2 + MAX_CHUNK..], &[1, 0, 0x41]);
+    }
+}
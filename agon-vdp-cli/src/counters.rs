@@ -0,0 +1,83 @@
+//! Per-session message tallies, to profile what a program asks the VDP to do.
+
+use agon_protocol::Message;
+use std::collections::BTreeMap;
+
+/// Counts of VDU commands and protocol messages seen in one session
+pub struct MessageCounters {
+    /// VDU command bytes (the first byte of each command) from the eZ80
+    vdu: [u64; 256],
+    /// Protocol messages received from the eZ80, by type
+    rx: BTreeMap<&'static str, u64>,
+    /// Protocol messages sent to the eZ80, by type
+    tx: BTreeMap<&'static str, u64>,
+}
+
+impl MessageCounters {
+    pub fn new() -> Self {
+        MessageCounters {
+            vdu: [0; 256],
+            rx: BTreeMap::new(),
+            tx: BTreeMap::new(),
+        }
+    }
+
+    /// Record the first byte of a VDU command
+    pub fn record_vdu(&mut self, cmd: u8) {
+        self.vdu[cmd as usize] += 1;
+    }
+
+    /// Record a message received from the eZ80
+    pub fn record_rx(&mut self, msg: &Message) {
+        *self.rx.entry(type_name(msg)).or_insert(0) += 1;
+    }
+
+    /// Record a message sent to the eZ80
+    pub fn record_tx(&mut self, msg: &Message) {
+        *self.tx.entry(type_name(msg)).or_insert(0) += 1;
+    }
+
+    /// Human readable report, one entry per line. Printable characters are
+    /// lumped together; other VDU commands are listed most frequent first.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (name, count) in &self.rx {
+            lines.push(format!("<- {:<14} {}", name, count));
+        }
+        for (name, count) in &self.tx {
+            lines.push(format!("-> {:<14} {}", name, count));
+        }
+
+        let text: u64 = self.vdu[0x20..0x7f].iter().sum();
+        if text > 0 {
+            lines.push(format!("VDU text chars    {}", text));
+        }
+        let mut cmds: Vec<(usize, u64)> = self
+            .vdu
+            .iter()
+            .enumerate()
+            .filter(|&(b, &n)| n > 0 && !(0x20..0x7f).contains(&b))
+            .map(|(b, &n)| (b, n))
+            .collect();
+        cmds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (cmd, count) in cmds {
+            lines.push(format!("VDU 0x{:02X}          {}", cmd, count));
+        }
+        lines
+    }
+}
+
+fn type_name(msg: &Message) -> &'static str {
+    match msg {
+        Message::UartData(_) => "UART_DATA",
+        Message::Vsync => "VSYNC",
+        Message::VsyncBatch(_) => "VSYNC_BATCH",
+        Message::Cts(_) => "CTS",
+        Message::Echo(_) => "ECHO",
+        Message::Hello { .. } => "HELLO",
+        Message::HelloAck { .. } => "HELLO_ACK",
+        Message::VersionReject { .. } => "VERSION_REJECT",
+        Message::Shutdown => "SHUTDOWN",
+        Message::Reset => "RESET",
+    }
+}
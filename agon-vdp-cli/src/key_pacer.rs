@@ -0,0 +1,88 @@
+//! Key event pacing for typed input.
+//!
+//! Key packets go out at least `--key-delay-ms` apart. While the eZ80 says
+//! it is busy (CTS not ready) nothing is sent, and each busy signal doubles
+//! the spacing, which then eases back towards the configured delay with
+//! every packet sent.
+
+use std::time::{Duration, Instant};
+
+/// Longest spacing the backoff will reach
+const MAX_KEY_DELAY: Duration = Duration::from_millis(500);
+
+pub struct KeyPacer {
+    base: Duration,
+    interval: Duration,
+    last: Instant,
+    ready: bool,
+}
+
+impl KeyPacer {
+    pub fn new(delay: Duration) -> Self {
+        KeyPacer {
+            base: delay,
+            interval: delay,
+            last: Instant::now(),
+            ready: true,
+        }
+    }
+
+    /// Current spacing between key events
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The eZ80's clear-to-send state. Going busy backs off the spacing
+    pub fn set_ready(&mut self, ready: bool) {
+        if self.ready && !ready {
+            // A zero --key-delay-ms still needs somewhere to back off from
+            let doubled = (self.interval * 2).max(Duration::from_millis(1));
+            self.interval = doubled.min(MAX_KEY_DELAY.max(self.base));
+        }
+        self.ready = ready;
+    }
+
+    /// Whether the next key event may be sent at `now`
+    pub fn due(&self, now: Instant) -> bool {
+        self.ready && now.duration_since(self.last) >= self.interval
+    }
+
+    /// Record a key event sent at `now`
+    pub fn sent(&mut self, now: Instant) {
+        self.last = now;
+        self.interval = (self.interval / 2).max(self.base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waits_for_delay() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut p = KeyPacer::new(ms(10));
+        p.sent(t0);
+        assert!(!p.due(t0 + ms(9)));
+        assert!(p.due(t0 + ms(10)));
+    }
+
+    #[test]
+    fn test_backs_off_while_busy() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut p = KeyPacer::new(ms(10));
+        p.sent(t0);
+        p.set_ready(false);
+        assert!(!p.due(t0 + ms(100)));
+        // repeated busy signals only back off once
+        p.set_ready(false);
+        p.set_ready(true);
+        assert_eq!(p.interval(), ms(20));
+        assert!(!p.due(t0 + ms(19)));
+        assert!(p.due(t0 + ms(20)));
+        p.sent(t0 + ms(20));
+        assert_eq!(p.interval(), ms(10));
+    }
+}
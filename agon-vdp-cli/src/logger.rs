@@ -4,6 +4,7 @@ use crate::parse_args::Verbosity;
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 /// Output destination for logger
 enum Output {
@@ -15,6 +16,8 @@ enum Output {
 pub struct Logger {
     output: Arc<Mutex<Output>>,
     verbosity: Verbosity,
+    timestamps: bool,
+    start: Instant,
 }
 
 impl Logger {
@@ -23,6 +26,8 @@ impl Logger {
         Logger {
             output: Arc::new(Mutex::new(Output::Stderr)),
             verbosity,
+            timestamps: false,
+            start: Instant::now(),
         }
     }
 
@@ -32,9 +37,17 @@ impl Logger {
         Ok(Logger {
             output: Arc::new(Mutex::new(Output::File(BufWriter::new(file)))),
             verbosity,
+            timestamps: false,
+            start: Instant::now(),
         })
     }
 
+    /// Prefix lines with seconds elapsed since the logger was created
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
     /// Get verbosity level
     pub fn verbosity(&self) -> Verbosity {
         self.verbosity
@@ -43,17 +56,7 @@ impl Logger {
     /// Log a message if verbosity level is met
     pub fn log(&self, level: Verbosity, msg: &str) {
         if self.verbosity >= level {
-            if let Ok(mut output) = self.output.lock() {
-                match &mut *output {
-                    Output::Stderr => {
-                        eprintln!("{}", msg);
-                    }
-                    Output::File(f) => {
-                        let _ = writeln!(f, "{}", msg);
-                        let _ = f.flush();
-                    }
-                }
-            }
+            self.write_line(msg);
         }
     }
 
@@ -74,13 +77,22 @@ impl Logger {
 
     /// Always log (for errors, important info)
     pub fn info(&self, msg: &str) {
+        self.write_line(msg);
+    }
+
+    fn write_line(&self, msg: &str) {
+        let line = if self.timestamps {
+            format!("[{:8.3}] {}", self.start.elapsed().as_secs_f64(), msg)
+        } else {
+            msg.to_string()
+        };
         if let Ok(mut output) = self.output.lock() {
             match &mut *output {
                 Output::Stderr => {
-                    eprintln!("{}", msg);
+                    eprintln!("{}", line);
                 }
                 Output::File(f) => {
-                    let _ = writeln!(f, "{}", msg);
+                    let _ = writeln!(f, "{}", line);
                     let _ = f.flush();
                 }
             }
@@ -93,6 +105,8 @@ impl Clone for Logger {
         Logger {
             output: self.output.clone(),
             verbosity: self.verbosity,
+            timestamps: self.timestamps,
+            start: self.start,
         }
     }
 }
@@ -1,18 +1,46 @@
+mod capture;
+mod counters;
+mod key_pacer;
 mod logger;
 mod parse_args;
 mod text_vdp;
+mod vsync_clock;
 
-use agon_protocol::{Message, ProtocolError, SocketAddr, SocketConnection, PROTOCOL_VERSION};
+use agon_protocol::{
+    fmt_payload, negotiate_version, process_session_token, Message, ProtocolError, SocketAddr,
+    SocketConnection, SocketWriter, PROTOCOL_VERSION,
+};
+use capture::Capture;
+use counters::MessageCounters;
+use key_pacer::KeyPacer;
 use logger::Logger;
 use parse_args::{parse_args, Verbosity};
 use text_vdp::TextVdp;
+use vsync_clock::VsyncClock;
 
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::{self, BufRead};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// How long the eZ80 must be quiet before the next --type-file line is
+/// typed, so each waits for the previous command to finish
+const TYPE_FILE_IDLE: Duration = Duration::from_millis(500);
+
+/// Per-session settings from the command line
+struct SessionOptions {
+    vsync_hz: f64,
+    /// Spacing between typed key events (--key-delay-ms)
+    key_delay: Duration,
+    /// Lines to type before reading stdin (--type-file)
+    type_lines: Vec<String>,
+    /// Send SHUTDOWN once `type_lines` have been typed
+    exit_after_typing: bool,
+}
+
 fn main() {
     let args = match parse_args() {
         Ok(a) => a,
@@ -37,7 +65,8 @@ fn main() {
             }
         }
         None => Logger::stderr(args.verbosity),
-    };
+    }
+    .with_timestamps(args.log_timestamps);
 
     // Determine socket address
     let addr = if let Some(tcp) = &args.tcp_addr {
@@ -46,10 +75,10 @@ fn main() {
         let path = args
             .socket_path
             .clone()
-            .unwrap_or_else(|| agon_protocol::socket::DEFAULT_SOCKET_PATH.to_string());
+            .unwrap_or_else(agon_protocol::socket::default_socket_path);
         #[cfg(unix)]
         {
-            SocketAddr::unix(&path)
+            SocketAddr::unix_or_abstract(&path)
         }
         #[cfg(not(unix))]
         {
@@ -58,6 +87,41 @@ fn main() {
         }
     };
 
+    let type_lines = match &args.type_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text.lines().map(str::to_string).collect(),
+            Err(e) => {
+                eprintln!("Failed to read '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => vec![],
+    };
+    let options = SessionOptions {
+        vsync_hz: args.vsync_hz,
+        key_delay: Duration::from_millis(args.key_delay_ms),
+        type_lines,
+        exit_after_typing: args.exit_after_typing,
+    };
+
+    let mut capture = args.capture.as_ref().map(|path| {
+        match std::fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => {
+                eprintln!("Capturing eZ80 output to: {}", path);
+                Capture::new(f)
+            }
+            Err(e) => {
+                eprintln!("Failed to open capture file '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    // Create text VDP. It outlives each connection, so is reset on connect
+    let mut vdp = TextVdp::new(logger.clone())
+        .with_screen_size(args.cols, args.rows, args.mode_size)
+        .with_announce(args.announce);
+
     // Main connection loop - supports reconnection
     loop {
         logger.verbose(&format!("[PROTO] Connecting to eZ80 at {}...", addr));
@@ -66,14 +130,31 @@ fn main() {
         }
 
         match SocketConnection::connect(&addr) {
-            Ok(conn) => {
+            Ok(mut conn) => {
+                conn.set_framed(args.framed);
                 logger.verbose("[PROTO] Connected!");
                 if logger.verbosity() < Verbosity::Verbose {
                     eprintln!("Connected!");
                 }
-                if let Err(e) = run_session(conn, &logger) {
+                let mut counters = MessageCounters::new();
+                let result = if args.null {
+                    run_null_session(conn, &logger, &mut counters, &mut capture, options.vsync_hz)
+                } else {
+                    vdp.reset();
+                    run_session(conn, &mut vdp, &logger, &mut counters, &mut capture, &options)
+                };
+                if let Err(e) = &result {
                     eprintln!("Session error: {}", e);
                 }
+                logger.verbose("[PROTO] Session message counts:");
+                for line in counters.report() {
+                    logger.verbose(&format!("[PROTO]   {}", line));
+                }
+                // A null or scripted VDP runs a single session, so a test
+                // can wait on it
+                if args.null || options.exit_after_typing {
+                    std::process::exit(if result.is_ok() { 0 } else { 1 });
+                }
                 eprintln!("Disconnected from eZ80, reconnecting...");
             }
             Err(e) => {
@@ -85,32 +166,36 @@ fn main() {
     }
 }
 
-/// Format bytes as hex string for debug output
-fn fmt_hex(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|b| format!("{:02X}", b))
-        .collect::<Vec<_>>()
-        .join(" ")
-}
-
-fn run_session(mut conn: SocketConnection, logger: &Logger) -> Result<(), ProtocolError> {
-    // Perform handshake (as connector, we send HELLO first)
-    let caps = r#"{"type":"cli","cols":80,"rows":25}"#;
-    logger.verbose(&format!("[PROTO] -> HELLO version={}, flags=0", PROTOCOL_VERSION));
+/// Perform the handshake (as connector, we send HELLO first)
+fn handshake(conn: &mut SocketConnection, caps: &str, logger: &Logger) -> Result<(), ProtocolError> {
+    logger.verbose(&format!("[PROTO] -> HELLO version={}, flags=0, caps={}", PROTOCOL_VERSION, caps));
     conn.send(&Message::Hello {
         version: PROTOCOL_VERSION,
         flags: 0,
+        session: Some(process_session_token()),
     })?;
 
     // Wait for HELLO_ACK
     let msg = conn.recv()?;
     match msg {
-        Message::HelloAck { version, capabilities } => {
+        Message::HelloAck { version, capabilities, .. } => {
             logger.verbose(&format!("[PROTO] <- HELLO_ACK version={}, caps={}", version, capabilities));
             if logger.verbosity() < Verbosity::Verbose {
                 eprintln!("eZ80 version {}, capabilities: {}", version, if capabilities.is_empty() { "(none)" } else { &capabilities });
             }
+            if let Err(e) = negotiate_version(version) {
+                logger.verbose(&format!("[PROTO] -> VERSION_REJECT supported={}", PROTOCOL_VERSION));
+                let _ = conn.send(&Message::VersionReject {
+                    supported: PROTOCOL_VERSION,
+                });
+                return Err(e);
+            }
+        }
+        Message::VersionReject { supported } => {
+            return Err(ProtocolError::UnsupportedVersion {
+                peer: PROTOCOL_VERSION,
+                supported,
+            });
         }
         _ => {
             return Err(ProtocolError::InvalidFormat(
@@ -119,11 +204,25 @@ fn run_session(mut conn: SocketConnection, logger: &Logger) -> Result<(), Protoc
         }
     }
     eprintln!("Handshake complete");
+    Ok(())
+}
+
+fn run_session(
+    mut conn: SocketConnection,
+    vdp: &mut TextVdp,
+    logger: &Logger,
+    counters: &mut MessageCounters,
+    capture: &mut Option<Capture<File>>,
+    options: &SessionOptions,
+) -> Result<(), ProtocolError> {
+    let (cols, rows) = vdp.text_size();
+    let caps = format!(r#"{{"type":"cli","cols":{},"rows":{}}}"#, cols, rows);
+    handshake(&mut conn, &caps, logger)?;
 
     let shutdown = Arc::new(AtomicBool::new(false));
-    let shutdown_clone = shutdown.clone();
 
-    // Set up stdin reader thread
+    // Set up stdin reader thread. On EOF it drops `tx_stdin`, so the
+    // session ends once everything queued has been typed.
     let (tx_stdin, rx_stdin): (Sender<String>, Receiver<String>) = mpsc::channel();
     let _stdin_thread = std::thread::spawn(move || {
         let stdin = io::stdin();
@@ -137,54 +236,52 @@ fn run_session(mut conn: SocketConnection, logger: &Logger) -> Result<(), Protoc
                 Err(_) => break,
             }
         }
-        // Signal EOF
-        shutdown_clone.store(true, Ordering::Relaxed);
     });
 
     // Split connection for bidirectional communication
     let (mut reader, mut writer) = conn.split();
 
-    // Create text VDP
-    let mut vdp = TextVdp::new(logger.clone());
-
     // Set up reader thread for incoming messages
     let (tx_from_ez80, rx_from_ez80): (Sender<Message>, Receiver<Message>) = mpsc::channel();
     let shutdown_reader = shutdown.clone();
     let _reader_thread = std::thread::spawn(move || {
-        loop {
+        for msg in reader.messages() {
             if shutdown_reader.load(Ordering::Relaxed) {
                 break;
             }
-            match reader.recv() {
+            match msg {
                 Ok(msg) => {
                     if tx_from_ez80.send(msg).is_err() {
                         break;
                     }
                 }
-                Err(ProtocolError::ConnectionClosed) => break,
-                Err(e) => {
-                    eprintln!("Reader error: {}", e);
-                    break;
-                }
+                Err(e) => eprintln!("Reader error: {}", e),
             }
         }
     });
 
     // Main loop
-    let mut last_vsync = Instant::now();
-    let mut last_key_event = Instant::now();
-    let vsync_interval = Duration::from_micros(16666); // ~60Hz
-    let key_event_interval = Duration::from_millis(10); // 10ms between key events (like original)
-    let mut vsync_count: u64 = 0;
+    let mut vsync = VsyncClock::new(options.vsync_hz);
+    let mut key_pacer = KeyPacer::new(options.key_delay);
+    let mut type_lines: VecDeque<String> = options.type_lines.iter().cloned().collect();
+    let mut last_rx = Instant::now();
     let mut pending_key_events: Vec<Vec<u8>> = Vec::new();
 
     while !shutdown.load(Ordering::Relaxed) {
         // Process messages from eZ80
         while let Ok(msg) = rx_from_ez80.try_recv() {
+            counters.record_rx(&msg);
             match msg {
                 Message::UartData(data) => {
-                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_hex(&data)));
+                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes): {}", data.len(), fmt_payload(&data)));
+                    last_rx = Instant::now();
+                    if let Some(capture) = capture.as_mut() {
+                        capture.record(&data);
+                    }
                     for byte in data {
+                        if !vdp.in_command() {
+                            counters.record_vdu(byte);
+                        }
                         vdp.process_byte(byte);
                     }
                 }
@@ -195,6 +292,13 @@ fn run_session(mut conn: SocketConnection, logger: &Logger) -> Result<(), Protoc
                     }
                     return Ok(());
                 }
+                Message::Cts(ready) => {
+                    logger.trace(&format!("[PROTO] <- CTS ready={}", ready));
+                    key_pacer.set_ready(ready);
+                    if !ready {
+                        logger.verbose(&format!("eZ80 busy, key delay now {:?}", key_pacer.interval()));
+                    }
+                }
                 other => {
                     logger.trace(&format!("[PROTO] <- {:?} (unexpected)", other));
                 }
@@ -204,51 +308,173 @@ fn run_session(mut conn: SocketConnection, logger: &Logger) -> Result<(), Protoc
         // Send any pending VDP responses
         let tx_bytes = vdp.get_tx_bytes();
         if !tx_bytes.is_empty() {
-            logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_hex(&tx_bytes)));
-            writer.send(&Message::UartData(tx_bytes))?;
+            logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes): {}", tx_bytes.len(), fmt_payload(&tx_bytes)));
+            queue(&mut writer, counters, Message::UartData(tx_bytes))?;
         }
 
-        // Send VSYNC at ~60Hz
-        if last_vsync.elapsed() >= vsync_interval {
-            vsync_count += 1;
+        // Send VSYNC (~60Hz by default)
+        if let Some(vsync_count) = vsync.tick() {
             if vsync_count % 60 == 0 {
                 logger.trace(&format!("[PROTO] -> VSYNC #{} (~{} seconds)", vsync_count, vsync_count / 60));
             }
-            writer.send(&Message::Vsync)?;
-            last_vsync = last_vsync
-                .checked_add(vsync_interval)
-                .unwrap_or_else(Instant::now);
+            queue(&mut writer, counters, Message::Vsync)?;
+            end_capture_frame(capture);
         }
 
-        // Process stdin input - queue key events
+        // Once a --type-file has been typed and the last command has
+        // finished, we're done
+        if options.exit_after_typing
+            && type_lines.is_empty()
+            && pending_key_events.is_empty()
+            && last_rx.elapsed() >= TYPE_FILE_IDLE
+        {
+            break;
+        }
+
+        // Process --type-file lines, then stdin input - queue key events
         if pending_key_events.is_empty() {
-            if let Ok(line) = rx_stdin.try_recv() {
+            let line = if type_lines.is_empty() {
+                match rx_stdin.try_recv() {
+                    Ok(line) => Some(line),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    // stdin closed
+                    Err(mpsc::TryRecvError::Disconnected) => break,
+                }
+            } else if last_rx.elapsed() >= TYPE_FILE_IDLE {
+                type_lines.pop_front()
+            } else {
+                None
+            };
+            if let Some(line) = line {
                 // Get individual key event packets with delays
                 pending_key_events = vdp.get_key_events_for_line(&line);
 
                 // Also send any immediate TX bytes (terminal mode raw data)
                 let tx_bytes = vdp.get_tx_bytes();
                 if !tx_bytes.is_empty() {
-                    logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes, terminal): {}", tx_bytes.len(), fmt_hex(&tx_bytes)));
-                    writer.send(&Message::UartData(tx_bytes))?;
+                    logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes, terminal): {}", tx_bytes.len(), fmt_payload(&tx_bytes)));
+                    queue(&mut writer, counters, Message::UartData(tx_bytes))?;
                 }
             }
         }
 
         // Send pending key events one at a time with delays
-        if !pending_key_events.is_empty() && last_key_event.elapsed() >= key_event_interval {
+        if !pending_key_events.is_empty() && key_pacer.due(Instant::now()) {
             let key_packet = pending_key_events.remove(0);
-            logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes, key): {}", key_packet.len(), fmt_hex(&key_packet)));
-            writer.send(&Message::UartData(key_packet))?;
-            last_key_event = Instant::now();
+            logger.trace(&format!("[PROTO] -> UART_DATA ({} bytes, key): {}", key_packet.len(), fmt_payload(&key_packet)));
+            queue(&mut writer, counters, Message::UartData(key_packet))?;
+            key_pacer.sent(Instant::now());
         }
 
+        // Everything queued this time round goes out together
+        writer.flush()?;
+
         // Small sleep to avoid busy-waiting
         std::thread::sleep(Duration::from_millis(1));
     }
 
-    // Send shutdown
+    // Send shutdown (after anything still queued)
+    shutdown.store(true, Ordering::Relaxed);
     logger.verbose("[PROTO] -> SHUTDOWN");
-    let _ = writer.send(&Message::Shutdown);
+    let _ = send(&mut writer, counters, Message::Shutdown);
     Ok(())
 }
+
+/// Conformance peer for headless tests: handshake, VSYNC at `vsync_hz`, and
+/// discard everything the eZ80 sends until SHUTDOWN
+fn run_null_session(
+    mut conn: SocketConnection,
+    logger: &Logger,
+    counters: &mut MessageCounters,
+    capture: &mut Option<Capture<File>>,
+    vsync_hz: f64,
+) -> Result<(), ProtocolError> {
+    handshake(&mut conn, r#"{"type":"null"}"#, logger)?;
+
+    let (mut reader, mut writer) = conn.split();
+    let (tx_from_ez80, rx_from_ez80): (Sender<Message>, Receiver<Message>) = mpsc::channel();
+    let _reader_thread = std::thread::spawn(move || {
+        for msg in reader.messages() {
+            match msg {
+                Ok(msg) => {
+                    if tx_from_ez80.send(msg).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => eprintln!("Reader error: {}", e),
+            }
+        }
+    });
+
+    let mut vsync = VsyncClock::new(vsync_hz);
+
+    loop {
+        loop {
+            let msg = match rx_from_ez80.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Err(ProtocolError::ConnectionClosed),
+            };
+            counters.record_rx(&msg);
+            match msg {
+                Message::UartData(data) => {
+                    logger.trace(&format!("[PROTO] <- UART_DATA ({} bytes, discarded)", data.len()));
+                    if let Some(capture) = capture.as_mut() {
+                        capture.record(&data);
+                    }
+                }
+                Message::Shutdown => {
+                    logger.verbose("[PROTO] <- SHUTDOWN");
+                    if logger.verbosity() < Verbosity::Verbose {
+                        eprintln!("Received SHUTDOWN");
+                    }
+                    return Ok(());
+                }
+                other => {
+                    logger.trace(&format!("[PROTO] <- {:?} (unexpected)", other));
+                }
+            }
+        }
+
+        if let Some(vsync_count) = vsync.tick() {
+            if vsync_count % 60 == 0 {
+                logger.trace(&format!("[PROTO] -> VSYNC #{} (~{} seconds)", vsync_count, vsync_count / 60));
+            }
+            send(&mut writer, counters, Message::Vsync)?;
+            end_capture_frame(capture);
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Write out a frame of --capture data at a VSYNC, giving up on the capture
+/// if the file can't be written
+fn end_capture_frame(capture: &mut Option<Capture<File>>) {
+    if let Some(c) = capture.as_mut() {
+        if let Err(e) = c.end_frame() {
+            eprintln!("Failed to write capture file: {} (capture stopped)", e);
+            *capture = None;
+        }
+    }
+}
+
+/// Send a message to the eZ80, counting it
+fn send(
+    writer: &mut SocketWriter,
+    counters: &mut MessageCounters,
+    msg: Message,
+) -> Result<(), ProtocolError> {
+    counters.record_tx(&msg);
+    writer.send(&msg)
+}
+
+/// Queue a message to the eZ80 without flushing, counting it
+fn queue(
+    writer: &mut SocketWriter,
+    counters: &mut MessageCounters,
+    msg: Message,
+) -> Result<(), ProtocolError> {
+    counters.record_tx(&msg);
+    writer.send_deferred(&msg)
+}
@@ -8,12 +8,36 @@ USAGE:
 
 OPTIONS:
   -h, --help            Prints help information
-  --socket <path>       Unix socket path (default: /tmp/agon-vdp.sock)
+  --socket <path>       Unix socket path, or @name for a Linux abstract
+                        socket (default: $AGON_VDP_SOCKET, or /tmp/agon-vdp.sock)
   --tcp <host:port>     Connect via TCP instead of Unix socket
+  --framed              Precede each message with a resync marker, for links
+                        that may lose bytes. agon-ez80 must use --framed too
   -v, --verbose         Show connection and protocol events
   -vv, --trace          Show all protocol messages
   -vvv, --trace-uart    Show individual UART bytes (very verbose)
   --log <file>          Write trace output to file instead of stderr
+  --log-timestamps      Prefix log lines with seconds since startup
+  --cols <n>            Text columns reported to the eZ80 (default: 80)
+  --rows <n>            Text rows reported to the eZ80 (default: 25)
+  --mode-size <WxH>     Pixel size reported to the eZ80 (default: 8x16 per char)
+  --announce            Send a general-poll reply and mode info on connect,
+                        for MOS builds that wait for the VDP to announce itself
+  --vsync-hz <N>        VSYNC rate sent to the eZ80 (default: 60, 0 = as fast
+                        as possible, for quick boot tests)
+  --key-delay-ms <N>    Time between typed key events (default: 10). Raise it
+                        if slow firmware drops characters; it also backs off
+                        while the eZ80 reports it is busy (CTS)
+  --type-file <path>    Type the lines of a file as keystrokes before reading
+                        stdin. Each line waits until the eZ80 has been quiet
+                        for 0.5s, e.g. back at the prompt.
+  --exit-after-typing   Send SHUTDOWN and exit once --type-file is typed and
+                        the eZ80 has gone quiet
+  --capture <file>      Append everything the eZ80 sends to <file>, one chunk
+                        per VSYNC, for replay with 'agon-vdp-sdl --replay'
+  --null                Null VDP for headless tests: handshake, send VSYNC (see
+                        --vsync-hz) and discard all output, then exit on SHUTDOWN
+                        (status 0) or disconnect (status 1). Ignores stdin.
 ";
 
 /// Verbosity level for debug output
@@ -39,8 +63,40 @@ impl Default for Verbosity {
 pub struct AppArgs {
     pub socket_path: Option<String>,
     pub tcp_addr: Option<String>,
+    pub framed: bool,
     pub verbosity: Verbosity,
     pub log_file: Option<String>,
+    pub log_timestamps: bool,
+    pub announce: bool,
+    pub null: bool,
+    pub vsync_hz: f64,
+    pub key_delay_ms: u64,
+    pub type_file: Option<String>,
+    pub exit_after_typing: bool,
+    pub capture: Option<String>,
+    pub cols: u8,
+    pub rows: u8,
+    pub mode_size: Option<(u16, u16)>,
+}
+
+/// Parse a pixel size such as "640x480"
+fn parse_mode_size(s: &str) -> Result<(u16, u16), String> {
+    let (w, h) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WxH, got '{}'", s))?;
+    let w = w.parse().map_err(|_| format!("invalid width '{}'", w))?;
+    let h = h.parse().map_err(|_| format!("invalid height '{}'", h))?;
+    Ok((w, h))
+}
+
+/// Parse a VSYNC rate: 0, or from 0.01 to 1000 Hz
+fn parse_vsync_hz(s: &str) -> Result<f64, String> {
+    let hz: f64 = s.parse().map_err(|_| format!("invalid rate '{}'", s))?;
+    if hz == 0.0 || (0.01..=1000.0).contains(&hz) {
+        Ok(hz)
+    } else {
+        Err(format!("VSYNC rate must be 0, or from 0.01 to 1000 Hz, got '{}'", s))
+    }
 }
 
 pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
@@ -65,8 +121,20 @@ pub fn parse_args() -> Result<AppArgs, pico_args::Error> {
     let args = AppArgs {
         socket_path: pargs.opt_value_from_str("--socket")?,
         tcp_addr: pargs.opt_value_from_str("--tcp")?,
+        framed: pargs.contains("--framed"),
         verbosity,
         log_file: pargs.opt_value_from_str("--log")?,
+        log_timestamps: pargs.contains("--log-timestamps"),
+        announce: pargs.contains("--announce"),
+        null: pargs.contains("--null"),
+        vsync_hz: pargs.opt_value_from_fn("--vsync-hz", parse_vsync_hz)?.unwrap_or(60.0),
+        key_delay_ms: pargs.opt_value_from_str("--key-delay-ms")?.unwrap_or(10),
+        type_file: pargs.opt_value_from_str("--type-file")?,
+        exit_after_typing: pargs.contains("--exit-after-typing"),
+        capture: pargs.opt_value_from_str("--capture")?,
+        cols: pargs.opt_value_from_str("--cols")?.unwrap_or(80),
+        rows: pargs.opt_value_from_str("--rows")?.unwrap_or(25),
+        mode_size: pargs.opt_value_from_fn("--mode-size", parse_mode_size)?,
     };
 
     let remaining = pargs.finish();
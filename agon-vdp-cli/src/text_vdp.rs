@@ -4,8 +4,15 @@
 //! Extracted from agon-cli-emulator's fake VDP logic.
 
 use crate::logger::Logger;
+use agon_protocol::vdu::vdu_23_args;
+use agon_protocol::{fmt_hex, fmt_payload};
 use std::collections::VecDeque;
 use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// A command still incomplete after this long is assumed lost (e.g. the
+/// eZ80 was reset mid-command) and abandoned
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Text VDP state
 pub struct TextVdp {
@@ -17,6 +24,16 @@ pub struct TextVdp {
     pending_cmd: Vec<u8>,
     /// Expected bytes for current command (0 = no command in progress)
     pending_bytes: usize,
+    /// When the current command started
+    pending_since: Instant,
+    /// Announce ourselves on connect rather than waiting to be polled
+    announce: bool,
+    /// Text size reported in mode information
+    cols: u8,
+    rows: u8,
+    /// Pixel size reported in mode information
+    width: u16,
+    height: u16,
     /// Logger for debug output
     logger: Logger,
 }
@@ -30,28 +47,84 @@ impl TextVdp {
             terminal_mode: false,
             pending_cmd: Vec::new(),
             pending_bytes: 0,
+            pending_since: Instant::now(),
+            announce: false,
+            cols: 80,
+            rows: 25,
+            width: 640,
+            height: 400,
             logger,
         }
     }
 
+    /// Set the screen size reported to the eZ80. Without an explicit pixel
+    /// size, characters are taken to be 8x16.
+    pub fn with_screen_size(mut self, cols: u8, rows: u8, pixels: Option<(u16, u16)>) -> Self {
+        let (width, height) = pixels.unwrap_or((cols as u16 * 8, rows as u16 * 16));
+        self.cols = cols;
+        self.rows = rows;
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Screen size as text columns and rows
+    pub fn text_size(&self) -> (u8, u8) {
+        (self.cols, self.rows)
+    }
+
+    /// Send a general-poll reply and mode information unprompted, on
+    /// construction and after each `reset`, as the real VDP does at boot.
+    /// Some MOS builds wait for this before starting.
+    pub fn with_announce(mut self, announce: bool) -> Self {
+        self.announce = announce;
+        if announce {
+            self.send_announce();
+        }
+        self
+    }
+
     /// Check if in terminal mode
     pub fn is_terminal_mode(&self) -> bool {
         self.terminal_mode
     }
 
-    /// Format bytes as hex string for debug output
-    fn fmt_hex(bytes: &[u8]) -> String {
-        bytes
-            .iter()
-            .map(|b| format!("{:02X}", b))
-            .collect::<Vec<_>>()
-            .join(" ")
+    /// Forget any partial command, queued responses and terminal mode, e.g.
+    /// for a new connection
+    pub fn reset(&mut self) {
+        self.tx_queue.clear();
+        self.terminal_mode = false;
+        self.pending_cmd.clear();
+        self.pending_bytes = 0;
+        if self.announce {
+            self.send_announce();
+        }
+    }
+
+    fn send_announce(&mut self) {
+        self.logger.verbose("[VDP] Announcing VDP (general poll + mode info)");
+        self.send_bytes(&[0x80, 1, 0]);
+        self.send_mode_info();
+    }
+
+    /// Whether a multi-byte command is part way through being received
+    pub fn in_command(&self) -> bool {
+        self.pending_bytes > 0
     }
 
     /// Process a byte from the eZ80
     pub fn process_byte(&mut self, byte: u8) {
         self.logger.trace_uart(&format!("[VDP] <- UART byte: {:02X}", byte));
 
+        if self.pending_bytes > 0 && self.pending_since.elapsed() > COMMAND_TIMEOUT {
+            self.logger.info(&format!(
+                "[VDP] Warning: abandoning incomplete command {}",
+                fmt_hex(&self.pending_cmd)
+            ));
+            self.pending_cmd.clear();
+            self.pending_bytes = 0;
+        }
+
         // If we're collecting bytes for a command
         if self.pending_bytes > 0 {
             self.pending_cmd.push(byte);
@@ -91,6 +164,7 @@ impl TextVdp {
                 self.pending_bytes = 1;
                 self.pending_cmd.clear();
                 self.pending_cmd.push(byte);
+                self.pending_since = Instant::now();
             }
             // Backspace or printable character
             v if v == 8 || (v >= 0x20 && v != 0x7f) => {
@@ -109,6 +183,7 @@ impl TextVdp {
                 self.pending_bytes = 1; // First byte is subcommand
                 self.pending_cmd.clear();
                 self.pending_cmd.push(byte);
+                self.pending_since = Instant::now();
             }
             // Home cursor
             0x1e => {
@@ -147,8 +222,27 @@ impl TextVdp {
                         }
                         self.handle_vdu_17_0();
                     }
+                    // Everything else has a fixed length, and only needs to be
+                    // consumed so the byte stream stays in sync
                     v => {
-                        self.logger.info(&format!("[VDP] Unknown VDU 0x17, 0x{:02X}", v));
+                        let len = 2 + vdu_23_args(v);
+                        if self.pending_cmd.len() < len {
+                            self.pending_bytes = len - self.pending_cmd.len();
+                            return;
+                        }
+                        if v >= 32 {
+                            self.logger.trace(&format!(
+                                "[VDP] VDU 23,{} (redefine char) {} (ignored)",
+                                v,
+                                fmt_hex(&self.pending_cmd[2..])
+                            ));
+                        } else {
+                            self.logger.trace(&format!(
+                                "[VDP] VDU 23,{} (cmd: {}) (ignored)",
+                                v,
+                                fmt_hex(&self.pending_cmd)
+                            ));
+                        }
                     }
                 }
             }
@@ -175,20 +269,8 @@ impl TextVdp {
             }
             // Video mode info
             0x86 => {
-                let w: u16 = 640;
-                let h: u16 = 400;
-                self.logger.trace(&format!("[VDP] VDU 0x17,0,0x86 (mode info) -> {}x{} 80x25", w, h));
-                self.send_bytes(&[
-                    0x86,
-                    7,
-                    (w & 0xff) as u8,
-                    ((w >> 8) & 0xff) as u8,
-                    (h & 0xff) as u8,
-                    ((h >> 8) & 0xff) as u8,
-                    80,
-                    25,
-                    1,
-                ]);
+                self.logger.trace("[VDP] VDU 0x17,0,0x86 (mode info)");
+                self.send_mode_info();
             }
             // Read RTC - need 1 more byte for mode
             0x87 => {
@@ -210,14 +292,31 @@ impl TextVdp {
                 self.terminal_mode = true;
             }
             v => {
-                self.logger.info(&format!("[VDP] Unknown VDU 0x17,0,0x{:02X} (cmd: {})", v, Self::fmt_hex(&self.pending_cmd)));
+                self.logger.info(&format!("[VDP] Unknown VDU 0x17,0,0x{:02X} (cmd: {})", v, fmt_hex(&self.pending_cmd)));
             }
         }
     }
 
+    /// Queue a mode information packet
+    fn send_mode_info(&mut self) {
+        let (w, h) = (self.width, self.height);
+        self.logger.trace(&format!("[VDP] mode info -> {}x{} {}x{}", w, h, self.cols, self.rows));
+        self.send_bytes(&[
+            0x86,
+            7,
+            (w & 0xff) as u8,
+            ((w >> 8) & 0xff) as u8,
+            (h & 0xff) as u8,
+            ((h >> 8) & 0xff) as u8,
+            self.cols,
+            self.rows,
+            1,
+        ]);
+    }
+
     /// Queue bytes to send to the eZ80
     fn send_bytes(&mut self, bytes: &[u8]) {
-        self.logger.trace(&format!("[VDP] -> UART response: {}", Self::fmt_hex(bytes)));
+        self.logger.trace(&format!("[VDP] -> UART response: {}", fmt_payload(bytes)));
         for b in bytes {
             self.tx_queue.push_back(*b);
         }
@@ -270,3 +369,58 @@ impl TextVdp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args::Verbosity;
+
+    #[test]
+    fn test_vdu_23_char_redefine_stays_in_sync() {
+        let mut vdp = TextVdp::new(Logger::stderr(Verbosity::Quiet));
+        for b in [23, 65, 0x18, 0x3c, 0x66, 0x7e, 0x66, 0x66, 0x66, 0x00] {
+            vdp.process_byte(b);
+        }
+        assert!(!vdp.in_command());
+
+        // a following poll is still parsed as a command
+        for b in [23, 0, 0x80, 0x42] {
+            vdp.process_byte(b);
+        }
+        assert_eq!(vdp.get_tx_bytes(), vec![0x80, 1, 0x42]);
+    }
+
+    #[test]
+    fn test_reset_clears_partial_command() {
+        let mut vdp = TextVdp::new(Logger::stderr(Verbosity::Quiet));
+        for b in [23, 0, 0xff, 23, 0] {
+            vdp.process_byte(b);
+        }
+        assert!(vdp.is_terminal_mode());
+        assert!(vdp.in_command());
+
+        vdp.reset();
+        assert!(!vdp.is_terminal_mode());
+        assert!(!vdp.in_command());
+    }
+
+    #[test]
+    fn test_announce_is_queued_on_reset() {
+        let mut vdp = TextVdp::new(Logger::stderr(Verbosity::Quiet)).with_announce(true);
+        assert_eq!(&vdp.get_tx_bytes()[..3], &[0x80, 1, 0]);
+
+        vdp.reset();
+        let tx = vdp.get_tx_bytes();
+        assert_eq!(&tx[..3], &[0x80, 1, 0]);
+        assert_eq!(tx[3], 0x86);
+    }
+
+    #[test]
+    fn test_mode_info_reports_screen_size() {
+        let mut vdp = TextVdp::new(Logger::stderr(Verbosity::Quiet)).with_screen_size(132, 43, None);
+        for b in [23, 0, 0x86] {
+            vdp.process_byte(b);
+        }
+        assert_eq!(vdp.get_tx_bytes(), vec![0x86, 7, 0x20, 0x04, 0xb0, 0x02, 132, 43, 1]);
+    }
+}
@@ -0,0 +1,67 @@
+//! VSYNC pacing for the session loop.
+
+use std::time::{Duration, Instant};
+
+/// Decides when the next VSYNC is due
+pub struct VsyncClock {
+    /// Time between VSYNCs; None sends one on every check
+    interval: Option<Duration>,
+    last: Instant,
+    count: u64,
+}
+
+impl VsyncClock {
+    /// A clock ticking at `hz`, or as fast as it is polled if `hz` is 0. A
+    /// rate too slow for a `Duration` never ticks.
+    pub fn new(hz: f64) -> Self {
+        VsyncClock {
+            interval: if hz > 0.0 {
+                Some(Duration::try_from_secs_f64(1.0 / hz).unwrap_or(Duration::MAX))
+            } else {
+                None
+            },
+            last: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// If a VSYNC is due, count it and return its number (from 1)
+    pub fn tick(&mut self) -> Option<u64> {
+        match self.interval {
+            Some(interval) => {
+                if self.last.elapsed() < interval {
+                    return None;
+                }
+                self.last = self.last.checked_add(interval).unwrap_or_else(Instant::now);
+            }
+            None => self.last = Instant::now(),
+        }
+        self.count += 1;
+        Some(self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_hz_is_always_due() {
+        let mut clock = VsyncClock::new(0.0);
+        assert_eq!(clock.tick(), Some(1));
+        assert_eq!(clock.tick(), Some(2));
+    }
+
+    #[test]
+    fn test_waits_for_interval() {
+        let mut clock = VsyncClock::new(1000.0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.tick(), Some(1));
+
+        let mut slow = VsyncClock::new(0.001);
+        assert_eq!(slow.tick(), None);
+
+        let mut never = VsyncClock::new(f64::MIN_POSITIVE);
+        assert_eq!(never.tick(), None);
+    }
+}
@@ -1,19 +1,33 @@
+use agon_vdp_sdl::VdpInterface;
 use sdl3::audio::{AudioCallback, AudioStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Maximum volume (percent)
+pub const MAX_VOLUME: u32 = 100;
 
-#[allow(non_snake_case)]
 pub struct VdpAudioStream {
     pub buffer: Vec<u8>,
-    pub getAudioSamples:
-        libloading::Symbol<'static, unsafe extern "C" fn(out: *mut u8, length: u32)>,
+    pub vdp: VdpInterface,
+    /// Playback volume in percent (0 = mute, 100 = unscaled)
+    pub volume: Arc<AtomicU32>,
 }
 impl AudioCallback<u8> for VdpAudioStream {
     fn callback(&mut self, stream: &mut AudioStream, requested: i32) {
         self.buffer.resize(requested as usize, 0);
 
         unsafe {
-            (*self.getAudioSamples)(&mut self.buffer[0] as *mut u8, requested as u32);
+            (*self.vdp.getAudioSamples)(&mut self.buffer[0] as *mut u8, requested as u32);
         };
 
+        let volume = self.volume.load(Ordering::Relaxed).min(MAX_VOLUME) as i32;
+        if volume < MAX_VOLUME as i32 {
+            // U8 samples are centred on 128
+            for s in self.buffer.iter_mut() {
+                *s = (128 + (*s as i32 - 128) * volume / MAX_VOLUME as i32) as u8;
+            }
+        }
+
         match stream.put_data(&self.buffer) {
             Ok(()) => {}
             Err(err) => println!("Failed to put audio data: {err}"),
@@ -0,0 +1,85 @@
+//! Synthetic key repeat (typematic), for `--key-repeat`.
+//!
+//! Like a real PS/2 keyboard, only the last key pressed repeats, and it
+//! stops when that key is released.
+
+use std::time::{Duration, Instant};
+
+pub struct KeyRepeat {
+    delay: Duration,
+    interval: Duration,
+    /// Held key (PS/2 scancode) and when it next repeats
+    held: Option<(u16, Instant)>,
+}
+
+impl KeyRepeat {
+    pub fn new(delay: Duration, interval: Duration) -> Self {
+        KeyRepeat {
+            delay,
+            interval,
+            held: None,
+        }
+    }
+
+    pub fn press(&mut self, ps2: u16, now: Instant) {
+        self.held = Some((ps2, now + self.delay));
+    }
+
+    pub fn release(&mut self, ps2: u16) {
+        if matches!(self.held, Some((held, _)) if held == ps2) {
+            self.held = None;
+        }
+    }
+
+    /// Forget the held key, e.g. when the window loses focus and won't see
+    /// its key-up
+    pub fn clear(&mut self) {
+        self.held = None;
+    }
+
+    /// The key to send again, if its repeat is due
+    pub fn due(&mut self, now: Instant) -> Option<u16> {
+        let (ps2, next) = self.held.as_mut()?;
+        if now < *next {
+            return None;
+        }
+        // Don't burst to catch up after a stall
+        *next = (*next + self.interval).max(now);
+        Some(*ps2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_repeat() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut r = KeyRepeat::new(ms(500), ms(100));
+        r.press(0x1c, t0);
+        assert_eq!(r.due(t0 + ms(499)), None);
+        assert_eq!(r.due(t0 + ms(500)), Some(0x1c));
+        assert_eq!(r.due(t0 + ms(550)), None);
+        assert_eq!(r.due(t0 + ms(600)), Some(0x1c));
+        // releasing another key keeps this one repeating
+        r.release(0x12);
+        assert_eq!(r.due(t0 + ms(700)), Some(0x1c));
+        r.release(0x1c);
+        assert_eq!(r.due(t0 + ms(800)), None);
+    }
+
+    #[test]
+    fn test_last_key_repeats() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut r = KeyRepeat::new(ms(250), ms(50));
+        r.press(0x1c, t0);
+        r.press(0x32, t0 + ms(100));
+        assert_eq!(r.due(t0 + ms(300)), None);
+        assert_eq!(r.due(t0 + ms(350)), Some(0x32));
+        r.clear();
+        assert_eq!(r.due(t0 + ms(400)), None);
+    }
+}
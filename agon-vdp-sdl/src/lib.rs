@@ -0,0 +1,24 @@
+//! VDP rendering as a library.
+//!
+//! The `agon-vdp-sdl` binary is built on this: [`vdp_interface`] loads a VDP
+//! `.so`, and [`render`] feeds it a VDU stream and reads back frames, with no
+//! SDL window or eZ80 connection needed.
+//!
+//! ```no_run
+//! use agon_vdp_sdl::{render_vdu_stream, vdp_interface, RenderOptions};
+//!
+//! let paths = vdp_interface::default_firmware_paths("console8");
+//! let vdp = vdp_interface::init(&paths, false).expect("no VDP library");
+//! let _vdp_thread = vdp.spawn();
+//!
+//! let stream = std::fs::read("stream.vdu").unwrap();
+//! for frame in render_vdu_stream(&vdp, &stream, RenderOptions::default()) {
+//!     println!("frame {}: {}x{}", frame.number, frame.width, frame.height);
+//! }
+//! ```
+
+pub mod render;
+pub mod vdp_interface;
+
+pub use render::{feed_vdu_bytes, index_vdu_chunks, render_vdu_chunks, render_vdu_stream, Frame, RenderFrames, RenderOptions};
+pub use vdp_interface::VdpInterface;
@@ -0,0 +1,146 @@
+//! Keyboard lock state (Caps/Num/Scroll Lock), followed by watching the
+//! packets the VDP sends to the eZ80.
+//!
+//! The VDP reports lock state in two places: the modifier byte of every
+//! keyboard packet, and the LED byte of the keyboard state packet it sends
+//! in reply to MOS setting the LEDs (`VDU 23, 0, &88, delay; rate; leds`).
+
+/// VDP -> eZ80 keyboard packet (PACKET_KEYCODE | 0x80)
+const PACKET_KEYCODE: u8 = 0x81;
+/// VDP -> eZ80 keyboard state packet (PACKET_KEYSTATE | 0x80)
+const PACKET_KEYSTATE: u8 = 0x88;
+
+// Keyboard packet modifier bits (as in MOS sysvar_keymods)
+const MOD_CAPS_LOCK: u8 = 0x10;
+const MOD_NUM_LOCK: u8 = 0x20;
+const MOD_SCROLL_LOCK: u8 = 0x40;
+
+// Keyboard state packet LED bits (as in MOS sysvar_keyled)
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_CAPS_LOCK: u8 = 0x02;
+const LED_NUM_LOCK: u8 = 0x04;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl LockState {
+    /// Short indicator for the window title, e.g. "CAPS NUM"
+    pub fn indicator(&self) -> String {
+        let mut on = vec![];
+        if self.caps {
+            on.push("CAPS");
+        }
+        if self.num {
+            on.push("NUM");
+        }
+        if self.scroll {
+            on.push("SCROLL");
+        }
+        on.join(" ")
+    }
+}
+
+/// Follows VDP -> eZ80 packet framing (`[code|0x80][len][data...]`) and
+/// picks the lock state out of keyboard packets
+#[derive(Default)]
+pub struct LockStateWatcher {
+    state: LockState,
+    code: Option<u8>,
+    len: Option<usize>,
+    data: Vec<u8>,
+}
+
+impl LockStateWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> LockState {
+        self.state
+    }
+
+    /// Feed bytes sent by the VDP. Returns true if the lock state changed.
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        let before = self.state;
+        for &b in bytes {
+            self.feed_byte(b);
+        }
+        self.state != before
+    }
+
+    fn feed_byte(&mut self, b: u8) {
+        let Some(code) = self.code else {
+            // Resync on the next packet code
+            if b & 0x80 != 0 {
+                self.code = Some(b);
+                self.len = None;
+                self.data.clear();
+            }
+            return;
+        };
+        let Some(len) = self.len else {
+            self.len = Some(b as usize);
+            if b == 0 {
+                self.code = None;
+            }
+            return;
+        };
+        self.data.push(b);
+        if self.data.len() == len {
+            self.packet(code);
+            self.code = None;
+        }
+    }
+
+    fn packet(&mut self, code: u8) {
+        match code {
+            PACKET_KEYCODE if self.data.len() >= 2 => {
+                let mods = self.data[1];
+                self.state = LockState {
+                    caps: mods & MOD_CAPS_LOCK != 0,
+                    num: mods & MOD_NUM_LOCK != 0,
+                    scroll: mods & MOD_SCROLL_LOCK != 0,
+                };
+            }
+            PACKET_KEYSTATE if self.data.len() >= 5 => {
+                let leds = self.data[4];
+                self.state = LockState {
+                    caps: leds & LED_CAPS_LOCK != 0,
+                    num: leds & LED_NUM_LOCK != 0,
+                    scroll: leds & LED_SCROLL_LOCK != 0,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_state_from_key_packet() {
+        let mut w = LockStateWatcher::new();
+        // Key packet for 'A' with Shift + Caps Lock, split across two feeds
+        assert!(!w.feed(&[0x81, 4, b'A']));
+        assert!(w.feed(&[0x12, 0x1c, 1]));
+        assert_eq!(w.state(), LockState { caps: true, num: false, scroll: false });
+        assert_eq!(w.state().indicator(), "CAPS");
+        // Same state again is no change
+        assert!(!w.feed(&[0x81, 4, b'A', 0x12, 0x1c, 0]));
+    }
+
+    #[test]
+    fn test_lock_state_from_keystate_packet() {
+        let mut w = LockStateWatcher::new();
+        // Mode packet first, then keystate: delay 500, rate 33, Num + Scroll
+        w.feed(&[0x86, 2, 0, 0]);
+        assert!(w.feed(&[0x88, 5, 0xf4, 0x01, 0x21, 0x00, 0x05]));
+        assert_eq!(w.state().indicator(), "NUM SCROLL");
+    }
+}
@@ -3,19 +3,25 @@
 //! Connects to a running agon-ez80 instance and provides graphics/audio.
 
 mod audio;
+mod key_repeat;
+mod lock_state;
+mod mouse;
 mod parse_args;
 mod sdl2ps2;
-mod vdp_interface;
 
-use agon_protocol::{Message, ProtocolError, SocketAddr, SocketConnection, PROTOCOL_VERSION};
-use parse_args::{parse_args, Verbosity};
-use vdp_interface::VdpInterface;
+use agon_protocol::{
+    negotiate_version, process_session_token, Features, Message, ProtocolError, SocketAddr, SocketConnection,
+    PROTOCOL_VERSION,
+};
+use agon_vdp_sdl::render::{render_vdu_chunks, render_vdu_stream};
+use agon_vdp_sdl::vdp_interface::{self, VdpInterface};
+use parse_args::{parse_args, DumpFormat, Verbosity};
 
 use sdl3::event::Event;
 use sdl3::keyboard::Keycode;
-use sdl3_sys::everything::{SDL_ScaleMode, SDL_SetTextureScaleMode, SDL_PixelFormat};
+use sdl3_sys::everything::{SDL_ScaleMode, SDL_SetTextureScaleMode, SDL_SetWindowRelativeMouseMode, SDL_PixelFormat};
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -36,7 +42,7 @@ fn main() {
         vdp_interface::default_firmware_paths(&args.firmware)
     };
 
-    let vdp = match vdp_interface::init(&firmware_paths, args.verbosity >= Verbosity::Verbose) {
+    let mut vdp = match load_vdp(&args.firmware, &firmware_paths, &args) {
         Some(v) => v,
         None => {
             eprintln!("Failed to load VDP firmware from any of: {:?}", firmware_paths);
@@ -76,91 +82,42 @@ fn main() {
     }
 
     // Initialize audio
-    let _audio_device = match (|| -> Result<_, sdl3::Error> {
-        let audio_subsystem = sdl_context.audio()?;
-        let desired_spec = sdl3::audio::AudioSpec {
-            format: Some(sdl3::audio::AudioFormat::U8),
-            freq: Some(16384),
-            channels: Some(1),
-        };
-        let device = audio_subsystem.open_playback_device(&desired_spec)?;
-        let stream = audio_subsystem.open_playback_stream_with_callback(
-            &device,
-            &desired_spec,
-            audio::VdpAudioStream {
-                buffer: vec![],
-                getAudioSamples: vdp.getAudioSamples.clone(),
-            },
-        )?;
-        stream.resume()?;
-        Ok(stream)
-    })() {
-        Ok(d) => Some(d),
-        Err(e) => {
-            eprintln!("Audio init error: {}", e);
-            None
-        }
-    };
+    let volume = Arc::new(AtomicU32::new(args.volume));
+    let mut audio_stream = open_audio(&sdl_context, &vdp, &volume, &args);
 
     // Start VDP thread BEFORE connecting
-    let vdp_setup = vdp.vdp_setup.clone();
-    let vdp_loop_fn = vdp.vdp_loop.clone();
-    let _vdp_thread = std::thread::spawn(move || unsafe {
-        (*vdp_setup)();
-        (*vdp_loop_fn)();
-    });
+    let mut vdp_thread = vdp.spawn();
+    if args.vdp_debug {
+        set_vdp_debug_logging(&vdp, true);
+    }
+
+    wait_for_vdp_ready(&vdp, &vdp_thread, &mut event_pump, &mut canvas, &mut texture);
 
-    // Warmup: render VDP while waiting for it to initialize
-    eprintln!("Initializing VDP...");
     let mut vgabuf: Vec<u8> = vec![0u8; 1024 * 768 * 3];
     let mut mode_w: u32 = 640;
     let mut mode_h: u32 = 480;
     let mut frame_rate_hz: f32 = 60.0;
 
-    for _ in 0..60 {  // ~1 second of warmup at 60fps
-        // Process SDL events during warmup
-        for event in event_pump.poll_iter() {
-            if let Event::Quit { .. } = event {
-                std::process::exit(0);
-            }
+    let mut frame_hashes = match args.frame_hashes.as_deref().map(FrameHashes::create) {
+        Some(Ok(h)) => Some(h),
+        Some(Err(e)) => {
+            eprintln!("Failed to create frame hash file: {}", e);
+            exit_after_vdp_shutdown(&vdp, &vdp_thread, 1);
         }
-
-        // Signal vblank
-        unsafe { (*vdp.signal_vblank)() };
-
-        // Copy and render framebuffer
-        unsafe {
-            (*vdp.copyVgaFramebuffer)(
-                &mut mode_w,
-                &mut mode_h,
-                vgabuf.as_mut_ptr(),
-                &mut frame_rate_hz,
-            );
-        }
-
-        if mode_w > 0 && mode_h > 0 {
-            let pitch = mode_w as usize * 3;
-            let _ = texture.update(
-                sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                &vgabuf[..pitch * mode_h as usize],
-                pitch,
-            );
-            let _ = canvas.clear();
-            let _ = canvas.copy(&texture,
-                sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                None);
-            canvas.present();
-        }
-
-        std::thread::sleep(Duration::from_millis(16));
-    }
-    eprintln!("VDP ready");
+        None => None,
+    };
 
     // Replay mode: feed VDU bytes from file instead of socket
     if let Some(ref replay_path) = args.replay {
         eprintln!("Replay mode: {}", replay_path.display());
-        run_replay_session(&vdp, &args, &mut event_pump, &mut canvas, &mut texture);
-        return;
+        let status = match run_replay_session(&vdp, &args, &mut event_pump, &mut canvas, &mut texture, &mut frame_hashes) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+        exit_after_vdp_shutdown(&vdp, &vdp_thread, status);
     }
 
     // Determine socket address
@@ -170,10 +127,10 @@ fn main() {
         let path = args
             .socket_path
             .clone()
-            .unwrap_or_else(|| agon_protocol::socket::DEFAULT_SOCKET_PATH.to_string());
+            .unwrap_or_else(agon_protocol::socket::default_socket_path);
         #[cfg(unix)]
         {
-            SocketAddr::unix(&path)
+            SocketAddr::unix_or_abstract(&path)
         }
         #[cfg(not(unix))]
         {
@@ -182,15 +139,31 @@ fn main() {
         }
     };
 
+    // Firmware requested with RCtrl+1, RCtrl+2... during a session
+    let firmware_swap = std::cell::Cell::new(None);
+
     // Main connection loop - supports reconnection
     loop {
         eprintln!("Connecting to eZ80 at {}...", addr);
 
         match SocketConnection::connect(&addr) {
-            Ok(conn) => {
+            Ok(mut conn) => {
+                conn.set_framed(args.framed);
                 eprintln!("Connected!");
-                if let Err(e) = run_session(conn, &vdp, &args, &mut event_pump, &mut canvas, &mut texture) {
-                    eprintln!("Session error: {}", e);
+                let handle = VdpHandle {
+                    vdp: &vdp,
+                    thread: &vdp_thread,
+                    volume: &volume,
+                    firmware_swap: &firmware_swap,
+                };
+                match run_session(conn, &handle, &args, &mut event_pump, &mut canvas, &mut texture, &mut frame_hashes) {
+                    Err(e) if e.is_disconnect() => {
+                        if args.verbosity >= Verbosity::Verbose {
+                            eprintln!("[VDP] Connection lost: {}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("Session error: {}", e),
+                    Ok(()) => {}
                 }
                 eprintln!("Disconnected from eZ80, reconnecting...");
             }
@@ -199,11 +172,23 @@ fn main() {
             }
         }
 
+        if let Some(firmware) = firmware_swap.take() {
+            // The audio stream holds on to the old VDP's symbols
+            drop(audio_stream.take());
+            (vdp, vdp_thread) = swap_firmware(firmware, vdp, vdp_thread, &args);
+            audio_stream = open_audio(&sdl_context, &vdp, &volume, &args);
+            if args.vdp_debug {
+                set_vdp_debug_logging(&vdp, true);
+            }
+            wait_for_vdp_ready(&vdp, &vdp_thread, &mut event_pump, &mut canvas, &mut texture);
+            continue;
+        }
+
         // Keep rendering during reconnect attempts
         for _ in 0..60 {  // ~1 second
             for event in event_pump.poll_iter() {
                 if let Event::Quit { .. } = event {
-                    std::process::exit(0);
+                    exit_after_vdp_shutdown(&vdp, &vdp_thread, 0);
                 }
             }
 
@@ -236,7 +221,334 @@ fn main() {
     }
 }
 
-fn save_frame_png(dir: &str, frame_num: u64, buf: &[u8], w: u32, h: u32) {
+/// How long to wait for the VDP to report a video mode at startup
+const VDP_READY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Range of VDP-reported refresh rates we pace VSYNC to; rates outside it
+/// are clamped
+const MIN_FRAME_RATE_HZ: f32 = 24.0;
+const MAX_FRAME_RATE_HZ: f32 = 120.0;
+
+/// Real-time VSYNC interval for the VDP's refresh rate at the given speed.
+/// Falls back to 60Hz if the VDP hasn't reported a rate
+fn vsync_interval_for(frame_rate_hz: f32, speed: f64) -> Duration {
+    let hz = if frame_rate_hz.is_finite() && frame_rate_hz > 0.0 {
+        frame_rate_hz.clamp(MIN_FRAME_RATE_HZ, MAX_FRAME_RATE_HZ)
+    } else {
+        60.0
+    };
+    Duration::from_secs_f64(1.0 / hz as f64).div_f64(speed)
+}
+/// Speed multiplier applied by the RCtrl+F fast-forward toggle
+const FAST_FORWARD_SPEED: f64 = 4.0;
+/// Speed multiplier applied by the RCtrl+D slow-motion toggle
+const SLOW_MOTION_SPEED: f64 = 0.25;
+
+/// Capture the mouse: SDL relative mode hides the cursor, keeps it in the
+/// window and reports pure motion deltas
+fn set_mouse_grab(window: &sdl3::video::Window, grab: bool, verbosity: Verbosity) {
+    if verbosity >= Verbosity::Verbose {
+        eprintln!("[VDP] Mouse {}", if grab { "captured" } else { "released" });
+    }
+    unsafe {
+        SDL_SetWindowRelativeMouseMode(window.raw(), grab);
+    }
+}
+
+/// Turn the VDP firmware's own debug output on or off
+fn set_vdp_debug_logging(vdp: &VdpInterface, on: bool) {
+    eprintln!("VDP debug logging {}", if on { "on" } else { "off" });
+    unsafe { (*vdp.setVdpDebugLogging)(on) };
+}
+
+/// Show the keyboard lock state in the window title, so it's visible that
+/// e.g. Caps Lock is on in the emulator even if the host keyboard's isn't
+fn show_lock_state(window: &mut sdl3::video::Window, state: lock_state::LockState, verbosity: Verbosity) {
+    let indicator = state.indicator();
+    if verbosity >= Verbosity::Verbose {
+        eprintln!("[VDP] Lock state: {}", if indicator.is_empty() { "(none)" } else { &indicator });
+    }
+    let title = if indicator.is_empty() {
+        "Agon VDP".to_string()
+    } else {
+        format!("Agon VDP [{}]", indicator)
+    };
+    let _ = window.set_title(&title);
+}
+
+/// How long to wait for the VDP thread to finish after `vdp_shutdown`
+const VDP_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Ask the VDP library to shut down and wait (bounded) for its thread to
+/// finish. Returns whether it did.
+fn shutdown_vdp(vdp: &VdpInterface, vdp_thread: &std::thread::JoinHandle<()>) -> bool {
+    unsafe { (*vdp.vdp_shutdown)() };
+
+    let deadline = Instant::now() + VDP_SHUTDOWN_TIMEOUT;
+    while !vdp_thread.is_finished() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if !vdp_thread.is_finished() {
+        eprintln!("VDP thread did not exit within {:?}", VDP_SHUTDOWN_TIMEOUT);
+        return false;
+    }
+    true
+}
+
+/// Shut the VDP down, then exit the process with `status`.
+fn exit_after_vdp_shutdown(vdp: &VdpInterface, vdp_thread: &std::thread::JoinHandle<()>, status: i32) -> ! {
+    shutdown_vdp(vdp, vdp_thread);
+    std::process::exit(status);
+}
+
+/// Render the VDP while waiting for it to initialize. It is ready once it
+/// reports a video mode
+fn wait_for_vdp_ready(
+    vdp: &VdpInterface,
+    vdp_thread: &std::thread::JoinHandle<()>,
+    event_pump: &mut sdl3::EventPump,
+    canvas: &mut sdl3::render::Canvas<sdl3::video::Window>,
+    texture: &mut sdl3::render::Texture,
+) {
+    eprintln!("Initializing VDP...");
+    let mut vgabuf: Vec<u8> = vec![0u8; 1024 * 768 * 3];
+    let mut mode_w: u32 = 0;
+    let mut mode_h: u32 = 0;
+    let mut frame_rate_hz: f32 = 60.0;
+
+    let warmup_start = Instant::now();
+    loop {
+        // Process SDL events during warmup
+        for event in event_pump.poll_iter() {
+            if let Event::Quit { .. } = event {
+                exit_after_vdp_shutdown(vdp, vdp_thread, 0);
+            }
+        }
+
+        // Signal vblank
+        unsafe { (*vdp.signal_vblank)() };
+
+        // Copy and render framebuffer
+        unsafe {
+            (*vdp.copyVgaFramebuffer)(
+                &mut mode_w,
+                &mut mode_h,
+                vgabuf.as_mut_ptr(),
+                &mut frame_rate_hz,
+            );
+        }
+
+        if mode_w > 0 && mode_h > 0 {
+            let pitch = mode_w as usize * 3;
+            let _ = texture.update(
+                sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
+                &vgabuf[..pitch * mode_h as usize],
+                pitch,
+            );
+            let _ = canvas.clear();
+            let _ = canvas.copy(texture,
+                sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
+                None);
+            canvas.present();
+            break;
+        }
+
+        if warmup_start.elapsed() >= VDP_READY_TIMEOUT {
+            eprintln!("VDP did not report a video mode after {:?}, continuing anyway", VDP_READY_TIMEOUT);
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(16));
+    }
+    eprintln!("VDP ready");
+}
+
+/// Open an audio stream fed from the VDP, unless disabled with --no-audio
+fn open_audio(
+    sdl_context: &sdl3::Sdl,
+    vdp: &VdpInterface,
+    volume: &Arc<AtomicU32>,
+    args: &parse_args::AppArgs,
+) -> Option<sdl3::audio::AudioStreamWithCallback<audio::VdpAudioStream>> {
+    if args.no_audio {
+        return None;
+    }
+    match (|| -> Result<_, sdl3::Error> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired_spec = sdl3::audio::AudioSpec {
+            format: Some(sdl3::audio::AudioFormat::U8),
+            freq: Some(16384),
+            channels: Some(1),
+        };
+        let device = audio_subsystem.open_playback_device(&desired_spec)?;
+        let stream = audio_subsystem.open_playback_stream_with_callback(
+            &device,
+            &desired_spec,
+            audio::VdpAudioStream {
+                buffer: vec![],
+                vdp: vdp.clone(),
+                volume: volume.clone(),
+            },
+        )?;
+        stream.resume()?;
+        Ok(stream)
+    })() {
+        Ok(d) => Some(d),
+        Err(e) => {
+            eprintln!("Audio init error: {}", e);
+            None
+        }
+    }
+}
+
+/// VDP library built in with the `embedded-vdp` feature
+#[cfg(feature = "embedded-vdp")]
+const EMBEDDED_VDP: Option<&[u8]> = Some(include_bytes!("../../firmware/vdp_console8.so"));
+#[cfg(not(feature = "embedded-vdp"))]
+const EMBEDDED_VDP: Option<&[u8]> = None;
+/// Firmware the embedded VDP library was built from
+const EMBEDDED_FIRMWARE: &str = "console8";
+
+/// Load the VDP library for `firmware` from the firmware paths, falling
+/// back to the embedded one (if built in, and the same firmware) unless a
+/// library was given with --vdp
+fn load_vdp(firmware: &str, paths: &[std::path::PathBuf], args: &parse_args::AppArgs) -> Option<VdpInterface> {
+    let verbose = args.verbosity >= Verbosity::Verbose;
+    vdp_interface::init(paths, verbose).or_else(|| match EMBEDDED_VDP {
+        Some(lib_bytes) if args.vdp_path.is_none() => {
+            if firmware != EMBEDDED_FIRMWARE {
+                eprintln!("The embedded VDP firmware is {}, not {}", EMBEDDED_FIRMWARE, firmware);
+                return None;
+            }
+            eprintln!("Using embedded VDP firmware");
+            vdp_interface::init_from_bytes(lib_bytes, verbose)
+        }
+        _ => None,
+    })
+}
+
+/// Firmwares selectable at runtime with RCtrl+1, RCtrl+2, ...
+const FIRMWARES: [&str; 3] = ["console8", "quark", "electron"];
+
+/// Load `firmware`, then shut the running VDP down and start the new one in
+/// its place. The current VDP is left running if the new firmware can't be
+/// loaded, or resolves to the library already running.
+fn swap_firmware(
+    firmware: &str,
+    vdp: VdpInterface,
+    vdp_thread: std::thread::JoinHandle<()>,
+    args: &parse_args::AppArgs,
+) -> (VdpInterface, std::thread::JoinHandle<()>) {
+    eprintln!("Switching VDP firmware to {}...", firmware);
+    let paths = vdp_interface::default_firmware_paths(firmware);
+    let new_vdp = match load_vdp(firmware, &paths, args) {
+        Some(v) => v,
+        None => {
+            eprintln!("Failed to load VDP firmware {}, keeping the current one", firmware);
+            return (vdp, vdp_thread);
+        }
+    };
+    if new_vdp.path == vdp.path {
+        // The loader handed back the running library, not a fresh copy
+        eprintln!("VDP firmware {} is already running", firmware);
+        return (vdp, vdp_thread);
+    }
+
+    if shutdown_vdp(&vdp, &vdp_thread) {
+        let _ = vdp_thread.join();
+        // Unloaded here, as the VDP thread and the audio stream (closed by
+        // the caller) had the only other clones
+        drop(vdp);
+    } else {
+        // Its thread is still running the old library's code, so leave the
+        // library loaded and the thread detached rather than unload it
+        eprintln!("Abandoning the old VDP thread");
+        std::mem::forget(vdp);
+        drop(vdp_thread);
+    }
+
+    let vdp_thread = new_vdp.spawn();
+    (new_vdp, vdp_thread)
+}
+
+/// Per-frame CRC32 fingerprints written by --frame-hashes
+struct FrameHashes {
+    out: std::io::LineWriter<std::fs::File>,
+}
+
+impl FrameHashes {
+    fn create(path: &str) -> std::io::Result<Self> {
+        Ok(FrameHashes {
+            out: std::io::LineWriter::new(std::fs::File::create(path)?),
+        })
+    }
+
+    fn record(&mut self, frame_num: u64, buf: &[u8], w: u32, h: u32) {
+        use std::io::Write as _;
+        let crc = crc32fast::hash(&buf[..w as usize * 3 * h as usize]);
+        let _ = writeln!(self.out, "{}: {:08x}", frame_num, crc);
+    }
+}
+
+/// The VDP library, its thread and its audio volume
+struct VdpHandle<'a> {
+    vdp: &'a VdpInterface,
+    thread: &'a std::thread::JoinHandle<()>,
+    volume: &'a AtomicU32,
+    /// Set to end the session and switch to another firmware
+    firmware_swap: &'a std::cell::Cell<Option<&'static str>>,
+}
+
+/// Write a frame as raw RGB24 (`frame_NNNNNN.rgb`), with its size in a
+/// `frame_NNNNNN.txt` sidecar ("<width> <height>")
+fn save_frame_raw(dir: &str, frame_num: u64, buf: &[u8], w: u32, h: u32) {
+    use std::fs;
+    use std::path::Path;
+
+    let dir_path = Path::new(dir);
+    if !dir_path.exists() {
+        if let Err(e) = fs::create_dir_all(dir_path) {
+            eprintln!("Failed to create dump directory {}: {}", dir, e);
+            return;
+        }
+    }
+
+    let filename = dir_path.join(format!("frame_{:06}.rgb", frame_num));
+    if let Err(e) = fs::write(&filename, &buf[..w as usize * 3 * h as usize]) {
+        eprintln!("Failed to write {}: {}", filename.display(), e);
+        return;
+    }
+    let sidecar = dir_path.join(format!("frame_{:06}.txt", frame_num));
+    if let Err(e) = fs::write(&sidecar, format!("{} {}\n", w, h)) {
+        eprintln!("Failed to write {}: {}", sidecar.display(), e);
+    }
+}
+
+/// Palette and per-pixel indices for an RGB24 image, if it has at most 256
+/// colours
+fn palettize(rgb: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut colours: std::collections::HashMap<[u8; 3], u8> = std::collections::HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgb.len() / 3);
+    for px in rgb.chunks_exact(3) {
+        let colour = [px[0], px[1], px[2]];
+        let index = match colours.get(&colour) {
+            Some(&i) => i,
+            None => {
+                if colours.len() == 256 {
+                    return None;
+                }
+                let i = colours.len() as u8;
+                colours.insert(colour, i);
+                palette.extend_from_slice(&colour);
+                i
+            }
+        };
+        indices.push(index);
+    }
+    Some((palette, indices))
+}
+
+fn save_frame_png(dir: &str, frame_num: u64, buf: &[u8], w: u32, h: u32, format: DumpFormat) {
     use std::fs;
     use std::io::BufWriter;
     use std::path::Path;
@@ -259,14 +571,32 @@ fn save_frame_png(dir: &str, frame_num: u64, buf: &[u8], w: u32, h: u32) {
     };
     let writer = BufWriter::new(file);
 
+    let rgb = &buf[..w as usize * 3 * h as usize];
+    let indexed = match format {
+        DumpFormat::Indexed => palettize(rgb),
+        _ => None,
+    };
+
     let mut encoder = png::Encoder::new(writer, w, h);
-    encoder.set_color(png::ColorType::Rgb);
     encoder.set_depth(png::BitDepth::Eight);
+    if format == DumpFormat::Fast {
+        encoder.set_compression(png::Compression::Fast);
+    }
+    let data = match indexed {
+        Some((palette, indices)) => {
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(palette);
+            std::borrow::Cow::Owned(indices)
+        }
+        None => {
+            encoder.set_color(png::ColorType::Rgb);
+            std::borrow::Cow::Borrowed(rgb)
+        }
+    };
 
     match encoder.write_header() {
         Ok(mut png_writer) => {
-            let row_bytes = w as usize * 3;
-            if let Err(e) = png_writer.write_image_data(&buf[..row_bytes * h as usize]) {
+            if let Err(e) = png_writer.write_image_data(&data) {
                 eprintln!("Failed to write PNG data: {}", e);
             }
         }
@@ -290,23 +620,91 @@ fn open_replay_log(path: &str) -> Box<dyn std::io::Write> {
     }
 }
 
+/// VSYNCs to keep rendering after a replay ends, so the VDP can finish
+/// buffered commands and VSYNC callbacks (~2 seconds at 60fps)
+const EOF_GRACE_FRAMES: u32 = 120;
+
+/// Read a replay stream from stdin on its own thread, so a slow producer
+/// never stalls rendering. Sends one message per VSYNC chunk, or whatever
+/// each read returns in raw mode. The channel closes at the end marker or EOF
+fn spawn_stdin_replay(raw: bool) -> Receiver<Vec<u8>> {
+    use std::io::Read as _;
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        if raw {
+            let mut buf = [0u8; 4096];
+            loop {
+                match stdin.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        } else {
+            let mut len_buf = [0u8; 2];
+            while stdin.read_exact(&mut len_buf).is_ok() {
+                let len = u16::from_le_bytes(len_buf) as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                if stdin.read_exact(&mut chunk).is_err() || tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// The data for the next VSYNC of a stdin replay: the next chunk, or in raw
+/// mode whatever has arrived so far. None at the end of the stream, or once
+/// `quit` returns true while waiting.
+fn next_stdin_chunk(rx: &Receiver<Vec<u8>>, raw: bool, quit: impl Fn() -> bool) -> Option<Vec<u8>> {
+    if raw {
+        let mut data = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(bytes) => data.extend(bytes),
+                Err(mpsc::TryRecvError::Empty) => return Some(data),
+                Err(mpsc::TryRecvError::Disconnected) => return (!data.is_empty()).then_some(data),
+            }
+        }
+    }
+    // Chunked: each VSYNC waits for its chunk, so frames stay in step with
+    // the producer
+    loop {
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            Ok(chunk) => return Some(chunk),
+            Err(mpsc::RecvTimeoutError::Timeout) if !quit() => {}
+            Err(_) => return None,
+        }
+    }
+}
+
 fn run_replay_session(
     vdp: &VdpInterface,
     args: &parse_args::AppArgs,
     event_pump: &mut sdl3::EventPump,
     canvas: &mut sdl3::render::Canvas<sdl3::video::Window>,
     texture: &mut sdl3::render::Texture,
-) {
-    use std::io::Read as _;
+    frame_hashes: &mut Option<FrameHashes>,
+) -> Result<(), String> {
     use std::io::Write as _;
 
     let replay_path = args.replay.as_ref().unwrap();
-    let file_data = match std::fs::read(replay_path) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("Failed to read replay file '{}': {}", replay_path.display(), e);
-            std::process::exit(1);
-        }
+    // `--replay -` streams from stdin, which can't be read up front
+    let stdin_rx = (replay_path.as_os_str() == "-").then(|| spawn_stdin_replay(args.replay_raw));
+    let file_data = if stdin_rx.is_some() {
+        Vec::new()
+    } else {
+        std::fs::read(replay_path)
+            .map_err(|e| format!("Failed to read replay file '{}': {}", replay_path.display(), e))?
     };
 
     let fps = args.replay_fps.unwrap_or(60.0);
@@ -319,18 +717,6 @@ fn run_replay_session(
     let mut log: Option<Box<dyn std::io::Write>> = args.replay_log.as_deref().map(open_replay_log);
     let start_time = Instant::now();
 
-    let mut vgabuf: Vec<u8> = vec![0u8; 1024 * 768 * 3];
-    let mut mode_w: u32 = 640;
-    let mut mode_h: u32 = 480;
-    let mut frame_rate_hz: f32 = 60.0;
-    let mut vsync_count: u64 = 0;
-    let mut dump_frame_num: u64 = 0;
-    let mut last_vsync = Instant::now();
-    let mut cursor = std::io::Cursor::new(&file_data);
-    let mut eof = false;
-    let mut eof_grace: u32 = 0; // vsyncs remaining after EOF before exit
-    const EOF_GRACE_FRAMES: u32 = 120; // ~2 seconds at 60fps
-
     macro_rules! replay_log {
         ($log:expr, $start:expr, $($arg:tt)*) => {
             if let Some(ref mut w) = $log {
@@ -341,177 +727,101 @@ fn run_replay_session(
         }
     }
 
-    loop {
-        // Process SDL events
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } => return,
-                Event::KeyDown { keycode: Some(Keycode::Q), .. } => return,
-                _ => {}
+    // The window is polled between frames, and while waiting on stdin
+    let event_pump = std::cell::RefCell::new(event_pump);
+    let quit = std::cell::Cell::new(false);
+    let poll_quit = || {
+        for event in event_pump.borrow_mut().poll_iter() {
+            if matches!(event, Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Q), .. }) {
+                quit.set(true);
             }
         }
+        quit.get()
+    };
 
-        // Check vsync timing
-        let do_vsync = match vsync_interval {
-            Some(interval) => last_vsync.elapsed() >= interval,
-            None => true,
-        };
-
-        if do_vsync && !eof {
-            // Feed next chunk to VDP
-            if args.replay_raw {
-                // Raw mode: feed everything at once on first vsync
-                if vsync_count == 0 {
-                    for &byte in file_data.iter() {
-                        unsafe { (*vdp.z80_send_to_vdp)(byte) };
-                    }
-                    replay_log!(log, start_time, "RAW: fed {} bytes", file_data.len());
-                }
-                eof = true;
-            } else {
-                // VSYNC-chunked: read [u16-LE length][data]
-                let mut len_buf = [0u8; 2];
-                match cursor.read_exact(&mut len_buf) {
-                    Ok(()) => {
-                        let chunk_len = u16::from_le_bytes(len_buf) as usize;
-                        if chunk_len == 0 {
-                            replay_log!(log, start_time, "EOF marker at byte {}", cursor.position());
-                            eof = true;
-                        } else {
-                            let pos = cursor.position() as usize;
-                            if pos + chunk_len > file_data.len() {
-                                replay_log!(log, start_time, "WARN: truncated chunk at byte {}", pos);
-                                eof = true;
-                            } else {
-                                for &byte in &file_data[pos..pos + chunk_len] {
-                                    // Respect CTS flow control (VDP may be busy)
-                                    let mut cts_waits = 0u32;
-                                    while !unsafe { (*vdp.z80_uart0_is_cts)() } {
-                                        cts_waits += 1;
-                                        if cts_waits > 1000 {
-                                            // VDP thread may need a vblank to make progress
-                                            unsafe { (*vdp.signal_vblank)() };
-                                            std::thread::sleep(Duration::from_micros(100));
-                                            cts_waits = 0;
-                                        } else {
-                                            std::thread::yield_now();
-                                        }
-                                    }
-                                    unsafe { (*vdp.z80_send_to_vdp)(byte) };
-                                }
-                                cursor.set_position((pos + chunk_len) as u64);
-                                replay_log!(log, start_time, "CHUNK: {} bytes at frame {}", chunk_len, vsync_count);
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        replay_log!(log, start_time, "EOF (end of file)");
-                        eof = true;
-                    }
-                }
-            }
+    let opts = agon_vdp_sdl::RenderOptions {
+        raw: args.replay_raw,
+        start_frame: args.replay_start_frame,
+        // Stopping at a given frame means no grace period: if the stream
+        // ends first, that frame was never reached
+        extra_frames: if args.stop_at_frame.is_some() { 0 } else { EOF_GRACE_FRAMES },
+    };
+    if args.replay_start_frame > 1 {
+        eprintln!("Fast-forwarding to frame {}...", args.replay_start_frame);
+    }
+    let frames = match &stdin_rx {
+        Some(rx) => render_vdu_chunks(vdp, || next_stdin_chunk(rx, args.replay_raw, &poll_quit), opts),
+        None => render_vdu_stream(vdp, &file_data, opts),
+    };
 
-            // Signal vblank
-            unsafe { (*vdp.signal_vblank)() };
-            vsync_count += 1;
-            replay_log!(log, start_time, "VSYNC #{}", vsync_count);
+    let dumping = args.dump_frames.is_some() || args.dump_keyframes.is_some() || args.dump_raw.is_some() || frame_hashes.is_some();
+    let mut next_vsync = Instant::now();
+    for frame in frames {
+        if poll_quit() {
+            return Ok(());
+        }
+        replay_log!(log, start_time, "Frame {} ({}x{})", frame.number, frame.width, frame.height);
 
-            // Drain VDP→eZ80 responses (discard, but log them)
-            loop {
-                let mut byte: u8 = 0;
-                if unsafe { (*vdp.z80_recv_from_vdp)(&mut byte) } {
-                    replay_log!(log, start_time, "VDP->eZ80: 0x{:02X}", byte);
-                } else {
-                    break;
-                }
+        if dumping && args.frame_spec.includes(frame.number) {
+            if let Some(dir) = args.dump_frames.as_deref().or(args.dump_keyframes.as_deref()) {
+                save_frame_png(dir, frame.number, &frame.rgb, frame.width, frame.height, args.dump_format);
             }
-
-            // Copy framebuffer
-            unsafe {
-                (*vdp.copyVgaFramebuffer)(
-                    &mut mode_w,
-                    &mut mode_h,
-                    vgabuf.as_mut_ptr(),
-                    &mut frame_rate_hz,
-                );
+            if let Some(dir) = args.dump_raw.as_deref() {
+                save_frame_raw(dir, frame.number, &frame.rgb, frame.width, frame.height);
             }
-
-            // Dump frame if requested
-            if mode_w > 0 && mode_h > 0 {
-                if args.dump_frames.is_some() || args.dump_keyframes.is_some() {
-                    dump_frame_num += 1;
-                    if args.frame_spec.includes(dump_frame_num) {
-                        let dir = args.dump_frames.as_deref()
-                            .or(args.dump_keyframes.as_deref())
-                            .unwrap();
-                        save_frame_png(dir, dump_frame_num, &vgabuf, mode_w, mode_h);
-                    }
-                }
+            if let Some(hashes) = frame_hashes.as_mut() {
+                hashes.record(frame.number, &frame.rgb, frame.width, frame.height);
             }
+        }
 
-            // Render
-            if mode_w > 0 && mode_h > 0 {
-                let pitch = mode_w as usize * 3;
-                let _ = texture.update(
-                    sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                    &vgabuf[..pitch * mode_h as usize],
-                    pitch,
-                );
-                let _ = canvas.clear();
-                let _ = canvas.copy(texture,
-                    sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                    None);
-                canvas.present();
-            }
+        let pitch = frame.width as usize * 3;
+        let _ = texture.update(sdl3::rect::Rect::new(0, 0, frame.width, frame.height), &frame.rgb, pitch);
+        let _ = canvas.clear();
+        let _ = canvas.copy(texture, sdl3::rect::Rect::new(0, 0, frame.width, frame.height), None);
+        canvas.present();
 
-            last_vsync = last_vsync
-                .checked_add(vsync_interval.unwrap_or(Duration::ZERO))
-                .unwrap_or_else(Instant::now);
-        } else if eof {
-            // After EOF, continue signaling vsyncs for grace period
-            // (lets VDP finish processing buffered commands / VSYNC callbacks)
-            eof_grace += 1;
-            if eof_grace > EOF_GRACE_FRAMES {
-                replay_log!(log, start_time, "EOF grace period done ({} vsyncs), exiting", EOF_GRACE_FRAMES);
-                return;
-            }
-            unsafe { (*vdp.signal_vblank)() };
-            unsafe {
-                (*vdp.copyVgaFramebuffer)(
-                    &mut mode_w,
-                    &mut mode_h,
-                    vgabuf.as_mut_ptr(),
-                    &mut frame_rate_hz,
-                );
-            }
-            if mode_w > 0 && mode_h > 0 {
-                let pitch = mode_w as usize * 3;
-                let _ = texture.update(
-                    sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                    &vgabuf[..pitch * mode_h as usize],
-                    pitch,
-                );
-                let _ = canvas.clear();
-                let _ = canvas.copy(texture,
-                    sdl3::rect::Rect::new(0, 0, mode_w, mode_h),
-                    None);
-                canvas.present();
+        if args.stop_at_frame.is_some_and(|n| frame.number >= n) {
+            replay_log!(log, start_time, "Reached frame {}, exiting", frame.number);
+            return Ok(());
+        }
+
+        if let Some(interval) = vsync_interval {
+            next_vsync += interval;
+            let now = Instant::now();
+            if next_vsync > now {
+                std::thread::sleep(next_vsync - now);
+            } else {
+                next_vsync = now;
             }
-            std::thread::sleep(Duration::from_millis(16));
-        } else {
-            std::thread::sleep(Duration::from_millis(1));
         }
     }
+
+    if quit.get() {
+        return Ok(());
+    }
+    if let Some(n) = args.stop_at_frame {
+        return Err(format!("Replay ended before --stop-at-frame {}", n));
+    }
+    replay_log!(log, start_time, "End of replay, exiting");
+    Ok(())
 }
 
 fn run_session(
     mut conn: SocketConnection,
-    vdp: &VdpInterface,
+    handle: &VdpHandle,
     args: &parse_args::AppArgs,
     event_pump: &mut sdl3::EventPump,
     canvas: &mut sdl3::render::Canvas<sdl3::video::Window>,
     texture: &mut sdl3::render::Texture,
+    frame_hashes: &mut Option<FrameHashes>,
 ) -> Result<(), ProtocolError> {
+    let VdpHandle {
+        vdp,
+        thread: vdp_thread,
+        volume,
+        firmware_swap,
+    } = *handle;
+
     // Perform handshake (as connector, we send HELLO first)
     let caps = r#"{"type":"sdl","width":640,"height":480,"audio":true}"#;
     if args.verbosity >= Verbosity::Verbose {
@@ -520,21 +830,45 @@ fn run_session(
     conn.send(&Message::Hello {
         version: PROTOCOL_VERSION,
         flags: 0,
+        session: Some(process_session_token()),
     })?;
 
     // Wait for HELLO_ACK
-    let msg = conn.recv()?;
-    match msg {
-        Message::HelloAck { version, capabilities } => {
+    conn.set_read_timeout(Some(Duration::from_secs(args.handshake_timeout)))?;
+    let msg = conn.recv().map_err(|e| {
+        if e.is_timeout() {
+            ProtocolError::InvalidFormat("Timed out waiting for HELLO_ACK".to_string())
+        } else {
+            e
+        }
+    })?;
+    conn.set_read_timeout(None)?;
+    let features = match msg {
+        Message::HelloAck { version, capabilities, .. } => {
             if args.verbosity >= Verbosity::Verbose {
                 eprintln!("[VDP] <- HELLO_ACK version={}, caps={}", version, capabilities);
             }
             eprintln!("eZ80 version {}, capabilities: {}", version, if capabilities.is_empty() { "(none)" } else { &capabilities });
+            match negotiate_version(version) {
+                Ok(v) => Features::for_version(v),
+                Err(e) => {
+                    let _ = conn.send(&Message::VersionReject {
+                        supported: PROTOCOL_VERSION,
+                    });
+                    return Err(e);
+                }
+            }
+        }
+        Message::VersionReject { supported } => {
+            return Err(ProtocolError::UnsupportedVersion {
+                peer: PROTOCOL_VERSION,
+                supported,
+            });
         }
         _ => {
             return Err(ProtocolError::InvalidFormat("Expected HELLO_ACK".to_string()));
         }
-    }
+    };
     eprintln!("Handshake complete");
 
     let shutdown = Arc::new(AtomicBool::new(false));
@@ -546,18 +880,13 @@ fn run_session(
     let (tx_from_ez80, rx_from_ez80): (Sender<Message>, Receiver<Message>) = mpsc::channel();
     let shutdown_reader = shutdown.clone();
     let _reader_thread = std::thread::spawn(move || {
-        loop {
+        for msg in reader.messages() {
             if shutdown_reader.load(Ordering::Relaxed) {
                 break;
             }
-            match reader.recv() {
-                Ok(msg) => {
-                    if tx_from_ez80.send(msg).is_err() {
-                        break;
-                    }
-                }
-                Err(ProtocolError::ConnectionClosed) => break,
-                Err(_) => break,
+            let Ok(msg) = msg else { break };
+            if tx_from_ez80.send(msg).is_err() {
+                break;
             }
         }
     });
@@ -567,15 +896,26 @@ fn run_session(
     let mut mode_w: u32 = 640;
     let mut mode_h: u32 = 480;
     let mut frame_rate_hz: f32 = 60.0;
-    let mut mouse_btn_state: u8 = 0;
+    let mut host_mouse = mouse::HostMouse::new();
+    let mut mouse_grabbed = args.grab_mouse;
+    let mut vdp_debug = args.vdp_debug;
+    let mut key_repeat = args
+        .key_repeat
+        .map(|(delay, interval)| key_repeat::KeyRepeat::new(delay, interval));
+    if mouse_grabbed {
+        set_mouse_grab(canvas.window(), true, args.verbosity);
+    }
 
     // Main loop
     let mut last_vsync = Instant::now();
-    let vsync_interval = Duration::from_micros(16666);
+    let mut speed: f64 = 1.0;
+    let mut vsync_interval = vsync_interval_for(frame_rate_hz, speed);
     let mut rctrl_pressed = false;
     let mut vsync_count: u64 = 0;
     let mut uart_had_activity = false;
     let mut dump_frame_num: u64 = 0;
+    let mut volume_before_mute: Option<u32> = None;
+    let mut lock_state = lock_state::LockStateWatcher::new();
 
     'running: loop {
         // Process SDL events
@@ -583,7 +923,8 @@ fn run_session(
             match event {
                 Event::Quit { .. } => {
                     shutdown.store(true, Ordering::Relaxed);
-                    std::process::exit(0);
+                    let _ = writer.send(&Message::Shutdown);
+                    exit_after_vdp_shutdown(vdp, vdp_thread, 0);
                 }
                 Event::KeyDown { scancode: Some(scancode), keycode, repeat: false, .. } => {
                     if scancode == sdl3::keyboard::Scancode::RCtrl {
@@ -594,54 +935,135 @@ fn run_session(
                         match keycode {
                             Some(Keycode::Q) => {
                                 shutdown.store(true, Ordering::Relaxed);
-                                std::process::exit(0);
+                                let _ = writer.send(&Message::Shutdown);
+                                exit_after_vdp_shutdown(vdp, vdp_thread, 0);
                             }
                             Some(Keycode::M) => unsafe {
                                 (*vdp.dump_vdp_mem_stats)();
                             }
+                            Some(Keycode::_1) | Some(Keycode::_2) | Some(Keycode::_3) => {
+                                let index = match keycode {
+                                    Some(Keycode::_1) => 0,
+                                    Some(Keycode::_2) => 1,
+                                    _ => 2,
+                                };
+                                firmware_swap.set(Some(FIRMWARES[index]));
+                                break 'running;
+                            }
+                            Some(Keycode::L) => {
+                                vdp_debug = !vdp_debug;
+                                set_vdp_debug_logging(vdp, vdp_debug);
+                            }
+                            Some(Keycode::G) => {
+                                mouse_grabbed = !mouse_grabbed;
+                                set_mouse_grab(canvas.window(), mouse_grabbed, args.verbosity);
+                            }
+                            Some(Keycode::R) if !features.reset => {
+                                eprintln!("Reset not supported by this eZ80");
+                            }
+                            Some(Keycode::R) => {
+                                if args.verbosity >= Verbosity::Verbose {
+                                    eprintln!("[VDP] -> RESET");
+                                }
+                                let _ = writer.send(&Message::Reset);
+                            }
+                            Some(Keycode::F) | Some(Keycode::D) => {
+                                let target = if keycode == Some(Keycode::F) {
+                                    FAST_FORWARD_SPEED
+                                } else {
+                                    SLOW_MOTION_SPEED
+                                };
+                                speed = if speed == target { 1.0 } else { target };
+                                vsync_interval = vsync_interval_for(frame_rate_hz, speed);
+                                if speed > 1.0 {
+                                    eprintln!("[>>] Fast-forward {}x", speed);
+                                } else if speed < 1.0 {
+                                    eprintln!("[<<] Slow-motion {}x", speed);
+                                } else {
+                                    eprintln!("[>] Normal speed");
+                                }
+                            }
+                            Some(Keycode::Minus) | Some(Keycode::KpMinus) => {
+                                volume_before_mute = None;
+                                let v = volume.load(Ordering::Relaxed).saturating_sub(10);
+                                volume.store(v, Ordering::Relaxed);
+                                eprintln!("Volume: {}%", v);
+                            }
+                            Some(Keycode::Equals) | Some(Keycode::Plus) | Some(Keycode::KpPlus) => {
+                                volume_before_mute = None;
+                                let v = (volume.load(Ordering::Relaxed) + 10).min(audio::MAX_VOLUME);
+                                volume.store(v, Ordering::Relaxed);
+                                eprintln!("Volume: {}%", v);
+                            }
+                            Some(Keycode::_0) => {
+                                if let Some(v) = volume_before_mute.take() {
+                                    volume.store(v, Ordering::Relaxed);
+                                    eprintln!("Volume: {}%", v);
+                                } else {
+                                    volume_before_mute = Some(volume.swap(0, Ordering::Relaxed));
+                                    eprintln!("Muted");
+                                }
+                            }
                             _ => {}
                         }
                         continue;
                     }
-                    let ps2 = sdl2ps2::sdl2ps2(scancode, false);
+                    let ps2 = sdl2ps2::sdl2ps2(scancode, args.swap_caps_and_ctrl);
                     unsafe { (*vdp.sendPS2KbEventToFabgl)(ps2, 1) };
+                    if let Some(repeat) = key_repeat.as_mut() {
+                        repeat.press(ps2, Instant::now());
+                    }
                 }
                 Event::KeyUp { scancode: Some(scancode), repeat: false, .. } => {
                     if scancode == sdl3::keyboard::Scancode::RCtrl {
                         rctrl_pressed = false;
                         continue;
                     }
-                    let ps2 = sdl2ps2::sdl2ps2(scancode, false);
+                    let ps2 = sdl2ps2::sdl2ps2(scancode, args.swap_caps_and_ctrl);
                     unsafe { (*vdp.sendPS2KbEventToFabgl)(ps2, 0) };
+                    if let Some(repeat) = key_repeat.as_mut() {
+                        repeat.release(ps2);
+                    }
                 }
-                Event::MouseMotion { .. } => {
-                    let packet: [u8; 4] = [0x08 | mouse_btn_state, 0, 0, 0];
-                    unsafe { (*vdp.sendHostMouseEventToFabgl)(packet.as_ptr()) };
+                Event::Window { win_event: sdl3::event::WindowEvent::FocusLost, .. } => {
+                    if let Some(repeat) = key_repeat.as_mut() {
+                        repeat.clear();
+                    }
+                    if mouse_grabbed {
+                        mouse_grabbed = false;
+                        set_mouse_grab(canvas.window(), false, args.verbosity);
+                    }
                 }
-                Event::MouseButtonDown { mouse_btn, .. } => {
-                    match mouse_btn {
-                        sdl3::mouse::MouseButton::Left => mouse_btn_state |= 1,
-                        sdl3::mouse::MouseButton::Right => mouse_btn_state |= 2,
-                        sdl3::mouse::MouseButton::Middle => mouse_btn_state |= 4,
-                        _ => {}
+                Event::MouseMotion { xrel, yrel, .. } => {
+                    for packet in host_mouse.motion(xrel, yrel) {
+                        unsafe { (*vdp.sendHostMouseEventToFabgl)(packet.as_ptr()) };
                     }
-                    let packet: [u8; 4] = [0x08 | mouse_btn_state, 0, 0, 0];
-                    unsafe { (*vdp.sendHostMouseEventToFabgl)(packet.as_ptr()) };
                 }
-                Event::MouseButtonUp { mouse_btn, .. } => {
-                    match mouse_btn {
-                        sdl3::mouse::MouseButton::Left => mouse_btn_state &= !1,
-                        sdl3::mouse::MouseButton::Right => mouse_btn_state &= !2,
-                        sdl3::mouse::MouseButton::Middle => mouse_btn_state &= !4,
-                        _ => {}
+                Event::MouseWheel { y, direction, .. } => {
+                    let y = if direction == sdl3::mouse::MouseWheelDirection::Flipped { -y } else { y };
+                    for packet in host_mouse.wheel(y) {
+                        unsafe { (*vdp.sendHostMouseEventToFabgl)(packet.as_ptr()) };
                     }
-                    let packet: [u8; 4] = [0x08 | mouse_btn_state, 0, 0, 0];
+                }
+                Event::MouseButtonDown { mouse_btn, .. } | Event::MouseButtonUp { mouse_btn, .. } => {
+                    let button = match mouse_btn {
+                        sdl3::mouse::MouseButton::Left => mouse::BUTTON_LEFT,
+                        sdl3::mouse::MouseButton::Right => mouse::BUTTON_RIGHT,
+                        sdl3::mouse::MouseButton::Middle => mouse::BUTTON_MIDDLE,
+                        _ => continue,
+                    };
+                    let down = matches!(event, Event::MouseButtonDown { .. });
+                    let packet = host_mouse.button(button, down);
                     unsafe { (*vdp.sendHostMouseEventToFabgl)(packet.as_ptr()) };
                 }
                 _ => {}
             }
         }
 
+        if let Some(ps2) = key_repeat.as_mut().and_then(|r| r.due(Instant::now())) {
+            unsafe { (*vdp.sendPS2KbEventToFabgl)(ps2, 1) };
+        }
+
         // Process messages from eZ80
         while let Ok(msg) = rx_from_ez80.try_recv() {
             match msg {
@@ -679,6 +1101,9 @@ fn run_session(
             if args.verbosity >= Verbosity::Trace {
                 eprintln!("[VDP] -> UART ({} bytes)", tx_bytes.len());
             }
+            if lock_state.feed(&tx_bytes) {
+                show_lock_state(canvas.window_mut(), lock_state.state(), args.verbosity);
+            }
             let _ = writer.send(&Message::UartData(tx_bytes));
         }
 
@@ -687,12 +1112,23 @@ fn run_session(
             // Signal vblank to VDP
             unsafe { (*vdp.signal_vblank)() };
 
-            // Send VSYNC to eZ80
-            vsync_count += 1;
-            if args.verbosity >= Verbosity::Trace && vsync_count % 60 == 0 {
-                eprintln!("[VDP] VSYNC #{} (~{} seconds)", vsync_count, vsync_count / 60);
+            // Send VSYNC to eZ80, catching up in one go on any frames we
+            // stalled through
+            let frames = if features.vsync_batch {
+                (last_vsync.elapsed().as_micros() / vsync_interval.as_micros().max(1)).clamp(1, 255) as u32
+            } else {
+                1
+            };
+            vsync_count += frames as u64;
+            if args.verbosity >= Verbosity::Trace && (frames > 1 || vsync_count % 60 == 0) {
+                eprintln!("[VDP] VSYNC #{} (~{} seconds), {} frame(s)", vsync_count, vsync_count / 60, frames);
             }
-            if let Err(e) = writer.send(&Message::Vsync) {
+            let msg = if frames > 1 {
+                Message::VsyncBatch(frames as u8)
+            } else {
+                Message::Vsync
+            };
+            if let Err(e) = writer.send(&msg) {
                 eprintln!("[VDP] Failed to send VSYNC: {}", e);
                 break 'running;
             }
@@ -709,15 +1145,23 @@ fn run_session(
 
             // Dump frame if requested
             if mode_w > 0 && mode_h > 0 {
+                // Hashes and raw dumps follow the PNG dump; on their own they
+                // cover every frame
                 let should_dump = args.dump_frames.is_some()
-                    || (args.dump_keyframes.is_some() && uart_had_activity);
+                    || (args.dump_keyframes.is_some() && uart_had_activity)
+                    || ((frame_hashes.is_some() || args.dump_raw.is_some()) && args.dump_keyframes.is_none());
                 if should_dump {
                     dump_frame_num += 1;
                     if args.frame_spec.includes(dump_frame_num) {
-                        let dir = args.dump_frames.as_deref()
-                            .or(args.dump_keyframes.as_deref())
-                            .unwrap();
-                        save_frame_png(dir, dump_frame_num, &vgabuf, mode_w, mode_h);
+                        if let Some(dir) = args.dump_frames.as_deref().or(args.dump_keyframes.as_deref()) {
+                            save_frame_png(dir, dump_frame_num, &vgabuf, mode_w, mode_h, args.dump_format);
+                        }
+                        if let Some(dir) = args.dump_raw.as_deref() {
+                            save_frame_raw(dir, dump_frame_num, &vgabuf, mode_w, mode_h);
+                        }
+                        if let Some(hashes) = frame_hashes.as_mut() {
+                            hashes.record(dump_frame_num, &vgabuf, mode_w, mode_h);
+                        }
                     }
                 }
                 uart_had_activity = false;
@@ -740,8 +1184,17 @@ fn run_session(
             }
 
             last_vsync = last_vsync
-                .checked_add(vsync_interval)
+                .checked_add(vsync_interval * frames)
                 .unwrap_or_else(Instant::now);
+
+            // Follow the refresh rate of the current video mode (50Hz, 70Hz...)
+            let interval = vsync_interval_for(frame_rate_hz, speed);
+            if interval != vsync_interval {
+                if args.verbosity >= Verbosity::Verbose {
+                    eprintln!("[VDP] Refresh rate {:.2}Hz", frame_rate_hz);
+                }
+                vsync_interval = interval;
+            }
         }
 
         // Small sleep
@@ -0,0 +1,119 @@
+//! Host mouse -> fabgl PS/2 mouse packets.
+//!
+//! fabgl expects 4-byte IntelliMouse packets: `[flags, dx, dy, dz]`, where
+//! flags holds the buttons (bits 0-2), an always-set bit 3 and the X/Y sign
+//! bits (4, 5). Y is positive upwards and the wheel (dz, 4-bit signed) is
+//! positive towards the user, both the opposite of SDL.
+
+pub const BUTTON_LEFT: u8 = 0x01;
+pub const BUTTON_RIGHT: u8 = 0x02;
+pub const BUTTON_MIDDLE: u8 = 0x04;
+
+const FLAG_ALWAYS_SET: u8 = 0x08;
+const FLAG_X_SIGN: u8 = 0x10;
+const FLAG_Y_SIGN: u8 = 0x20;
+
+/// Largest movement one packet can carry (9-bit two's complement)
+const MAX_DELTA: i32 = 255;
+/// Largest wheel movement one packet can carry (4-bit two's complement)
+const MAX_WHEEL: i32 = 7;
+
+/// Button state plus motion/wheel not yet sent. Fractional movement (from
+/// high-resolution mice, touchpads or window scaling) is kept until it adds
+/// up to a whole step.
+#[derive(Debug, Default)]
+pub struct HostMouse {
+    buttons: u8,
+    x: f32,
+    y: f32,
+    wheel: f32,
+}
+
+impl HostMouse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Press or release buttons. Returns the packet reporting the new state.
+    pub fn button(&mut self, button: u8, down: bool) -> [u8; 4] {
+        if down {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+        packet(self.buttons, 0, 0, 0)
+    }
+
+    /// Relative motion in SDL coordinates (y down)
+    pub fn motion(&mut self, xrel: f32, yrel: f32) -> Vec<[u8; 4]> {
+        self.x += xrel;
+        self.y -= yrel;
+        self.drain()
+    }
+
+    /// Wheel movement in SDL coordinates (positive away from the user)
+    pub fn wheel(&mut self, y: f32) -> Vec<[u8; 4]> {
+        self.wheel -= y;
+        self.drain()
+    }
+
+    /// Packets for the whole steps accumulated so far, split so no
+    /// movement is lost to a packet's range
+    fn drain(&mut self) -> Vec<[u8; 4]> {
+        let mut packets = vec![];
+        loop {
+            let dx = (self.x.trunc() as i32).clamp(-MAX_DELTA, MAX_DELTA);
+            let dy = (self.y.trunc() as i32).clamp(-MAX_DELTA, MAX_DELTA);
+            let dz = (self.wheel.trunc() as i32).clamp(-MAX_WHEEL - 1, MAX_WHEEL);
+            if dx == 0 && dy == 0 && dz == 0 {
+                return packets;
+            }
+            self.x -= dx as f32;
+            self.y -= dy as f32;
+            self.wheel -= dz as f32;
+            packets.push(packet(self.buttons, dx, dy, dz));
+        }
+    }
+}
+
+fn packet(buttons: u8, dx: i32, dy: i32, dz: i32) -> [u8; 4] {
+    let mut flags = FLAG_ALWAYS_SET | (buttons & 0x07);
+    if dx < 0 {
+        flags |= FLAG_X_SIGN;
+    }
+    if dy < 0 {
+        flags |= FLAG_Y_SIGN;
+    }
+    [flags, dx as u8, dy as u8, (dz as u8) & 0x0f]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_motion_packets() {
+        let mut m = HostMouse::new();
+        m.button(BUTTON_LEFT, true);
+        // right and down on screen: +x, -y
+        assert_eq!(m.motion(3.0, 2.0), vec![[0x29, 3, 0xfe, 0]]);
+        assert_eq!(m.motion(-1.0, -1.0), vec![[0x19, 0xff, 1, 0]]);
+        assert_eq!(m.button(BUTTON_LEFT, false), [0x08, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_fractional_and_large_motion() {
+        let mut m = HostMouse::new();
+        assert!(m.motion(0.5, 0.0).is_empty());
+        assert_eq!(m.motion(0.5, 0.0), vec![[0x08, 1, 0, 0]]);
+        // beyond one packet's range
+        assert_eq!(m.motion(300.0, 0.0), vec![[0x08, 255, 0, 0], [0x08, 45, 0, 0]]);
+    }
+
+    #[test]
+    fn test_wheel_packets() {
+        let mut m = HostMouse::new();
+        assert_eq!(m.wheel(1.0), vec![[0x08, 0, 0, 0x0f]]);
+        assert_eq!(m.wheel(-2.0), vec![[0x08, 0, 0, 2]]);
+    }
+}
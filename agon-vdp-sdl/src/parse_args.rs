@@ -74,19 +74,55 @@ impl FrameSpec {
     }
 }
 
+/// PNG flavour written by --dump-frames / --dump-keyframes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// RGB8, default compression
+    Rgb,
+    /// RGB8, fast compression: bigger files, much quicker to write
+    Fast,
+    /// Palette PNG when the frame has at most 256 colours (RGB8 otherwise)
+    Indexed,
+}
+
+impl DumpFormat {
+    pub fn parse(s: &str) -> Result<DumpFormat, String> {
+        match s {
+            "rgb" => Ok(DumpFormat::Rgb),
+            "fast" => Ok(DumpFormat::Fast),
+            "indexed" => Ok(DumpFormat::Indexed),
+            _ => Err(format!("Invalid dump format '{}' (expected rgb, fast or indexed)", s)),
+        }
+    }
+}
+
 pub struct AppArgs {
     pub socket_path: Option<String>,
     pub tcp_addr: Option<String>,
+    pub framed: bool,
     pub firmware: String,
     pub vdp_path: Option<PathBuf>,
     pub verbosity: Verbosity,
+    pub vdp_debug: bool,
     pub fullscreen: bool,
+    pub swap_caps_and_ctrl: bool,
+    /// Synthetic key repeat: (delay, interval)
+    pub key_repeat: Option<(std::time::Duration, std::time::Duration)>,
+    pub grab_mouse: bool,
+    pub volume: u32,
+    pub no_audio: bool,
+    pub handshake_timeout: u64,
     pub dump_frames: Option<String>,
     pub dump_keyframes: Option<String>,
+    pub dump_raw: Option<String>,
     pub frame_spec: FrameSpec,
+    pub dump_format: DumpFormat,
+    pub frame_hashes: Option<String>,
     pub replay: Option<PathBuf>,
     pub replay_raw: bool,
     pub replay_fps: Option<f64>,
+    pub replay_start_frame: u64,
+    pub stop_at_frame: Option<u64>,
     pub replay_log: Option<String>,
 }
 
@@ -94,16 +130,29 @@ pub fn parse_args() -> Result<AppArgs, String> {
     let mut args = AppArgs {
         socket_path: None,
         tcp_addr: None,
+        framed: false,
         firmware: "console8".to_string(),
         vdp_path: None,
         verbosity: Verbosity::Quiet,
+        vdp_debug: false,
         fullscreen: false,
+        swap_caps_and_ctrl: false,
+        key_repeat: None,
+        grab_mouse: false,
+        volume: 100,
+        no_audio: false,
+        handshake_timeout: 5,
         dump_frames: None,
         dump_keyframes: None,
+        dump_raw: None,
         frame_spec: FrameSpec::all(),
+        dump_format: DumpFormat::Rgb,
+        frame_hashes: None,
         replay: None,
         replay_raw: false,
         replay_fps: None,
+        replay_start_frame: 0,
+        stop_at_frame: None,
         replay_log: None,
     };
 
@@ -147,9 +196,50 @@ pub fn parse_args() -> Result<AppArgs, String> {
             "-vv" => {
                 args.verbosity = Verbosity::Trace;
             }
+            "--vdp-debug" => {
+                args.vdp_debug = true;
+            }
             "--fullscreen" => {
                 args.fullscreen = true;
             }
+            "--swap-caps-ctrl" => {
+                args.swap_caps_and_ctrl = true;
+            }
+            "--key-repeat" => {
+                if argv.is_empty() {
+                    return Err("--key-repeat requires <delay,rate> in ms (e.g. 500,33)".to_string());
+                }
+                args.key_repeat = Some(parse_key_repeat(&argv.remove(0))?);
+            }
+            "--grab-mouse" => {
+                args.grab_mouse = true;
+            }
+            "--volume" => {
+                if argv.is_empty() {
+                    return Err("--volume requires a value (0-100)".to_string());
+                }
+                let val: u32 = argv.remove(0).parse()
+                    .map_err(|_| "--volume requires a number (0-100)".to_string())?;
+                if val > 100 {
+                    return Err("--volume must be between 0 and 100".to_string());
+                }
+                args.volume = val;
+            }
+            "--no-audio" => {
+                args.no_audio = true;
+            }
+            "--framed" => {
+                args.framed = true;
+            }
+            "--handshake-timeout" => {
+                if argv.is_empty() {
+                    return Err("--handshake-timeout requires a number of seconds".to_string());
+                }
+                args.handshake_timeout = match argv.remove(0).parse() {
+                    Ok(n) if n >= 1 => n,
+                    _ => return Err("--handshake-timeout requires a number of seconds (1 or more)".to_string()),
+                };
+            }
             "--dump-frames" => {
                 if argv.is_empty() {
                     return Err("--dump-frames requires a directory path".to_string());
@@ -162,6 +252,12 @@ pub fn parse_args() -> Result<AppArgs, String> {
                 }
                 args.dump_keyframes = Some(argv.remove(0));
             }
+            "--dump-raw" => {
+                if argv.is_empty() {
+                    return Err("--dump-raw requires a directory path".to_string());
+                }
+                args.dump_raw = Some(argv.remove(0));
+            }
             s if s.starts_with("--frame-spec=") => {
                 let spec = s.trim_start_matches("--frame-spec=");
                 args.frame_spec = FrameSpec::parse(spec)?;
@@ -172,6 +268,18 @@ pub fn parse_args() -> Result<AppArgs, String> {
                 }
                 args.frame_spec = FrameSpec::parse(&argv.remove(0))?;
             }
+            "--dump-format" => {
+                if argv.is_empty() {
+                    return Err("--dump-format requires a value (rgb, fast or indexed)".to_string());
+                }
+                args.dump_format = DumpFormat::parse(&argv.remove(0))?;
+            }
+            "--frame-hashes" => {
+                if argv.is_empty() {
+                    return Err("--frame-hashes requires a file path".to_string());
+                }
+                args.frame_hashes = Some(argv.remove(0));
+            }
             "--replay" => {
                 if argv.is_empty() {
                     return Err("--replay requires a file path".to_string());
@@ -189,6 +297,21 @@ pub fn parse_args() -> Result<AppArgs, String> {
                     .map_err(|_| "--replay-fps requires a valid number".to_string())?;
                 args.replay_fps = Some(val);
             }
+            "--replay-start-frame" => {
+                if argv.is_empty() {
+                    return Err("--replay-start-frame requires a frame number".to_string());
+                }
+                args.replay_start_frame = argv.remove(0).parse()
+                    .map_err(|_| "--replay-start-frame requires a valid frame number".to_string())?;
+            }
+            "--stop-at-frame" => {
+                if argv.is_empty() {
+                    return Err("--stop-at-frame requires a frame number".to_string());
+                }
+                let n: u64 = argv.remove(0).parse()
+                    .map_err(|_| "--stop-at-frame requires a valid frame number".to_string())?;
+                args.stop_at_frame = Some(n);
+            }
             "--replay-log" => {
                 if argv.is_empty() {
                     return Err("--replay-log requires a file path (or '-' for stderr)".to_string());
@@ -201,9 +324,26 @@ pub fn parse_args() -> Result<AppArgs, String> {
         }
     }
 
+    // A raw stream has no VSYNC chunks, so no frames to skip to
+    if args.replay_raw && args.replay_start_frame > 1 {
+        return Err("--replay-start-frame can't be used with --replay-raw".to_string());
+    }
+
     Ok(args)
 }
 
+/// Parse `delay,rate` (both in ms) for --key-repeat
+fn parse_key_repeat(s: &str) -> Result<(std::time::Duration, std::time::Duration), String> {
+    let err = || format!("Invalid --key-repeat '{}' (expected <delay,rate> in ms, e.g. 500,33)", s);
+    let (delay, rate) = s.split_once(',').ok_or_else(err)?;
+    let delay: u64 = delay.trim().parse().map_err(|_| err())?;
+    let rate: u64 = rate.trim().parse().map_err(|_| err())?;
+    if rate == 0 {
+        return Err(err());
+    }
+    Ok((std::time::Duration::from_millis(delay), std::time::Duration::from_millis(rate)))
+}
+
 fn print_help() {
     eprintln!(
         r#"agon-vdp-sdl - Graphical VDP client for Agon emulator
@@ -214,19 +354,41 @@ USAGE:
     agon-vdp-sdl [OPTIONS]
 
 OPTIONS:
-    -s, --socket <path>     Unix socket path (default: /tmp/agon-vdp.sock)
+    -s, --socket <path>     Unix socket path, or @name for a Linux abstract
+                            socket (default: $AGON_VDP_SOCKET, or /tmp/agon-vdp.sock)
     --tcp <host:port>       Connect via TCP instead of Unix socket
+    --framed                Precede each message with a resync marker, for links
+                            that may lose bytes. agon-ez80 must use --framed too
     -f, --firmware <name>   VDP firmware: console8, quark, electron (default: console8)
     --vdp <path>            Explicit path to VDP .so library
     -v                      Verbose output
     -vv                     Trace output (more verbose)
+    --vdp-debug             Enable the VDP firmware's debug logging (toggle with RCtrl+L)
     --fullscreen            Start in fullscreen mode
+    --swap-caps-ctrl        Swap the Caps Lock and Ctrl keys
+    --key-repeat <d,r>      Repeat held keys after <d> ms, every <r> ms (e.g. 500,33)
+    --grab-mouse            Start with the mouse captured (toggle with RCtrl+G)
+    --volume <0-100>        Audio volume in percent (default: 100)
+    --no-audio              Don't open an audio device
+    --handshake-timeout <s> Give up if the eZ80 doesn't answer HELLO within <s> seconds (default: 5, minimum 1)
     --dump-frames <dir>     Save every frame as PNG on each vsync
     --dump-keyframes <dir>  Save frame only when UART data arrived since last vsync
+    --dump-raw <dir>        Save frames as raw RGB24 (frame_NNNNNN.rgb, with
+                            its '<width> <height>' in frame_NNNNNN.txt)
     --frame-spec <spec>     Only dump specific frames (e.g. 1,2,3,500,600..800)
+    --dump-format <fmt>     PNG format for dumped frames: rgb (default), fast
+                            (quicker, larger files), or indexed (palette PNG
+                            for frames with up to 256 colours)
+    --frame-hashes <file>   Write 'frame: crc32' per frame (honours --frame-spec),
+                            for diffing runs without comparing PNGs
     --replay <file>         Replay VDU bytes from file instead of connecting
+                            ('-' streams from stdin)
     --replay-raw            Treat replay file as raw bytes (no chunk framing)
     --replay-fps <N>        Override VSYNC rate for replay (default: 60, 0=max speed)
+    --replay-start-frame <N>  Start displaying at frame N (earlier frames are fed
+                            to the VDP without rendering; not with --replay-raw)
+    --stop-at-frame <N>     Exit once replay frame N has been rendered and dumped;
+                            exit with status 1 if the replay ends before it
     --replay-log <file>     Log replay events to file ('-' for stderr)
     -h, --help              Show this help
 
@@ -243,6 +405,19 @@ EXAMPLES:
     # Replay a VDU stream and dump specific frames
     agon-vdp-sdl --replay stream.vdu --dump-frames ./frames --frame-spec 1,100..200
 
+    # Jump straight to frame 5000 of a long capture
+    agon-vdp-sdl --replay stream.vdu --replay-start-frame 5000
+
+    # Dump just frame 1234 and quit
+    agon-vdp-sdl --replay stream.vdu --replay-fps 0 --dump-frames ./frames \
+        --frame-spec 1234 --stop-at-frame 1234
+
+    # Fingerprint every frame of a replay, to diff against a later run
+    agon-vdp-sdl --replay stream.vdu --replay-fps 0 --frame-hashes hashes.txt
+
+    # Visualize a VDU stream as another program generates it
+    vdu-generator | agon-vdp-sdl --replay -
+
     # Quick parse-check of a VDU stream
     agon-vdp-sdl --replay stream.vdu --replay-fps 0 --replay-log -
 "#
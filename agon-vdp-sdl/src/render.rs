@@ -0,0 +1,196 @@
+//! Render a VDU stream to frames, as fast as the VDP can draw them.
+//!
+//! Streams are either VSYNC-chunked (`[len:u16-LE][data]` per frame, ended
+//! by a zero length, as written by agon-ez80's capture) or raw bytes.
+
+use crate::vdp_interface::VdpInterface;
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// Largest framebuffer the VDP can return (1024x768 RGB)
+const FRAMEBUFFER_SIZE: usize = 1024 * 768 * 3;
+
+/// One rendered frame
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// 1-based frame number (the VSYNC it was rendered at)
+    pub number: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Refresh rate of the video mode, as reported by the VDP
+    pub frame_rate_hz: f32,
+    /// `width * height` RGB24 pixels
+    pub rgb: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// The stream is raw bytes, not VSYNC chunks. It is all fed before the
+    /// first frame
+    pub raw: bool,
+    /// First frame to return; earlier chunks are fed with their VSYNCs but
+    /// not read back
+    pub start_frame: u64,
+    /// Frames to keep rendering after the stream ends, so the VDP can finish
+    /// buffered commands and VSYNC-driven output
+    pub extra_frames: u32,
+}
+
+/// Find the data of each VSYNC chunk in a chunked stream, as
+/// (offset, length) pairs. Stops at the end marker or a truncated chunk.
+pub fn index_vdu_chunks(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+    while pos + 2 <= data.len() {
+        let len = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2;
+        if len == 0 || pos + len > data.len() {
+            break;
+        }
+        chunks.push((pos, len));
+        pos += len;
+    }
+    chunks
+}
+
+/// Send VDU bytes to the VDP, respecting CTS flow control
+pub fn feed_vdu_bytes(vdp: &VdpInterface, bytes: &[u8]) {
+    for &byte in bytes {
+        let mut cts_waits = 0u32;
+        while !unsafe { (*vdp.z80_uart0_is_cts)() } {
+            cts_waits += 1;
+            if cts_waits > 1000 {
+                // VDP thread may need a vblank to make progress
+                unsafe { (*vdp.signal_vblank)() };
+                std::thread::sleep(Duration::from_micros(100));
+                cts_waits = 0;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        unsafe { (*vdp.z80_send_to_vdp)(byte) };
+    }
+}
+
+/// Render `bytes` on a VDP that is already running (see
+/// [`VdpInterface::spawn`]), one frame per VSYNC chunk.
+///
+/// Frames are rendered lazily, as the iterator is advanced. VSYNCs where
+/// the VDP has no video mode yet are skipped. Anything the VDP sends back
+/// to the eZ80 is discarded.
+pub fn render_vdu_stream<'a>(vdp: &'a VdpInterface, bytes: &'a [u8], opts: RenderOptions) -> RenderFrames<'a> {
+    let chunks = if opts.raw {
+        vec![(0, bytes.len())]
+    } else {
+        index_vdu_chunks(bytes)
+    };
+    let mut chunks = chunks.into_iter();
+    let next_chunk = move || chunks.next().map(|(pos, len)| Cow::Borrowed(&bytes[pos..pos + len]));
+    RenderFrames::new(vdp, Box::new(next_chunk), opts)
+}
+
+/// Like [`render_vdu_stream`], for a stream that isn't all in memory (e.g.
+/// read from a pipe): `next_chunk` returns the data for each VSYNC as it is
+/// needed, and None at the end of the stream. `opts.raw` is not used.
+pub fn render_vdu_chunks<'a>(
+    vdp: &'a VdpInterface,
+    mut next_chunk: impl FnMut() -> Option<Vec<u8>> + 'a,
+    opts: RenderOptions,
+) -> RenderFrames<'a> {
+    RenderFrames::new(vdp, Box::new(move || next_chunk().map(Cow::Owned)), opts)
+}
+
+/// Iterator returned by [`render_vdu_stream`] and [`render_vdu_chunks`]
+pub struct RenderFrames<'a> {
+    vdp: &'a VdpInterface,
+    /// Data for the next VSYNC; None once the stream has ended
+    next_chunk: Box<dyn FnMut() -> Option<Cow<'a, [u8]>> + 'a>,
+    stream_ended: bool,
+    extra_frames: u32,
+    start_frame: u64,
+    vsync_count: u64,
+}
+
+impl<'a> RenderFrames<'a> {
+    fn new(
+        vdp: &'a VdpInterface,
+        next_chunk: Box<dyn FnMut() -> Option<Cow<'a, [u8]>> + 'a>,
+        opts: RenderOptions,
+    ) -> Self {
+        RenderFrames {
+            vdp,
+            next_chunk,
+            stream_ended: false,
+            extra_frames: opts.extra_frames,
+            start_frame: opts.start_frame,
+            vsync_count: 0,
+        }
+    }
+
+    /// Feed the next chunk (if any is left) and signal its VSYNC. Returns
+    /// false once the stream and the extra frames are used up
+    fn vsync(&mut self) -> bool {
+        let chunk = if self.stream_ended { None } else { (self.next_chunk)() };
+        if let Some(chunk) = chunk {
+            feed_vdu_bytes(self.vdp, &chunk);
+        } else if self.extra_frames > 0 {
+            self.stream_ended = true;
+            self.extra_frames -= 1;
+        } else {
+            self.stream_ended = true;
+            return false;
+        }
+        unsafe { (*self.vdp.signal_vblank)() };
+        let mut byte: u8 = 0;
+        while unsafe { (*self.vdp.z80_recv_from_vdp)(&mut byte) } {}
+        self.vsync_count += 1;
+        true
+    }
+}
+
+impl Iterator for RenderFrames<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        loop {
+            if !self.vsync() {
+                return None;
+            }
+            if self.vsync_count < self.start_frame {
+                continue;
+            }
+
+            let mut rgb = vec![0u8; FRAMEBUFFER_SIZE];
+            let mut width: u32 = 0;
+            let mut height: u32 = 0;
+            let mut frame_rate_hz: f32 = 0.0;
+            unsafe {
+                (*self.vdp.copyVgaFramebuffer)(&mut width, &mut height, rgb.as_mut_ptr(), &mut frame_rate_hz);
+            }
+            if width == 0 || height == 0 {
+                continue;
+            }
+            rgb.truncate(width as usize * height as usize * 3);
+            return Some(Frame {
+                number: self.vsync_count,
+                width,
+                height,
+                frame_rate_hz,
+                rgb,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index_vdu_chunks;
+
+    #[test]
+    fn test_index_vdu_chunks() {
+        let stream = [2, 0, b'A', b'B', 1, 0, 12, 0, 0, 3, 0, 1];
+        assert_eq!(index_vdu_chunks(&stream), vec![(2, 2), (6, 1)]);
+        // truncated last chunk
+        assert_eq!(index_vdu_chunks(&stream[..5]), vec![(2, 2)]);
+    }
+}
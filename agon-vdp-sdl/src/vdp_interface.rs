@@ -1,14 +1,26 @@
 //! VDP library interface - loads and provides access to VDP .so functions.
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
+/// A symbol that doesn't borrow its library; [`VdpInterface`] keeps the
+/// library loaded instead
+#[cfg(unix)]
+type RawSymbol<T> = libloading::os::unix::Symbol<T>;
+#[cfg(windows)]
+type RawSymbol<T> = libloading::os::windows::Symbol<T>;
+
+/// Entry points of a loaded VDP library. Cheap to clone; the library stays
+/// loaded until the last clone is dropped, so clones can be moved to other
+/// threads (the VDP thread, the audio callback)
+#[derive(Clone)]
 #[allow(non_snake_case)]
 pub struct VdpInterface {
-    pub vdp_setup: libloading::Symbol<'static, unsafe extern "C" fn()>,
-    pub vdp_loop: libloading::Symbol<'static, unsafe extern "C" fn()>,
-    pub signal_vblank: libloading::Symbol<'static, unsafe extern "C" fn()>,
-    pub copyVgaFramebuffer: libloading::Symbol<
-        'static,
+    pub vdp_setup: RawSymbol<unsafe extern "C" fn()>,
+    pub vdp_loop: RawSymbol<unsafe extern "C" fn()>,
+    pub signal_vblank: RawSymbol<unsafe extern "C" fn()>,
+    pub copyVgaFramebuffer: RawSymbol<
         unsafe extern "C" fn(
             outWidth: *mut u32,
             outHeight: *mut u32,
@@ -16,49 +28,99 @@ pub struct VdpInterface {
             frameRateHz: *mut f32,
         ),
     >,
-    pub set_startup_screen_mode: libloading::Symbol<'static, unsafe extern "C" fn(m: u32)>,
-    pub z80_uart0_is_cts: libloading::Symbol<'static, unsafe extern "C" fn() -> bool>,
-    pub z80_send_to_vdp: libloading::Symbol<'static, unsafe extern "C" fn(b: u8)>,
-    pub z80_recv_from_vdp: libloading::Symbol<'static, unsafe extern "C" fn(out: *mut u8) -> bool>,
-    pub sendVKeyEventToFabgl: libloading::Symbol<'static, unsafe extern "C" fn(vkey: u32, isDown: u8)>,
-    pub sendPS2KbEventToFabgl: libloading::Symbol<'static, unsafe extern "C" fn(ps2scancode: u16, isDown: u8)>,
-    pub sendHostMouseEventToFabgl: libloading::Symbol<'static, unsafe extern "C" fn(mouse_packet: *const u8)>,
-    pub setVdpDebugLogging: libloading::Symbol<'static, unsafe extern "C" fn(state: bool)>,
-    pub getAudioSamples: libloading::Symbol<'static, unsafe extern "C" fn(out: *mut u8, length: u32)>,
-    pub dump_vdp_mem_stats: libloading::Symbol<'static, unsafe extern "C" fn()>,
-    pub vdp_shutdown: libloading::Symbol<'static, unsafe extern "C" fn()>,
+    pub set_startup_screen_mode: RawSymbol<unsafe extern "C" fn(m: u32)>,
+    pub z80_uart0_is_cts: RawSymbol<unsafe extern "C" fn() -> bool>,
+    pub z80_send_to_vdp: RawSymbol<unsafe extern "C" fn(b: u8)>,
+    pub z80_recv_from_vdp: RawSymbol<unsafe extern "C" fn(out: *mut u8) -> bool>,
+    pub sendVKeyEventToFabgl: RawSymbol<unsafe extern "C" fn(vkey: u32, isDown: u8)>,
+    pub sendPS2KbEventToFabgl: RawSymbol<unsafe extern "C" fn(ps2scancode: u16, isDown: u8)>,
+    pub sendHostMouseEventToFabgl: RawSymbol<unsafe extern "C" fn(mouse_packet: *const u8)>,
+    pub setVdpDebugLogging: RawSymbol<unsafe extern "C" fn(state: bool)>,
+    pub getAudioSamples: RawSymbol<unsafe extern "C" fn(out: *mut u8, length: u32)>,
+    pub dump_vdp_mem_stats: RawSymbol<unsafe extern "C" fn()>,
+    pub vdp_shutdown: RawSymbol<unsafe extern "C" fn()>,
+    /// File the library was loaded from, canonicalized so two loads of the
+    /// same library compare equal
+    pub path: PathBuf,
+    _lib: Arc<libloading::Library>,
+    /// Where a library loaded from memory was written, removed after it
+    _temp_file: Option<Arc<TempFile>>,
 }
 
-static mut VDP_DLL: *const libloading::Library = std::ptr::null();
+/// A file, and the directory made for it, deleted when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn remove(&self) {
+        let _ = std::fs::remove_file(&self.0);
+        if let Some(dir) = self.0.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
 
 impl VdpInterface {
-    fn new(lib: &'static libloading::Library) -> Self {
-        unsafe {
-            VdpInterface {
-                vdp_setup: lib.get(b"vdp_setup").unwrap(),
-                vdp_loop: lib.get(b"vdp_loop").unwrap(),
-                signal_vblank: lib.get(b"signal_vblank").unwrap(),
-                copyVgaFramebuffer: lib.get(b"copyVgaFramebuffer").unwrap(),
-                z80_uart0_is_cts: lib.get(b"z80_uart0_is_cts").unwrap(),
-                z80_send_to_vdp: lib.get(b"z80_send_to_vdp").unwrap(),
-                z80_recv_from_vdp: lib.get(b"z80_recv_from_vdp").unwrap(),
-                set_startup_screen_mode: lib.get(b"set_startup_screen_mode").unwrap(),
-                sendVKeyEventToFabgl: lib.get(b"sendVKeyEventToFabgl").unwrap(),
-                sendPS2KbEventToFabgl: lib.get(b"sendPS2KbEventToFabgl").unwrap(),
-                sendHostMouseEventToFabgl: lib.get(b"sendHostMouseEventToFabgl").unwrap(),
-                setVdpDebugLogging: lib.get(b"setVdpDebugLogging").unwrap(),
-                getAudioSamples: lib.get(b"getAudioSamples").unwrap(),
-                dump_vdp_mem_stats: lib.get(b"dump_vdp_mem_stats").unwrap(),
-                vdp_shutdown: lib.get(b"vdp_shutdown").unwrap(),
-            }
+    /// Look up every VDP entry point. Fails with the names of any the
+    /// library doesn't export, which means it was built for another version
+    fn new(lib: libloading::Library, path: PathBuf) -> Result<Self, String> {
+        let mut missing: Vec<&str> = vec![];
+        // Fields are named after the symbols they hold
+        macro_rules! symbols {
+            ($($name:ident),* $(,)?) => {{
+                $(
+                    let $name = unsafe { lib.get(stringify!($name).as_bytes()).map(|sym| sym.into_raw()) }
+                        .map_err(|_| missing.push(stringify!($name)));
+                )*
+                if !missing.is_empty() {
+                    return Err(format!("VDP library is missing: {} — incompatible version?", missing.join(", ")));
+                }
+                VdpInterface {
+                    $($name: $name.unwrap(),)*
+                    path,
+                    _lib: Arc::new(lib),
+                    _temp_file: None,
+                }
+            }};
         }
+        Ok(symbols!(
+            vdp_setup,
+            vdp_loop,
+            signal_vblank,
+            copyVgaFramebuffer,
+            z80_uart0_is_cts,
+            z80_send_to_vdp,
+            z80_recv_from_vdp,
+            set_startup_screen_mode,
+            sendVKeyEventToFabgl,
+            sendPS2KbEventToFabgl,
+            sendHostMouseEventToFabgl,
+            setVdpDebugLogging,
+            getAudioSamples,
+            dump_vdp_mem_stats,
+            vdp_shutdown,
+        ))
+    }
+}
+
+impl VdpInterface {
+    /// Start the VDP firmware (setup, then its main loop) on its own thread
+    pub fn spawn(&self) -> std::thread::JoinHandle<()> {
+        let vdp = self.clone();
+        std::thread::spawn(move || unsafe {
+            (*vdp.vdp_setup)();
+            (*vdp.vdp_loop)();
+        })
     }
 }
 
 /// Load VDP library from given paths (tries each until one succeeds)
 pub fn init(firmware_paths: &[std::path::PathBuf], verbose: bool) -> Option<VdpInterface> {
-    assert!(unsafe { VDP_DLL.is_null() });
-
     if verbose {
         eprintln!("VDP firmware search paths: {:?}", firmware_paths);
     }
@@ -69,11 +131,15 @@ pub fn init(firmware_paths: &[std::path::PathBuf], verbose: bool) -> Option<VdpI
         }
         match unsafe { libloading::Library::new(p) } {
             Ok(lib) => {
-                eprintln!("Loaded VDP firmware: {:?}", p);
-                unsafe {
-                    VDP_DLL = Box::leak(Box::new(lib));
+                match VdpInterface::new(lib, p.canonicalize().unwrap_or_else(|_| p.clone())) {
+                    Ok(vdp) => {
+                        eprintln!("Loaded VDP firmware: {:?}", p);
+                        return Some(vdp);
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {}", p.display(), e);
+                    }
                 }
-                return Some(VdpInterface::new(unsafe { VDP_DLL.as_ref() }.unwrap()));
             }
             Err(e) => {
                 if verbose {
@@ -85,6 +151,60 @@ pub fn init(firmware_paths: &[std::path::PathBuf], verbose: bool) -> Option<VdpI
     None
 }
 
+/// Load a VDP library from memory, e.g. one embedded in the executable.
+/// It is written to a temporary file for the dynamic loader, in a directory
+/// only this user can access, both removed once the library is unloaded
+/// (on unix, as soon as it's loaded)
+pub fn init_from_bytes(lib_bytes: &[u8], verbose: bool) -> Option<VdpInterface> {
+    // A fresh name each time, as loading the same path again would just
+    // return the library already loaded from it
+    static TEMP_COUNTER: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "agon-vdp-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_file = match write_private(&dir, lib_bytes) {
+        Ok(f) => Arc::new(f),
+        Err(e) => {
+            eprintln!("Failed to write VDP firmware to {}: {}", dir.display(), e);
+            return None;
+        }
+    };
+
+    let mut vdp = init(&[temp_file.0.clone()], verbose)?;
+    // A loaded library stays mapped after it is unlinked, which also covers
+    // exiting without running destructors
+    #[cfg(unix)]
+    temp_file.remove();
+    vdp._temp_file = Some(temp_file);
+    Some(vdp)
+}
+
+/// Create `dir`, which must not already exist, and write `bytes` to a new
+/// library file in it. On unix both are private to this user, so nobody
+/// else can swap the library out before it is loaded.
+fn write_private(dir: &Path, bytes: &[u8]) -> std::io::Result<TempFile> {
+    use std::io::Write;
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut dir_builder = std::fs::DirBuilder::new();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        dir_builder.mode(0o700);
+        options.mode(0o700);
+    }
+    dir_builder.create(dir)?;
+
+    // Cleaned up on failure from here on
+    let file = TempFile(dir.join(format!("vdp.{}", std::env::consts::DLL_EXTENSION)));
+    options.open(&file.0)?.write_all(bytes)?;
+    Ok(file)
+}
+
 /// Get default firmware paths for a given firmware version
 pub fn default_firmware_paths(firmware: &str) -> Vec<std::path::PathBuf> {
     let prefix = option_env!("PREFIX");
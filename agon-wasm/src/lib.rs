@@ -4,14 +4,22 @@
 
 use wasm_bindgen::prelude::*;
 use std::cell::Cell;
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 use ez80::Reg16;
+use rand::Rng;
+
+mod rtc;
 
 // Memory sizes
 const EXTERNAL_RAM_SIZE: usize = 512 * 1024;
 const ROM_SIZE: usize = 128 * 1024;
 const ONCHIP_RAM_SIZE: usize = 8 * 1024;
 
+// Pending output bytes held for JS before the UART reports itself busy
+const UART_TX_FIFO_CAPACITY: usize = 16 * 1024;
+// Input bytes from JS not yet read by the guest; more is dropped
+const UART_RX_FIFO_CAPACITY: usize = 16 * 1024;
+
 // eZ80 I/O ports for UART0
 const UART0_RBR_THR: u8 = 0xC0; // Receive/Transmit buffer
 const UART0_IER: u8 = 0xC1;     // Interrupt enable
@@ -24,6 +32,34 @@ const LSR_DR: u8 = 0x01;   // Data ready
 const LSR_THRE: u8 = 0x20; // Transmit holding register empty
 const LSR_TEMT: u8 = 0x40; // Transmitter empty
 
+// UART IER bits
+const IER_RX: u8 = 0x01; // Receive data available interrupt
+const IER_TX: u8 = 0x02; // Transmit holding register empty interrupt
+
+// UART IIR values
+const IIR_NONE: u8 = 0x01;     // No interrupt pending
+const IIR_TX_EMPTY: u8 = 0x02; // Transmit holding register empty
+const IIR_RX_DATA: u8 = 0x04;  // Receive data available
+
+// eZ80F92 interrupt vector for UART0
+const UART0_VECTOR: u8 = 0x18;
+
+// Key packet modifier bits (as in MOS sysvar_keymods)
+pub const MOD_CTRL: u8 = 0x01;
+pub const MOD_SHIFT: u8 = 0x02;
+pub const MOD_ALT_LEFT: u8 = 0x04;
+pub const MOD_ALT_RIGHT: u8 = 0x08;
+pub const MOD_CAPS_LOCK: u8 = 0x10;
+pub const MOD_NUM_LOCK: u8 = 0x20;
+pub const MOD_SCROLL_LOCK: u8 = 0x40;
+pub const MOD_GUI: u8 = 0x80;
+
+// VDP -> MOS mouse packet (PACKET_MOUSE | 0x80)
+const PACKET_MOUSE: u8 = 0x89;
+// Pointer position is clamped to the default 640x480 screen
+const MOUSE_MAX_X: i32 = 639;
+const MOUSE_MAX_Y: i32 = 479;
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -45,15 +81,36 @@ struct AgonMachine {
     uart_tx_fifo: VecDeque<u8>,
     uart_ier: u8,
     uart_lcr: u8,
+    // Transmitter-empty interrupt latched, until IIR is read
+    uart_thre_pending: bool,
 
     // Cycle counter for timing
     cycle_counter: Cell<i32>,
 
     // GPIO for vsync
     gpio_b: u8,
+
+    // On-chip real-time clock
+    rtc: rtc::Rtc,
+
+    // Record accesses to unmapped memory
+    strict_memory: bool,
+    // First out-of-bounds access seen: (address, is_write)
+    bad_access: Cell<Option<(u32, bool)>>,
 }
 
 impl AgonMachine {
+    /// Fill RAM with zeroes, or random values as on real hardware
+    fn init_ram(&mut self, zero: bool) {
+        for ram in [&mut self.mem_external, &mut self.mem_internal] {
+            if zero {
+                ram.fill(0);
+            } else {
+                rand::thread_rng().fill(&mut ram[..]);
+            }
+        }
+    }
+
     fn new() -> Self {
         AgonMachine {
             mem_external: vec![0; EXTERNAL_RAM_SIZE],
@@ -63,8 +120,32 @@ impl AgonMachine {
             uart_tx_fifo: VecDeque::new(),
             uart_ier: 0,
             uart_lcr: 0,
+            uart_thre_pending: true,
             cycle_counter: Cell::new(0),
             gpio_b: 0,
+            rtc: rtc::Rtc::new(),
+            strict_memory: false,
+            bad_access: Cell::new(None),
+        }
+    }
+
+    fn uart_rx_irq_due(&self) -> bool {
+        self.uart_ier & IER_RX != 0 && !self.uart_rx_fifo.is_empty()
+    }
+
+    fn uart_tx_irq_due(&self) -> bool {
+        self.uart_ier & IER_TX != 0 && self.uart_thre_pending && self.uart_tx_fifo.len() < UART_TX_FIFO_CAPACITY
+    }
+
+    /// UART0 wants the CPU's attention: received data is waiting, or the
+    /// transmitter has room and MOS asked to be told
+    fn uart_irq_due(&self) -> bool {
+        self.uart_rx_irq_due() || self.uart_tx_irq_due()
+    }
+
+    fn record_bad_access(&self, addr: usize, is_write: bool) {
+        if self.strict_memory && self.bad_access.get().is_none() {
+            self.bad_access.set(Some((addr as u32, is_write)));
         }
     }
 }
@@ -84,6 +165,7 @@ impl ez80::Machine for AgonMachine {
             // Internal RAM: 0x0BC000 - 0x0BDFFF (mirrored at various addresses)
             self.mem_internal[addr - 0x0BC000]
         } else {
+            self.record_bad_access(addr, false);
             0xFF
         }
     }
@@ -97,6 +179,8 @@ impl ez80::Machine for AgonMachine {
         } else if addr >= 0x0BC000 && addr < 0x0BC000 + ONCHIP_RAM_SIZE {
             // Internal RAM
             self.mem_internal[addr - 0x0BC000] = value;
+        } else if addr >= ROM_SIZE {
+            self.record_bad_access(addr, true);
         }
         // ROM writes are ignored
     }
@@ -110,11 +194,27 @@ impl ez80::Machine for AgonMachine {
                 self.uart_rx_fifo.pop_front().unwrap_or(0)
             }
             UART0_IER => self.uart_ier,
-            UART0_IIR_FCR => 0x01, // No interrupt pending
+            UART0_IIR_FCR => {
+                if self.uart_rx_irq_due() {
+                    IIR_RX_DATA
+                } else if self.uart_tx_irq_due() {
+                    // Reading IIR acknowledges the transmit interrupt, until
+                    // the next write to THR or the output is drained
+                    self.uart_thre_pending = false;
+                    IIR_TX_EMPTY
+                } else {
+                    IIR_NONE
+                }
+            }
             UART0_LCR => self.uart_lcr,
             UART0_LSR => {
-                // Line status: check if data ready and transmit empty
-                let mut status = LSR_THRE | LSR_TEMT; // TX always ready
+                // Line status: check if data ready and transmit empty.
+                // TX is ready unless JS has stopped draining the output
+                let mut status = if self.uart_tx_fifo.len() < UART_TX_FIFO_CAPACITY {
+                    LSR_THRE | LSR_TEMT
+                } else {
+                    0
+                };
                 if !self.uart_rx_fifo.is_empty() {
                     status |= LSR_DR; // Data ready
                 }
@@ -122,6 +222,7 @@ impl ez80::Machine for AgonMachine {
             }
             // GPIO Port B
             0x9A => self.gpio_b,
+            0xE0..=0xED => self.rtc.read(port_lo),
             _ => 0xFF,
         }
     }
@@ -131,13 +232,24 @@ impl ez80::Machine for AgonMachine {
 
         match port_lo {
             UART0_RBR_THR => {
-                // Write to UART transmit buffer
-                self.uart_tx_fifo.push_back(value);
+                // Write to UART transmit buffer (overrun: dropped when full)
+                if self.uart_tx_fifo.len() < UART_TX_FIFO_CAPACITY {
+                    self.uart_tx_fifo.push_back(value);
+                }
+                self.uart_thre_pending = true;
+            }
+            UART0_IER => {
+                // As on a 16550, enabling the transmit interrupt raises it
+                // again if the transmitter has room
+                if value & IER_TX != 0 && self.uart_ier & IER_TX == 0 {
+                    self.uart_thre_pending = true;
+                }
+                self.uart_ier = value;
             }
-            UART0_IER => self.uart_ier = value,
             UART0_LCR => self.uart_lcr = value,
             // GPIO Port B
             0x9A => self.gpio_b = value,
+            0xE0..=0xED => self.rtc.write(port_lo, value),
             _ => {}
         }
     }
@@ -153,7 +265,22 @@ pub struct AgonEmulator {
     cpu: ez80::Cpu,
     machine: AgonMachine,
     total_cycles: u64,
+    // total_cycles at the last mark_cycles
+    cycle_mark: u64,
+    // Instructions retired, for cycles-per-instruction profiling
+    instructions: u64,
     vsync_cycles: u64,
+    // Absolute pointer position reported in mouse packets
+    mouse_x: u16,
+    mouse_y: u16,
+    // RAM contents after a hard reset: zeroes (default) or random
+    zero_ram: bool,
+    // Return from run_cycles on the first out-of-bounds access
+    stop_on_bad_access: bool,
+    // Interrupt vectors raised from JS and not yet taken
+    pending_irqs: BTreeSet<u8>,
+    // Inputs dropped because the rx FIFO was full
+    rx_overruns: u32,
 }
 
 #[wasm_bindgen]
@@ -174,7 +301,15 @@ impl AgonEmulator {
             cpu,
             machine: AgonMachine::new(),
             total_cycles: 0,
+            cycle_mark: 0,
+            instructions: 0,
             vsync_cycles: 0,
+            mouse_x: 0,
+            mouse_y: 0,
+            zero_ram: true,
+            stop_on_bad_access: false,
+            pending_irqs: BTreeSet::new(),
+            rx_overruns: 0,
         }
     }
 
@@ -196,6 +331,11 @@ impl AgonEmulator {
         while self.machine.cycle_counter.get() < max_cycles as i32 {
             // Execute one instruction
             self.cpu.fast_execute_instruction(&mut self.machine);
+            self.instructions += 1;
+            self.do_interrupts();
+            if self.stop_on_bad_access && self.machine.bad_access.get().is_some() {
+                break;
+            }
 
             // Check for vsync (every ~307,200 cycles at 18.432 MHz = 60 Hz)
             let cycles_now = self.total_cycles + self.machine.cycle_counter.get() as u64;
@@ -212,52 +352,257 @@ impl AgonEmulator {
         (self.total_cycles - start_cycles) as u32
     }
 
+    /// Record the first access to unmapped memory (reads normally return
+    /// 0xFF and writes are dropped silently). If `stop` is set, `run_cycles`
+    /// returns straight after the offending instruction.
+    #[wasm_bindgen]
+    pub fn set_strict_memory(&mut self, enabled: bool, stop: bool) {
+        self.machine.strict_memory = enabled;
+        self.stop_on_bad_access = enabled && stop;
+    }
+
+    /// Address of the first out-of-bounds access, if any
+    #[wasm_bindgen]
+    pub fn last_bad_access_addr(&self) -> Option<u32> {
+        self.machine.bad_access.get().map(|(addr, _)| addr)
+    }
+
+    /// True if the first out-of-bounds access was a write
+    #[wasm_bindgen]
+    pub fn last_bad_access_was_write(&self) -> bool {
+        matches!(self.machine.bad_access.get(), Some((_, true)))
+    }
+
+    /// Forget the recorded out-of-bounds access, so the next one is caught
+    #[wasm_bindgen]
+    pub fn clear_bad_access(&mut self) {
+        self.machine.bad_access.set(None);
+    }
+
+    /// Set the time seen by the eZ80 RTC, in seconds since the Unix epoch
+    /// (e.g. `Date.now() / 1000`; add the timezone offset for local time).
+    /// Call this regularly, as the emulator has no clock of its own.
+    #[wasm_bindgen]
+    pub fn set_rtc(&mut self, unix_secs: f64) {
+        self.machine.rtc.set_host_time(unix_secs);
+    }
+
+    /// Raise a maskable interrupt. It is taken before the next instruction
+    /// once interrupts are enabled, through the eZ80 vectored interrupt
+    /// table (IM 2: handler address read from `I:vector`). Raising a vector
+    /// that is already pending has no further effect.
+    ///
+    /// eZ80F92 vectors: 0x0A-0x14 PRT0-5, 0x16 RTC, 0x18 UART0, 0x1A UART1,
+    /// 0x1C I2C, 0x1E SPI, 0x30-0x3E port B pins 0-7, 0x40-0x4E port C,
+    /// 0x50-0x5E port D (Agon vsync is port B pin 1, 0x32).
+    #[wasm_bindgen]
+    pub fn raise_irq(&mut self, vector: u8) {
+        self.pending_irqs.insert(vector);
+    }
+
+    /// True if the CPU currently accepts maskable interrupts (IFF1 set)
+    #[wasm_bindgen]
+    pub fn interrupts_enabled(&self) -> bool {
+        self.cpu.state.reg.get_iff1()
+    }
+
     /// Send a byte to the emulator (from VDP)
     #[wasm_bindgen]
     pub fn send_byte(&mut self, byte: u8) {
-        self.machine.uart_rx_fifo.push_back(byte);
+        self.queue_rx(&[byte]);
     }
 
-    /// Send keyboard input (VDP key packet format)
+    /// Send several bytes to the emulator (from VDP). They are queued
+    /// together or, if they don't all fit, dropped together
+    #[wasm_bindgen]
+    pub fn send_bytes(&mut self, bytes: &[u8]) {
+        self.queue_rx(bytes);
+    }
+
+    /// Number of input bytes the guest has yet to read
+    #[wasm_bindgen]
+    pub fn rx_fifo_len(&self) -> usize {
+        self.machine.uart_rx_fifo.len()
+    }
+
+    /// Number of inputs (bytes, byte runs, key or mouse packets) dropped
+    /// because the rx FIFO was full since the last reset. If this goes up,
+    /// send less until `rx_fifo_len` falls.
+    #[wasm_bindgen]
+    pub fn rx_overrun_count(&self) -> u32 {
+        self.rx_overruns
+    }
+
+    /// Send keyboard input (VDP key packet format), without modifiers
     #[wasm_bindgen]
     pub fn send_key(&mut self, ascii: u8, down: bool) {
+        self.send_key_full(ascii, 0, 0, down);
+    }
+
+    /// Send keyboard input with modifiers and FabGL virtual keycode.
+    ///
+    /// Modifier bits: 0 Ctrl, 1 Shift, 2 Left Alt, 3 Right Alt (AltGr),
+    /// 4 Caps Lock, 5 Num Lock, 6 Scroll Lock, 7 GUI. `vkey` is the FabGL
+    /// VirtualKey code, needed for keys without an ASCII value (arrows,
+    /// function keys).
+    #[wasm_bindgen]
+    pub fn send_key_full(&mut self, ascii: u8, modifiers: u8, vkey: u8, down: bool) {
         // VDP key packet: 0x81, len, ascii, modifiers, vkey, down
-        self.machine.uart_rx_fifo.push_back(0x81);
-        self.machine.uart_rx_fifo.push_back(4);
-        self.machine.uart_rx_fifo.push_back(ascii);
-        self.machine.uart_rx_fifo.push_back(0); // modifiers
-        self.machine.uart_rx_fifo.push_back(0); // vkey
-        self.machine.uart_rx_fifo.push_back(if down { 1 } else { 0 });
+        self.queue_rx(&[0x81, 4, ascii, modifiers, vkey, if down { 1 } else { 0 }]);
+    }
+
+    /// Send a mouse event, as the VDP would after a PS/2 mouse report.
+    ///
+    /// `buttons`: bit 0 left, bit 1 right, bit 2 middle. `dx`/`dy` are
+    /// screen-space deltas (positive dy = down); the absolute position is
+    /// tracked here and clamped to the screen.
+    #[wasm_bindgen]
+    pub fn send_mouse(&mut self, buttons: u8, dx: i16, dy: i16, wheel: i8) {
+        self.mouse_x = (self.mouse_x as i32 + dx as i32).clamp(0, MOUSE_MAX_X) as u16;
+        self.mouse_y = (self.mouse_y as i32 + dy as i32).clamp(0, MOUSE_MAX_Y) as u16;
+
+        // Mouse packet: 0x89, len, x:u16, y:u16, buttons, wheel, dx:i16, dy:i16
+        let mut packet = vec![PACKET_MOUSE, 10];
+        packet.extend(self.mouse_x.to_le_bytes());
+        packet.extend(self.mouse_y.to_le_bytes());
+        packet.push(buttons & 0x07);
+        packet.push(wheel as u8);
+        packet.extend(dx.to_le_bytes());
+        packet.extend(dy.to_le_bytes());
+        self.queue_rx(&packet);
     }
 
     /// Get pending output bytes (to VDP)
     #[wasm_bindgen]
     pub fn get_output(&mut self) -> Vec<u8> {
+        self.machine.uart_thre_pending = true;
         self.machine.uart_tx_fifo.drain(..).collect()
     }
 
+    /// Get pending output as text, for console-style front-ends.
+    /// Each byte maps to the Latin-1 character of the same value, so VDU
+    /// control codes are passed through rather than dropped.
+    #[wasm_bindgen]
+    pub fn get_output_text(&mut self) -> String {
+        self.machine.uart_thre_pending = true;
+        self.machine.uart_tx_fifo.drain(..).map(char::from).collect()
+    }
+
     /// Check if there's pending output
     #[wasm_bindgen]
     pub fn has_output(&self) -> bool {
         !self.machine.uart_tx_fifo.is_empty()
     }
 
+    /// Number of pending output bytes. Once this reaches the FIFO capacity
+    /// the UART reports busy and MOS blocks until output is drained.
+    #[wasm_bindgen]
+    pub fn tx_fifo_len(&self) -> usize {
+        self.machine.uart_tx_fifo.len()
+    }
+
     /// Get total cycles executed
     #[wasm_bindgen]
     pub fn get_cycles(&self) -> u64 {
         self.total_cycles
     }
 
-    /// Reset the emulator
+    /// Start timing a guest routine: `cycles_since_mark` counts from here.
+    /// `get_cycles` is unaffected
+    #[wasm_bindgen]
+    pub fn mark_cycles(&mut self) {
+        self.cycle_mark = self.total_cycles;
+    }
+
+    /// Cycles executed since the last `mark_cycles` (or reset)
+    #[wasm_bindgen]
+    pub fn cycles_since_mark(&self) -> u64 {
+        self.total_cycles - self.cycle_mark
+    }
+
+    /// Get total instructions executed
+    #[wasm_bindgen]
+    pub fn get_instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Choose how a hard reset initializes RAM: zeroes (the default) or
+    /// random values, like a real power-on
+    #[wasm_bindgen]
+    pub fn set_zero_ram(&mut self, zero: bool) {
+        self.zero_ram = zero;
+    }
+
+    /// Soft reset: CPU registers and UART FIFOs only
     #[wasm_bindgen]
     pub fn reset(&mut self) {
+        self.reset_mode(false);
+    }
+
+    /// Reset the emulator. A soft reset only resets the CPU and FIFOs; a
+    /// hard reset is a power cycle that also re-initializes RAM and all
+    /// peripheral state. The loaded MOS is kept either way.
+    #[wasm_bindgen]
+    pub fn reset_mode(&mut self, hard: bool) {
+        if hard {
+            self.cpu = ez80::Cpu::new();
+            self.cpu.state.reg.adl = true;
+            self.machine.init_ram(self.zero_ram);
+            self.machine.uart_ier = 0;
+            self.machine.uart_lcr = 0;
+            self.machine.gpio_b = 0;
+            self.mouse_x = 0;
+            self.mouse_y = 0;
+        }
         self.cpu.state.set_pc(0x000000);
         self.cpu.state.reg.set24(Reg16::SP, 0x0BFFFF); // Stack in RAM
         self.machine.uart_rx_fifo.clear();
         self.machine.uart_tx_fifo.clear();
+        self.machine.uart_thre_pending = true;
+        self.rx_overruns = 0;
+        self.machine.bad_access.set(None);
+        self.pending_irqs.clear();
         self.total_cycles = 0;
+        self.cycle_mark = 0;
+        self.instructions = 0;
         self.vsync_cycles = 0;
-        console_log!("Emulator reset");
+        console_log!("Emulator {} reset", if hard { "hard" } else { "soft" });
+    }
+}
+
+impl AgonEmulator {
+    /// Take the highest priority pending interrupt, if the CPU accepts them
+    /// (IFF1 set). The lowest vector has the highest priority, as on the eZ80
+    fn do_interrupts(&mut self) {
+        if !self.cpu.state.reg.get_iff1() {
+            return;
+        }
+        let uart = self.machine.uart_irq_due().then_some(UART0_VECTOR);
+        let raised = self.pending_irqs.first().copied();
+        let Some(vector) = raised.into_iter().chain(uart).min() else {
+            return;
+        };
+        if raised == Some(vector) {
+            self.pending_irqs.pop_first();
+        }
+        ez80::Environment::new(&mut self.cpu.state, &mut self.machine).interrupt(vector as u32);
+    }
+
+    /// Queue input for the guest, all or nothing, so a packet is never cut
+    /// short. Counts an overrun if it doesn't fit
+    fn queue_rx(&mut self, bytes: &[u8]) {
+        let fifo = &mut self.machine.uart_rx_fifo;
+        if fifo.len() + bytes.len() > UART_RX_FIFO_CAPACITY {
+            self.rx_overruns = self.rx_overruns.saturating_add(1);
+            return;
+        }
+        fifo.extend(bytes);
+    }
+
+    /// First out-of-bounds access as (address, is_write), if strict memory
+    /// mode is on and one has happened
+    pub fn last_bad_access(&self) -> Option<(u32, bool)> {
+        self.machine.bad_access.get()
     }
 }
 
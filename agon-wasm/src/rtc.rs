@@ -0,0 +1,208 @@
+//! eZ80 on-chip real-time clock (ports 0xE0-0xED).
+//!
+//! WASM has no clock of its own, so the host time is pushed in from JS with
+//! `set_host_time` (call it regularly, e.g. once per frame). Guest writes to
+//! the time registers don't change the host time; they are kept as an
+//! offset from it.
+
+pub const RTC_SEC: u8 = 0xE0;
+pub const RTC_MIN: u8 = 0xE1;
+pub const RTC_HRS: u8 = 0xE2;
+pub const RTC_DOW: u8 = 0xE3;
+pub const RTC_DOM: u8 = 0xE4;
+pub const RTC_MON: u8 = 0xE5;
+pub const RTC_YR: u8 = 0xE6;
+pub const RTC_CEN: u8 = 0xE7;
+pub const RTC_ACTRL: u8 = 0xEC;
+pub const RTC_CTRL: u8 = 0xED;
+
+// RTC_CTRL bits
+const CTRL_UNLOCK: u8 = 0x01; // time registers writable
+const CTRL_BCD_EN: u8 = 0x10; // registers in BCD rather than binary
+
+/// Broken-down UTC time as held in the RTC registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: i64,
+    month: u32, // 1-12
+    day: u32,   // 1-31
+    hour: u32,
+    min: u32,
+    sec: u32,
+    dow: u32, // 1 = Sunday .. 7 = Saturday
+}
+
+pub struct Rtc {
+    host_secs: i64,
+    offset_secs: i64,
+    ctrl: u8,
+    // alarm registers 0xE8-0xEC, stored but not acted on
+    alarm: [u8; 5],
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc {
+            host_secs: 0,
+            offset_secs: 0,
+            ctrl: CTRL_BCD_EN,
+            alarm: [0; 5],
+        }
+    }
+
+    /// Set the current host time, in seconds since the Unix epoch
+    pub fn set_host_time(&mut self, unix_secs: f64) {
+        self.host_secs = unix_secs.floor() as i64;
+    }
+
+    pub fn read(&self, port: u8) -> u8 {
+        let t = DateTime::from_unix(self.host_secs + self.offset_secs);
+        let value = match port {
+            RTC_SEC => t.sec,
+            RTC_MIN => t.min,
+            RTC_HRS => t.hour,
+            RTC_DOW => t.dow,
+            RTC_DOM => t.day,
+            RTC_MON => t.month,
+            RTC_YR => t.year.rem_euclid(100) as u32,
+            RTC_CEN => t.year.div_euclid(100) as u32,
+            0xE8..=RTC_ACTRL => return self.alarm[(port - 0xE8) as usize],
+            RTC_CTRL => return self.ctrl,
+            _ => return 0xFF,
+        };
+        self.encode(value as u8)
+    }
+
+    pub fn write(&mut self, port: u8, value: u8) {
+        match port {
+            RTC_SEC..=RTC_CEN => {
+                if self.ctrl & CTRL_UNLOCK == 0 {
+                    return;
+                }
+                let value = self.decode(value) as u32;
+                let mut t = DateTime::from_unix(self.host_secs + self.offset_secs);
+                match port {
+                    RTC_SEC => t.sec = value,
+                    RTC_MIN => t.min = value,
+                    RTC_HRS => t.hour = value,
+                    // derived from the date, so writes are ignored
+                    RTC_DOW => return,
+                    RTC_DOM => t.day = value,
+                    RTC_MON => t.month = value,
+                    RTC_YR => t.year = t.year.div_euclid(100) * 100 + value as i64,
+                    _ => t.year = value as i64 * 100 + t.year.rem_euclid(100),
+                }
+                self.offset_secs = t.to_unix() - self.host_secs;
+            }
+            0xE8..=RTC_ACTRL => self.alarm[(port - 0xE8) as usize] = value,
+            RTC_CTRL => self.ctrl = value,
+            _ => {}
+        }
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.ctrl & CTRL_BCD_EN != 0 {
+            ((value / 10) << 4) | (value % 10)
+        } else {
+            value
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.ctrl & CTRL_BCD_EN != 0 {
+            (value >> 4) * 10 + (value & 0x0F)
+        } else {
+            value
+        }
+    }
+}
+
+impl DateTime {
+    fn from_unix(secs: i64) -> DateTime {
+        let days = secs.div_euclid(86400);
+        let rem = secs.rem_euclid(86400) as u32;
+
+        // civil-from-days (Howard Hinnant's algorithm)
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            min: rem / 60 % 60,
+            sec: rem % 60,
+            // 1970-01-01 was a Thursday
+            dow: ((days + 4).rem_euclid(7) + 1) as u32,
+        }
+    }
+
+    fn to_unix(self) -> i64 {
+        // days-from-civil (Howard Hinnant's algorithm)
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * 86400 + self.hour as i64 * 3600 + self.min as i64 * 60 + self.sec as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_bcd_registers() {
+        let mut rtc = Rtc::new();
+        // 2024-02-29 13:45:56 UTC, a Thursday
+        rtc.set_host_time(1709214356.5);
+        assert_eq!(rtc.read(RTC_SEC), 0x56);
+        assert_eq!(rtc.read(RTC_MIN), 0x45);
+        assert_eq!(rtc.read(RTC_HRS), 0x13);
+        assert_eq!(rtc.read(RTC_DOW), 0x05);
+        assert_eq!(rtc.read(RTC_DOM), 0x29);
+        assert_eq!(rtc.read(RTC_MON), 0x02);
+        assert_eq!(rtc.read(RTC_YR), 0x24);
+        assert_eq!(rtc.read(RTC_CEN), 0x20);
+    }
+
+    #[test]
+    fn test_write_sets_offset() {
+        let mut rtc = Rtc::new();
+        rtc.set_host_time(1709214356.0);
+
+        // locked: ignored
+        rtc.write(RTC_HRS, 0x08);
+        assert_eq!(rtc.read(RTC_HRS), 0x13);
+
+        rtc.write(RTC_CTRL, CTRL_BCD_EN | CTRL_UNLOCK);
+        rtc.write(RTC_HRS, 0x08);
+        assert_eq!(rtc.read(RTC_HRS), 0x08);
+
+        // the offset persists as host time advances
+        rtc.set_host_time(1709214356.0 + 60.0);
+        assert_eq!(rtc.read(RTC_HRS), 0x08);
+        assert_eq!(rtc.read(RTC_MIN), 0x46);
+    }
+
+    #[test]
+    fn test_binary_mode() {
+        let mut rtc = Rtc::new();
+        rtc.set_host_time(1709214356.0);
+        rtc.write(RTC_CTRL, 0);
+        assert_eq!(rtc.read(RTC_SEC), 56);
+        assert_eq!(rtc.read(RTC_YR), 24);
+    }
+}
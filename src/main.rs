@@ -1,6 +1,6 @@
 use crate::parse_args::parse_args;
 use agon_ez80_emulator::debugger::{DebugCmd, DebugResp, DebuggerConnection, PauseReason, Trigger};
-use agon_ez80_emulator::{gpio, AgonMachine, AgonMachineConfig, GpioVgaFrame, RamInit, SerialLink};
+use agon_ez80_emulator::{gpio, AgonMachine, AgonMachineConfig, ExitReason, GpioVgaFrame, RamInit, SerialLink};
 use sdl3;
 use sdl3::event::Event;
 use sdl3_sys::everything::{SDL_ScaleMode, SDL_SetTextureScaleMode};
@@ -92,6 +92,7 @@ pub fn main_loop() -> i32 {
     let emulator_shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let soft_reset = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let exit_status = std::sync::Arc::new(std::sync::atomic::AtomicI32::new(0));
+    let exit_reason = std::sync::Arc::new(std::sync::atomic::AtomicU8::new(ExitReason::None as u8));
 
     for breakpoint in &args.breakpoints {
         let trigger = Trigger {
@@ -175,6 +176,7 @@ pub fn main_loop() -> i32 {
 
     let _cpu_thread = {
         let _exit_status = exit_status.clone();
+        let _exit_reason = exit_reason.clone();
         let _ez80_paused = ez80_paused.clone();
         let _emulator_shutdown = emulator_shutdown.clone();
         let soft_reset_ez80 = soft_reset.clone();
@@ -234,6 +236,7 @@ pub fn main_loop() -> i32 {
                     soft_reset: soft_reset_ez80,
                     emulator_shutdown: _emulator_shutdown,
                     exit_status: _exit_status,
+                    exit_reason: _exit_reason,
                     paused: _ez80_paused,
                     tx_gpio_vga_frame,
                     clockspeed_hz: if args.unlimited_cpu {
@@ -244,6 +247,7 @@ pub fn main_loop() -> i32 {
                     mos_bin: ez80_firmware,
                     embedded_mos: Some(include_bytes!("../firmware/mos_console8.bin")),
                     interrupt_precision: if args.precise_interrupts { 1 } else { 16 },
+                    max_instructions: None,
                 });
                 machine.set_sdcard_directory(sdcard_dir);
                 machine.set_sdcard_image(sdcard_img_file);
@@ -745,7 +749,12 @@ pub fn main_loop() -> i32 {
     }
     std::thread::sleep(std::time::Duration::from_millis(200));
 
-    return exit_status.load(std::sync::atomic::Ordering::Relaxed);
+    let status = exit_status.load(std::sync::atomic::Ordering::Relaxed);
+    let reason = ExitReason::from_u8(exit_reason.load(std::sync::atomic::Ordering::Relaxed));
+    if reason.ends_run() {
+        eprintln!("{}", reason.exit_message(status));
+    }
+    return status;
 }
 
 fn calc_int_scale(canvas_size: (u32, u32), agon_size: (u32, u32)) -> (u32, u32) {